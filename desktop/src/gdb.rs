@@ -1,7 +1,8 @@
 use std::error::Error;
 
-use gdbstub::{arch, target::{Target, TargetResult, ext::{base::{ResumeAction, singlethread::{SingleThreadOps, StopReason}}, breakpoints::{HwBreakpoint, HwWatchpoint, SwBreakpoint, SwBreakpointOps}}}};
+use gdbstub::{arch, target::{Target, TargetResult, ext::{base::{ResumeAction, singlethread::{SingleThreadOps, StopReason}}, breakpoints::{HwBreakpoint, HwWatchpoint, SwBreakpoint, SwBreakpointOps, WatchKind}}}};
 use crate::{EmuMessage, EmuState, emu_loop_step};
+use psx_emu::{HaltReason, WatchKind as EmuWatchKind};
 
 impl Target for EmuState {
     type Arch = arch::mips::Mips;
@@ -57,7 +58,17 @@ impl SingleThreadOps for EmuState {
                 loop {
                     if self.emu.halt_requested() {
                         println!("Halt hit!");
-                        return Ok(StopReason::SwBreak);
+                        return Ok(match self.emu.last_halt_reason() {
+                            Some(HaltReason::Watchpoint { addr, kind }) => StopReason::Watch {
+                                kind: match kind {
+                                    EmuWatchKind::Read => WatchKind::Read,
+                                    EmuWatchKind::Write => WatchKind::Write,
+                                    EmuWatchKind::Access => WatchKind::ReadWrite,
+                                },
+                                addr,
+                            },
+                            _ => StopReason::SwBreak,
+                        });
                     }
                     if let Err(e) = emu_loop_step(self) {
                         println!("EmuThread: Encountered error: {:?}, exiting...", e);
@@ -88,9 +99,9 @@ impl SingleThreadOps for EmuState {
         regs.lo = self.emu.r3000.lo;
         regs.pc = self.emu.r3000.pc;
 
-        regs.cp0.status = self.emu.r3000.cop0.read_reg(12);
-        regs.cp0.cause = self.emu.r3000.cop0.read_reg(13);
-        regs.cp0.badvaddr = self.emu.r3000.cop0.read_reg(14);
+        regs.cp0.status = self.emu.status();
+        regs.cp0.cause = self.emu.cause();
+        regs.cp0.badvaddr = self.emu.bad_vaddr();
 
         Ok(())
     }
@@ -106,9 +117,9 @@ impl SingleThreadOps for EmuState {
         self.emu.r3000.lo = regs.lo;
         self.emu.r3000.pc = regs.pc;
 
-        self.emu.r3000.cop0.write_reg(12, regs.cp0.status);
-        self.emu.r3000.cop0.write_reg(13, regs.cp0.cause);
-        self.emu.r3000.cop0.write_reg(14, regs.cp0.badvaddr);
+        self.emu.write_cop0_reg(12, regs.cp0.status);
+        self.emu.write_cop0_reg(13, regs.cp0.cause);
+        self.emu.write_cop0_reg(8, regs.cp0.badvaddr);
 
         Ok(())
     }
@@ -118,9 +129,8 @@ impl SingleThreadOps for EmuState {
         start_addr: u32,
         data: &mut [u8],
     ) -> gdbstub::target::TargetResult<(), Self> {
-        for i in 0..data.len() {
-            data[i] = self.emu.r3000.read_bus_byte(start_addr + i as u32);
-        }
+        let memory = self.emu.read_memory(start_addr, data.len());
+        data.copy_from_slice(&memory);
         Ok(())
     }
 
@@ -129,9 +139,7 @@ impl SingleThreadOps for EmuState {
         start_addr: u32,
         data: &[u8],
     ) -> gdbstub::target::TargetResult<(), Self> {
-        for i in 0..data.len() {
-            self.emu.r3000.main_bus.write_byte(start_addr + i as u32, data[i]);
-        }
+        self.emu.write_memory(start_addr, data);
 
         Ok(())
     }
@@ -175,7 +183,12 @@ impl HwWatchpoint for EmuState {
         kind: gdbstub::target::ext::breakpoints::WatchKind,
     ) -> TargetResult<bool, Self> {
         println!("Trying to add watchpoint...");
-        self.emu.add_watchpoint(addr);
+        let kind = match kind {
+            WatchKind::Read => EmuWatchKind::Read,
+            WatchKind::Write => EmuWatchKind::Write,
+            WatchKind::ReadWrite => EmuWatchKind::Access,
+        };
+        self.emu.add_watchpoint(addr, kind);
         TargetResult::<bool, Self>::Ok(true)
     }
 
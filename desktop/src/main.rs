@@ -1,5 +1,3 @@
-use byteorder::{ByteOrder, LittleEndian};
-use disc::*;
 use gdbstub::{DisconnectReason, GdbStub, GdbStubError};
 use getopts::Options;
 use psx_emu::controller::ButtonState;
@@ -19,7 +17,6 @@ use std::time::SystemTime;
 use std::time::Duration;
 use simple_logger::SimpleLogger;
 
-mod disc;
 mod gdb;
 mod gui;
 mod support;
@@ -71,15 +68,13 @@ fn main() {
         DEFAULT_BIOS_PATH.to_string()
     };
 
-    let bios_data = match fs::read(&bios_path) {
-        Ok(data) => data,
-        _ => {
-            println!("Unable to read bios file!");
+    let mut emu = match PSXEmu::from_bios_file(Path::new(&bios_path)) {
+        Ok(emu) => emu,
+        Err(e) => {
+            println!("Unable to start emulator: {}", e);
             return;
         }
     };
-
-    let mut emu = PSXEmu::new(bios_data);
     emu.reset();
 
     if matches.opt_present("l") {
@@ -94,22 +89,22 @@ fn main() {
 
     if let Some(disc_path) = matches.opt_str("c") {
         println!("Loading CUE: {}", disc_path);
-        let disc = load_disc_from_cuesheet(Path::new(&disc_path).to_path_buf());
-        emu.load_disc(disc);
+        if let Err(e) = emu.load_disc_file(Path::new(&disc_path)) {
+            println!("Unable to load disc: {}", e);
+            return;
+        }
+        if let Some(disc) = emu.loaded_disc() {
+            println!("Disc region: {:?}", disc.region());
+        }
     }
 
     if let Some(exe_path) = matches.opt_str("e") {
         println!("Loading executable: {}", exe_path);
         let exe = fs::read(exe_path).unwrap();
-        let exe_data = exe[0x800..].to_vec();
-        let destination = LittleEndian::read_u32(&exe[0x18..0x1C]);
-        let entrypoint = LittleEndian::read_u32(&exe[0x10..0x14]);
-        let init_sp = LittleEndian::read_u32(&exe[0x30..0x34]);
-        println!(
-            "Destination is {:#X}\nEntrypoint is {:#X}\nSP is {:#X}",
-            destination, entrypoint, init_sp
-        );
-        emu.load_executable(destination, entrypoint, init_sp, &exe_data);
+        if let Err(e) = emu.sideload_psx_exe(&exe) {
+            println!("Unable to load executable: {}", e);
+            return;
+        }
     }
 
     let (emu_sender, client_receiver) = channel();
@@ -273,6 +268,11 @@ fn emu_loop_step(state: &mut EmuState) -> Result<(), EmuThreadError> {
     if !state.halted && !state.waiting_for_client {
         state.emu.step_cycle();
 
+        let tty = state.emu.take_tty_output();
+        if !tty.is_empty() {
+            print!("{}", tty);
+        }
+
         if state.emu.frame_ready() {
             //Check for any viewport resolution changes
             if state.emu.display_resolution() != state.current_resolution {
@@ -288,7 +288,12 @@ fn emu_loop_step(state: &mut EmuState) -> Result<(), EmuThreadError> {
                 .expect("Error getting frame duration")
                 .as_millis();
     
-            let frame = state.emu.get_vram().clone();
+            // Games blank the display while loading, don't keep showing the last frame
+            let frame = if state.emu.display_enabled() {
+                state.emu.get_vram().clone()
+            } else {
+                vec![0; state.emu.get_vram().len()]
+            };
     
             // Send the new frame over to the gui thread
             if let Err(_) = state
@@ -1,12 +1,32 @@
 use byteorder::{ByteOrder, LittleEndian};
 
+use crate::crc::crc32;
+use crate::EmuInitError;
+
+/// Every PSX BIOS image is exactly 512 KiB
+pub const BIOS_SIZE: usize = 512 * 1024;
+
+/// CRC-32s of unmodified retail BIOS dumps
+const KNOWN_BIOSES: [(u32, &str); 4] = [
+    (0x3715_7331, "SCPH-1001 (NTSC-U v2.2)"),
+    (0xFF3E_EB8C, "SCPH-5500 (NTSC-J v3.0)"),
+    (0x8D8C_B7E4, "SCPH-5501 (NTSC-U v3.0)"),
+    (0xD786_F0B9, "SCPH-5502 (PAL v3.0)"),
+];
+
 pub struct Bios {
     data: Vec<u8>,
 }
 
 impl Bios {
-    pub fn new(data: Vec<u8>) -> Bios {
-        Bios { data }
+    pub fn new(data: Vec<u8>) -> Result<Bios, EmuInitError> {
+        if data.len() != BIOS_SIZE {
+            return Err(EmuInitError::BiosWrongSize {
+                expected: BIOS_SIZE,
+                got: data.len(),
+            });
+        }
+        Ok(Bios { data })
     }
 
     pub fn read_word(&self, addr: u32) -> u32 {
@@ -20,4 +40,13 @@ impl Bios {
     pub fn get_data(&self) -> &Vec<u8> {
         &self.data
     }
+
+    /// The model this image was dumped from, or None for a patched or unknown BIOS
+    pub fn identify(&self) -> Option<&'static str> {
+        let crc = crc32(&self.data);
+        KNOWN_BIOSES
+            .iter()
+            .find(|(known, _)| *known == crc)
+            .map(|(_, model)| *model)
+    }
 }
@@ -1,25 +1,130 @@
+use byteorder::{ByteOrder, LittleEndian};
 use log::{error, info, warn};
+use std::fmt;
 
-use crate::LOGGING;
+use crate::{HaltReason, WatchKind};
 use crate::bios::Bios;
 use crate::cdrom::CDDrive;
 use crate::controller::Controllers;
 use crate::dma::DMAState;
 use crate::gpu::Gpu;
-use crate::memory::Memory;
-use crate::spu::SPU;
+use crate::interrupts::{Interrupts, I_MASK, I_MASK_END, I_STAT};
+use crate::mem_control::{MemControl, MEM_CONTROL_END, MEM_CONTROL_START, RAM_SIZE};
+use crate::memory::{Memory, RAM_BYTES, RAM_MASK};
+use crate::rng::SplitMix64;
+use crate::spu::Spu;
+
+/// Cache control register at 0xFFFE0130, as seen after masking off the segment bits
+const CACHE_CONTROL: u32 = 0x1FFE_0130;
+
+/// The hardware registers, which is what the bus log records
+const IO_START: u32 = 0x1F80_1000;
+const IO_END: u32 = 0x1F80_2FFF;
+/// Expansion region 1, where a cartridge would sit
+const EXPANSION_1_START: u32 = 0x1F00_0000;
+const EXPANSION_1_END: u32 = 0x1F7F_FFFF;
+
+/// A bus access hit an address that isn't mapped to any device
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BusError {
+    pub addr: u32,
+    /// Access width in bytes
+    pub width: u8,
+    pub is_write: bool,
+}
+
+impl BusError {
+    /// Unemulated hardware registers and expansion devices read back as 0 instead of raising
+    /// a bus error on CPU loads, like the open bus they are on a console without them
+    fn reads_as_zero(&self) -> bool {
+        let addr = self.addr & 0x1fffffff;
+        (IO_START..=IO_END).contains(&addr) || (EXPANSION_1_START..=EXPANSION_1_END).contains(&addr)
+    }
+}
+
+impl fmt::Display for BusError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self.width {
+            4 => "word",
+            2 => "half word",
+            _ => "byte",
+        };
+        write!(
+            f,
+            "Invalid {} {} at address {:#X}! This address is not mapped to any device.",
+            kind,
+            if self.is_write { "write" } else { "read" },
+            self.addr
+        )
+    }
+}
+
+impl std::error::Error for BusError {}
+
+/// A hardware register access recorded by the bus log
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BusAccess {
+    /// Address of the instruction that made the access
+    pub pc: u32,
+    /// Physical address
+    pub addr: u32,
+    pub value: u32,
+    /// Access width in bytes
+    pub width: u8,
+    pub is_write: bool,
+}
+
+/// The last capacity I/O accesses, oldest first
+struct BusLog {
+    capacity: usize,
+    /// Holds up to twice capacity accesses, so the oldest only have to be shifted out once
+    /// every capacity pushes and the newest stay in one slice
+    entries: Vec<BusAccess>,
+    /// Set by the CPU before every instruction
+    pc: u32,
+}
+
+/// The scratchpad is data cache, so it can't be reached through the uncached KSEG1 segment
+fn uncached_scratchpad(og_addr: u32) -> bool {
+    (0xBF80_0000..=0xBF80_03FF).contains(&og_addr)
+}
+
+/// A ROM image mapped over the rest of the address space, for running CPU test suites
+struct TestRom {
+    /// Physical address of the first byte
+    base: u32,
+    data: Vec<u8>,
+}
+
+impl TestRom {
+    fn bytes(&self, addr: u32, width: usize) -> Option<&[u8]> {
+        let offset = addr.wrapping_sub(self.base) as usize;
+        self.data.get(offset..offset.checked_add(width)?)
+    }
+}
 
 pub struct MainBus {
     pub bios: Bios,
     pub memory: Memory,
     pub gpu: Gpu,
     pub dma: DMAState,
-    spu: SPU,
+    pub spu: Spu,
     pub cd_drive: CDDrive,
     scratchpad: Memory,
     pub(super) controllers: Controllers,
+    pub interrupts: Interrupts,
+    mem_control: MemControl,
 
     pub last_touched_addr: u32,
+    strict: bool,
+    cache_control: u32,
+
+    watchpoints: Vec<(u32, WatchKind)>,
+    watch_hit: Option<HaltReason>,
+    test_rom: Option<TestRom>,
+    /// Seeds the garbage power on leaves in memory. None leaves it zeroed
+    power_on_seed: Option<u64>,
+    bus_log: Option<BusLog>,
 }
 
 impl MainBus {
@@ -29,130 +134,321 @@ impl MainBus {
             memory,
             gpu,
             dma: DMAState::new(),
-            spu: SPU::new(),
+            spu: Spu::new(),
             cd_drive: CDDrive::new(),
             scratchpad: Memory::new_scratchpad(),
             controllers: Controllers::new(),
+            interrupts: Interrupts::new(),
+            mem_control: MemControl::new(),
 
             last_touched_addr: 0,
+            strict: false,
+            cache_control: 0,
+
+            watchpoints: Vec::new(),
+            watch_hit: None,
+            test_rom: None,
+            power_on_seed: None,
+            bus_log: None,
+        }
+    }
+
+    /// From the next power on, RAM, VRAM and SPU RAM start out filled from seed
+    pub fn set_power_on_seed(&mut self, seed: u64) {
+        self.power_on_seed = Some(seed);
+    }
+
+    /// Maps a read only ROM at base, in front of whatever is normally there. Writes to it
+    /// still go to the underlying device
+    pub fn map_test_rom(&mut self, base: u32, data: Vec<u8>) {
+        self.test_rom = Some(TestRom {
+            base: base & 0x1fffffff,
+            data,
+        });
+    }
+
+    /// Starts recording the last capacity hardware register accesses, dropping anything
+    /// logged so far. A capacity of 0 turns the log off
+    pub fn enable_bus_log(&mut self, capacity: usize) {
+        self.bus_log = if capacity == 0 {
+            None
+        } else {
+            Some(BusLog {
+                capacity,
+                entries: Vec::with_capacity(capacity * 2),
+                pc: 0,
+            })
+        };
+    }
+
+    /// The recorded accesses, oldest first. Empty while the log is off
+    pub fn bus_log(&self) -> &[BusAccess] {
+        match &self.bus_log {
+            Some(log) => &log.entries[log.entries.len().saturating_sub(log.capacity)..],
+            None => &[],
+        }
+    }
+
+    /// Tells the log which instruction the following accesses belong to
+    pub fn set_log_pc(&mut self, pc: u32) {
+        if let Some(log) = &mut self.bus_log {
+            log.pc = pc;
+        }
+    }
+
+    fn log_access(&mut self, addr: u32, value: u32, width: u8, is_write: bool) {
+        if let Some(log) = &mut self.bus_log {
+            if !(IO_START..=IO_END).contains(&addr) {
+                return;
+            }
+            if log.entries.len() == log.capacity * 2 {
+                log.entries.drain(..log.capacity);
+            }
+            log.entries.push(BusAccess {
+                pc: log.pc,
+                addr,
+                value,
+                width,
+                is_write,
+            });
         }
     }
 
+    fn test_rom_bytes(&self, addr: u32, width: usize) -> Option<&[u8]> {
+        self.test_rom.as_ref().and_then(|rom| rom.bytes(addr, width))
+    }
+
+    /// Puts every device back in its power on state, clearing RAM, VRAM, SPU RAM and the
+    /// scratchpad, or filling the first three from the power on seed if there is one. The BIOS,
+    /// the inserted disc and memory card, the video mode, internal scale and debugger settings are kept
+    pub fn power_on(&mut self) {
+        let disc = self.cd_drive.remove_disc();
+        let memory_card = self.controllers.remove_memory_card();
+        let video_mode = self.gpu.video_mode();
+        let internal_scale = self.gpu.internal_scale();
+
+        self.memory = Memory::new();
+        self.gpu = Gpu::new();
+        self.gpu.set_video_mode(video_mode);
+        self.dma = DMAState::new();
+        self.spu = Spu::new();
+        self.cd_drive = CDDrive::new();
+        self.scratchpad = Memory::new_scratchpad();
+        self.controllers = Controllers::new();
+        self.interrupts = Interrupts::new();
+        self.mem_control = MemControl::new();
+        self.last_touched_addr = 0;
+        self.cache_control = 0;
+        self.watch_hit = None;
+
+        if let Some(seed) = self.power_on_seed {
+            let mut rng = SplitMix64::new(seed);
+            rng.fill_bytes(&mut self.memory.data);
+            rng.fill_halfwords(self.gpu.get_vram_mut());
+            rng.fill_bytes(self.spu.ram_mut());
+        }
+        // Builds the upscaled VRAM from whatever the native one now holds
+        self.gpu.set_internal_scale(internal_scale);
+        if let Some(disc) = disc {
+            self.cd_drive.load_disc(disc);
+        }
+        if let Some(card) = memory_card {
+            self.controllers.insert_memory_card(card);
+        }
+    }
+
+    /// When strict, accesses of any width to unmapped addresses panic instead of being logged.
+    /// Off by default
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    pub fn strict(&self) -> bool {
+        self.strict
+    }
+
+    fn unmapped_access(&self, err: BusError) {
+        if self.strict {
+            panic!("{}", err);
+        }
+        error!("{}", err);
+    }
+
+    /// Like try_read_word, but unemulated hardware registers read as 0. Anything else that
+    /// isn't mapped is handed back for the CPU to raise a bus error with
+    fn load<T: Default>(&mut self, result: Result<T, BusError>) -> Result<T, BusError> {
+        result.or_else(|err| {
+            self.unmapped_access(err);
+            if err.reads_as_zero() {
+                Ok(T::default())
+            } else {
+                Err(err)
+            }
+        })
+    }
+
+    /// A CPU data load. Unmapped reads outside the hardware registers are errors
+    pub fn load_word(&mut self, og_addr: u32) -> Result<u32, BusError> {
+        self.check_watchpoints(og_addr, 4, WatchKind::Read);
+        let result = self.try_read_word(og_addr);
+        self.load(result)
+    }
+
+    pub fn load_half_word(&mut self, og_addr: u32) -> Result<u16, BusError> {
+        self.check_watchpoints(og_addr, 2, WatchKind::Read);
+        let result = self.try_read_half_word(og_addr);
+        self.load(result)
+    }
+
+    pub fn load_byte(&mut self, og_addr: u32) -> Result<u8, BusError> {
+        self.check_watchpoints(og_addr, 1, WatchKind::Read);
+        let result = self.try_read_byte(og_addr);
+        self.load(result)
+    }
+
     pub fn read_word(&mut self, og_addr: u32) -> u32 {
+        self.check_watchpoints(og_addr, 4, WatchKind::Read);
+        self.try_read_word(og_addr).unwrap_or_else(|err| {
+            self.unmapped_access(err);
+            0
+        })
+    }
+
+    pub fn try_read_word(&mut self, og_addr: u32) -> Result<u32, BusError> {
         let addr = og_addr & 0x1fffffff;
-        if addr == 0x1F01F00{
-            println!("The thingy got read")
+        if uncached_scratchpad(og_addr) {
+            return Err(BusError { addr: og_addr, width: 4, is_write: false });
+        }
+        if let Some(bytes) = self.test_rom_bytes(addr, 4) {
+            return Ok(LittleEndian::read_u32(bytes));
         }
         let word = match addr {
-            0x0..=0x001f_ffff => self.memory.read_word(addr),
+            addr if self.ram_mapped(addr) => self.memory.read_word(addr & RAM_MASK),
+            I_STAT | I_MASK => self.interrupts.read_word(addr),
             0x1f801810 => self.gpu.read_word_gp0(),
             0x1f801814 => self.gpu.read_status_register(),
-            0x1F80101C => 0x00070777, //Expansion 2 delay/size
+            MEM_CONTROL_START..=MEM_CONTROL_END | RAM_SIZE => self.mem_control.read_word(addr),
             0x1F801080..=0x1F8010F4 => self.dma.read_word(addr),
             0x1fc0_0000..=0x1fc7_ffff => self.bios.read_word(addr - 0x1fc0_0000),
             0x1F800000..=0x1F8003FF => self.scratchpad.read_word(addr - 0x1F800000),
-            0x1F801014 => 0x200931E1, //SPU_DELAY
-            0x1F801060 => 0x00000B88, //RAM_SIZE
             0x1F801824 => 0, //MDEC_IN
-            _ => panic!(
-                "Invalid word read at address {:#X}! This address is not mapped to any device.",
-                addr
-            ),
+            CACHE_CONTROL => self.cache_control,
+            0x1F801C00..=0x1F801E80 => {
+                self.spu.read_half_word(addr) as u32
+                    | (self.spu.read_half_word(addr + 2) as u32) << 16
+            }
+            _ => return Err(BusError { addr, width: 4, is_write: false }),
         };
         //println!("Read {:#X} word from bus address {:#X}", word, addr);
-        if unsafe{LOGGING} {println!("Loaded {:#X} from addr {:#X}", word, addr)};
-        word
+        self.log_access(addr, word, 4, false);
+        Ok(word)
     }
 
     pub fn write_word(&mut self, og_addr: u32, word: u32) {
+        self.check_watchpoints(og_addr, 4, WatchKind::Write);
         let addr = og_addr & 0x1fffffff;
-        self.last_touched_addr = addr;
-
-        if addr == 0x121CA8 {
-            println!("touched");
+        if uncached_scratchpad(og_addr) {
+            error!("Scratchpad write through KSEG1 at {:#X} ignored", og_addr);
+            return;
         }
+        self.last_touched_addr = addr;
+        self.log_access(addr, word, 4, true);
 
         match addr {
             0x1F802002 => info!("Serial: {}", word),
             0x1F802023 => info!("DUART A: {}", word),
             0x1F80202B => info!("DUART B: {}", word),
             0x1F801050 => info!("SIO: {}", word),
-            0x0..=0x001f_ffff => self.memory.write_word(addr, word), //KUSEG
-            0x1F801000 => info!("Expansion 1 base write"),
-            0x1F801004 => info!("Expansion 2 base write"),
-            0x1F801008 => info!("Expansion 1 delay/size write"),
-            0x1F801010 => info!("BIOS ROM Control WORD write"),
-            0x1F801060 => info!("RAM SIZE WORD write {:#X}", word),
-            0x1F801020 => info!("COM_DELAY WORD write"),
-            0x1F801014 => info!("SPU_DELAY size write"),
-            0x1F801018 => info!("CDROM_DELAY size write"),
-            0x1F80101C => info!("Expansion 2 delay/size write"),
+            addr if self.ram_mapped(addr) => self.memory.write_word(addr & RAM_MASK, word),
+            I_STAT | I_MASK => self.interrupts.write_word(addr, word),
+            MEM_CONTROL_START..=MEM_CONTROL_END | RAM_SIZE => self.mem_control.write_word(addr, word),
             0x1F801080..=0x1F8010F4 => self.dma.write_word(addr, word),
-            0x1F80100C => info!("Expansion 3 Delay/size write"),
             0x1F801810 => self.gpu.send_gp0_command(word),
             0x1F801814 => self.gpu.send_gp1_command(word),
             0x1F800000..=0x1F8003FF => self.scratchpad.write_word(addr - 0x1F800000, word),
+            0x1F801C00..=0x1F801E80 => {
+                self.spu.write_half_word(addr, word as u16);
+                self.spu.write_half_word(addr + 2, (word >> 16) as u16);
+            }
             0x1f80_1000..=0x1f80_2fff => warn!("Something tried to write to the hardware control registers. These are not currently emulated. The address was {:#X}. Value {:#X}", addr, word),
+            CACHE_CONTROL => self.cache_control = word,
             0x1FFE0000..=0x1FFE0200 => warn!("Something tried to write to the cache control registers. These are not currently emulated. The address was {:#X}", addr),
-            _ => {
-                panic!(
-                    "Invalid word write at address {:#X}! This address is not mapped to any device.",
-                    addr
-                );
-            }
+            _ => self.unmapped_access(BusError { addr, width: 4, is_write: true }),
         }
     }
 
     pub fn read_half_word(&mut self, og_addr: u32) -> u16 {
+        self.check_watchpoints(og_addr, 2, WatchKind::Read);
+        self.try_read_half_word(og_addr).unwrap_or_else(|err| {
+            self.unmapped_access(err);
+            0
+        })
+    }
+
+    pub fn try_read_half_word(&mut self, og_addr: u32) -> Result<u16, BusError> {
         let addr = og_addr & 0x1fffffff;
+        if uncached_scratchpad(og_addr) {
+            return Err(BusError { addr: og_addr, width: 2, is_write: false });
+        }
+        if let Some(bytes) = self.test_rom_bytes(addr, 2) {
+            return Ok(LittleEndian::read_u16(bytes));
+        }
         let val = match addr {
-            0x1F801070 => {
-                panic!("Tried to read i_status half");
-            },
-            0x0..=0x001f_ffff => self.memory.read_half_word(addr),
+            I_STAT..=I_MASK_END => self.interrupts.read_half_word(addr),
+            addr if self.ram_mapped(addr) => self.memory.read_half_word(addr & RAM_MASK),
             0x1F801C00..=0x1F801E80 => self.spu.read_half_word(addr),
             0x1F800000..=0x1F8003FF => self.scratchpad.read_half_word(addr - 0x1F800000),
             0x1F80_1040..=0x1F80_104E => self.controllers.read_half_word(addr),
-            _ => panic!("Invalid half word read at address {:#X}! This address is not mapped to any device.", addr)
+            _ => return Err(BusError { addr, width: 2, is_write: false }),
         };
-        if unsafe{LOGGING} {println!("Loaded {:#X} from addr {:#X}", val, addr)};
-        val
+        self.log_access(addr, val as u32, 2, false);
+        Ok(val)
     }
 
     pub fn write_half_word(&mut self, og_addr: u32, value: u16) {
+        self.check_watchpoints(og_addr, 2, WatchKind::Write);
         let addr = og_addr & 0x1fffffff;
-        self.last_touched_addr = addr;
-
-        if addr == 0x121CA8 {
-            println!("touched");
+        if uncached_scratchpad(og_addr) {
+            error!("Scratchpad write through KSEG1 at {:#X} ignored", og_addr);
+            return;
         }
+        self.last_touched_addr = addr;
+        self.log_access(addr, value as u32, 2, true);
 
         match addr {
             0x1F802002 => info!("Serial: {}", value),
             0x1F802023 => info!("DUART A: {}", value),
             0x1F80202B => info!("DUART B: {}", value),
             0x1F801050 => info!("SIO: {}", value),
-            0x0..=0x001f_ffff => self.memory.write_half_word(addr, value), //KUSEG
+            addr if self.ram_mapped(addr) => self.memory.write_half_word(addr & RAM_MASK, value),
+            I_STAT..=I_MASK_END => self.interrupts.write_half_word(addr, value),
             0x1F801C00..=0x1F801E80 => self.spu.write_half_word(addr, value),
             0x1F800000..=0x1F8003FF => self.scratchpad.write_half_word(addr - 0x1F800000, value),
             0x1F80_1040..=0x1F80_104E => self.controllers.write_half_word(addr, value),
             0x1F80_1000..=0x1F80_2000 => warn!("Something tried to half word write to the I/O ports. This is not currently emulated. The address was {:#X}. value was {:#X}", addr, value),
-            _ => println!("Invalid half word write at address {:#X}! This address is not mapped to any device.", addr)
+            _ => self.unmapped_access(BusError { addr, width: 2, is_write: true }),
         }
     }
 
     pub fn read_byte(&mut self, og_addr: u32) -> u8 {
+        self.check_watchpoints(og_addr, 1, WatchKind::Read);
+        self.try_read_byte(og_addr).unwrap_or_else(|err| {
+            self.unmapped_access(err);
+            0
+        })
+    }
+
+    pub fn try_read_byte(&mut self, og_addr: u32) -> Result<u8, BusError> {
         let addr = og_addr & 0x1fffffff;
+        if uncached_scratchpad(og_addr) {
+            return Err(BusError { addr: og_addr, width: 1, is_write: false });
+        }
+        if let Some(bytes) = self.test_rom_bytes(addr, 1) {
+            return Ok(bytes[0]);
+        }
         let val = match addr {
-            0x1F801070 => {
-                warn!("Tried to read i_status word");
-                0
-            }
-            0x1F801074 => {
-                warn!("Tried to read i_mask byte");
-                0
-            }
-            0x0..=0x001f_ffff => self.memory.read_byte(addr), //KUSEG
+            I_STAT..=I_MASK_END => self.interrupts.read_byte(addr),
+            addr if self.ram_mapped(addr) => self.memory.read_byte(addr & RAM_MASK),
             0x1F00_0000..=0x1f00_FFFF => {
                 //println!("Something tried to read the parallel port. This is not currently emulated, so a 0 was returned. The address was {:#X}", addr);
                 0
@@ -161,28 +457,25 @@ impl MainBus {
             0x1F801800..=0x1F801803 => self.cd_drive.read_byte(addr), //CDROM
             0x1F80_1040..=0x1F80_104E => self.controllers.read_byte(addr),
             0x1F800000..=0x1F8003FF => self.scratchpad.read_byte(addr - 0x1F800000),
-            _ => {
-                error!(
-                    "Invalid byte read at address {:#X}! This address is not mapped to any device.",
-                    addr
-                );
-                0
-            }
+            _ => return Err(BusError { addr, width: 1, is_write: false }),
         };
-        if unsafe{LOGGING} {println!("Loaded {:#X} from addr {:#X}", val, addr)};
-        val
+        self.log_access(addr, val as u32, 1, false);
+        Ok(val)
     }
 
     pub fn write_byte(&mut self, og_addr: u32, value: u8) {
+        self.check_watchpoints(og_addr, 1, WatchKind::Write);
         let addr = og_addr & 0x1fffffff;
-        self.last_touched_addr = addr & 0x1fffffff;
-
-        if addr == 0x121CA8 {
-            println!("touched");
+        if uncached_scratchpad(og_addr) {
+            error!("Scratchpad write through KSEG1 at {:#X} ignored", og_addr);
+            return;
         }
+        self.last_touched_addr = addr & 0x1fffffff;
+        self.log_access(addr, value as u32, 1, true);
 
         match addr {
-            0x0..=0x001f_ffff => self.memory.write_byte(addr, value), //KUSEG
+            addr if self.ram_mapped(addr) => self.memory.write_byte(addr & RAM_MASK, value),
+            I_STAT..=I_MASK_END => self.interrupts.write_byte(addr, value),
             0x1F801800..=0x1F801803 => self.cd_drive.write_byte(addr, value), //CDROM
             0x1F802002 => info!("Serial: {}", value),
             0x1F802023 => info!("DUART A: {}", value),
@@ -191,10 +484,269 @@ impl MainBus {
             0x1F802000..=0x1F803000 => (), //Expansion port 2
             0x1F801040 => self.controllers.write_byte(addr, value),
             0x1F800000..=0x1F8003FF => self.scratchpad.write_byte(addr - 0x1F800000, value),
-            _ => error!(
-                "Invalid byte write at address {:#X}! This address is not mapped to any device.",
-                addr
-            ),
+            _ => self.unmapped_access(BusError { addr, width: 1, is_write: true }),
+        }
+    }
+
+    /// Copies data to addr. Runs that land in RAM are copied straight into it, skipping
+    /// watchpoints, and anything else goes through write_byte
+    pub fn load_bytes(&mut self, addr: u32, data: &[u8]) {
+        let mut offset = 0;
+        while offset < data.len() {
+            let target = addr.wrapping_add(offset as u32);
+            let physical = target & 0x1fffffff;
+            if !self.ram_mapped(physical) {
+                self.write_byte(target, data[offset]);
+                offset += 1;
+                continue;
+            }
+            // Stop at the end of the RAM window or wherever the next mirror starts
+            let start = (physical & RAM_MASK) as usize;
+            let len = (data.len() - offset)
+                .min((self.mem_control.ram_window() - physical) as usize)
+                .min(RAM_BYTES - start);
+            self.memory.data[start..start + len].copy_from_slice(&data[offset..offset + len]);
+            offset += len;
+        }
+    }
+
+    pub fn add_watchpoint(&mut self, addr: u32, kind: WatchKind) {
+        self.watchpoints.push((addr & 0x1fffffff, kind));
+    }
+
+    pub fn remove_watchpoint(&mut self, addr: u32) {
+        self.watchpoints.retain(|(watched, _)| *watched != addr & 0x1fffffff);
+    }
+
+    /// Returns the watchpoint hit since the last call, if any
+    pub fn take_watch_hit(&mut self) -> Option<HaltReason> {
+        self.watch_hit.take()
+    }
+
+    /// Records a hit if an access of width bytes at og_addr covers a watched address
+    fn check_watchpoints(&mut self, og_addr: u32, width: u32, access: WatchKind) {
+        if self.watchpoints.is_empty() || self.watch_hit.is_some() {
+            return;
+        }
+        let addr = og_addr & 0x1fffffff;
+        let hit = self.watchpoints.iter().find(|(watched, kind)| {
+            (*kind == access || *kind == WatchKind::Access)
+                && (addr..addr.wrapping_add(width)).contains(watched)
+        });
+        if let Some((watched, _)) = hit {
+            self.watch_hit = Some(HaltReason::Watchpoint {
+                addr: *watched,
+                kind: access,
+            });
+        }
+    }
+
+    pub fn cache_control(&self) -> u32 {
+        self.cache_control
+    }
+
+    /// RAM sits in the first 8MiB of every segment, mirrored across however much of that RAM_SIZE maps
+    fn ram_mapped(&self, addr: u32) -> bool {
+        addr < self.mem_control.ram_window()
+    }
+
+    /// Reads a byte from a memory region without side effects. Returns None for I/O or unmapped addresses
+    pub fn peek_byte(&self, og_addr: u32) -> Option<u8> {
+        if uncached_scratchpad(og_addr) {
+            return None;
+        }
+        let addr = og_addr & 0x1fffffff;
+        match addr {
+            addr if self.ram_mapped(addr) => Some(self.memory.read_byte(addr & RAM_MASK)),
+            0x1fc0_0000..=0x1fc7_ffff => Some(self.bios.read_byte(addr - 0x1fc0_0000)),
+            0x1F800000..=0x1F8003FF => Some(self.scratchpad.read_byte(addr - 0x1F800000)),
+            _ => None,
+        }
+    }
+
+    /// Writes a byte to a memory region without side effects. Returns false if the address isn't writable memory
+    pub fn poke_byte(&mut self, og_addr: u32, value: u8) -> bool {
+        if uncached_scratchpad(og_addr) {
+            return false;
+        }
+        let addr = og_addr & 0x1fffffff;
+        match addr {
+            addr if self.ram_mapped(addr) => self.memory.write_byte(addr & RAM_MASK, value),
+            0x1F800000..=0x1F8003FF => self.scratchpad.write_byte(addr - 0x1F800000, value),
+            _ => return false,
+        };
+        true
+    }
+}
+
+#[cfg(test)]
+mod bus_tests {
+    use super::*;
+    use crate::bios::BIOS_SIZE;
+
+    fn test_bus() -> MainBus {
+        MainBus::new(Bios::new(vec![0; BIOS_SIZE]).unwrap(), Memory::new(), Gpu::new())
+    }
+
+    #[test]
+    fn test_unmapped_read_returns_error() {
+        let mut bus = test_bus();
+        assert_eq!(
+            bus.try_read_word(0x1F10_0000),
+            Err(BusError { addr: 0x1F10_0000, width: 4, is_write: false })
+        );
+        assert_eq!(
+            bus.try_read_half_word(0x9F10_0000),
+            Err(BusError { addr: 0x1F10_0000, width: 2, is_write: false })
+        );
+        assert_eq!(bus.read_word(0x1F10_0000), 0);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_strict_unmapped_read_panics() {
+        let mut bus = test_bus();
+        bus.set_strict(true);
+        bus.read_word(0x1F10_0000);
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid byte read")]
+    fn test_strict_unmapped_byte_read_panics() {
+        let mut bus = test_bus();
+        bus.set_strict(true);
+        bus.read_byte(0x1F10_0000);
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid word write")]
+    fn test_strict_unmapped_write_panics() {
+        let mut bus = test_bus();
+        bus.set_strict(true);
+        bus.write_word(0x1F10_0000, 0);
+    }
+
+    #[test]
+    fn test_unmapped_accesses_logged_when_not_strict() {
+        let mut bus = test_bus();
+        bus.write_word(0x1F10_0000, 0);
+        bus.write_half_word(0x1F10_0000, 0);
+        bus.write_byte(0x1F10_0000, 0);
+        assert_eq!(bus.read_byte(0x1F10_0000), 0);
+
+        // CPU loads only error outside the hardware registers
+        assert_eq!(
+            bus.load_byte(0x1F90_0000),
+            Err(BusError { addr: 0x1F90_0000, width: 1, is_write: false })
+        );
+        assert_eq!(bus.load_word(0x1F80_1FF0), Ok(0));
+        assert_eq!(bus.load_word(0x1F10_0000), Ok(0));
+    }
+
+    #[test]
+    fn test_bus_log_wraps() {
+        let mut bus = test_bus();
+        bus.enable_bus_log(3);
+        for value in 0..10 {
+            bus.write_word(0x1F80_1C00, value);
         }
+
+        let values: Vec<u32> = bus.bus_log().iter().map(|access| access.value).collect();
+        assert_eq!(values, vec![7, 8, 9]);
+    }
+
+    #[test]
+    fn test_scratchpad_little_endian() {
+        let mut bus = test_bus();
+        bus.write_word(0x1F80_0010, 0x4433_2211);
+        assert_eq!(bus.read_byte(0x1F80_0010), 0x11);
+        assert_eq!(bus.read_byte(0x1F80_0011), 0x22);
+        assert_eq!(bus.read_byte(0x1F80_0012), 0x33);
+        assert_eq!(bus.read_byte(0x1F80_0013), 0x44);
+        assert_eq!(bus.read_half_word(0x9F80_0012), 0x4433);
+        assert_eq!(bus.read_word(0x9F80_0010), 0x4433_2211);
+    }
+
+    #[test]
+    fn test_scratchpad_not_mapped_in_kseg1() {
+        let mut bus = test_bus();
+        bus.write_word(0x1F80_0000, 0x1234_5678);
+        assert!(bus.try_read_word(0xBF80_0000).is_err());
+        assert!(bus.try_read_byte(0xBF80_0003).is_err());
+
+        bus.write_word(0xBF80_0000, 0);
+        assert_eq!(bus.read_word(0x1F80_0000), 0x1234_5678);
+    }
+
+    #[test]
+    fn test_spu_word_access() {
+        let mut bus = test_bus();
+        bus.write_word(0x1F80_1C00, 0x1FFF_3FFF);
+        assert_eq!(bus.read_half_word(0x1F80_1C00), 0x3FFF);
+        assert_eq!(bus.read_half_word(0x1F80_1C02), 0x1FFF);
+        assert_eq!(bus.read_word(0xBF80_1C00), 0x1FFF_3FFF);
+    }
+
+    #[test]
+    fn test_ram_mirrors() {
+        let mut bus = test_bus();
+        bus.write_word(0x0000_0004, 0xDEAD_BEEF);
+        assert_eq!(bus.read_word(0x0020_0004), 0xDEAD_BEEF);
+        assert_eq!(bus.read_word(0x0060_0004), 0xDEAD_BEEF);
+        assert_eq!(bus.read_word(0x8000_0004), 0xDEAD_BEEF);
+        assert_eq!(bus.read_half_word(0xA020_0006), 0xDEAD);
+
+        // Shrinking the window to 2MiB unmaps the mirrors
+        bus.write_word(RAM_SIZE, 0x0000_0888);
+        assert_eq!(bus.read_word(0x001F_FFFC), 0);
+        assert!(bus.try_read_word(0x0020_0004).is_err());
+    }
+
+    #[test]
+    fn test_load_bytes() {
+        let mut bus = test_bus();
+        let data: Vec<u8> = (0..0x10000u32).map(|i| (i ^ (i >> 8)) as u8).collect();
+        bus.load_bytes(0x8001_0000, &data);
+        assert_eq!(bus.read_word(0x0001_0000), 0x0302_0100);
+        assert_eq!(bus.read_word(0x8001_8124), 0xA6A7_A4A5);
+        assert_eq!(bus.read_word(0xA001_FFFC), 0x0001_0203);
+
+        // Runs wrap at the end of RAM, and the scratchpad goes through the slow path
+        bus.load_bytes(0x001F_FFFE, &[1, 2, 3, 4]);
+        assert_eq!(bus.read_half_word(0x001F_FFFE), 0x0201);
+        assert_eq!(bus.read_half_word(0x0000_0000), 0x0403);
+        bus.load_bytes(0x1F80_0000, &[5, 6, 7, 8]);
+        assert_eq!(bus.read_word(0x1F80_0000), 0x0807_0605);
+    }
+
+    #[test]
+    fn test_gpu_status_through_all_segments() {
+        let mut bus = test_bus();
+        let status = bus.read_word(0x1F80_1814);
+        assert_eq!(bus.read_word(0x9F80_1814), status);
+        assert_eq!(bus.read_word(0xBF80_1814), status);
+
+        // GP1 0x03 blanks the display, which shows up in bit 23 whichever segment reads it
+        bus.write_word(0xBF80_1814, 0x0300_0001);
+        assert_ne!(bus.read_word(0x1F80_1814) & (1 << 23), 0);
+        assert_ne!(bus.read_word(0xBF80_1814) & (1 << 23), 0);
+    }
+
+    #[test]
+    fn test_cdrom_get_stat_through_registers() {
+        let mut bus = test_bus();
+        bus.write_byte(0x1F80_1800, 1);
+        bus.write_byte(0x1F80_1802, 0x1F); // Enable every interrupt
+        assert_eq!(bus.read_byte(0x1F80_1803), 0xE0);
+        bus.write_byte(0x1F80_1800, 0);
+        assert_eq!(bus.read_byte(0xBF80_1803), 0xFF);
+
+        bus.write_byte(0xBF80_1801, 0x01); // GetStat
+        while !bus.cd_drive.execute_cycle() {}
+
+        bus.write_byte(0x1F80_1800, 1);
+        assert_eq!(bus.read_byte(0x1F80_1803) & 0x7, 3);
+        assert_ne!(bus.read_byte(0x1F80_1800) & (1 << 5), 0);
+        assert_eq!(bus.read_byte(0x1F80_1801), 0x02); // Motor on
     }
 }
@@ -1,10 +1,11 @@
 use bit_field::BitField;
-use log::trace;
+use log::{trace, warn};
 
-use super::{CDDrive, DriveState, IntCause, MotorState, Packet, disc::dec_to_bcd};
+use super::{CDDrive, DriveState, IntCause, MotorState, Packet, disc::{bcd_to_dec, dec_to_bcd}};
 use crate::cdrom::{DriveSpeed, disc::DiscIndex};
 
 pub(super) const AVG_FIRST_RESPONSE_TIME: u32 = 0xc4e1;
+pub(super) const AVG_SECOND_RESPONSE_TIME: u32 = 0x4a00;
 
 pub(super) fn get_bios_date() -> Packet {
     Packet {
@@ -34,12 +35,14 @@ pub(super) fn get_stat(state: &CDDrive) -> Packet {
 
 pub(super) fn get_id(state: &CDDrive) -> Packet {
     //Only handles 'No Disk' and 'Licensed Game' states
-    if state.disc.is_some() {
+    if let Some(disc) = &state.disc {
         let mut first_response = stat(state, 0x1a);
+        let mut response = vec![state.get_stat(), 0x00, 0x20, 0x00];
+        response.extend_from_slice(disc.region().license_string());
         let second_response = Packet {
             cause: IntCause::INT2,
-            response: vec![state.get_stat(), 0x00, 0x20, 0x00, 0x53, 0x43, 0x45, 0x41], //SCEA disk inserted
-            execution_cycles: 0x4a00,
+            response,
+            execution_cycles: AVG_SECOND_RESPONSE_TIME,
             extra_response: None,
             command: 0x1a,
         };
@@ -50,7 +53,7 @@ pub(super) fn get_id(state: &CDDrive) -> Packet {
         let second_response = Packet {
             cause: IntCause::INT5,
             response: vec![0x08, 0x40, 0, 0, 0, 0, 0, 0], //No disk
-            execution_cycles: 0x4a00,
+            execution_cycles: AVG_SECOND_RESPONSE_TIME,
             extra_response: None,
             command: 0x1a
         };
@@ -72,6 +75,7 @@ pub(super) fn set_loc(state: &mut CDDrive, minutes: u8, seconds: u8, frames: u8)
     state.seek_target = DiscIndex::new(minutes as usize, seconds as usize, frames as usize);
     state.seek_complete = false;
     state.read_offset = 0;
+    state.xa_active = false;
     state.data_queue.clear();
     //println!("set_loc to {:?}, total sectors: {}", state.seek_target, state.seek_target.as_address() / BYTES_PER_SECTOR as u32);
     stat(state, 0x2)
@@ -103,17 +107,13 @@ pub(super) fn set_mode(state: &mut CDDrive, mode: u8) -> Packet {
 pub(super) fn read_with_retry(state: &mut CDDrive) -> Packet {
     let mut initial_response = stat(state, 0x6);
     state.drive_state = DriveState::Read;
+    state.motor_state = MotorState::On; // Reading after a Stop spins the disc back up
     state.read_enabled = true;
 
-    let cycles = match state.drive_speed() {
-        DriveSpeed::Single => 0x6e1cd,
-        DriveSpeed::Double => 0x36cd2,
-    };
-
     let response_packet = Packet {
         cause: IntCause::INT1,
         response: vec![state.get_stat()],
-        execution_cycles: cycles,
+        execution_cycles: state.sector_cycles(),
         extra_response: None,
         command: 0x6,
     };
@@ -154,7 +154,50 @@ pub(super) fn pause_read(state: &mut CDDrive) -> Packet {
     initial_response
 }
 
+// Stop
+// Ends any read or playback like Pause, then spins the motor down. The second response
+// only comes once the disc has stopped, which takes longer from double speed
+pub(super) fn stop(state: &mut CDDrive) -> Packet {
+    let mut initial_response = stat(state, 0x8);
+
+    let cycles = if state.motor_state == MotorState::Off {
+        0x1d7b
+    } else {
+        match state.drive_speed() {
+            DriveSpeed::Double => 0x18a6076,
+            DriveSpeed::Single => 0xd38aca,
+        }
+    };
+
+    state.drive_state = DriveState::Idle;
+    state.motor_state = MotorState::Off;
+    state.read_enabled = false;
+
+    let response_packet = Packet {
+        cause: IntCause::INT2,
+        response: vec![state.get_stat()],
+        execution_cycles: cycles,
+        extra_response: None,
+        command: 0x8,
+    };
+    initial_response.execution_cycles = AVG_FIRST_RESPONSE_TIME;
+
+    initial_response.extra_response = Some(Box::new(response_packet));
+    initial_response
+}
+
+pub(super) fn set_filter(state: &mut CDDrive, file: u8, channel: u8) -> Packet {
+    state.xa_filter = (file, channel);
+    stat(state, 0xD)
+}
+
+pub(super) fn mute(state: &mut CDDrive) -> Packet {
+    state.muted = true;
+    stat(state, 0xB)
+}
+
 pub(super) fn demute(state: &mut CDDrive) -> Packet {
+    state.muted = false;
     stat(state, 0xC)
 }
 
@@ -185,6 +228,76 @@ pub(super) fn get_td(state: &mut CDDrive, track: u8) -> Packet {
     initial_response
 }
 
-pub(super) fn play(state: &mut CDDrive) -> Packet {
-    stat(state, 0x3)
-}
\ No newline at end of file
+// Play
+// Starts CD-DA playback from the given track, or from the SetLoc position without one.
+// Sectors are played from the post condition, the same way ReadN delivers them
+pub(super) fn play(state: &mut CDDrive, track: Option<u8>) -> Packet {
+    let disc = match &state.disc {
+        Some(disc) => disc,
+        None => return stat(state, 0x3),
+    };
+
+    if let Some(track) = track.filter(|track| *track != 0) {
+        let track = bcd_to_dec(track as usize) as u8;
+        match disc.tracks().iter().find(|t| t.number() == track) {
+            Some(start) => {
+                state.seek_target = DiscIndex::from_lba(start.start_lba());
+                state.read_offset = 0;
+            }
+            None => warn!("CD: Tried to play nonexistent track {}", track),
+        }
+    }
+    let lba = match state.seek_target.plus_sector_offset(state.read_offset).lba() {
+        Some(lba) => lba,
+        None => {
+            // There's no audio in the lead-in, so playback starts at the first track instead
+            state.seek_target = DiscIndex::from_lba(0);
+            state.read_offset = 0;
+            0
+        }
+    };
+    state.play_track = disc.track_for_lba(lba).map(|t| t.number()).unwrap_or(0);
+
+    let mut initial_response = stat(state, 0x3);
+    state.drive_state = DriveState::Play;
+    state.motor_state = MotorState::On;
+    state.read_enabled = false;
+
+    let response_packet = Packet {
+        cause: IntCause::INT1,
+        response: vec![state.get_stat()],
+        execution_cycles: state.sector_cycles(),
+        extra_response: None,
+        command: 0x3,
+    };
+    initial_response.extra_response = Some(Box::new(response_packet));
+    initial_response
+}
+
+// GetlocL
+// Header and subheader of the last sector read. These come back without a stat byte
+pub(super) fn get_loc_l(state: &mut CDDrive) -> Packet {
+    let mut initial_response = stat(state, 0x10);
+    initial_response.response = state.last_sector_header.to_vec();
+    initial_response
+}
+
+// GetlocP
+// Track, index, position in the track and absolute position of the head, all in BCD
+pub(super) fn get_loc_p(state: &mut CDDrive) -> Packet {
+    // Positions in the lead-in, like the head before any SetLoc, report as the start of track 1
+    let lba = state.head_position().lba().unwrap_or(0);
+    let track = state.disc.as_ref().and_then(|disc| disc.track_for_lba(lba));
+    let (number, index, relative) = match track {
+        // Inside the pregap the relative time counts down to INDEX 01
+        Some(track) if lba < track.start_lba() => (track.number(), 0, track.start_lba() - lba),
+        Some(track) => (track.number(), 1, lba - track.start_lba()),
+        None => (0, 0, 0),
+    };
+
+    let mut initial_response = stat(state, 0x11);
+    initial_response.response = vec![dec_to_bcd(number as usize) as u8, index];
+    initial_response.response.extend_from_slice(&DiscIndex::from_sectors(relative).to_bcd());
+    initial_response.response.extend_from_slice(&DiscIndex::from_lba(lba).to_bcd());
+    initial_response
+}
@@ -0,0 +1,159 @@
+use super::disc::{DiscError, TrackType, SECTORS_PER_SECOND};
+
+type Result<T> = std::result::Result<T, DiscError>;
+
+/// A FILE entry from a cue sheet along with the tracks stored in it
+#[derive(Debug, PartialEq)]
+pub(super) struct CueFile {
+    pub name: String,
+    pub tracks: Vec<CueTrack>,
+}
+
+#[derive(Debug, PartialEq)]
+pub(super) struct CueTrack {
+    pub number: u8,
+    pub track_type: TrackType,
+    /// Silence that isn't stored in the file, in sectors
+    pub pregap: usize,
+    /// (index number, sector offset from the start of the file)
+    pub indices: Vec<(u8, usize)>,
+}
+
+impl CueTrack {
+    pub fn index(&self, number: u8) -> Option<usize> {
+        self.indices
+            .iter()
+            .find(|(index, _)| *index == number)
+            .map(|(_, offset)| *offset)
+    }
+}
+
+pub(super) fn parse(sheet: &str) -> Result<Vec<CueFile>> {
+    let mut files: Vec<CueFile> = Vec::new();
+
+    for line in sheet.lines() {
+        let line = line.trim();
+        let (keyword, args) = match line.find(' ') {
+            Some(split) => (&line[..split], line[split..].trim()),
+            None => (line, ""),
+        };
+
+        match keyword.to_ascii_uppercase().as_str() {
+            "FILE" => {
+                let name = if let Some(quoted) = args.strip_prefix('"') {
+                    let end = quoted
+                        .find('"')
+                        .ok_or_else(|| invalid(format!("Unterminated file name: {}", line)))?;
+                    &quoted[..end]
+                } else {
+                    args.split_whitespace().next().unwrap_or("")
+                };
+                files.push(CueFile {
+                    name: name.to_string(),
+                    tracks: Vec::new(),
+                });
+            }
+            "TRACK" => {
+                let mut parts = args.split_whitespace();
+                let number = parts
+                    .next()
+                    .and_then(|n| n.parse().ok())
+                    .ok_or_else(|| invalid(format!("Bad track number: {}", line)))?;
+                let track_type = match parts.next().map(|t| t.to_ascii_uppercase()).as_deref() {
+                    Some("AUDIO") => TrackType::Audio,
+                    Some("MODE1/2352") | Some("MODE2/2352") => TrackType::Data,
+                    _ => return Err(DiscError::UnsupportedFormat(format!("Unsupported track type: {}", line))),
+                };
+                let file = files
+                    .last_mut()
+                    .ok_or_else(|| invalid(format!("TRACK before FILE: {}", line)))?;
+                file.tracks.push(CueTrack {
+                    number,
+                    track_type,
+                    pregap: 0,
+                    indices: Vec::new(),
+                });
+            }
+            "INDEX" => {
+                let mut parts = args.split_whitespace();
+                let number = parts
+                    .next()
+                    .and_then(|n| n.parse().ok())
+                    .ok_or_else(|| invalid(format!("Bad index number: {}", line)))?;
+                let offset = parse_msf(parts.next().unwrap_or(""))?;
+                current_track(&mut files, line)?.indices.push((number, offset));
+            }
+            "PREGAP" => {
+                let length = parse_msf(args)?;
+                current_track(&mut files, line)?.pregap = length;
+            }
+            _ => (), // REM, TITLE, PERFORMER, FLAGS etc. don't matter to the drive
+        }
+    }
+
+    if files.iter().all(|file| file.tracks.is_empty()) {
+        return Err(invalid("Cue sheet has no tracks".to_string()));
+    }
+    Ok(files)
+}
+
+fn current_track<'a>(files: &'a mut [CueFile], line: &str) -> Result<&'a mut CueTrack> {
+    files
+        .last_mut()
+        .and_then(|file| file.tracks.last_mut())
+        .ok_or_else(|| invalid(format!("{} outside of a TRACK", line)))
+}
+
+/// Parses an mm:ss:ff timestamp into a sector count
+fn parse_msf(msf: &str) -> Result<usize> {
+    let parts: Vec<usize> = msf
+        .split(':')
+        .map(|part| part.parse())
+        .collect::<std::result::Result<_, _>>()
+        .map_err(|_| invalid(format!("Bad timestamp: {}", msf)))?;
+    match parts.as_slice() {
+        [minutes, seconds, frames] => Ok((minutes * 60 + seconds) * SECTORS_PER_SECOND + frames),
+        _ => Err(invalid(format!("Bad timestamp: {}", msf))),
+    }
+}
+
+fn invalid(message: String) -> DiscError {
+    DiscError::InvalidCue(message)
+}
+
+#[cfg(test)]
+mod cue_tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_single_bin() {
+        let sheet = "FILE \"Game (USA).bin\" BINARY\r\n\
+                     \x20 TRACK 01 MODE2/2352\r\n\
+                     \x20   INDEX 01 00:00:00\r\n\
+                     \x20 TRACK 02 AUDIO\r\n\
+                     \x20   INDEX 00 01:02:03\r\n\
+                     \x20   INDEX 01 01:04:03\r\n";
+        let files = parse(sheet).unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].name, "Game (USA).bin");
+
+        let tracks = &files[0].tracks;
+        assert_eq!(tracks[0].track_type, TrackType::Data);
+        assert_eq!(tracks[0].index(1), Some(0));
+        assert_eq!(tracks[1].number, 2);
+        assert_eq!(tracks[1].track_type, TrackType::Audio);
+        assert_eq!(tracks[1].index(0), Some(62 * 75 + 3));
+        assert_eq!(tracks[1].index(1), Some(64 * 75 + 3));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(parse("TRACK 01 AUDIO").is_err());
+        assert!(matches!(
+            parse("FILE a.bin BINARY\nTRACK 01 MODE1/2048"),
+            Err(DiscError::UnsupportedFormat(_))
+        ));
+        assert!(parse("FILE a.bin BINARY\nTRACK 01 AUDIO\nINDEX 01 00:00").is_err());
+        assert!(parse("REM nothing here").is_err());
+    }
+}
@@ -1,10 +1,54 @@
+use byteorder::{ByteOrder, LittleEndian};
+use log::warn;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use super::cue;
 use super::SectorSize;
 
 pub(super) const SECTORS_PER_SECOND: usize = 75;
 pub(super) const BYTES_PER_SECTOR: usize = 2352;
+const DATA_OFFSET: usize = 24;
+const LICENSE_LBA: usize = 4;
+const PRIMARY_VOLUME_DESCRIPTOR_LBA: usize = 16;
 // Sector format is Mode2/Form1 CD-XA
 
-#[derive(Debug)]
+/// Errors that can occur while loading a disc image
+#[derive(Debug, PartialEq)]
+pub enum DiscError {
+    /// The cue sheet or one of its BIN files couldn't be read
+    Io(io::ErrorKind),
+    /// The image isn't a cue sheet, or uses a track format the drive can't read
+    UnsupportedFormat(String),
+    InvalidCue(String),
+    /// A track's indices point past the end of its BIN file, usually because it's truncated
+    TrackNotFound { track: u8, file: String },
+}
+
+impl fmt::Display for DiscError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DiscError::Io(kind) => write!(f, "Couldn't read the disc image: {}", kind),
+            DiscError::UnsupportedFormat(message) => write!(f, "Unsupported disc image: {}", message),
+            DiscError::InvalidCue(message) => write!(f, "Invalid cue sheet: {}", message),
+            DiscError::TrackNotFound { track, file } => {
+                write!(f, "Track {} doesn't fit in {}", track, file)
+            }
+        }
+    }
+}
+
+impl std::error::Error for DiscError {}
+
+impl From<io::Error> for DiscError {
+    fn from(err: io::Error) -> Self {
+        DiscError::Io(err.kind())
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
 pub struct DiscIndex {
     minutes: usize,
     seconds: usize,
@@ -43,6 +87,31 @@ impl DiscIndex {
         (total_frames * BYTES_PER_SECTOR) as u32
     }
 
+    /// Sector number counted from 00:02:00, the start of the first track. None for positions
+    /// in the lead-in before that
+    pub fn lba(&self) -> Option<usize> {
+        ((self.minutes * 60 + self.seconds) * SECTORS_PER_SECOND + self.sectors).checked_sub(150)
+    }
+
+    pub fn from_lba(lba: usize) -> Self {
+        DiscIndex::from_sectors(lba + 150)
+    }
+
+    /// A length of time on the disc, with no 2 second lead-in
+    pub fn from_sectors(sectors: usize) -> Self {
+        let seconds = sectors / SECTORS_PER_SECOND;
+        DiscIndex::new_dec(seconds / 60, seconds % 60, sectors % SECTORS_PER_SECOND)
+    }
+
+    /// Minutes, seconds and sectors in BCD, the way the drive reports positions
+    pub fn to_bcd(&self) -> [u8; 3] {
+        [
+            dec_to_bcd(self.minutes) as u8,
+            dec_to_bcd(self.seconds) as u8,
+            dec_to_bcd(self.sectors) as u8,
+        ]
+    }
+
     pub fn plus_sector_offset(&self, offset_sectors: usize) -> DiscIndex {
         let sectors = (self.sectors + offset_sectors) % 75;
         let raw_seconds = self.seconds + ((self.sectors + offset_sectors) / SECTORS_PER_SECOND);
@@ -52,14 +121,70 @@ impl DiscIndex {
     }
 }
 
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum TrackType {
+    Data,
+    Audio,
+}
+
 pub struct DiscTrack {
+    number: u8,
+    track_type: TrackType,
+    // Sectors at the start of data that come before INDEX 01
+    pregap: usize,
+    start_lba: usize,
     data: Vec<u8>,
 }
 
 impl DiscTrack {
     pub fn new(data: Vec<u8>) -> Self {
+        Self::with_layout(0, TrackType::Data, 0, data)
+    }
+
+    /// A track whose data begins with `pregap` sectors before INDEX 01. A number of 0 is
+    /// replaced with the track's position on the disc
+    pub fn with_layout(number: u8, track_type: TrackType, pregap: usize, data: Vec<u8>) -> Self {
         Self {
-            data
+            number,
+            track_type,
+            pregap,
+            start_lba: 0,
+            data,
+        }
+    }
+
+    pub fn number(&self) -> u8 {
+        self.number
+    }
+
+    pub fn track_type(&self) -> TrackType {
+        self.track_type
+    }
+
+    /// LBA of INDEX 01, where 00:02:00 is LBA 0
+    pub fn start_lba(&self) -> usize {
+        self.start_lba
+    }
+
+    fn sectors(&self) -> usize {
+        self.data.len() / BYTES_PER_SECTOR
+    }
+}
+
+/// Region the disc was licensed for, as reported by GetID
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Region {
+    NorthAmerica,
+    Europe,
+    Japan,
+}
+
+impl Region {
+    pub fn license_string(&self) -> &'static [u8; 4] {
+        match self {
+            Region::NorthAmerica => b"SCEA",
+            Region::Europe => b"SCEE",
+            Region::Japan => b"SCEI",
         }
     }
 }
@@ -67,6 +192,7 @@ impl DiscTrack {
 pub struct Disc {
     tracks: Vec<DiscTrack>,
     title: String,
+    region: Region,
 }
 
 impl Disc {
@@ -74,6 +200,7 @@ impl Disc {
         Self {
             tracks: Vec::new(),
             title: String::from(title),
+            region: Region::NorthAmerica,
         }
     }
 
@@ -81,10 +208,187 @@ impl Disc {
         &self.title
     }
 
-    pub fn add_track(&mut self, track: DiscTrack) {
+    pub fn region(&self) -> Region {
+        self.region
+    }
+
+    pub fn set_region(&mut self, region: Region) {
+        self.region = region;
+    }
+
+    /// Works out the region from the license text in the system area, falling back to the
+    /// boot executable named in SYSTEM.CNF. Defaults to NorthAmerica if neither is recognised
+    pub fn detect_region(&mut self) {
+        let region = self
+            .region_from_license()
+            .or_else(|| self.region_from_system_cnf());
+        self.region = match region {
+            Some(region) => region,
+            None => {
+                warn!(
+                    "Unable to determine region of disc {}. Assuming NorthAmerica",
+                    self.title
+                );
+                Region::NorthAmerica
+            }
+        };
+    }
+
+    fn region_from_license(&self) -> Option<Region> {
+        let data = self.user_data(LICENSE_LBA)?;
+        if contains(data, b"Sony Computer Entertainment Amer") {
+            Some(Region::NorthAmerica)
+        } else if contains(data, b"Sony Computer Entertainment Euro") {
+            Some(Region::Europe)
+        } else if contains(data, b"Sony Computer Entertainment Inc.") {
+            Some(Region::Japan)
+        } else {
+            None
+        }
+    }
+
+    fn region_from_system_cnf(&self) -> Option<Region> {
+        let pvd = self.user_data(PRIMARY_VOLUME_DESCRIPTOR_LBA)?;
+        if &pvd[1..6] != b"CD001" {
+            return None;
+        }
+        let root_lba = LittleEndian::read_u32(&pvd[158..162]) as usize;
+        let root_size = LittleEndian::read_u32(&pvd[166..170]) as usize;
+
+        let cnf_lba = self.find_file(root_lba, root_size, b"SYSTEM.CNF;1")?;
+        let cnf = self.user_data(cnf_lba)?;
+        let boot = &cnf[cnf.windows(4).position(|w| w == b"BOOT")?..];
+        let name = &boot[boot.windows(6).position(|w| w == b"cdrom:")? + 6..];
+        let name = name.strip_prefix(b"\\").unwrap_or(name);
+        if name.len() < 4 {
+            return None;
+        }
+        match &name[..4] {
+            b"SLUS" | b"SCUS" => Some(Region::NorthAmerica),
+            b"SLES" | b"SCES" | b"SCED" => Some(Region::Europe),
+            b"SLPS" | b"SCPS" | b"SLPM" | b"SIPS" => Some(Region::Japan),
+            _ => None,
+        }
+    }
+
+    /// Looks up a file in an ISO9660 directory and returns the LBA of its first sector
+    fn find_file(&self, dir_lba: usize, dir_size: usize, name: &[u8]) -> Option<usize> {
+        for sector in 0..dir_size.div_ceil(0x800) {
+            let records = self.user_data(dir_lba + sector)?;
+            let mut offset = 0;
+            while offset < records.len() {
+                let record = &records[offset..];
+                // A zero length pads out the rest of the sector
+                let length = record[0] as usize;
+                if length == 0 || record.len() < 33 {
+                    break;
+                }
+                let name_length = record[32] as usize;
+                if record.len() < 33 + name_length {
+                    break;
+                }
+                if &record[33..33 + name_length] == name {
+                    return Some(LittleEndian::read_u32(&record[2..6]) as usize);
+                }
+                offset += length;
+            }
+        }
+        None
+    }
+
+    /// 2048 bytes of user data from a Mode2/Form1 sector, or None if it's past the end of the disc
+    fn user_data(&self, lba: usize) -> Option<&[u8]> {
+        self.raw_sector(lba)
+            .map(|sector| &sector[DATA_OFFSET..DATA_OFFSET + 0x800])
+    }
+
+    /// The whole 2352 byte sector at lba, or None if it's past the end of the disc
+    pub fn raw_sector(&self, lba: usize) -> Option<&[u8]> {
+        let address = lba * BYTES_PER_SECTOR;
+        let mut track_start = 0;
+        for track in &self.tracks {
+            if address >= track_start && address + BYTES_PER_SECTOR <= track_start + track.data.len() {
+                return Some(&track.data[address - track_start..address - track_start + BYTES_PER_SECTOR]);
+            }
+            track_start += track.data.len();
+        }
+        None
+    }
+
+    /// Loads a cue sheet and the BIN files it references
+    pub fn load_cue(path: &Path) -> Result<Disc, DiscError> {
+        let sheet = fs::read_to_string(path)?;
+        let files = cue::parse(&sheet)?;
+        let dir = path.parent().unwrap_or_else(|| Path::new(""));
+        let title = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+
+        let mut disc = Disc::new(&title);
+        for file in files {
+            let data = fs::read(dir.join(&file.name))?;
+            let file_sectors = data.len() / BYTES_PER_SECTOR;
+
+            // Tracks sharing a file are split at INDEX 00 when it's there, so each track owns its pregap
+            let starts: Vec<usize> = file
+                .tracks
+                .iter()
+                .map(|track| track.index(0).or_else(|| track.index(1)).unwrap_or(0))
+                .collect();
+            for (i, track) in file.tracks.iter().enumerate() {
+                let start = starts[i];
+                let end = starts.get(i + 1).cloned().unwrap_or(file_sectors);
+                if start > end || end > file_sectors {
+                    return Err(DiscError::TrackNotFound {
+                        track: track.number,
+                        file: file.name.clone(),
+                    });
+                }
+                let index1 = track.index(1).unwrap_or(start);
+                if index1 < start || index1 > end {
+                    return Err(DiscError::InvalidCue(format!(
+                        "Track {} starts outside of its pregap and data",
+                        track.number
+                    )));
+                }
+
+                let mut track_data = vec![0; track.pregap * BYTES_PER_SECTOR];
+                track_data
+                    .extend_from_slice(&data[start * BYTES_PER_SECTOR..end * BYTES_PER_SECTOR]);
+                disc.add_track(DiscTrack::with_layout(
+                    track.number,
+                    track.track_type,
+                    track.pregap + index1 - start,
+                    track_data,
+                ));
+            }
+        }
+        disc.detect_region();
+        Ok(disc)
+    }
+
+    pub fn add_track(&mut self, mut track: DiscTrack) {
+        let data_start: usize = self.tracks.iter().map(|t| t.sectors()).sum();
+        track.start_lba = data_start + track.pregap;
+        if track.number == 0 {
+            track.number = self.tracks.len() as u8 + 1;
+        }
         self.tracks.push(track);
     }
 
+    pub fn tracks(&self) -> &[DiscTrack] {
+        &self.tracks
+    }
+
+    /// Returns the track an LBA falls in. Sectors in a track's pregap belong to that track
+    pub fn track_for_lba(&self, lba: usize) -> Option<&DiscTrack> {
+        self.tracks.iter().find(|track| {
+            lba >= track.start_lba - track.pregap
+                && lba < track.start_lba - track.pregap + track.sectors()
+        })
+    }
+
     pub fn read_sector(&self, location: DiscIndex, sector_size: &SectorSize) -> &[u8] {
         let address = location.as_address() as usize;
         let (track, track_offset) = self.track_of_offset(address as usize);
@@ -115,3 +419,195 @@ impl Disc {
     }
 }
 
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack.windows(needle.len()).any(|w| w == needle)
+}
+
+#[cfg(test)]
+mod disc_tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn write_temp_files(name: &str, files: &[(&str, Vec<u8>)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("psx-emu-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for (file, data) in files {
+            fs::write(dir.join(file), data).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn test_load_cue_single_bin() {
+        let sheet = "FILE \"game.bin\" BINARY\n  TRACK 01 MODE2/2352\n    INDEX 01 00:00:00\n  TRACK 02 AUDIO\n    INDEX 00 00:00:03\n    INDEX 01 00:00:05\n";
+        let mut image = blank_image(8);
+        image[7 * BYTES_PER_SECTOR] = 0xAA;
+        let dir = write_temp_files(
+            "single",
+            &[("game.cue", sheet.as_bytes().to_vec()), ("game.bin", image)],
+        );
+
+        let disc = Disc::load_cue(&dir.join("game.cue")).unwrap();
+        assert_eq!(disc.title(), "game.cue");
+        assert_eq!(disc.track_count(), 2);
+        assert_eq!(disc.tracks()[1].track_type(), TrackType::Audio);
+        assert_eq!(disc.tracks()[1].start_lba(), 5);
+
+        assert_eq!(disc.track_for_lba(2).unwrap().number(), 1);
+        assert_eq!(disc.track_for_lba(3).unwrap().number(), 2);
+        assert_eq!(disc.track_for_lba(7).unwrap().number(), 2);
+        assert!(disc.track_for_lba(8).is_none());
+
+        let sector = disc.read_sector(DiscIndex::new(0, 2, 7), &SectorSize::WholeSector);
+        assert_eq!(sector[0], 0xAA);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_load_cue_pregap_between_files() {
+        let sheet = "FILE \"track1.bin\" BINARY\n  TRACK 01 MODE2/2352\n    INDEX 01 00:00:00\nFILE \"track2.bin\" BINARY\n  TRACK 02 AUDIO\n    PREGAP 00:00:02\n    INDEX 01 00:00:00\n";
+        let mut track2 = blank_image(3);
+        track2[0] = 0x55;
+        let dir = write_temp_files(
+            "pregap",
+            &[
+                ("game.cue", sheet.as_bytes().to_vec()),
+                ("track1.bin", blank_image(4)),
+                ("track2.bin", track2),
+            ],
+        );
+
+        let disc = Disc::load_cue(&dir.join("game.cue")).unwrap();
+        assert_eq!(disc.tracks()[1].start_lba(), 6);
+        assert_eq!(disc.track_for_lba(4).unwrap().number(), 2);
+        assert!(disc.track_for_lba(9).is_none());
+
+        // The pregap is silence that wasn't in the file
+        let sector = disc.read_sector(DiscIndex::new(0, 2, 6), &SectorSize::WholeSector);
+        assert_eq!(sector[0], 0x55);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_load_cue_missing_bin() {
+        let sheet = "FILE \"missing.bin\" BINARY\n  TRACK 01 MODE2/2352\n    INDEX 01 00:00:00\n";
+        let dir = write_temp_files("missing", &[("game.cue", sheet.as_bytes().to_vec())]);
+        assert_eq!(
+            Disc::load_cue(&dir.join("game.cue")).err(),
+            Some(DiscError::Io(io::ErrorKind::NotFound))
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_load_cue_truncated_bin() {
+        let sheet = "FILE \"game.bin\" BINARY\n  TRACK 01 MODE2/2352\n    INDEX 01 00:00:00\n  TRACK 02 AUDIO\n    INDEX 01 00:00:05\n";
+        // Cut off partway through the first track
+        let mut image = blank_image(3);
+        image.truncate(image.len() - 100);
+        let dir = write_temp_files(
+            "truncated",
+            &[("game.cue", sheet.as_bytes().to_vec()), ("game.bin", image)],
+        );
+        assert_eq!(
+            Disc::load_cue(&dir.join("game.cue")).err(),
+            Some(DiscError::TrackNotFound {
+                track: 1,
+                file: "game.bin".to_string()
+            })
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_load_cue_index_before_pregap() {
+        let sheet = "FILE \"game.bin\" BINARY\n  TRACK 01 MODE2/2352\n    INDEX 01 00:00:00\n  TRACK 02 AUDIO\n    INDEX 00 00:00:03\n    INDEX 01 00:00:01\n";
+        let dir = write_temp_files(
+            "index-order",
+            &[("game.cue", sheet.as_bytes().to_vec()), ("game.bin", blank_image(8))],
+        );
+        assert!(matches!(
+            Disc::load_cue(&dir.join("game.cue")),
+            Err(DiscError::InvalidCue(_))
+        ));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    fn blank_image(sectors: usize) -> Vec<u8> {
+        vec![0; sectors * BYTES_PER_SECTOR]
+    }
+
+    fn write_user_data(image: &mut [u8], lba: usize, offset: usize, data: &[u8]) {
+        let start = lba * BYTES_PER_SECTOR + DATA_OFFSET + offset;
+        image[start..start + data.len()].copy_from_slice(data);
+    }
+
+    fn disc_from_image(image: Vec<u8>) -> Disc {
+        let mut disc = Disc::new("test");
+        disc.add_track(DiscTrack::new(image));
+        disc.detect_region();
+        disc
+    }
+
+    #[test]
+    fn test_region_from_license() {
+        for (text, region) in [
+            (&b"Sony Computer Entertainment Amer  ica "[..], Region::NorthAmerica),
+            (&b"Sony Computer Entertainment Euro pe   "[..], Region::Europe),
+            (&b"Sony Computer Entertainment Inc."[..], Region::Japan),
+        ]
+        .iter()
+        {
+            let mut image = blank_image(5);
+            write_user_data(&mut image, LICENSE_LBA, 0, b"          Licensed  by          ");
+            write_user_data(&mut image, LICENSE_LBA, 32, text);
+            assert_eq!(disc_from_image(image).region(), *region);
+        }
+    }
+
+    #[test]
+    fn test_region_from_system_cnf() {
+        let mut image = blank_image(24);
+
+        // Primary volume descriptor with the root directory at LBA 20
+        write_user_data(&mut image, 16, 0, b"\x01CD001");
+        write_user_data(&mut image, 16, 158, &20u32.to_le_bytes());
+        write_user_data(&mut image, 16, 166, &0x800u32.to_le_bytes());
+
+        // Root directory with a single SYSTEM.CNF entry at LBA 22
+        let name = b"SYSTEM.CNF;1";
+        let mut record = vec![0; 33 + name.len() + 1];
+        record[0] = record.len() as u8;
+        record[2..6].copy_from_slice(&22u32.to_le_bytes());
+        record[32] = name.len() as u8;
+        record[33..33 + name.len()].copy_from_slice(name);
+        write_user_data(&mut image, 20, 0, &record);
+
+        write_user_data(&mut image, 22, 0, b"BOOT = cdrom:\\SLES_123.45;1\r\nTCB = 4\r\n");
+        assert_eq!(disc_from_image(image).region(), Region::Europe);
+    }
+
+    #[test]
+    fn test_directory_record_at_end_of_sector() {
+        let mut image = blank_image(24);
+        write_user_data(&mut image, 16, 0, b"\x01CD001");
+        write_user_data(&mut image, 16, 158, &20u32.to_le_bytes());
+        write_user_data(&mut image, 16, 166, &0x800u32.to_le_bytes());
+
+        // Unnamed records fill the sector up to 16 bytes before the end, too close for a header
+        for i in 0..8 {
+            write_user_data(&mut image, 20, i * 254, &[254]);
+        }
+        write_user_data(&mut image, 20, 0x800 - 16, &[16]);
+        assert_eq!(disc_from_image(image).region(), Region::NorthAmerica);
+    }
+
+    #[test]
+    fn test_unknown_region_defaults_to_north_america() {
+        let mut disc = disc_from_image(blank_image(2));
+        assert_eq!(disc.region(), Region::NorthAmerica);
+        disc.set_region(Region::Japan);
+        disc.detect_region();
+        assert_eq!(disc.region(), Region::NorthAmerica);
+    }
+}
@@ -1,13 +1,17 @@
 use bit_field::BitField;
+use byteorder::{ByteOrder, LittleEndian};
 use commands::*;
 use disc::*;
 use log::{trace, warn};
+use xa::XaDecoder;
 
 use crate::cpu::{InterruptSource, R3000};
 use std::{borrow::{Borrow, BorrowMut}, collections::VecDeque};
 
 mod commands;
+mod cue;
 pub mod disc;
+mod xa;
 
 
 #[derive(Debug, PartialEq, Copy, Clone)]
@@ -101,12 +105,26 @@ pub struct CDDrive {
     seek_target: DiscIndex,
     seek_complete: bool,
     read_offset: usize,
+    sector_buffer: Vec<u8>,
 
     reg_interrupt_flag: u8,
     reg_interrupt_enable: u8,
 
     read_enabled: bool,
 
+    xa_decoder: XaDecoder,
+    /// Set once ReadN hits an XA audio sector, until reading stops or seeks elsewhere
+    xa_active: bool,
+    /// Sync-less header and subheader of the last sector read, for GetlocL
+    last_sector_header: [u8; 8],
+    /// Track Play started in. Playback stops when the head leaves it
+    play_track: u8,
+    cdda_output: Vec<(i16, i16)>,
+    /// File and channel set by SetFilter. With filtering on in the mode, other XA sectors are skipped
+    xa_filter: (u8, u8),
+    /// Set by Mute. CD-DA and XA audio are thrown away instead of reaching the SPU
+    muted: bool,
+
     //Probably useless registers
     reg_sound_map_data_out: u8,
 }
@@ -135,9 +153,18 @@ impl CDDrive {
             seek_target: DiscIndex::new(0, 0, 0),
             seek_complete: false,
             read_offset: 0,
+            sector_buffer: Vec::new(),
 
             read_enabled: false,
 
+            xa_decoder: XaDecoder::new(),
+            xa_active: false,
+            last_sector_header: [0; 8],
+            play_track: 0,
+            cdda_output: Vec::new(),
+            xa_filter: (0, 0),
+            muted: false,
+
             reg_interrupt_flag: 0,
             reg_interrupt_enable: 0,
 
@@ -152,7 +179,7 @@ impl CDDrive {
             0x1F801801 => match self.status_index {
                 0 => self.execute_command(val),
                 1 => self.reg_sound_map_data_out = val,
-                2 => panic!("CD: 0x1F801801 write byte unknown index 2"),
+                2 => trace!("CD: Wrote sound map coding info"),
                 3 => trace!("CD: Wrote Right-CD-Out Right SPU volume"),
                 _ => unreachable!(),
             },
@@ -166,8 +193,10 @@ impl CDDrive {
             0x1F801803 => match self.status_index {
                 0 => {
                     self.want_data = val.get_bit(7); //Only handle want_data. This will probably bite me later
-                    if !self.want_data {
-                        self.data_queue.clear();
+                    self.data_queue.clear();
+                    if self.want_data {
+                        // Move the last delivered sector over to the data FIFO
+                        self.data_queue.extend(self.sector_buffer.iter());
                     }
                 },
                 1 => self.write_interrupt_flag_register(val),
@@ -185,22 +214,14 @@ impl CDDrive {
     pub fn read_byte(&mut self, addr: u32) -> u8 {
         match addr {
             0x1F801800 => self.get_status_register(),
-            0x1F801801 => match self.status_index {
-                0 => panic!("CD: 0x1F801801 read byte unknown index 0"),
-                1 => self.pop_response(),
-                2 => panic!("CD: 0x1F801803 read byte unknown index 2"),
-                3 => panic!("CD: 0x1F801801 read byte unknown index 3"),
-                _ => unreachable!(),
+            // The FIFOs can be read through any bank
+            0x1F801801 => self.pop_response(),
+            0x1F801802 => self.pop_data(),
+            // Banks 2 and 3 mirror 0 and 1. The unused top bits read as set
+            0x1F801803 => match self.status_index {
+                0 | 2 => self.reg_interrupt_enable | 0xE0,
+                _ => self.reg_interrupt_flag | 0xE0,
             },
-            0x1F801803 => {
-                match self.status_index {
-                    0 => self.pop_data(),
-                    1 => self.reg_interrupt_flag,
-                    2 => panic!("CD: 0x1F801803 read byte unknown index 2"),
-                    3 => self.reg_interrupt_flag, //Register mirror
-                    _ => unreachable!(),
-                }
-            }
             _ => panic!(
                 "CD: Tried to read unknown byte. Address: {:#X} Index: {}",
                 addr, self.status_index
@@ -212,8 +233,8 @@ impl CDDrive {
         self.disc = Some(disc);
     }
 
-    pub fn remove_disc(&mut self) {
-        self.disc = None;
+    pub fn remove_disc(&mut self) -> Option<Disc> {
+        self.disc.take()
     }
 
     pub fn disc(&self) -> &Option<Disc> {
@@ -227,7 +248,6 @@ impl CDDrive {
             false
         };
 
-        println!("Attemping to execute command! {}", command);
         // Make sure theres no pending command
         // We can safely overwrite pending readn's though. Otherwise those will clog up the system
         if self.pending_response.is_none() || is_readn {
@@ -238,11 +258,15 @@ impl CDDrive {
                 let response = match command {
                     0x1 => get_stat(self),
                     0x2 => set_loc(self, parameters[0], parameters[1], parameters[2]),
-                    0x3 => play(self),
+                    0x3 => play(self, parameters.first().copied()),
                     0x6 => read_with_retry(self),
+                    0x8 => stop(self),
                     0x9 => pause_read(self),
                     0xA => init(self),
+                    0xB => mute(self),
                     0xE => set_mode(self, parameters[0]),
+                    0x10 => get_loc_l(self),
+                    0x11 => get_loc_p(self),
                     0x13 => get_tn(self),
                     0x14 => get_td(self, parameters[0]),
                     0x15 => seek_data(self),
@@ -250,6 +274,7 @@ impl CDDrive {
                     0x1A => get_id(self),
                     0x1B => read_with_retry(self), // This is actually ReadS (read without retry), but it behaves the same as ReadN, so I'm just using that
                     0xC => demute(self),
+                    0xD => set_filter(self, parameters[0], parameters[1]),
                     0x19 => {
                         //sub_function commands
                         match parameters[0] {
@@ -331,18 +356,6 @@ impl CDDrive {
     }
 
     pub fn pop_data(&mut self) -> u8 {
-        if self.want_data && self.data_queue.is_empty() {
-            //Out of data, get some more
-            //println!("Fetching more data!");
-            let data = self.disc.as_ref().expect("Tried to read nonexistant disc!").read_sector(
-                        self.seek_target.plus_sector_offset(self.read_offset),
-                        self.sector_size()
-                    );
-        
-            self.read_offset += 1;
-            self.data_queue.extend(data.iter());
-            //println!("Fetched {} bytes!", self.data_queue.len())
-        }
         match self.data_queue.pop_front() {
             Some(val) => val,
             None => {
@@ -352,17 +365,112 @@ impl CDDrive {
         }
     }
 
-    pub fn sector_data_take(&mut self) -> &[u8] {
-        
-        //println!("Fetching more data!");
-        let data = self.disc.as_ref().expect("Tried to read nonexistant disc!").read_sector(
-                    self.seek_target.plus_sector_offset(self.read_offset),
-                    self.sector_size()
-                );
-    
+    /// Takes up to len bytes from the data FIFO for a DMA transfer
+    pub fn sector_data_take(&mut self, len: usize) -> Vec<u8> {
+        let len = len.min(self.data_queue.len());
+        self.data_queue.drain(..len).collect()
+    }
+
+    /// Reads the sector under the head into the sector buffer and moves on to the next one.
+    /// Called when the INT1 for a sector is delivered. With XA-ADPCM enabled in the mode,
+    /// audio sectors are played instead and false is returned, since they don't raise an INT1.
+    /// With the XA filter also on, only audio sectors matching SetFilter's file and channel play
+    fn read_next_sector(&mut self) -> bool {
+        let location = self.seek_target.plus_sector_offset(self.read_offset);
+        self.read_offset += 1;
+        let disc = self.disc.as_ref().expect("Tried to read nonexistant disc!");
+        let lba = match location.lba() {
+            Some(lba) => lba,
+            None => {
+                warn!("CD: Tried to read {:?} in the lead-in, skipping it", location);
+                return false;
+            }
+        };
+        if let Some(sector) = disc.raw_sector(lba) {
+            self.last_sector_header.copy_from_slice(&sector[12..20]);
+        }
+        if self.drive_mode.get_bit(6) {
+            let sector = disc.read_sector(location, &SectorSize::WholeSector);
+            if xa::is_audio_sector(sector) {
+                let filtered = self.drive_mode.get_bit(3) && xa::file_and_channel(sector) != self.xa_filter;
+                if !filtered {
+                    self.xa_decoder.decode_sector(sector);
+                    self.xa_active = true;
+                }
+                return false;
+            }
+        }
+        self.sector_buffer = disc.read_sector(location, self.sector_size()).to_vec();
+        true
+    }
+
+    /// Plays the sector under the head as CD-DA and moves on to the next one. Returns true when
+    /// the sector raises an interrupt, which packet is rewritten for. That's a position report
+    /// every 10 sectors with Report set in the mode, or INT4 at the end of the track with AutoPause
+    fn play_next_sector(&mut self, packet: &mut Packet) -> bool {
+        let location = self.seek_target.plus_sector_offset(self.read_offset);
+        let disc = self.disc.as_ref().expect("Tried to play nonexistant disc!");
+        // A SetLoc back into the lead-in leaves the track just like reaching its end does
+        let lba = location.lba();
+        let track = lba.and_then(|lba| disc.track_for_lba(lba)).map(|track| track.number());
+        let lba = match lba {
+            Some(lba) if track == Some(self.play_track) => lba,
+            _ => {
+                self.drive_state = DriveState::Idle;
+                if self.drive_mode.get_bit(1) {
+                    packet.cause = IntCause::INT4;
+                    packet.response = vec![self.get_stat()];
+                    return true;
+                }
+                return false;
+            }
+        };
+
         self.read_offset += 1;
-        data
-        
+        let sector = match disc.raw_sector(lba) {
+            Some(sector) => sector,
+            None => {
+                warn!("CDROM: Track {} has no data at LBA {}, stopping playback", self.play_track, lba);
+                self.drive_state = DriveState::Idle;
+                return false;
+            }
+        };
+        self.cdda_output.extend(sector.chunks(4).map(|sample| {
+            (LittleEndian::read_i16(&sample[0..2]), LittleEndian::read_i16(&sample[2..4]))
+        }));
+
+        let position = location.to_bcd();
+        if self.drive_mode.get_bit(2) && position[2] & 0xF == 0 {
+            packet.response = vec![
+                self.get_stat(),
+                dec_to_bcd(self.play_track as usize) as u8,
+                0x01,
+                position[0],
+                position[1],
+                position[2],
+                0,
+                0,
+            ];
+            return true;
+        }
+        false
+    }
+
+    /// The sector last read or played, or the SetLoc target if nothing has been read there yet
+    fn head_position(&self) -> DiscIndex {
+        self.seek_target.plus_sector_offset(self.read_offset.saturating_sub(1))
+    }
+
+    fn sector_cycles(&self) -> u32 {
+        match self.drive_speed() {
+            DriveSpeed::Single => 0x6e1cd,
+            DriveSpeed::Double => 0x36cd2,
+        }
+    }
+
+    /// Whether ReadN is currently streaming XA-ADPCM audio
+    pub fn xa_playing(&self) -> bool {
+        self.read_enabled && self.xa_active
     }
 
     fn write_interrupt_flag_register(&mut self, val: u8) {
@@ -376,78 +484,525 @@ impl CDDrive {
     fn write_interrupt_enable_register(&mut self, val: u8) {
         self.reg_interrupt_enable = val & 0x1f;
     }
+
+    /// Counts down the pending response and delivers it once its execution time has passed.
+    /// Returns true when the delivered response should raise the CDROM interrupt
+    pub fn execute_cycle(&mut self) -> bool {
+        let pending_response = match &mut self.pending_response {
+            Some(pending_response) => pending_response,
+            None => return false,
+        };
+        pending_response.execution_cycles = pending_response.execution_cycles.saturating_sub(1);
+        if pending_response.execution_cycles > 0 {
+            return false;
+        }
+
+        let mut packet = self.pending_response.take().unwrap();
+
+        // If this is a read packet and reading has already been disabled, abort the entire command sequence
+        if packet.command == 0x6 && !self.read_enabled {
+            return false;
+        }
+
+        // Likewise for Play once the drive has been paused
+        if packet.command == 0x3 && packet.cause == IntCause::INT1 && self.drive_state != DriveState::Play {
+            return false;
+        }
+
+        let mut deliver = true;
+        if packet.command == 0x6 && packet.cause == IntCause::INT1 {
+            deliver = self.read_next_sector();
+        }
+        if packet.command == 0x3 && packet.cause == IntCause::INT1 {
+            deliver = self.play_next_sector(&mut packet);
+        }
+
+        if deliver {
+            self.response_queue = VecDeque::with_capacity(packet.response.len()); //Clear queue
+            self.response_queue.extend(packet.response.iter());
+            self.reg_interrupt_flag = packet.cause.bitflag();
+        }
+
+        trace!("CDROM command {:#X} completed", packet.command);
+
+        //If the response has an extra response, it's up next
+        if let Some(ext_response) = packet.extra_response.take() {
+            self.pending_response = Some(*ext_response);
+        };
+
+        match packet.command {
+            // The second SeekL response ends the seek and returns the drive to idle
+            0x15 if packet.cause == IntCause::INT2 => {
+                self.read_offset = 0;
+                self.drive_state = DriveState::Idle;
+            }
+
+            //ReadN
+            0x6 if self.read_enabled && packet.cause == IntCause::INT1 => {
+                trace!("Inserting next ReadN");
+                self.pending_response = Some(Packet {
+                    cause: IntCause::INT1,
+                    response: vec![self.get_stat()],
+                    execution_cycles: self.sector_cycles(),
+                    extra_response: None,
+                    command: 0x6,
+                });
+            }
+
+            //Play
+            0x3 if self.drive_state == DriveState::Play => {
+                self.pending_response = Some(Packet {
+                    cause: IntCause::INT1,
+                    response: vec![self.get_stat()],
+                    execution_cycles: self.sector_cycles(),
+                    extra_response: None,
+                    command: 0x3,
+                });
+            }
+            _ => () //No actions for this command
+        };
+
+        deliver && self.reg_interrupt_enable & packet.cause.bitflag() == packet.cause.bitflag()
+    }
 }
 
 pub fn step_cycle(cpu: &mut R3000) {
-    if let Some(pending_response) = &mut cpu.main_bus.cd_drive.pending_response {
-        pending_response.execution_cycles -= 1;
-        //println!("{}", pending_response.execution_cycles);
-        if pending_response.execution_cycles == 0 {
-    
-            let mut packet = cpu.main_bus.cd_drive.pending_response.take().unwrap();
-
-            // If this is a read packet and reading has already been disabled, abort the entire command sequence
-            if packet.command == 0x6 && !cpu.main_bus.cd_drive.read_enabled {    
+    if cpu.main_bus.cd_drive.execute_cycle() {
+        cpu.fire_external_interrupt(InterruptSource::CDROM);
+    }
+    let muted = cpu.main_bus.cd_drive.muted;
+    if cpu.main_bus.cd_drive.xa_decoder.has_output() {
+        let samples = cpu.main_bus.cd_drive.xa_decoder.take_output();
+        if !muted {
+            cpu.main_bus.spu.push_cd_audio(&samples);
+        }
+    }
+    if !cpu.main_bus.cd_drive.cdda_output.is_empty() {
+        let samples = std::mem::take(&mut cpu.main_bus.cd_drive.cdda_output);
+        if !muted {
+            cpu.main_bus.spu.push_cd_audio(&samples);
+        }
+    }
+}
+
+#[cfg(test)]
+mod cdrom_tests {
+    use super::*;
+    use crate::bios::{Bios, BIOS_SIZE};
+    use crate::bus::MainBus;
+    use crate::gpu::Gpu;
+    use crate::memory::Memory;
+
+    fn test_cpu() -> R3000 {
+        let mut cpu = R3000::new(MainBus::new(
+            Bios::new(vec![0; BIOS_SIZE]).unwrap(),
+            Memory::new(),
+            Gpu::new(),
+        ));
+
+        // Three sectors with their index stored as the first data byte
+        let mut data = vec![0; BYTES_PER_SECTOR * 3];
+        for sector in 0..3 {
+            data[sector * BYTES_PER_SECTOR + 24] = sector as u8;
+        }
+        let mut disc = Disc::new("test");
+        disc.add_track(DiscTrack::new(data));
+        cpu.main_bus.cd_drive.load_disc(disc);
+        cpu
+    }
+
+    fn send_command(cpu: &mut R3000, command: u8, parameters: &[u8]) {
+        let drive = &mut cpu.main_bus.cd_drive;
+        drive.write_byte(0x1F801800, 0);
+        for param in parameters {
+            drive.write_byte(0x1F801802, *param);
+        }
+        drive.write_byte(0x1F801801, command);
+    }
+
+    fn wait_for_irq(cpu: &mut R3000, cause: IntCause) {
+        for _ in 0..2_000_000 {
+            step_cycle(cpu);
+            if cpu.main_bus.cd_drive.reg_interrupt_flag == cause.bitflag() {
+                // Acknowledge
+                cpu.main_bus.cd_drive.write_byte(0x1F801800, 1);
+                cpu.main_bus.cd_drive.write_byte(0x1F801803, 0x1F);
                 return;
             }
-           
-            cpu.main_bus.cd_drive.response_queue = VecDeque::with_capacity(packet.response.len()); //Clear queue
-            cpu.main_bus.cd_drive.response_queue.extend(packet.response.iter());
-            cpu.main_bus.cd_drive.reg_interrupt_flag = packet.cause.bitflag();
-
-            trace!("CDROM command {:#X} completed", packet.command);
-        
-    
-            
-            //Check if interrupt enabled. If so, fire interrupt
-            //println!("Interrupts {:#X} cause {:#X} command {:#X}", cpu.main_bus.cd_drive.reg_interrupt_enable, packet.cause.bitflag(), packet.command);
-            if cpu.main_bus.cd_drive.reg_interrupt_enable & packet.cause.bitflag()
-            == packet.cause.bitflag()
-            {
-                    cpu.fire_external_interrupt(InterruptSource::CDROM);
+        }
+        panic!("Timed out waiting for {:?}", cause);
+    }
+
+    fn first_data_byte(cpu: &mut R3000) -> u8 {
+        let drive = &mut cpu.main_bus.cd_drive;
+        drive.write_byte(0x1F801800, 0);
+        drive.write_byte(0x1F801803, 0x80); // Want data
+        drive.read_byte(0x1F801802)
+    }
+
+    #[test]
+    fn test_readn_delivers_sequential_sectors() {
+        let mut cpu = test_cpu();
+        send_command(&mut cpu, 0x2, &[0x00, 0x02, 0x00]);
+        wait_for_irq(&mut cpu, IntCause::INT3);
+
+        send_command(&mut cpu, 0x6, &[]);
+        wait_for_irq(&mut cpu, IntCause::INT3);
+        assert_eq!(cpu.main_bus.cd_drive.drive_state, DriveState::Read);
+
+        wait_for_irq(&mut cpu, IntCause::INT1);
+        assert_eq!(first_data_byte(&mut cpu), 0);
+        assert_eq!(cpu.main_bus.cd_drive.data_queue.len(), 0x800 - 1);
+
+        // The next sector arrives even though the last one wasn't fully read
+        wait_for_irq(&mut cpu, IntCause::INT1);
+        assert_eq!(first_data_byte(&mut cpu), 1);
+    }
+
+    #[test]
+    fn test_dma_copies_sector_to_ram() {
+        let mut cpu = test_cpu();
+        send_command(&mut cpu, 0x2, &[0x00, 0x02, 0x02]);
+        wait_for_irq(&mut cpu, IntCause::INT3);
+        send_command(&mut cpu, 0x6, &[]);
+        wait_for_irq(&mut cpu, IntCause::INT3);
+        wait_for_irq(&mut cpu, IntCause::INT1);
+        cpu.main_bus.cd_drive.write_byte(0x1F801800, 0);
+        cpu.main_bus.cd_drive.write_byte(0x1F801803, 0x80); // Want data
+
+        cpu.main_bus.write_word(0x1F8010F0, 0x0765_C321); // Enable channel 3
+        cpu.main_bus.write_word(0x1F8010F4, 0x0088_0000); // IRQ on channel 3 completion
+        cpu.main_bus.write_word(0x1F8010B0, 0x10000);
+        cpu.main_bus.write_word(0x1F8010B4, 0x0000_0100); // 0x100 words
+        cpu.main_bus.write_word(0x1F8010B8, 0x1100_0000);
+        crate::dma::execute_dma_cycle(&mut cpu);
+
+        assert_eq!(cpu.main_bus.memory.read_byte(0x10000), 2);
+        assert_eq!(cpu.main_bus.cd_drive.data_queue.len(), 0x800 - 0x400);
+        assert!(!cpu.main_bus.read_word(0x1F8010B8).get_bit(24));
+        assert!(cpu.main_bus.read_word(0x1F8010F4).get_bit(27));
+        assert!(cpu.main_bus.read_word(0x1F801070).get_bit(3));
+    }
+
+    #[test]
+    fn test_play_streams_cdda_to_the_spu() {
+        let mut cpu = test_cpu();
+        let mut disc = Disc::new("audio");
+        disc.add_track(DiscTrack::new(vec![0; BYTES_PER_SECTOR * 2]));
+        // Two sectors of a constant (0x1000, -0x1000) stereo sample
+        let audio = [0x00, 0x10, 0x00, 0xF0].repeat(BYTES_PER_SECTOR / 2);
+        disc.add_track(DiscTrack::with_layout(0, TrackType::Audio, 0, audio));
+        cpu.main_bus.cd_drive.load_disc(disc);
+
+        let spu = &mut cpu.main_bus.spu;
+        spu.write_half_word(0x1F801DAA, 0xC001);
+        spu.write_half_word(0x1F801D80, 0x3FFF);
+        spu.write_half_word(0x1F801D82, 0x3FFF);
+        spu.write_half_word(0x1F801DB0, 0x7FFF);
+        spu.write_half_word(0x1F801DB2, 0x7FFF);
+
+        send_command(&mut cpu, 0xE, &[0x02]); // AutoPause
+        wait_for_irq(&mut cpu, IntCause::INT3);
+        send_command(&mut cpu, 0x3, &[0x02]);
+        wait_for_irq(&mut cpu, IntCause::INT3);
+        assert_eq!(cpu.main_bus.cd_drive.drive_state, DriveState::Play);
+        assert_eq!(cpu.main_bus.cd_drive.get_stat() & 0x80, 0x80);
+
+        // Both sectors play, then the drive pauses at the end of the track
+        wait_for_irq(&mut cpu, IntCause::INT4);
+        assert_eq!(cpu.main_bus.cd_drive.drive_state, DriveState::Idle);
+        assert_eq!(cpu.main_bus.cd_drive.read_offset, 2);
+
+        let spu = &mut cpu.main_bus.spu;
+        for _ in 0..768 * 4 {
+            spu.execute_cycle();
+        }
+        let samples = spu.drain_samples();
+        let (left, right) = samples[samples.len() - 1];
+        assert!((left as i32 - 0x1000).abs() < 4);
+        assert!((right as i32 + 0x1000).abs() < 4);
+    }
+
+    #[test]
+    fn test_play_before_setloc_starts_at_first_track() {
+        let mut cpu = test_cpu();
+        let mut disc = Disc::new("audio");
+        disc.add_track(DiscTrack::with_layout(0, TrackType::Audio, 0, vec![0; BYTES_PER_SECTOR * 2]));
+        cpu.main_bus.cd_drive.load_disc(disc);
+
+        // The head starts at 00:00:00, which is in the lead-in
+        send_command(&mut cpu, 0xE, &[0x02]); // AutoPause
+        wait_for_irq(&mut cpu, IntCause::INT3);
+        send_command(&mut cpu, 0x3, &[]);
+        wait_for_irq(&mut cpu, IntCause::INT3);
+        assert_eq!(cpu.main_bus.cd_drive.play_track, 1);
+        assert_eq!(cpu.main_bus.cd_drive.seek_target.lba(), Some(0));
+
+        wait_for_irq(&mut cpu, IntCause::INT4);
+        assert_eq!(cpu.main_bus.cd_drive.read_offset, 2);
+    }
+
+    /// Sends a command without parameters and reads back its first response through the registers
+    fn command_response(cpu: &mut R3000, command: u8) -> Vec<u8> {
+        send_command(cpu, command, &[]);
+        while cpu.main_bus.cd_drive.reg_interrupt_flag == 0 {
+            step_cycle(cpu);
+        }
+        let mut response = Vec::new();
+        while cpu.main_bus.cd_drive.get_status_register() & 0x20 != 0 {
+            response.push(cpu.main_bus.cd_drive.read_byte(0x1F801801));
+        }
+        cpu.main_bus.cd_drive.write_byte(0x1F801800, 1);
+        cpu.main_bus.cd_drive.write_byte(0x1F801803, 0x1F);
+        response
+    }
+
+    #[test]
+    fn test_getloc_reports_bcd_positions() {
+        let mut cpu = test_cpu();
+        let mut disc = Disc::new("tracks");
+        disc.add_track(DiscTrack::new(vec![0; BYTES_PER_SECTOR * 12]));
+        // Track 2 has a 2 sector pregap and starts at LBA 14
+        let mut audio = vec![0; BYTES_PER_SECTOR * 4];
+        audio[BYTES_PER_SECTOR * 3 + 12..BYTES_PER_SECTOR * 3 + 20]
+            .copy_from_slice(&[0x00, 0x02, 0x15, 0x02, 0x01, 0x02, 0x64, 0x00]);
+        disc.add_track(DiscTrack::with_layout(0, TrackType::Audio, 2, audio));
+        cpu.main_bus.cd_drive.load_disc(disc);
+
+        // Read the data sector at 00:02:15, which sits in track 2 at 00:00:01
+        send_command(&mut cpu, 0x2, &[0x00, 0x02, 0x15]);
+        wait_for_irq(&mut cpu, IntCause::INT3);
+        send_command(&mut cpu, 0x6, &[]);
+        wait_for_irq(&mut cpu, IntCause::INT3);
+        wait_for_irq(&mut cpu, IntCause::INT1);
+
+        assert_eq!(command_response(&mut cpu, 0x11), vec![0x02, 0x01, 0x00, 0x00, 0x01, 0x00, 0x02, 0x15]);
+
+        assert_eq!(command_response(&mut cpu, 0x10), vec![0x00, 0x02, 0x15, 0x02, 0x01, 0x02, 0x64, 0x00]);
+
+        // In the pregap the index is 0 and the relative time counts down
+        send_command(&mut cpu, 0x2, &[0x00, 0x02, 0x12]);
+        wait_for_irq(&mut cpu, IntCause::INT3);
+        assert_eq!(command_response(&mut cpu, 0x11), vec![0x02, 0x00, 0x00, 0x00, 0x02, 0x00, 0x02, 0x12]);
+    }
+
+    #[test]
+    fn test_getlocp_before_setloc_reports_first_track() {
+        let mut cpu = test_cpu();
+        assert_eq!(command_response(&mut cpu, 0x11), vec![0x01, 0x01, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00]);
+    }
+
+    #[test]
+    fn test_seekl_returns_to_idle() {
+        let mut cpu = test_cpu();
+        send_command(&mut cpu, 0x2, &[0x00, 0x02, 0x01]);
+        wait_for_irq(&mut cpu, IntCause::INT3);
+
+        send_command(&mut cpu, 0x15, &[]);
+        wait_for_irq(&mut cpu, IntCause::INT3);
+        assert_eq!(cpu.main_bus.cd_drive.drive_state, DriveState::Seek);
+        wait_for_irq(&mut cpu, IntCause::INT2);
+        assert_eq!(cpu.main_bus.cd_drive.drive_state, DriveState::Idle);
+
+        send_command(&mut cpu, 0x6, &[]);
+        wait_for_irq(&mut cpu, IntCause::INT1);
+        assert_eq!(first_data_byte(&mut cpu), 1);
+    }
+
+    #[test]
+    fn test_get_id_reports_disc_region() {
+        for (region, license) in [
+            (Region::NorthAmerica, b"SCEA"),
+            (Region::Europe, b"SCEE"),
+            (Region::Japan, b"SCEI"),
+        ]
+        .iter()
+        {
+            let mut cpu = test_cpu();
+            let mut disc = Disc::new("test");
+            disc.set_region(*region);
+            cpu.main_bus.cd_drive.load_disc(disc);
+
+            send_command(&mut cpu, 0x1A, &[]);
+            wait_for_irq(&mut cpu, IntCause::INT3);
+            step_cycle(&mut cpu);
+            while cpu.main_bus.cd_drive.reg_interrupt_flag == 0 {
+                step_cycle(&mut cpu);
             }
-    
-            //If the response has an extra response, push that to the front of the line
-            if let Some(ext_response) = packet.extra_response.take() {
-                //println!("Extra response, filling. {:?}", ext_response);
-                cpu.main_bus
-                .cd_drive
-                .pending_response = Some(*ext_response);
-            };
-          
-    
-            
-            match packet.command {
-                0x15 => {
-                    //Make sure this is the second response
-                    if packet.extra_response.is_some() {
-                        //End seek and return drive to idle state
-                        cpu.main_bus.cd_drive.read_offset = 0;
-                        cpu.main_bus.cd_drive.drive_state = DriveState::Idle;
-                    }
-                }
-    
-                0x6 => {
-                    //ReadN                  
-                    if cpu.main_bus.cd_drive.read_enabled && packet.cause == IntCause::INT1 {
-                        trace!("Inserting next ReadN");
-                        let cycles = match cpu.main_bus.cd_drive.drive_speed() {
-                            DriveSpeed::Single => 0x6e1cd,
-                            DriveSpeed::Double => 0x36cd2,
-                        };
-                        let response_packet = Packet {
-                            cause: IntCause::INT1,
-                            response: vec![cpu.main_bus.cd_drive.get_stat()],
-                            execution_cycles: cycles,
-                            extra_response: None,
-                            command: 0x6,
-                        };
-    
-                        cpu.main_bus.cd_drive.pending_response = Some(response_packet);
-                    }
-                }
-                _ => () //No actions for this command
-            };
+            assert_eq!(cpu.main_bus.cd_drive.reg_interrupt_flag, IntCause::INT2.bitflag());
+
+            let response: Vec<u8> = cpu.main_bus.cd_drive.response_queue.iter().cloned().collect();
+            assert_eq!(&response[1..4], &[0x00, 0x20, 0x00]);
+            assert_eq!(&response[4..], &license[..]);
+        }
+    }
+
+    /// Steps the drive until a response lands, returning how many cycles it took
+    fn cycles_until_response(drive: &mut CDDrive) -> u32 {
+        let mut cycles = 0;
+        while drive.reg_interrupt_flag == 0 {
+            drive.execute_cycle();
+            cycles += 1;
+            assert!(cycles < 2_000_000, "Timed out waiting for a response");
         }
+        cycles
+    }
+
+    #[test]
+    fn test_responses_arrive_after_their_delays() {
+        let mut cpu = test_cpu();
+        cpu.main_bus.cd_drive.remove_disc();
+        send_command(&mut cpu, 0x1A, &[]);
+
+        let drive = &mut cpu.main_bus.cd_drive;
+        assert_eq!(cycles_until_response(drive), AVG_FIRST_RESPONSE_TIME);
+        assert_eq!(drive.reg_interrupt_flag, IntCause::INT3.bitflag());
+        drive.write_byte(0x1F801800, 1);
+        drive.write_byte(0x1F801803, 0x1F);
+
+        assert_eq!(cycles_until_response(drive), AVG_SECOND_RESPONSE_TIME);
+        assert_eq!(drive.reg_interrupt_flag, IntCause::INT5.bitflag());
+        assert_eq!(drive.response_queue.iter().take(2).collect::<Vec<_>>(), vec![&0x08, &0x40]);
+        assert!(drive.pending_response.is_none());
+    }
+
+    /// Starts ReadN in the given mode and returns the cycles between its first two sectors
+    fn sector_interval(mode: u8) -> u32 {
+        let mut cpu = test_cpu();
+        send_command(&mut cpu, 0xE, &[mode]);
+        wait_for_irq(&mut cpu, IntCause::INT3);
+        send_command(&mut cpu, 0x2, &[0x00, 0x02, 0x00]);
+        wait_for_irq(&mut cpu, IntCause::INT3);
+        send_command(&mut cpu, 0x6, &[]);
+        wait_for_irq(&mut cpu, IntCause::INT3);
+        wait_for_irq(&mut cpu, IntCause::INT1);
+        cycles_until_response(&mut cpu.main_bus.cd_drive)
+    }
+
+    /// Steps the drive until a response lands, then acknowledges it. Returns the cause and
+    /// response bytes, along with how many cycles it took
+    fn next_response(drive: &mut CDDrive) -> (u8, Vec<u8>, u32) {
+        let mut cycles = 0;
+        while drive.reg_interrupt_flag == 0 {
+            drive.execute_cycle();
+            cycles += 1;
+            assert!(cycles < 30_000_000, "Timed out waiting for a response");
+        }
+        let response = (drive.reg_interrupt_flag, drive.response_queue.iter().copied().collect(), cycles);
+        drive.write_byte(0x1F801800, 1);
+        drive.write_byte(0x1F801803, 0x1F);
+        response
+    }
+
+    #[test]
+    fn test_pause_and_stop_end_reading() {
+        let mut cpu = test_cpu();
+        send_command(&mut cpu, 0x2, &[0x00, 0x02, 0x00]);
+        wait_for_irq(&mut cpu, IntCause::INT3);
+        send_command(&mut cpu, 0x6, &[]);
+        let drive = &mut cpu.main_bus.cd_drive;
+        assert_eq!(next_response(drive).0, IntCause::INT3.bitflag());
+        assert_eq!(next_response(drive).0, IntCause::INT1.bitflag());
+
+        // Pause halts reading but leaves the motor on
+        send_command(&mut cpu, 0x9, &[]);
+        let drive = &mut cpu.main_bus.cd_drive;
+        assert_eq!(next_response(drive), (IntCause::INT3.bitflag(), vec![0x22], AVG_FIRST_RESPONSE_TIME));
+        assert_eq!(next_response(drive), (IntCause::INT2.bitflag(), vec![0x02], 0x21181c));
+        assert_eq!(drive.drive_state, DriveState::Idle);
+        assert_eq!(drive.motor_state, MotorState::On);
+        assert!(drive.pending_response.is_none());
+
+        send_command(&mut cpu, 0x8, &[]);
+        let drive = &mut cpu.main_bus.cd_drive;
+        assert_eq!(next_response(drive), (IntCause::INT3.bitflag(), vec![0x02], AVG_FIRST_RESPONSE_TIME));
+        assert_eq!(next_response(drive), (IntCause::INT2.bitflag(), vec![0x00], 0xd38aca));
+        assert_eq!(drive.motor_state, MotorState::Off);
+
+        // Stopping again is quick, and reading spins the motor back up
+        send_command(&mut cpu, 0x8, &[]);
+        let drive = &mut cpu.main_bus.cd_drive;
+        next_response(drive);
+        assert_eq!(next_response(drive).2, 0x1d7b);
+        send_command(&mut cpu, 0x6, &[]);
+        let drive = &mut cpu.main_bus.cd_drive;
+        assert_eq!(next_response(drive).0, IntCause::INT3.bitflag());
+        assert_eq!(drive.motor_state, MotorState::On);
+        assert_eq!(next_response(drive).0, IntCause::INT1.bitflag());
+    }
+
+    #[test]
+    fn test_double_speed_halves_sector_time() {
+        let single = sector_interval(0x00);
+        let double = sector_interval(0x80);
+        assert_eq!(single, 0x6e1cd);
+        assert_eq!(double, 0x36cd2);
+    }
+
+    #[test]
+    fn test_xa_filter_selects_channel() {
+        let mut cpu = test_cpu();
+        // Interleaved audio on channels 0 and 1 of file 1, then a data sector
+        let mut data = vec![0; BYTES_PER_SECTOR * 3];
+        for (channel, nibble) in [(0, 0x07), (1, 0x09)].iter() {
+            let sector = &mut data[channel * BYTES_PER_SECTOR..];
+            sector[16] = 1;
+            sector[17] = *channel as u8;
+            sector[18] = 0x24;
+            sector[24 + 4] = 0x0C; // Shift 12, so the first sample is the nibble
+            sector[24 + 16] = *nibble;
+        }
+        data[2 * BYTES_PER_SECTOR + 24] = 2;
+        let mut disc = Disc::new("test");
+        disc.add_track(DiscTrack::new(data));
+        cpu.main_bus.cd_drive.load_disc(disc);
+
+        send_command(&mut cpu, 0xE, &[0x48]); // XA-ADPCM with the filter
+        wait_for_irq(&mut cpu, IntCause::INT3);
+        send_command(&mut cpu, 0xD, &[1, 1]);
+        wait_for_irq(&mut cpu, IntCause::INT3);
+        send_command(&mut cpu, 0x2, &[0x00, 0x02, 0x00]);
+        wait_for_irq(&mut cpu, IntCause::INT3);
+
+        // Step the drive on its own so the decoded audio isn't handed to the SPU
+        send_command(&mut cpu, 0x6, &[]);
+        let drive = &mut cpu.main_bus.cd_drive;
+        for cause in [IntCause::INT3, IntCause::INT1].iter() {
+            cycles_until_response(drive);
+            assert_eq!(drive.reg_interrupt_flag, cause.bitflag());
+            drive.write_byte(0x1F801800, 1);
+            drive.write_byte(0x1F801803, 0x1F);
+        }
+        assert_eq!(first_data_byte(&mut cpu), 2);
+
+        // Only the channel 1 sector, whose samples start at -7, was decoded
+        let output = cpu.main_bus.cd_drive.xa_decoder.take_output();
+        assert_eq!(output.len(), 4704);
+        assert_eq!(output[0], (-7, -7));
+    }
+
+    #[test]
+    fn test_readn_plays_xa_sectors() {
+        let mut cpu = test_cpu();
+        let mut data = vec![0; BYTES_PER_SECTOR * 2];
+        data[18] = 0x24; // Form 2 audio
+        data[BYTES_PER_SECTOR + 24] = 1;
+        let mut disc = Disc::new("test");
+        disc.add_track(DiscTrack::new(data));
+        cpu.main_bus.cd_drive.load_disc(disc);
+
+        send_command(&mut cpu, 0xE, &[0x40]);
+        wait_for_irq(&mut cpu, IntCause::INT3);
+        send_command(&mut cpu, 0x2, &[0x00, 0x02, 0x00]);
+        wait_for_irq(&mut cpu, IntCause::INT3);
+        send_command(&mut cpu, 0x6, &[]);
+        wait_for_irq(&mut cpu, IntCause::INT3);
+        assert!(!cpu.main_bus.cd_drive.xa_playing());
+
+        // The audio sector doesn't interrupt, so the first INT1 is for the data sector after it
+        wait_for_irq(&mut cpu, IntCause::INT1);
+        assert!(cpu.main_bus.cd_drive.xa_playing());
+        assert!(!cpu.main_bus.cd_drive.xa_decoder.has_output());
+        assert_eq!(first_data_byte(&mut cpu), 1);
     }
 }
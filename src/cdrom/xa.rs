@@ -0,0 +1,209 @@
+use std::collections::VecDeque;
+
+const SOUND_GROUPS: usize = 18;
+const SOUND_GROUP_SIZE: usize = 128;
+const SAMPLES_PER_UNIT: usize = 28;
+
+/// Sound groups start after the sync, header and subheader
+const DATA_OFFSET: usize = 24;
+const FILE_OFFSET: usize = 16;
+const CHANNEL_OFFSET: usize = 17;
+const SUBMODE_OFFSET: usize = 18;
+const CODING_OFFSET: usize = 19;
+
+const SUBMODE_AUDIO: u8 = 0x04;
+const SUBMODE_FORM2: u8 = 0x20;
+
+const OUTPUT_RATE: u32 = 44100;
+
+/// XA only uses the first four SPU-ADPCM filters
+const POS_COEFFICIENTS: [i32; 4] = [0, 60, 115, 98];
+const NEG_COEFFICIENTS: [i32; 4] = [0, 0, -52, -55];
+
+/// Whether a raw sector's subheader marks it as Mode 2 Form 2 audio
+pub(super) fn is_audio_sector(sector: &[u8]) -> bool {
+    let submode = sector[SUBMODE_OFFSET];
+    submode & SUBMODE_AUDIO != 0 && submode & SUBMODE_FORM2 != 0
+}
+
+/// The file and channel numbers of a raw sector's subheader, which SetFilter selects between
+pub(super) fn file_and_channel(sector: &[u8]) -> (u8, u8) {
+    (sector[FILE_OFFSET], sector[CHANNEL_OFFSET])
+}
+
+/// The coding info byte of an audio sector's subheader
+#[derive(Debug, Clone, Copy)]
+struct Coding {
+    stereo: bool,
+    sample_rate: u32,
+    eight_bit: bool,
+}
+
+impl Coding {
+    fn from_byte(byte: u8) -> Self {
+        Self {
+            stereo: byte & 0x3 == 1,
+            sample_rate: if byte & 0xC == 0 { 37800 } else { 18900 },
+            eight_bit: byte & 0x30 == 0x10,
+        }
+    }
+}
+
+/// Decodes XA-ADPCM sectors into 44100Hz stereo samples for the SPU's CD input
+pub(super) struct XaDecoder {
+    /// Last two samples of each channel, newest first
+    history: [[i16; 2]; 2],
+    /// Resampling position, in units of 1/OUTPUT_RATE of an input sample
+    phase: u32,
+    output: VecDeque<(i16, i16)>,
+}
+
+impl XaDecoder {
+    pub fn new() -> Self {
+        Self {
+            history: [[0; 2]; 2],
+            phase: 0,
+            output: VecDeque::new(),
+        }
+    }
+
+    /// Decodes a whole raw sector and queues the resampled audio
+    pub fn decode_sector(&mut self, sector: &[u8]) {
+        let coding = Coding::from_byte(sector[CODING_OFFSET]);
+        for (left, right) in self.decode_samples(sector) {
+            self.phase += OUTPUT_RATE;
+            while self.phase >= coding.sample_rate {
+                self.phase -= coding.sample_rate;
+                self.output.push_back((left, right));
+            }
+        }
+    }
+
+    /// Takes the audio decoded so far
+    pub fn take_output(&mut self) -> Vec<(i16, i16)> {
+        self.output.drain(..).collect()
+    }
+
+    pub fn has_output(&self) -> bool {
+        !self.output.is_empty()
+    }
+
+    /// The sector's samples at their original rate. Mono audio is copied to both channels
+    fn decode_samples(&mut self, sector: &[u8]) -> Vec<(i16, i16)> {
+        let coding = Coding::from_byte(sector[CODING_OFFSET]);
+        let mut left = Vec::new();
+        let mut right = Vec::new();
+        for group in sector[DATA_OFFSET..DATA_OFFSET + SOUND_GROUPS * SOUND_GROUP_SIZE]
+            .chunks(SOUND_GROUP_SIZE)
+        {
+            let units = if coding.eight_bit { 4 } else { 8 };
+            for unit in 0..units {
+                let channel = if coding.stereo { unit & 1 } else { 0 };
+                let samples = decode_unit(group, unit, coding.eight_bit, &mut self.history[channel]);
+                if channel == 0 {
+                    left.extend_from_slice(&samples);
+                } else {
+                    right.extend_from_slice(&samples);
+                }
+            }
+        }
+
+        if coding.stereo {
+            left.into_iter().zip(right).collect()
+        } else {
+            left.into_iter().map(|sample| (sample, sample)).collect()
+        }
+    }
+}
+
+/// Decodes the 28 samples of one sound unit. Their nibbles or bytes are interleaved with
+/// the other units across the group's 28 data words
+fn decode_unit(
+    group: &[u8],
+    unit: usize,
+    eight_bit: bool,
+    history: &mut [i16; 2],
+) -> [i16; SAMPLES_PER_UNIT] {
+    let parameters = group[4 + unit];
+    let mut shift = parameters & 0xF;
+    if shift > 12 {
+        shift = 9;
+    }
+    let filter = ((parameters >> 4) & 0x3) as usize;
+
+    let mut samples = [0; SAMPLES_PER_UNIT];
+    for (i, sample) in samples.iter_mut().enumerate() {
+        let raw = if eight_bit {
+            ((group[16 + i * 4 + unit] as u16) << 8) as i16
+        } else {
+            let byte = group[16 + i * 4 + unit / 2];
+            let nibble = if unit & 1 == 0 { byte & 0xF } else { byte >> 4 };
+            ((nibble as u16) << 12) as i16
+        };
+        let prediction = (history[0] as i32 * POS_COEFFICIENTS[filter]
+            + history[1] as i32 * NEG_COEFFICIENTS[filter]
+            + 32)
+            / 64;
+        let decoded = ((raw >> shift) as i32 + prediction).clamp(i16::MIN as i32, i16::MAX as i32) as i16;
+
+        history[1] = history[0];
+        history[0] = decoded;
+        *sample = decoded;
+    }
+    samples
+}
+
+#[cfg(test)]
+mod xa_tests {
+    use super::*;
+    use crate::cdrom::disc::BYTES_PER_SECTOR;
+
+    fn audio_sector(coding: u8) -> Vec<u8> {
+        let mut sector = vec![0; BYTES_PER_SECTOR];
+        sector[SUBMODE_OFFSET] = SUBMODE_AUDIO | SUBMODE_FORM2;
+        sector[CODING_OFFSET] = coding;
+        sector
+    }
+
+    #[test]
+    fn test_decode_mono_sound_unit() {
+        let mut sector = audio_sector(0);
+        let group = DATA_OFFSET;
+        // Unit 0 uses shift 12 and filter 1, unit 1 is unfiltered
+        sector[group + 4] = 0x1C;
+        sector[group + 5] = 0x00;
+        sector[group + 16] = 0x17;
+        sector[group + 20] = 0x27;
+        assert!(is_audio_sector(&sector));
+
+        let samples = XaDecoder::new().decode_samples(&sector);
+        assert_eq!(samples.len(), SOUND_GROUPS * 8 * SAMPLES_PER_UNIT);
+        let left: Vec<i16> = samples.iter().map(|(left, _)| *left).collect();
+        assert_eq!(&left[..4], &[7, 14, 13, 12]);
+        assert_eq!(&left[28..30], &[0x1000, 0x2000]);
+        assert!(samples.iter().all(|(left, right)| left == right));
+    }
+
+    #[test]
+    fn test_decode_stereo_8bit_sound_unit() {
+        let mut sector = audio_sector(0x11);
+        let group = DATA_OFFSET;
+        sector[group + 16] = 0x12;
+        sector[group + 17] = 0xF0;
+
+        let samples = XaDecoder::new().decode_samples(&sector);
+        assert_eq!(samples.len(), SOUND_GROUPS * 2 * SAMPLES_PER_UNIT);
+        assert_eq!(samples[0], (0x1200, -0x1000));
+    }
+
+    #[test]
+    fn test_resamples_to_44100() {
+        for coding in [0x00, 0x05].iter() {
+            let mut decoder = XaDecoder::new();
+            decoder.decode_sector(&audio_sector(*coding));
+            // Both a 37800Hz mono and an 18900Hz stereo sector last 2/15ths of a second
+            assert_eq!(decoder.take_output().len(), 4704);
+            assert!(!decoder.has_output());
+        }
+    }
+}
@@ -4,6 +4,7 @@ use bit_field::BitField;
 use log::{error, warn};
 
 use crate::cpu::{InterruptSource, R3000};
+use crate::memory_card::MemoryCard;
 
 pub(super) const JOY_DATA: u32 = 0x1F801040;
 pub(super) const JOY_STAT: u32 = 0x1F801044;
@@ -15,9 +16,48 @@ const DEFAULT_JOY_BAUD: u16 = 0x88;
 
 const MEMORY_CARD_SELECT_BYTE: u8 = 0x81;
 const CONTROLER_SELECT_BYTE: u8 = 0x1;
+const READ_COMMAND: u8 = 0x42;
+const CONFIG_COMMAND: u8 = 0x43;
+const SET_MODE_COMMAND: u8 = 0x44;
+const GET_MODE_COMMAND: u8 = 0x45;
+const GET_ACTUATOR_COMMAND: u8 = 0x46;
+const GET_COMBINATION_COMMAND: u8 = 0x47;
+const GET_ACTUATOR_MODE_COMMAND: u8 = 0x4C;
+const RUMBLE_MAPPING_COMMAND: u8 = 0x4D;
+
+/// Actuator numbers the rumble mapping assigns to poll parameter bytes
+const SMALL_MOTOR: u8 = 0x00;
+const LARGE_MOTOR: u8 = 0x01;
+const NO_MOTOR: u8 = 0xFF;
+
+const DIGITAL_ID: u8 = 0x41;
+const ANALOG_ID: u8 = 0x73;
+const CONFIG_ID: u8 = 0xF3;
 
+#[derive(Debug, PartialEq, Copy, Clone)]
 pub enum ControllerType {
     DigitalPad,
+    DualShock,
+}
+
+/// Stick positions for a DualShock. 0x00 is fully up/left, 0xFF fully down/right
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct AnalogState {
+    pub left_x: u8,
+    pub left_y: u8,
+    pub right_x: u8,
+    pub right_y: u8,
+}
+
+impl AnalogState {
+    pub fn centered() -> Self {
+        Self {
+            left_x: 0x80,
+            left_y: 0x80,
+            right_x: 0x80,
+            right_y: 0x80,
+        }
+    }
 }
 
 pub struct ButtonState {
@@ -73,6 +113,11 @@ impl ButtonState {
         }
     }
 
+    /// The two button bytes a digital pad sends, low byte first. Pressed buttons read as 0
+    pub fn digital_word(&self) -> u16 {
+        self.digital_low_byte() as u16 | (self.digital_high_byte() as u16) << 8
+    }
+
     fn digital_low_byte(&self) -> u8 {
         let mut result = 0;
 
@@ -130,6 +175,18 @@ pub(super) struct Controllers {
     irq_cycle_timer: usize,
 
     latest_button_state: ButtonState,
+    latest_analog_state: AnalogState,
+    analog_mode: bool,
+    config_mode: bool,
+    pad_command: u8,
+    /// Bytes the pad sends after the id byte
+    pad_response: Vec<u8>,
+    /// Which motor each 0x42 parameter byte drives, set by 0x4D
+    rumble_mapping: [u8; 6],
+    small_motor: u8,
+    large_motor: u8,
+
+    memory_card: Option<MemoryCard>,
 }
 
 impl Controllers {
@@ -147,13 +204,48 @@ impl Controllers {
             irq_cycle_timer: 0,
 
             latest_button_state: ButtonState::new_digital_pad(),
+            latest_analog_state: AnalogState::centered(),
+            analog_mode: false,
+            config_mode: false,
+            pad_command: 0,
+            pad_response: Vec::new(),
+            rumble_mapping: [NO_MOTOR; 6],
+            small_motor: 0,
+            large_motor: 0,
+
+            memory_card: None,
         }
     }
 
     pub(super) fn update_button_state(&mut self, new_state: ButtonState) {
+        if new_state.controller_type != self.latest_button_state.controller_type {
+            // Plugging in a different pad starts it out in digital mode
+            self.analog_mode = false;
+            self.config_mode = false;
+            self.rumble_mapping = [NO_MOTOR; 6];
+            self.small_motor = 0;
+            self.large_motor = 0;
+        }
         self.latest_button_state = new_state;
     }
 
+    /// The small motor is either off (0) or on (0xFF), the large one has a speed
+    pub(super) fn rumble(&self) -> (u8, u8) {
+        (self.small_motor, self.large_motor)
+    }
+
+    pub(super) fn insert_memory_card(&mut self, card: MemoryCard) {
+        self.memory_card = Some(card);
+    }
+
+    pub(super) fn remove_memory_card(&mut self) -> Option<MemoryCard> {
+        self.memory_card.take()
+    }
+
+    pub(super) fn update_analog_state(&mut self, new_state: AnalogState) {
+        self.latest_analog_state = new_state;
+    }
+
     pub(super) fn write_half_word(&mut self, addr: u32, val: u16) {
         match addr {
             JOY_CTRL => self.write_joy_ctrl(val),
@@ -218,6 +310,9 @@ impl Controllers {
             self.tx_state = TXstate::Disabled;
             // self.pending_irq = false;
             // self.irq_cycle_timer = 0;
+        } else if !val.get_bit(1) {
+            // Deselecting the port ends whatever transfer was running
+            self.tx_state = TXstate::Ready;
         }
 
         if val.get_bit(4) {
@@ -233,57 +328,162 @@ impl Controllers {
 
     fn write_joy_data(&mut self, val: u8) {
         //println!("Joy data written {:#X} state = {:?}", val, self.tx_state);
-        let new_state = match self.tx_state.clone() {
+        let new_state = match self.tx_state {
             TXstate::Disabled => {
                 warn!("CONTROLLER: Tried to write JOY_DATA while TX is disabled!");
                 TXstate::Disabled
             }
             TXstate::Ready => {
-                let slot = if val == CONTROLER_SELECT_BYTE {
-                    Slot::Controller
-                } else {
-                    Slot::MemoryCard
-                };
+                // Nothing answers on the bus unless a device recognizes the address byte
+                self.push_rx_buf(0xFF);
 
-                if slot == Slot::MemoryCard {
+                if self.joy_ctrl.get_bit(13) {
+                    // Only port 1 has anything plugged in
                     return;
                 }
 
-               
-
-                self.push_rx_buf(0);
-                self.queue_interrupt();
-                TXstate::Transfering {
-                    slot: slot,
-                    step: 0,
-                }
-            }
-            TXstate::Transfering { slot, step } => {
-                if slot == Slot::Controller {
-                     
-                    let response = match step {
-                        0 => 0x41, // Digital pad idlo
-                        1 => 0x5A, // Digital pad idhi
-                        2 => self.latest_button_state.digital_low_byte(),
-                        3 => self.latest_button_state.digital_high_byte(),
-                        _ => 0,
-                    };
-                    self.push_rx_buf(response);
-                    if step < 3 {
+                match val {
+                    CONTROLER_SELECT_BYTE => {
                         self.queue_interrupt();
+                        TXstate::Transfering {
+                            slot: Slot::Controller,
+                            step: 0,
+                        }
                     }
-                    TXstate::Transfering {
-                        slot: slot.clone(),
-                        step: step + 1
+                    MEMORY_CARD_SELECT_BYTE if self.memory_card.is_some() => {
+                        self.queue_interrupt();
+                        TXstate::Transfering {
+                            slot: Slot::MemoryCard,
+                            step: 0,
+                        }
                     }
-                } else {
-                    panic!("Tried to read memory card! It's not implemented yet :(");
+                    _ => TXstate::Ready,
                 }
             }
+            TXstate::Transfering { slot, step } => match slot {
+                Slot::Controller => self.controller_transfer(val, step),
+                Slot::MemoryCard => self.memory_card_transfer(val, step),
+            },
         };
         self.tx_state = new_state;
     }
 
+    /// Pad side of the transfer. Every byte except the last is acked with an interrupt
+    fn controller_transfer(&mut self, val: u8, step: usize) -> TXstate {
+        let (response, last) = match step {
+            0 => match self.start_pad_command(val) {
+                Some(id) => (id, false),
+                None => {
+                    warn!("CONTROLLER: Unsupported pad command {:#X}", val);
+                    self.push_rx_buf(0xFF);
+                    return TXstate::Ready;
+                }
+            },
+            1 => (0x5A, false), // idhi
+            _ => {
+                let index = step - 2;
+                self.pad_parameter(index, val);
+                let response = self.pad_response.get(index).copied().unwrap_or(0xFF);
+                (response, index + 1 >= self.pad_response.len())
+            }
+        };
+        self.push_rx_buf(response);
+
+        if last {
+            TXstate::Ready
+        } else {
+            self.queue_interrupt();
+            TXstate::Transfering {
+                slot: Slot::Controller,
+                step: step + 1,
+            }
+        }
+    }
+
+    fn memory_card_transfer(&mut self, val: u8, step: usize) -> TXstate {
+        let (response, more) = match self.memory_card.as_mut() {
+            Some(card) => card.transfer(step, val),
+            None => (0xFF, false),
+        };
+        self.push_rx_buf(response);
+
+        if more {
+            self.queue_interrupt();
+            TXstate::Transfering {
+                slot: Slot::MemoryCard,
+                step: step + 1,
+            }
+        } else {
+            TXstate::Ready
+        }
+    }
+
+    /// Builds the response for a command byte and returns the pad's id, or None if the pad
+    /// doesn't understand the command
+    fn start_pad_command(&mut self, command: u8) -> Option<u8> {
+        let dualshock = self.latest_button_state.controller_type == ControllerType::DualShock;
+        self.pad_command = command;
+
+        if self.config_mode {
+            self.pad_response = match command {
+                READ_COMMAND => self.button_response(),
+                CONFIG_COMMAND | SET_MODE_COMMAND => vec![0; 6],
+                GET_MODE_COMMAND => vec![0x01, 0x02, self.analog_mode as u8, 0x02, 0x01, 0x00],
+                GET_ACTUATOR_COMMAND => vec![0x00, 0x00, 0x01, 0x02, 0x00, 0x0A],
+                GET_COMBINATION_COMMAND => vec![0x00, 0x00, 0x02, 0x00, 0x01, 0x00],
+                GET_ACTUATOR_MODE_COMMAND => vec![0x00, 0x00, 0x00, 0x04, 0x00, 0x00],
+                // Answers with the old mapping while the new one is sent
+                RUMBLE_MAPPING_COMMAND => self.rumble_mapping.to_vec(),
+                _ => return None,
+            };
+            return Some(CONFIG_ID);
+        }
+
+        match command {
+            READ_COMMAND => (),
+            CONFIG_COMMAND if dualshock => (),
+            _ => return None,
+        }
+        self.pad_response = self.button_response();
+        Some(if self.analog_mode { ANALOG_ID } else { DIGITAL_ID })
+    }
+
+    fn button_response(&self) -> Vec<u8> {
+        let buttons = &self.latest_button_state;
+        let mut response = vec![buttons.digital_low_byte(), buttons.digital_high_byte()];
+        if self.analog_mode {
+            let sticks = &self.latest_analog_state;
+            response.extend_from_slice(&[
+                sticks.right_x,
+                sticks.right_y,
+                sticks.left_x,
+                sticks.left_y,
+            ]);
+        }
+        response
+    }
+
+    /// Handles a byte the console sent after the id, for commands that take parameters
+    fn pad_parameter(&mut self, index: usize, val: u8) {
+        match (self.pad_command, index) {
+            (CONFIG_COMMAND, 0) => self.config_mode = val == 1,
+            (SET_MODE_COMMAND, 0) if self.config_mode => self.analog_mode = val == 1,
+            (GET_ACTUATOR_COMMAND, 0) if self.config_mode && val == 1 => {
+                self.pad_response = vec![0x00, 0x00, 0x01, 0x01, 0x01, 0x14];
+            }
+            (GET_ACTUATOR_MODE_COMMAND, 0) if self.config_mode && val == 1 => {
+                self.pad_response = vec![0x00, 0x00, 0x00, 0x07, 0x00, 0x00];
+            }
+            (RUMBLE_MAPPING_COMMAND, 0..=5) if self.config_mode => self.rumble_mapping[index] = val,
+            (READ_COMMAND, 0..=5) => match self.rumble_mapping[index] {
+                SMALL_MOTOR => self.small_motor = if val & 1 != 0 { 0xFF } else { 0 },
+                LARGE_MOTOR => self.large_motor = val,
+                _ => (),
+            },
+            _ => (),
+        }
+    }
+
     fn read_joy_stat(&mut self) -> u16 {
         let mut val: u16 = 0;
 
@@ -342,10 +542,7 @@ impl Controllers {
     }
 
     fn pop_rx_buf(&mut self) -> u8 {
-        match self.rx_buf.pop_front() {
-            Some(val) => val,
-            _ => 0
-        }
+        self.rx_buf.pop_front().unwrap_or(0xFF)
     }
 
     fn queue_interrupt(&mut self) {
@@ -366,4 +563,164 @@ pub(super) fn controller_execute_cycle(cpu: &mut R3000) {
         cpu.fire_external_interrupt(InterruptSource::Controller);
         cpu.main_bus.controllers.pending_irq = false;
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod controller_tests {
+    use super::*;
+    use crate::bios::{Bios, BIOS_SIZE};
+    use crate::bus::MainBus;
+    use crate::gpu::Gpu;
+    use crate::memory::Memory;
+
+    fn test_cpu() -> R3000 {
+        let bus = MainBus::new(Bios::new(vec![0; BIOS_SIZE]).unwrap(), Memory::new(), Gpu::new());
+        R3000::new(bus)
+    }
+
+    /// Sends one byte and returns the response along with whether the pad acked it
+    fn exchange(cpu: &mut R3000, val: u8) -> (u8, bool) {
+        let controllers = &mut cpu.main_bus.controllers;
+        controllers.write_byte(JOY_DATA, val);
+        let response = controllers.read_byte(JOY_DATA);
+
+        let acked = controllers.pending_irq;
+        for _ in 0..=200 {
+            controller_execute_cycle(cpu);
+        }
+        let fired = cpu.main_bus.interrupts.status().get_bit(InterruptSource::Controller as usize);
+        assert_eq!(acked, fired);
+
+        cpu.main_bus.interrupts.write_word(crate::interrupts::I_STAT, 0);
+        cpu.main_bus.controllers.write_half_word(JOY_CTRL, 0x1013);
+        (response, acked)
+    }
+
+    #[test]
+    fn test_digital_pad_transfer() {
+        let mut cpu = test_cpu();
+        let mut state = ButtonState::new_digital_pad();
+        state.button_x = true;
+        state.button_start = true;
+        state.button_up = true;
+        cpu.main_bus.controllers.update_button_state(state);
+        cpu.main_bus.controllers.write_half_word(JOY_CTRL, 0x1003);
+
+        assert_eq!(exchange(&mut cpu, 0x01), (0xFF, true));
+        assert_eq!(exchange(&mut cpu, 0x42), (0x41, true));
+        assert_eq!(exchange(&mut cpu, 0x00), (0x5A, true));
+        assert_eq!(exchange(&mut cpu, 0x00), (0xE7, true));
+        assert_eq!(exchange(&mut cpu, 0x00), (0xBF, false));
+        assert_eq!(cpu.main_bus.controllers.latest_button_state.digital_word(), 0xBFE7);
+    }
+
+    fn send_command(cpu: &mut R3000, command: u8, params: &[u8]) -> Vec<u8> {
+        assert_eq!(exchange(cpu, 0x01), (0xFF, true));
+        let mut response = vec![exchange(cpu, command).0];
+        for (i, param) in [0x00].iter().chain(params.iter()).enumerate() {
+            let (val, acked) = exchange(cpu, *param);
+            response.push(val);
+            assert_eq!(acked, i < params.len());
+        }
+        response
+    }
+
+    #[test]
+    fn test_dualshock_analog_mode() {
+        let mut cpu = test_cpu();
+        let mut state = ButtonState::new_digital_pad();
+        state.controller_type = ControllerType::DualShock;
+        state.button_circle = true;
+        cpu.main_bus.controllers.update_button_state(state);
+        cpu.main_bus.controllers.update_analog_state(AnalogState {
+            left_x: 0x00,
+            left_y: 0x40,
+            right_x: 0xC0,
+            right_y: 0xFF,
+        });
+        cpu.main_bus.controllers.write_half_word(JOY_CTRL, 0x1003);
+
+        // Starts in digital mode
+        assert_eq!(send_command(&mut cpu, 0x42, &[0, 0]), vec![0x41, 0x5A, 0xFF, 0xDF]);
+
+        // Enter config mode, switch to analog, and exit again
+        assert_eq!(send_command(&mut cpu, 0x43, &[1, 0]), vec![0x41, 0x5A, 0xFF, 0xDF]);
+        assert_eq!(
+            send_command(&mut cpu, 0x44, &[1, 2, 0, 0, 0, 0]),
+            vec![0xF3, 0x5A, 0, 0, 0, 0, 0, 0]
+        );
+        assert_eq!(
+            send_command(&mut cpu, 0x45, &[0, 0, 0, 0, 0, 0]),
+            vec![0xF3, 0x5A, 0x01, 0x02, 0x01, 0x02, 0x01, 0x00]
+        );
+        assert_eq!(
+            send_command(&mut cpu, 0x43, &[0, 0, 0, 0, 0, 0]),
+            vec![0xF3, 0x5A, 0, 0, 0, 0, 0, 0]
+        );
+
+        assert_eq!(
+            send_command(&mut cpu, 0x42, &[0, 0, 0, 0, 0, 0]),
+            vec![0x73, 0x5A, 0xFF, 0xDF, 0xC0, 0xFF, 0x00, 0x40]
+        );
+    }
+
+    #[test]
+    fn test_dualshock_rumble() {
+        let mut cpu = test_cpu();
+        let mut state = ButtonState::new_digital_pad();
+        state.controller_type = ControllerType::DualShock;
+        cpu.main_bus.controllers.update_button_state(state);
+        cpu.main_bus.controllers.write_half_word(JOY_CTRL, 0x1003);
+
+        // Map the first poll byte to the small motor and the second to the large one
+        send_command(&mut cpu, 0x43, &[1, 0]);
+        send_command(&mut cpu, 0x44, &[1, 3, 0, 0, 0, 0]);
+        assert_eq!(
+            send_command(&mut cpu, 0x4D, &[0x00, 0x01, 0xFF, 0xFF, 0xFF, 0xFF]),
+            vec![0xF3, 0x5A, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF]
+        );
+        send_command(&mut cpu, 0x43, &[0, 0, 0, 0, 0, 0]);
+        assert_eq!(cpu.main_bus.controllers.rumble(), (0, 0));
+
+        send_command(&mut cpu, 0x42, &[0x01, 0x80, 0, 0, 0, 0]);
+        assert_eq!(cpu.main_bus.controllers.rumble(), (0xFF, 0x80));
+        send_command(&mut cpu, 0x42, &[0x00, 0x00, 0, 0, 0, 0]);
+        assert_eq!(cpu.main_bus.controllers.rumble(), (0, 0));
+    }
+
+    #[test]
+    fn test_digital_pad_ignores_config() {
+        let mut cpu = test_cpu();
+        cpu.main_bus.controllers.write_half_word(JOY_CTRL, 0x1003);
+        assert_eq!(exchange(&mut cpu, 0x01), (0xFF, true));
+        assert_eq!(exchange(&mut cpu, 0x43), (0xFF, false));
+    }
+
+    #[test]
+    fn test_empty_port_does_not_ack() {
+        let mut cpu = test_cpu();
+        // Select port 2, which has nothing plugged in
+        cpu.main_bus.controllers.write_half_word(JOY_CTRL, 0x3003);
+        assert_eq!(exchange(&mut cpu, 0x01), (0xFF, false));
+
+        // No memory card inserted either
+        cpu.main_bus.controllers.write_half_word(JOY_CTRL, 0x1003);
+        assert_eq!(exchange(&mut cpu, 0x81), (0xFF, false));
+    }
+
+    #[test]
+    fn test_memory_card_get_id() {
+        let mut cpu = test_cpu();
+        cpu.main_bus.controllers.insert_memory_card(MemoryCard::new());
+        cpu.main_bus.controllers.write_half_word(JOY_CTRL, 0x1003);
+
+        assert_eq!(exchange(&mut cpu, 0x81), (0xFF, true));
+        let responses: Vec<(u8, bool)> = [0x53, 0, 0, 0, 0, 0, 0, 0, 0]
+            .iter()
+            .map(|val| exchange(&mut cpu, *val))
+            .collect();
+        assert_eq!(responses[0], (0x08, true));
+        assert_eq!(responses[5], (0x04, true));
+        assert_eq!(responses[8], (0x80, false));
+    }
+}
@@ -4,6 +4,22 @@ use crate::cpu::Exception;
 
 use super::InterruptSource;
 
+/// The COP0 registers that exist on the PSX, numbered as MFC0/MTC0 address them
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Cop0Register {
+    Bpc = 3,
+    Bda = 5,
+    JumpDest = 6,
+    Dcic = 7,
+    BadVaddr = 8,
+    Bdam = 9,
+    Bpcm = 11,
+    Status = 12,
+    Cause = 13,
+    Epc = 14,
+    Prid = 15,
+}
+
 #[derive(Debug)]
 pub struct Cop0 {
     gen_registers: [u32; 32],
@@ -12,7 +28,7 @@ pub struct Cop0 {
 impl Cop0 {
     pub fn new() -> Cop0 {
         let mut regs = [0; 32];
-        regs[12] = 1; //Initially enable interrupts
+        regs[Cop0Register::Status as usize] = 1; //Initially enable interrupts
         Cop0 {
             gen_registers: regs,
         }
@@ -28,21 +44,78 @@ impl Cop0 {
         self.gen_registers[register_number as usize] = value;
     }
 
+    pub fn read(&self, register: Cop0Register) -> u32 {
+        self.gen_registers[register as usize]
+    }
+
+    pub fn write(&mut self, register: Cop0Register, value: u32) {
+        self.gen_registers[register as usize] = value;
+    }
+
+    pub fn status(&self) -> u32 {
+        self.read(Cop0Register::Status)
+    }
+
+    pub fn set_status(&mut self, value: u32) {
+        self.write(Cop0Register::Status, value);
+    }
+
+    pub fn cause(&self) -> u32 {
+        self.read(Cop0Register::Cause)
+    }
+
+    pub fn set_cause(&mut self, value: u32) {
+        self.write(Cop0Register::Cause, value);
+    }
+
+    pub fn epc(&self) -> u32 {
+        self.read(Cop0Register::Epc)
+    }
+
+    pub fn set_epc(&mut self, value: u32) {
+        self.write(Cop0Register::Epc, value);
+    }
+
+    pub fn bad_vaddr(&self) -> u32 {
+        self.read(Cop0Register::BadVaddr)
+    }
+
+    pub fn set_bad_vaddr(&mut self, value: u32) {
+        self.write(Cop0Register::BadVaddr, value);
+    }
+
     pub fn cache_isolated(&self) -> bool {
-        ((self.gen_registers[12] >> 16) & 0x1) == 1
+        ((self.status() >> 16) & 0x1) == 1
     }
 
     pub fn set_cause_execode(&mut self, exception: &Exception) {
-        self.gen_registers[13] =
-            (!((0x1F as u32) << 2) & self.gen_registers[13]) | ((exception.clone() as u32) << 2);
+        self.set_cause((!(0x1F << 2) & self.cause()) | ((*exception as u32) << 2));
     }
 
     pub fn interrupts_enabled(&self) -> bool {
-        self.gen_registers[12].get_bit(0)
+        self.status().get_bit(0)
     }
 
+    /// The IM field of Status, bits 8-15
     pub fn interrupt_mask(&self) -> u8 {
-        ((self.gen_registers[12] << 8) & 0xFF) as u8
+        ((self.status() >> 8) & 0xFF) as u8
+    }
+
+    /// The IP field of Cause. Bits 0-1 are the software interrupts and bit 2 is the interrupt controller
+    pub fn interrupt_pending(&self) -> u8 {
+        ((self.cause() >> 8) & 0xFF) as u8
+    }
+
+    /// Mirrors the interrupt controller's output into IP2
+    pub fn set_hardware_interrupt(&mut self, pending: bool) {
+        let mut cause = self.cause();
+        cause.set_bit(10, pending);
+        self.set_cause(cause);
+    }
+
+    /// Whether an interrupt exception should be taken. Needs IEc set and a pending IP bit that IM allows through
+    pub fn should_interrupt(&self) -> bool {
+        self.interrupts_enabled() && self.interrupt_pending() & self.interrupt_mask() != 0
     }
 }
 
@@ -58,4 +131,23 @@ mod cop0_tests {
         cop0.write_reg(12, 0);
         assert_eq!(cop0.cache_isolated(), false);
     }
+
+    #[test]
+    fn test_named_registers_match_indices() {
+        let mut cop0 = Cop0::new();
+        assert_eq!(cop0.status(), 1);
+        cop0.set_status(0x1234);
+        cop0.set_cause(0x5678);
+        cop0.set_epc(0x8000_0080);
+        cop0.set_bad_vaddr(0x2006);
+        assert_eq!(cop0.read_reg(12), 0x1234);
+        assert_eq!(cop0.read_reg(13), 0x5678);
+        assert_eq!(cop0.read_reg(14), 0x8000_0080);
+        assert_eq!(cop0.read_reg(8), 0x2006);
+
+        cop0.write_reg(7, 0xABCD);
+        assert_eq!(cop0.read(Cop0Register::Dcic), 0xABCD);
+        cop0.write(Cop0Register::Prid, 2);
+        assert_eq!(cop0.read_reg(15), 2);
+    }
 }
@@ -1,7 +1,7 @@
 use bit_field::BitField;
-use fixed::types::{I16F16, I20F12, I28F4, I4F12, I8F24, I8F8};
-use log::{error, warn};
+use log::error;
 
+#[derive(Clone, Copy)]
 struct Color {
     pub r: u8,
     pub g: u8,
@@ -25,8 +25,19 @@ impl Color {
         self.b = ((val >> 16) & 0xFF) as u8;
         self.c = ((val >> 24) & 0xFF) as u8;
     }
+
+    fn word(&self) -> u32 {
+        (self.r as u32) | ((self.g as u32) << 8) | ((self.b as u32) << 16) | ((self.c as u32) << 24)
+    }
 }
 
+// MAC1-3 are 44 bits wide internally
+const MAC_MAX: i64 = 0x7FF_FFFF_FFFF;
+const MAC_MIN: i64 = -0x800_0000_0000;
+
+// FLAG bits that also set the error bit (31)
+const FLAG_ERROR_MASK: u32 = 0x7F87_E000;
+
 pub(super) struct GTE {
     // Control Registers
     ZSF3: i16,
@@ -74,7 +85,6 @@ pub(super) struct GTE {
     TRZ: i32,
     FLAG: u32,
     LZCS: i32,
-    LZCR: i32,
 
     // Data registers
     VX0: i16,
@@ -86,6 +96,7 @@ pub(super) struct GTE {
     VX2: i16,
     VY2: i16,
     VZ2: i16,
+    OTZ: u16,
     IR0: i16,
     IR1: i16,
     IR2: i16,
@@ -98,13 +109,17 @@ pub(super) struct GTE {
     SZ1: u16,
     SZ2: u16,
     SZ3: u16,
-    SX0: u16,
-    SX1: u16,
-    SX2: u16,
-    SY0: u16,
-    SY1: u16,
-    SY2: u16,
+    SX0: i16,
+    SX1: i16,
+    SX2: i16,
+    SY0: i16,
+    SY1: i16,
+    SY2: i16,
     RGB: Color,
+    RGB0: Color,
+    RGB1: Color,
+    RGB2: Color,
+    RES1: u32,
 }
 
 // Interface
@@ -157,7 +172,6 @@ impl GTE {
             TRZ: 0,
             FLAG: 0,
             LZCS: 0,
-            LZCR: 0,
 
             // Data Registers
             VX0: 0,
@@ -169,6 +183,7 @@ impl GTE {
             VX2: 0,
             VY2: 0,
             VZ2: 0,
+            OTZ: 0,
             IR0: 0,
             IR1: 0,
             IR2: 0,
@@ -188,6 +203,10 @@ impl GTE {
             SY1: 0,
             SY2: 0,
             RGB: Color::new(),
+            RGB0: Color::new(),
+            RGB1: Color::new(),
+            RGB2: Color::new(),
+            RES1: 0,
         }
     }
 
@@ -260,6 +279,7 @@ impl GTE {
             28 => {self.DQB = val as i32},
             29 => {self.ZSF3 = val as i16},
             30 => {self.ZSF4 = val as i16},
+            31 => {self.FLAG = val & 0x7FFF_F000; self.update_error_flag()},
             _ => error!("Tried to write unknown GTE control register {} ({} RAW)", ctrl_reg_name[reg], reg)
         }
     }
@@ -282,47 +302,148 @@ impl GTE {
             },
             5 => {self.VZ2 = val as i16},
             6 => self.RGB.set_word(val),
+            7 => self.OTZ = val as u16,
             8 => self.IR0 = val as i16,
             9 => {self.IR1 = val as i16},
             10 => {self.IR2 = val as i16},
             11 => {self.IR3 = val as i16},
+            12 => {
+                self.SX0 = val as i16;
+                self.SY0 = (val >> 16) as i16;
+            },
+            13 => {
+                self.SX1 = val as i16;
+                self.SY1 = (val >> 16) as i16;
+            },
+            14 => {
+                self.SX2 = val as i16;
+                self.SY2 = (val >> 16) as i16;
+            },
+            15 => {
+                // SXYP pushes a new entry onto the screen coordinate FIFO
+                self.push_sx(val as i16);
+                self.push_sy((val >> 16) as i16);
+            },
+            16 => self.SZ0 = val as u16,
+            17 => self.SZ1 = val as u16,
+            18 => self.SZ2 = val as u16,
+            19 => self.SZ3 = val as u16,
+            20 => self.RGB0.set_word(val),
+            21 => self.RGB1.set_word(val),
+            22 => self.RGB2.set_word(val),
+            23 => self.RES1 = val,
+            24 => self.MAC0 = val as i32,
+            25 => self.MAC1 = val as i32,
+            26 => self.MAC2 = val as i32,
+            27 => self.MAC3 = val as i32,
+            28 => {
+                // IRGB expands 5 bit color components into IR1-3
+                self.IR1 = ((val & 0x1F) * 0x80) as i16;
+                self.IR2 = (((val >> 5) & 0x1F) * 0x80) as i16;
+                self.IR3 = (((val >> 10) & 0x1F) * 0x80) as i16;
+            },
+            29 => (), // ORGB is read only
             30 => self.LZCS = val as i32,
+            31 => (), // LZCR is read only
             _ => error!("Tried to write unknown GTE data register {} ({} RAW)", data_reg_name[reg], reg)
         }
     }
 
     pub(super) fn data_register(&self, reg: usize) -> u32 {
         match reg {
-            0 => ((self.VY0 as u32) << 16 & self.VX0 as u32),
+            0 => ((self.VY0 as u16 as u32) << 16) | self.VX0 as u16 as u32,
             1 => self.VZ0 as u32,
-            2 => ((self.VY1 as u32) << 16 & self.VX1 as u32),
+            2 => ((self.VY1 as u16 as u32) << 16) | self.VX1 as u16 as u32,
             3 => self.VZ1 as u32,
-            4 => ((self.VY2 as u32) << 16 & self.VX2 as u32),
+            4 => ((self.VY2 as u16 as u32) << 16) | self.VX2 as u16 as u32,
             5 => self.VZ2 as u32,
+            6 => self.RGB.word(),
+            7 => self.OTZ as u32,
+            8 => self.IR0 as u32,
             9 => self.IR1 as u32,
             10 => self.IR2 as u32,
             11 => self.IR3 as u32,
+            12 => ((self.SY0 as u16 as u32) << 16) | self.SX0 as u16 as u32,
+            13 => ((self.SY1 as u16 as u32) << 16) | self.SX1 as u16 as u32,
+            14 | 15 => ((self.SY2 as u16 as u32) << 16) | self.SX2 as u16 as u32,
+            16 => self.SZ0 as u32,
+            17 => self.SZ1 as u32,
+            18 => self.SZ2 as u32,
+            19 => self.SZ3 as u32,
+            20 => self.RGB0.word(),
+            21 => self.RGB1.word(),
+            22 => self.RGB2.word(),
+            23 => self.RES1,
             24 => self.MAC0 as u32,
+            25 => self.MAC1 as u32,
+            26 => self.MAC2 as u32,
+            27 => self.MAC3 as u32,
+            28 | 29 => self.orgb(),
+            30 => self.LZCS as u32,
             31 => self.lzcr(),
-            _ => {error!("Tried to read unknown GTE data register {} ({} RAW)", data_reg_name[reg], reg); 10}
+            _ => {error!("Tried to read unknown GTE data register {} ({} RAW)", data_reg_name[reg], reg); 0}
         }
     }
 
     pub(super) fn control_register(&self, reg: usize) -> u32 {
         match reg {
+            0 => ((self.RT12 as u16 as u32) << 16) | self.RT11 as u16 as u32,
+            1 => ((self.RT21 as u16 as u32) << 16) | self.RT13 as u16 as u32,
+            2 => ((self.RT23 as u16 as u32) << 16) | self.RT22 as u16 as u32,
+            3 => ((self.RT32 as u16 as u32) << 16) | self.RT31 as u16 as u32,
+            4 => self.RT33 as u32,
+            5 => self.TRX as u32,
+            6 => self.TRY as u32,
+            7 => self.TRZ as u32,
+            8 => ((self.L12 as u16 as u32) << 16) | self.L11 as u16 as u32,
+            9 => ((self.L21 as u16 as u32) << 16) | self.L13 as u16 as u32,
+            10 => ((self.L23 as u16 as u32) << 16) | self.L22 as u16 as u32,
+            11 => ((self.L32 as u16 as u32) << 16) | self.L31 as u16 as u32,
+            12 => self.L33 as u32,
+            13 => self.RBK as u32,
+            14 => self.GBK as u32,
+            15 => self.BBK as u32,
+            16 => ((self.LR2 as u16 as u32) << 16) | self.LR1 as u16 as u32,
+            17 => ((self.LG1 as u16 as u32) << 16) | self.LR3 as u16 as u32,
+            18 => ((self.LG3 as u16 as u32) << 16) | self.LG2 as u16 as u32,
+            19 => ((self.LB2 as u16 as u32) << 16) | self.LB1 as u16 as u32,
+            20 => self.LB3 as u32,
+            21 => self.RFC as u32,
+            22 => self.GFC as u32,
+            23 => self.BFC as u32,
+            24 => self.OFX as u32,
+            25 => self.OFY as u32,
+            // H is unsigned, but reads back sign extended (hardware quirk)
+            26 => self.H as i16 as u32,
+            27 => self.DQA as u32,
+            28 => self.DQB as u32,
+            29 => self.ZSF3 as u32,
+            30 => self.ZSF4 as u32,
             31 => self.FLAG,
-            _ => {error!("Tried to read unknown GTE control register {} ({} RAW)", ctrl_reg_name[reg], reg); 10}
+            _ => {error!("Tried to read unknown GTE control register {} ({} RAW)", ctrl_reg_name[reg], reg); 0}
         }
     }
 
     pub(super) fn execute_command(&mut self, command: u32) {
         self.FLAG = 0; // Reset calculation error flags
+        let sf = command.get_bit(19);
+        let lm = command.get_bit(10);
         match command & 0x3F {
-            0x6 => self.nclip(),
-            0x13 => self.ncds(),
-            0x30 => self.rtpt(command),
+            0x01 => self.rtps(sf, lm),
+            0x06 => self.nclip(),
+            0x0C => self.op(sf, lm),
+            0x12 => self.mvmva(command, sf, lm),
+            0x13 => self.ncds(sf, lm),
+            0x1E => self.ncs(sf, lm),
+            0x28 => self.sqr(sf, lm),
+            0x2D => self.avsz3(),
+            0x2E => self.avsz4(),
+            0x30 => self.rtpt(sf, lm),
+            0x3D => self.gpf(sf, lm),
+            0x3E => self.gpl(sf, lm),
             _ => error!("Unknown GTE command {:#X}!", command & 0x3F)
         };
+        self.update_error_flag();
     }
 }
 
@@ -335,18 +456,30 @@ impl GTE {
        self.SZ3 = val;
    }
 
-   fn push_sx(&mut self, val: u16) {
+   fn push_sx(&mut self, val: i16) {
     self.SX0 = self.SX1;
     self.SX1 = self.SX2;
     self.SX2 = val;
    }
 
-   fn push_sy(&mut self, val: u16) {
+   fn push_sy(&mut self, val: i16) {
     self.SY0 = self.SY1;
     self.SY1 = self.SY2;
     self.SY2 = val;
    }
 
+   fn push_color(&mut self, r: i32, g: i32, b: i32) {
+       let color = Color {
+           r: self.saturate_color(21, r),
+           g: self.saturate_color(20, g),
+           b: self.saturate_color(19, b),
+           c: self.RGB.c,
+       };
+       self.RGB0 = self.RGB1;
+       self.RGB1 = self.RGB2;
+       self.RGB2 = color;
+   }
+
    fn lzcr(&self) -> u32 {
        if self.LZCS >= 0 {
            self.LZCS.leading_zeros()
@@ -354,40 +487,344 @@ impl GTE {
            self.LZCS.leading_ones()
        }
    }
+
+   fn orgb(&self) -> u32 {
+       let component = |ir: i16| (ir as i32 / 0x80).clamp(0, 0x1F) as u32;
+       component(self.IR1) | (component(self.IR2) << 5) | (component(self.IR3) << 10)
+   }
+
+   fn update_error_flag(&mut self) {
+       let error = self.FLAG & FLAG_ERROR_MASK != 0;
+       self.FLAG.set_bit(31, error);
+   }
+}
+
+// Math helpers
+impl GTE {
+    /// Checks a 44 bit MAC1-3 intermediate result for overflow, and wraps it to 44 bits
+    fn check_mac(&mut self, index: usize, value: i64) -> i64 {
+        if value > MAC_MAX {
+            self.FLAG.set_bit(31 - index, true);
+        } else if value < MAC_MIN {
+            self.FLAG.set_bit(28 - index, true);
+        }
+        (value << 20) >> 20
+    }
+
+    fn set_mac(&mut self, index: usize, value: i64, sf: bool) -> i32 {
+        let value = (self.check_mac(index, value) >> (sf as usize * 12)) as i32;
+        match index {
+            1 => self.MAC1 = value,
+            2 => self.MAC2 = value,
+            3 => self.MAC3 = value,
+            _ => unreachable!(),
+        };
+        value
+    }
+
+    fn set_mac0(&mut self, value: i64) -> i64 {
+        if value > i32::MAX as i64 {
+            self.FLAG.set_bit(16, true);
+        } else if value < i32::MIN as i64 {
+            self.FLAG.set_bit(15, true);
+        }
+        self.MAC0 = value as i32;
+        value
+    }
+
+    fn set_ir(&mut self, index: usize, value: i32, lm: bool) {
+        let min = if lm { 0 } else { -0x8000 };
+        let clamped = if value < min {
+            self.FLAG.set_bit(25 - index, true);
+            min
+        } else if value > 0x7FFF {
+            self.FLAG.set_bit(25 - index, true);
+            0x7FFF
+        } else {
+            value
+        } as i16;
+        match index {
+            1 => self.IR1 = clamped,
+            2 => self.IR2 = clamped,
+            3 => self.IR3 = clamped,
+            _ => unreachable!(),
+        };
+    }
+
+    fn set_ir0(&mut self, value: i64) {
+        let clamped = if value < 0 {
+            self.FLAG.set_bit(12, true);
+            0
+        } else if value > 0x1000 {
+            self.FLAG.set_bit(12, true);
+            0x1000
+        } else {
+            value
+        };
+        self.IR0 = clamped as i16;
+    }
+
+    fn saturate_sz(&mut self, value: i64) -> u16 {
+        if value < 0 {
+            self.FLAG.set_bit(18, true);
+            0
+        } else if value > 0xFFFF {
+            self.FLAG.set_bit(18, true);
+            0xFFFF
+        } else {
+            value as u16
+        }
+    }
+
+    fn saturate_screen(&mut self, bit: usize, value: i64) -> i16 {
+        if value < -0x400 {
+            self.FLAG.set_bit(bit, true);
+            -0x400
+        } else if value > 0x3FF {
+            self.FLAG.set_bit(bit, true);
+            0x3FF
+        } else {
+            value as i16
+        }
+    }
+
+    fn saturate_color(&mut self, bit: usize, value: i32) -> u8 {
+        if value < 0 {
+            self.FLAG.set_bit(bit, true);
+            0
+        } else if value > 0xFF {
+            self.FLAG.set_bit(bit, true);
+            0xFF
+        } else {
+            value as u8
+        }
+    }
+
+    /// Sets MAC1-3 and IR1-3 to (translation * 0x1000 + matrix * vector) >> (sf * 12)
+    fn multiply_matrix(&mut self, matrix: [[i16; 3]; 3], vector: [i16; 3], translation: [i32; 3], sf: bool, lm: bool) {
+        for row in 0..3 {
+            let index = row + 1;
+            let mut sum = self.check_mac(index, (translation[row] as i64) << 12);
+            for column in 0..3 {
+                sum = self.check_mac(index, sum + matrix[row][column] as i64 * vector[column] as i64);
+            }
+            let mac = self.set_mac(index, sum, sf);
+            self.set_ir(index, mac, lm);
+        }
+    }
+
+    /// Unsigned Newton-Raphson division used by the perspective transformation (H / SZ3)
+    fn divide(&mut self) -> u32 {
+        let h = self.H as u32;
+        let sz3 = self.SZ3 as u32;
+        if h < sz3 * 2 {
+            let shift = (sz3 as u16).leading_zeros();
+            let n = (h as u64) << shift;
+            let d = (sz3 << shift) as u64;
+            let u = unr_table(((d as u32 & 0x7FFF) + 0x40) >> 7) as u64 + 0x101;
+            let d2 = (0x2000080u64.wrapping_sub(d * u)) >> 8;
+            let d3 = (0x0000080 + d2 * u) >> 8;
+            (((n * d3) + 0x8000) >> 16).min(0x1FFFF) as u32
+        } else {
+            self.FLAG.set_bit(17, true);
+            0x1FFFF
+        }
+    }
+
+    fn rotation_matrix(&self) -> [[i16; 3]; 3] {
+        [
+            [self.RT11, self.RT12, self.RT13],
+            [self.RT21, self.RT22, self.RT23],
+            [self.RT31, self.RT32, self.RT33],
+        ]
+    }
+
+    fn light_matrix(&self) -> [[i16; 3]; 3] {
+        [
+            [self.L11, self.L12, self.L13],
+            [self.L21, self.L22, self.L23],
+            [self.L31, self.L32, self.L33],
+        ]
+    }
+
+    fn color_matrix(&self) -> [[i16; 3]; 3] {
+        [
+            [self.LR1, self.LR2, self.LR3],
+            [self.LG1, self.LG2, self.LG3],
+            [self.LB1, self.LB2, self.LB3],
+        ]
+    }
+
+    fn vector(&self, index: usize) -> [i16; 3] {
+        match index {
+            0 => [self.VX0, self.VY0, self.VZ0],
+            1 => [self.VX1, self.VY1, self.VZ1],
+            2 => [self.VX2, self.VY2, self.VZ2],
+            _ => [self.IR1, self.IR2, self.IR3],
+        }
+    }
+
+    fn ir_vector(&self) -> [i16; 3] {
+        [self.IR1, self.IR2, self.IR3]
+    }
 }
 
 // Internal GTE commands
 impl GTE {
-    fn rtpt(&mut self, command: u32) {
-        // self.MAC1 = (self.TRX * 0x1000 + (self.RT11*self.VX0 + self.RT12*self.VY0 + self.RT13*self.VZ0) as i32) >> ((command.get_bit(19) as usize) * 12);
-        // self.MAC2 = (self.TRY * 0x1000 + (self.RT21*self.VX0 + self.RT22*self.VY0 + self.RT23*self.VZ0) as i32) >> ((command.get_bit(19) as usize) * 12);
-        // self.MAC3 = (self.TRZ * 0x1000 + (self.RT31*self.VX0 + self.RT32*self.VY0 + self.RT33*self.VZ0) as i32) >> ((command.get_bit(19) as usize) * 12);
-        // self.IR1 = self.MAC1 as i16;
-        // self.IR2 = self.MAC2 as i16;
-        // self.IR3 = self.MAC3 as i16;
-        // self.push_sz((self.MAC3 >> ((!command.get_bit(19) as usize) * 12)) as u16);
-
-        // let mut div_val = (((self.H as u32*0x20000/self.SZ3 as u32)+1)/2);
-        // if div_val > 0x1FFFF {
-        //     div_val = 0x1FFFF;
-        //     self.FLAG.set_bit(17, true);
-        // }
-        // self.push_sx(((div_val * self.IR1 as u32 + self.OFX as u32) / 0x10000) as u16);
-        // self.push_sy(((div_val * self.IR2 as u32 + self.OFY as u32) / 0x10000) as u16);
-        // self.IR0 = ((div_val * self.IR1 as u32 + self.OFX as u32) / 0x10000) as i16;
-        // self.MAC0 = (div_val * self.IR1 as u32 + self.OFX as u32) as i32;
+    /// Perspective transformation of a single vertex. Only the last vertex of a command updates the depth cue
+    fn perspective_transform(&mut self, vector: [i16; 3], sf: bool, lm: bool, last: bool) {
+        self.multiply_matrix(self.rotation_matrix(), vector, [self.TRX, self.TRY, self.TRZ], sf, lm);
+
+        let z = if sf { self.MAC3 as i64 } else { (self.MAC3 >> 12) as i64 };
+        let sz = self.saturate_sz(z);
+        self.push_sz(sz);
+
+        let div = self.divide() as i64;
+        let x = self.set_mac0(div * self.IR1 as i64 + self.OFX as i64);
+        let sx = self.saturate_screen(14, x >> 16);
+        let y = self.set_mac0(div * self.IR2 as i64 + self.OFY as i64);
+        let sy = self.saturate_screen(13, y >> 16);
+        self.push_sx(sx);
+        self.push_sy(sy);
+
+        if last {
+            let depth = self.set_mac0(div * self.DQA as i64 + self.DQB as i64);
+            self.set_ir0(depth >> 12);
+        }
+    }
+
+    fn rtps(&mut self, sf: bool, lm: bool) {
+        self.perspective_transform(self.vector(0), sf, lm, true);
+    }
+
+    fn rtpt(&mut self, sf: bool, lm: bool) {
+        for i in 0..3 {
+            self.perspective_transform(self.vector(i), sf, lm, i == 2);
+        }
     }
 
     fn nclip(&mut self) {
-        warn!("GTE NCLIP mostly stubbed");
-        //self.MAC0 = (self.SX0 * self.SY1 + self.SX1 * self.SY2 + self.SX2 * self.SY0 - self.SX0 * self.SY2 - self.SX1 * self.SY0 - self.SX2 * self.SY1) as i32;
+        let (sx0, sy0) = (self.SX0 as i64, self.SY0 as i64);
+        let (sx1, sy1) = (self.SX1 as i64, self.SY1 as i64);
+        let (sx2, sy2) = (self.SX2 as i64, self.SY2 as i64);
+        self.set_mac0(sx0 * sy1 + sx1 * sy2 + sx2 * sy0 - sx0 * sy2 - sx1 * sy0 - sx2 * sy1);
+    }
+
+    fn op(&mut self, sf: bool, lm: bool) {
+        let (d1, d2, d3) = (self.RT11 as i64, self.RT22 as i64, self.RT33 as i64);
+        let (ir1, ir2, ir3) = (self.IR1 as i64, self.IR2 as i64, self.IR3 as i64);
+        let mac1 = self.set_mac(1, ir3 * d2 - ir2 * d3, sf);
+        let mac2 = self.set_mac(2, ir1 * d3 - ir3 * d1, sf);
+        let mac3 = self.set_mac(3, ir2 * d1 - ir1 * d2, sf);
+        self.set_ir(1, mac1, lm);
+        self.set_ir(2, mac2, lm);
+        self.set_ir(3, mac3, lm);
+    }
+
+    fn mvmva(&mut self, command: u32, sf: bool, lm: bool) {
+        let matrix = match command.get_bits(17..19) {
+            0 => self.rotation_matrix(),
+            1 => self.light_matrix(),
+            2 => self.color_matrix(),
+            _ => {
+                // Garbage matrix built from RGBC, IR0 and RT13/RT22
+                let r = (self.RGB.r as i16) << 4;
+                [
+                    [-r, r, self.IR0],
+                    [self.RT13, self.RT13, self.RT13],
+                    [self.RT22, self.RT22, self.RT22],
+                ]
+            }
+        };
+        let vector = self.vector(command.get_bits(15..17) as usize);
+        let translation = match command.get_bits(13..15) {
+            0 => [self.TRX, self.TRY, self.TRZ],
+            1 => [self.RBK, self.GBK, self.BBK],
+            2 => [self.RFC, self.GFC, self.BFC],
+            _ => [0, 0, 0],
+        };
+        self.multiply_matrix(matrix, vector, translation, sf, lm);
+    }
+
+    /// Light source and light color calculation for vertex 0, leaving the result in IR1-3
+    fn light(&mut self, sf: bool, lm: bool) {
+        self.multiply_matrix(self.light_matrix(), self.vector(0), [0, 0, 0], sf, lm);
+        self.multiply_matrix(self.color_matrix(), self.ir_vector(), [self.RBK, self.GBK, self.BBK], sf, lm);
+    }
+
+    fn ncs(&mut self, sf: bool, lm: bool) {
+        self.light(sf, lm);
+        self.push_color(self.MAC1 >> 4, self.MAC2 >> 4, self.MAC3 >> 4);
+    }
+
+    fn ncds(&mut self, sf: bool, lm: bool) {
+        self.light(sf, lm);
+
+        let color = [self.RGB.r as i64, self.RGB.g as i64, self.RGB.b as i64];
+        let far_color = [self.RFC as i64, self.GFC as i64, self.BFC as i64];
+        let ir = self.ir_vector();
+        let mut products = [0i64; 3];
+        for i in 0..3 {
+            products[i] = self.check_mac(i + 1, (color[i] * ir[i] as i64) << 4);
+        }
+
+        // Depth cue towards the far color
+        for i in 0..3 {
+            let diff = self.set_mac(i + 1, (far_color[i] << 12) - products[i], sf);
+            self.set_ir(i + 1, diff, false);
+        }
+        let ir = self.ir_vector();
+        for i in 0..3 {
+            let mac = self.set_mac(i + 1, ir[i] as i64 * self.IR0 as i64 + products[i], sf);
+            self.set_ir(i + 1, mac, lm);
+        }
+        self.push_color(self.MAC1 >> 4, self.MAC2 >> 4, self.MAC3 >> 4);
+    }
+
+    fn sqr(&mut self, sf: bool, lm: bool) {
+        for (i, ir) in self.ir_vector().iter().enumerate() {
+            let mac = self.set_mac(i + 1, *ir as i64 * *ir as i64, sf);
+            self.set_ir(i + 1, mac, lm);
+        }
     }
 
-    fn ncds(&mut self) {
-        warn!("GTE NCDS stubbed");
+    fn avsz3(&mut self) {
+        let sum = self.SZ1 as i64 + self.SZ2 as i64 + self.SZ3 as i64;
+        let mac = self.set_mac0(self.ZSF3 as i64 * sum);
+        self.OTZ = self.saturate_sz(mac >> 12);
+    }
+
+    fn avsz4(&mut self) {
+        let sum = self.SZ0 as i64 + self.SZ1 as i64 + self.SZ2 as i64 + self.SZ3 as i64;
+        let mac = self.set_mac0(self.ZSF4 as i64 * sum);
+        self.OTZ = self.saturate_sz(mac >> 12);
+    }
+
+    fn gpf(&mut self, sf: bool, lm: bool) {
+        for (i, ir) in self.ir_vector().iter().enumerate() {
+            let mac = self.set_mac(i + 1, *ir as i64 * self.IR0 as i64, sf);
+            self.set_ir(i + 1, mac, lm);
+        }
+        self.push_color(self.MAC1 >> 4, self.MAC2 >> 4, self.MAC3 >> 4);
+    }
+
+    fn gpl(&mut self, sf: bool, lm: bool) {
+        let ir = self.ir_vector();
+        let mac = [self.MAC1 as i64, self.MAC2 as i64, self.MAC3 as i64];
+        for i in 0..3 {
+            let base = mac[i] << (sf as usize * 12);
+            let value = self.set_mac(i + 1, base + ir[i] as i64 * self.IR0 as i64, sf);
+            self.set_ir(i + 1, value, lm);
+        }
+        self.push_color(self.MAC1 >> 4, self.MAC2 >> 4, self.MAC3 >> 4);
     }
 }
 
+/// Reciprocal table used by the GTE divider
+fn unr_table(index: u32) -> u32 {
+    (0x40000 / (index + 0x100)).div_ceil(2).saturating_sub(0x101)
+}
+
 
 const data_reg_name: [&str; 32] = [
     "vxy0", "vz0",  "vxy1", "vz1",  "vxy2", "vz2",  "rgb",  "otz",   // 00
@@ -401,4 +838,94 @@ const ctrl_reg_name: [&str; 32] = [
     "l11l12", "l13l21", "l22l23", "l31l32", "l33", "rbk",  "gbk",  "bbk",   // 08
     "lr1lr2", "lr3lg1", "lg2lg3", "lb1lb2", "lb3", "rfc",  "gfc",  "bfc",   // 10
     "ofx",    "ofy",    "h",      "dqa",    "dqb", "zsf3", "zsf4", "flag",  // 18
-];
\ No newline at end of file
+];
+
+#[cfg(test)]
+mod gte_tests {
+    use super::*;
+
+    fn identity_gte() -> GTE {
+        let mut gte = GTE::new();
+        // Identity rotation in 1.3.12 fixed point
+        gte.set_control_register(0, 0x1000);
+        gte.set_control_register(2, 0x1000);
+        gte.set_control_register(4, 0x1000);
+        gte
+    }
+
+    #[test]
+    fn test_register_round_trip() {
+        let mut gte = GTE::new();
+        gte.set_data_register(0, 0xFFFE_0003);
+        assert_eq!(gte.data_register(0), 0xFFFE_0003);
+        gte.set_data_register(1, 0x8000);
+        assert_eq!(gte.data_register(1), 0xFFFF_8000);
+        gte.set_control_register(0, 0x1234_5678);
+        assert_eq!(gte.control_register(0), 0x1234_5678);
+        gte.set_control_register(26, 0x8000);
+        assert_eq!(gte.control_register(26), 0xFFFF_8000);
+    }
+
+    #[test]
+    fn test_sxyp_pushes_fifo() {
+        let mut gte = GTE::new();
+        gte.set_data_register(15, 0x0001_0002);
+        gte.set_data_register(15, 0x0003_0004);
+        assert_eq!(gte.data_register(13), 0x0001_0002);
+        assert_eq!(gte.data_register(14), 0x0003_0004);
+    }
+
+    #[test]
+    fn test_rtps_projects_vertex() {
+        let mut gte = identity_gte();
+        gte.set_control_register(26, 0x100); // H = 256
+        gte.set_control_register(24, 160 << 16); // OFX
+        gte.set_control_register(25, 120 << 16); // OFY
+        gte.set_data_register(0, (0x20 << 16) | 0x40); // X = 64, Y = 32
+        gte.set_data_register(1, 0x100); // Z = 256
+        gte.execute_command((1 << 19) | 0x01);
+
+        assert_eq!(gte.data_register(19), 0x100);
+        assert_eq!(gte.data_register(14), ((120 + 32) << 16) | (160 + 64));
+        assert_eq!(gte.control_register(31), 0);
+    }
+
+    #[test]
+    fn test_nclip_winding() {
+        let mut gte = GTE::new();
+        gte.set_data_register(12, 0);
+        gte.set_data_register(13, 10);
+        gte.set_data_register(14, 10 << 16);
+        gte.execute_command(0x06);
+        assert_eq!(gte.data_register(24) as i32, 100);
+
+        gte.set_data_register(13, 10 << 16);
+        gte.set_data_register(14, 10);
+        gte.execute_command(0x06);
+        assert_eq!(gte.data_register(24) as i32, -100);
+    }
+
+    #[test]
+    fn test_avsz3() {
+        let mut gte = GTE::new();
+        gte.set_control_register(29, 0x555); // ~1/3
+        gte.set_data_register(17, 300);
+        gte.set_data_register(18, 300);
+        gte.set_data_register(19, 300);
+        gte.execute_command(0x2D);
+        assert_eq!(gte.data_register(7), (0x555 * 900) >> 12);
+    }
+
+    #[test]
+    fn test_mvmva_identity() {
+        let mut gte = identity_gte();
+        gte.set_data_register(2, (0xFFFF << 16) | 5); // V1 = (5, -1, 7)
+        gte.set_data_register(3, 7);
+        // sf=1, mx=RT, v=V1, cv=none
+        gte.execute_command((1 << 19) | (1 << 15) | (3 << 13) | 0x12);
+        assert_eq!(gte.data_register(25), 5);
+        assert_eq!(gte.data_register(26) as i32, -1);
+        assert_eq!(gte.data_register(27), 7);
+        assert_eq!(gte.data_register(9), 5);
+    }
+}
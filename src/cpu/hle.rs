@@ -0,0 +1,203 @@
+use log::warn;
+
+use super::R3000;
+
+/// Guest strings are cut off here in case they're never terminated
+const MAX_STRING_LENGTH: u32 = 0x1000;
+
+impl R3000 {
+    /// Runs the BIOS function being called when pc sits on the A0, B0 or C0 vector and it's
+    /// implemented here, then returns straight to the caller. Returns false if the real BIOS
+    /// code has to handle the call instead
+    pub(super) fn call_bios_hle(&mut self) -> bool {
+        let vector = self.pc & 0x1FFF_FFFF;
+        if vector != 0xA0 && vector != 0xB0 && vector != 0xC0 {
+            return false;
+        }
+
+        // The call is a jump, so the load in its delay slot has already landed by now
+        if let Some(load) = self.load_delay.take() {
+            self.write_reg(load.register, load.value);
+        }
+
+        let function = self.read_reg(9);
+        let result = match (vector, function) {
+            (0xA0, 0x1B) => Some(self.hle_strlen()),
+            (0xA0, 0x2A) => Some(self.hle_memcpy()),
+            (0xA0, 0x2B) => Some(self.hle_memset()),
+            (0xA0, 0x3C) | (0xB0, 0x3D) => self.hle_putchar(),
+            (0xA0, 0x3E) | (0xB0, 0x3F) => self.hle_puts(),
+            (0xA0, 0x3F) => self.hle_printf(),
+            // Pad and card setup only matters to the real BIOS, so just report success
+            (0xB0, 0x12..=0x14) | (0xB0, 0x4A..=0x4C) => Some(1),
+            _ => {
+                warn!("HLE: Unimplemented BIOS function {:#X}({:#X})", vector, function);
+                return false;
+            }
+        };
+
+        if let Some(value) = result {
+            self.write_reg(2, value);
+        }
+        self.pc = self.read_reg(31);
+        true
+    }
+
+    /// Arguments past the fourth are passed on the stack, after the space reserved for a0-a3
+    fn hle_arg(&mut self, index: u32) -> u32 {
+        if index < 4 {
+            self.read_reg(4 + index as u8)
+        } else {
+            let addr = self.read_reg(29).wrapping_add(index * 4);
+            self.main_bus.read_word(addr)
+        }
+    }
+
+    fn read_c_string(&mut self, addr: u32) -> Vec<u8> {
+        let mut string = Vec::new();
+        for offset in 0..MAX_STRING_LENGTH {
+            let byte = self.main_bus.read_byte(addr.wrapping_add(offset));
+            if byte == 0 {
+                break;
+            }
+            string.push(byte);
+        }
+        string
+    }
+
+    fn hle_strlen(&mut self) -> u32 {
+        let src = self.hle_arg(0);
+        self.read_c_string(src).len() as u32
+    }
+
+    fn hle_memcpy(&mut self) -> u32 {
+        let (dst, src, len) = (self.hle_arg(0), self.hle_arg(1), self.hle_arg(2));
+        for offset in 0..len {
+            let byte = self.main_bus.read_byte(src.wrapping_add(offset));
+            self.main_bus.write_byte(dst.wrapping_add(offset), byte);
+        }
+        dst
+    }
+
+    fn hle_memset(&mut self) -> u32 {
+        let (dst, fill, len) = (self.hle_arg(0), self.hle_arg(1), self.hle_arg(2));
+        for offset in 0..len {
+            self.main_bus.write_byte(dst.wrapping_add(offset), fill as u8);
+        }
+        dst
+    }
+
+    fn hle_putchar(&mut self) -> Option<u32> {
+        let c = self.hle_arg(0);
+        self.tty.push(c as u8 as char);
+        Some(c)
+    }
+
+    fn hle_puts(&mut self) -> Option<u32> {
+        let src = self.hle_arg(0);
+        let string = self.read_c_string(src);
+        self.tty.push_str(&String::from_utf8_lossy(&string));
+        None
+    }
+
+    fn hle_printf(&mut self) -> Option<u32> {
+        let format = self.hle_arg(0);
+        let format = self.read_c_string(format);
+        let output = self.format_printf(&format);
+        self.tty.push_str(&output);
+        Some(output.len() as u32)
+    }
+
+    /// Handles the conversions the BIOS printf supports, with the '-' and '0' flags, a width,
+    /// and a precision for strings. Length modifiers are accepted and ignored
+    fn format_printf(&mut self, format: &[u8]) -> String {
+        let mut output = String::new();
+        let mut next_arg = 1;
+        let mut chars = format.iter().copied().peekable();
+        while let Some(c) = chars.next() {
+            if c != b'%' {
+                output.push(c as char);
+                continue;
+            }
+
+            let mut left_align = false;
+            let mut zero_pad = false;
+            while let Some(flag) = chars.peek().copied() {
+                match flag {
+                    b'-' => left_align = true,
+                    b'0' => zero_pad = true,
+                    _ => break,
+                }
+                chars.next();
+            }
+            let mut width = 0;
+            while let Some(digit) = chars.peek().copied().filter(u8::is_ascii_digit) {
+                width = width * 10 + (digit - b'0') as usize;
+                chars.next();
+            }
+            let mut precision = None;
+            if chars.peek() == Some(&b'.') {
+                chars.next();
+                let mut value = 0;
+                while let Some(digit) = chars.peek().copied().filter(u8::is_ascii_digit) {
+                    value = value * 10 + (digit - b'0') as usize;
+                    chars.next();
+                }
+                precision = Some(value);
+            }
+            while let Some(b'l') | Some(b'h') = chars.peek() {
+                chars.next();
+            }
+
+            let conversion = match chars.next() {
+                Some(conversion) => conversion,
+                None => break,
+            };
+            let text = match conversion {
+                b'%' => {
+                    output.push('%');
+                    continue;
+                }
+                b'd' | b'i' => (self.hle_arg(next_arg) as i32).to_string(),
+                b'u' => self.hle_arg(next_arg).to_string(),
+                b'x' => format!("{:x}", self.hle_arg(next_arg)),
+                b'X' => format!("{:X}", self.hle_arg(next_arg)),
+                b'p' => format!("{:08x}", self.hle_arg(next_arg)),
+                b'o' => format!("{:o}", self.hle_arg(next_arg)),
+                b'c' => (self.hle_arg(next_arg) as u8 as char).to_string(),
+                b's' => {
+                    let addr = self.hle_arg(next_arg);
+                    let mut string = self.read_c_string(addr);
+                    if let Some(precision) = precision {
+                        string.truncate(precision);
+                    }
+                    String::from_utf8_lossy(&string).into_owned()
+                }
+                _ => {
+                    warn!("HLE: Unknown printf conversion %{}", conversion as char);
+                    continue;
+                }
+            };
+            next_arg += 1;
+
+            let padding = width.saturating_sub(text.len());
+            if left_align {
+                output.push_str(&text);
+                output.push_str(&" ".repeat(padding));
+            } else if zero_pad && conversion != b's' && conversion != b'c' {
+                // Keep the sign in front of the zeroes
+                let (sign, digits) = match text.strip_prefix('-') {
+                    Some(digits) => ("-", digits),
+                    None => ("", text.as_str()),
+                };
+                output.push_str(sign);
+                output.push_str(&"0".repeat(padding));
+                output.push_str(digits);
+            } else {
+                output.push_str(&" ".repeat(padding));
+                output.push_str(&text);
+            }
+        }
+        output
+    }
+}
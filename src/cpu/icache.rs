@@ -0,0 +1,80 @@
+const NUM_LINES: usize = 256;
+const WORDS_PER_LINE: usize = 4;
+
+#[derive(Debug, Clone, Copy)]
+struct CacheLine {
+    tag: u32,
+    valid: [bool; WORDS_PER_LINE],
+    words: [u32; WORDS_PER_LINE],
+}
+
+/// The 4KiB instruction cache. Lines hold 4 words, each with its own valid bit
+#[derive(Debug)]
+pub struct ICache {
+    lines: [CacheLine; NUM_LINES],
+}
+
+fn line_index(addr: u32) -> usize {
+    ((addr >> 4) as usize) & (NUM_LINES - 1)
+}
+
+fn word_index(addr: u32) -> usize {
+    ((addr >> 2) as usize) & (WORDS_PER_LINE - 1)
+}
+
+/// The cache is physically tagged, so KUSEG and KSEG0 share lines
+fn tag(addr: u32) -> u32 {
+    (addr & 0x1FFF_FFFF) >> 12
+}
+
+impl ICache {
+    pub fn new() -> Self {
+        Self {
+            lines: [CacheLine {
+                tag: 0,
+                valid: [false; WORDS_PER_LINE],
+                words: [0; WORDS_PER_LINE],
+            }; NUM_LINES],
+        }
+    }
+
+    /// The cached word at addr, or None on a miss
+    pub fn read(&self, addr: u32) -> Option<u32> {
+        let line = &self.lines[line_index(addr)];
+        let word = word_index(addr);
+        if line.tag == tag(addr) && line.valid[word] {
+            Some(line.words[word])
+        } else {
+            None
+        }
+    }
+
+    /// Stores a word fetched from memory. Moving a line to a new tag drops the rest of its words
+    pub fn fill(&mut self, addr: u32, value: u32) {
+        let line = &mut self.lines[line_index(addr)];
+        if line.tag != tag(addr) {
+            line.tag = tag(addr);
+            line.valid = [false; WORDS_PER_LINE];
+        }
+        line.words[word_index(addr)] = value;
+        line.valid[word_index(addr)] = true;
+    }
+
+    /// An isolated store outside of tag test mode. It overwrites the word in whichever line addr selects
+    pub fn write(&mut self, addr: u32, value: u32) {
+        self.lines[line_index(addr)].words[word_index(addr)] = value;
+    }
+
+    /// An isolated store in tag test mode, which is how the BIOS flushes the cache
+    pub fn invalidate(&mut self, addr: u32) {
+        let line = &mut self.lines[line_index(addr)];
+        line.tag = tag(addr);
+        line.valid = [false; WORDS_PER_LINE];
+    }
+
+    pub fn invalidate_all(&mut self) {
+        for line in self.lines.iter_mut() {
+            line.valid = [false; WORDS_PER_LINE];
+        }
+    }
+}
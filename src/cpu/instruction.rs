@@ -268,6 +268,114 @@ pub(super) fn decode_opcode(inst: u32) -> Option<Instruction> {
 
 }
 
+/// Returns a human readable MIPS assembly representation of an instruction word
+pub fn disassemble(instruction: u32) -> String {
+    let inst = match decode_opcode(instruction) {
+        Some(inst) => inst,
+        None => return format!(".word {:#010x}", instruction),
+    };
+
+    match inst {
+        Instruction::SLL { rt: 0, rd: 0, sa: 0 } => "nop".to_string(),
+        Instruction::SLL { rt, rd, sa } => format!("sll {}, {}, {}", reg(rd), reg(rt), sa),
+        Instruction::SRL { rt, rd, sa } => format!("srl {}, {}, {}", reg(rd), reg(rt), sa),
+        Instruction::SRA { rt, rd, sa } => format!("sra {}, {}, {}", reg(rd), reg(rt), sa),
+        Instruction::SLLV { rd, rt, rs } => format!("sllv {}, {}, {}", reg(rd), reg(rt), reg(rs)),
+        Instruction::SRLV { rd, rt, rs } => format!("srlv {}, {}, {}", reg(rd), reg(rt), reg(rs)),
+        Instruction::SRAV { rd, rt, rs } => format!("srav {}, {}, {}", reg(rd), reg(rt), reg(rs)),
+        Instruction::JR { rs } => format!("jr {}", reg(rs)),
+        Instruction::JALR { rd, rs } => format!("jalr {}, {}", reg(rd), reg(rs)),
+        Instruction::SYSCALL { code } => format!("syscall {:#x}", code),
+        Instruction::BREAK { code } => format!("break {:#x}", code),
+        Instruction::MFHI { rd } => format!("mfhi {}", reg(rd)),
+        Instruction::MTHI { rs } => format!("mthi {}", reg(rs)),
+        Instruction::MFLO { rd } => format!("mflo {}", reg(rd)),
+        Instruction::MTLO { rs } => format!("mtlo {}", reg(rs)),
+        Instruction::DIV { rs, rt } => format!("div {}, {}", reg(rs), reg(rt)),
+        Instruction::DIVU { rs, rt } => format!("divu {}, {}", reg(rs), reg(rt)),
+        Instruction::MULT { rs, rt } => format!("mult {}, {}", reg(rs), reg(rt)),
+        Instruction::MULTU { rs, rt } => format!("multu {}, {}", reg(rs), reg(rt)),
+        Instruction::ADD { rd, rs, rt } => three_reg("add", rd, rs, rt),
+        Instruction::ADDU { rd, rs, rt } => three_reg("addu", rd, rs, rt),
+        Instruction::SUB { rd, rs, rt } => three_reg("sub", rd, rs, rt),
+        Instruction::SUBU { rd, rs, rt } => three_reg("subu", rd, rs, rt),
+        Instruction::AND { rd, rs, rt } => three_reg("and", rd, rs, rt),
+        Instruction::OR { rd, rs, rt } => three_reg("or", rd, rs, rt),
+        Instruction::XOR { rd, rs, rt } => three_reg("xor", rd, rs, rt),
+        Instruction::NOR { rd, rs, rt } => three_reg("nor", rd, rs, rt),
+        Instruction::SLT { rd, rs, rt } => three_reg("slt", rd, rs, rt),
+        Instruction::SLTU { rd, rs, rt } => three_reg("sltu", rd, rs, rt),
+        Instruction::BLTZ { rs, offset } => format!("bltz {}, {}", reg(rs), branch_offset(offset)),
+        Instruction::BGEZ { rs, offset } => format!("bgez {}, {}", reg(rs), branch_offset(offset)),
+        Instruction::BLTZAL { rs, offset } => format!("bltzal {}, {}", reg(rs), branch_offset(offset)),
+        Instruction::BGEZAL { rs, offset } => format!("bgezal {}, {}", reg(rs), branch_offset(offset)),
+        Instruction::BLEZ { rs, offset } => format!("blez {}, {}", reg(rs), branch_offset(offset)),
+        Instruction::BGTZ { rs, offset } => format!("bgtz {}, {}", reg(rs), branch_offset(offset)),
+        Instruction::BEQ { rs, rt, offset } => format!("beq {}, {}, {}", reg(rs), reg(rt), branch_offset(offset)),
+        Instruction::BNE { rs, rt, offset } => format!("bne {}, {}, {}", reg(rs), reg(rt), branch_offset(offset)),
+        Instruction::J { target } => format!("j {:#x}", target << 2),
+        Instruction::JAL { target } => format!("jal {:#x}", target << 2),
+        Instruction::ADDI { rt, rs, immediate } => format!("addi {}, {}, {}", reg(rt), reg(rs), signed_hex(immediate as i16 as i32)),
+        Instruction::ADDIU { rt, rs, immediate } => format!("addiu {}, {}, {}", reg(rt), reg(rs), signed_hex(immediate as i16 as i32)),
+        Instruction::SLTI { rt, rs, immediate } => format!("slti {}, {}, {}", reg(rt), reg(rs), signed_hex(immediate as i16 as i32)),
+        Instruction::SLTIU { rt, rs, immediate } => format!("sltiu {}, {}, {}", reg(rt), reg(rs), signed_hex(immediate as i16 as i32)),
+        Instruction::ANDI { rt, rs, immediate } => format!("andi {}, {}, {:#x}", reg(rt), reg(rs), immediate),
+        Instruction::ORI { rt, rs, immediate } => format!("ori {}, {}, {:#x}", reg(rt), reg(rs), immediate),
+        Instruction::XORI { rt, rs, immediate } => format!("xori {}, {}, {:#x}", reg(rt), reg(rs), immediate),
+        Instruction::LUI { rt, immediate } => format!("lui {}, {:#x}", reg(rt), immediate),
+        Instruction::MTC0 { rt, rd } => format!("mtc0 {}, ${}", reg(rt), rd),
+        Instruction::MFC0 { rt, rd } => format!("mfc0 {}, ${}", reg(rt), rd),
+        Instruction::RFE => "rfe".to_string(),
+        Instruction::MFC2 { rt, rd } => format!("mfc2 {}, ${}", reg(rt), rd),
+        Instruction::CTC2 { rt, rd } => format!("ctc2 {}, ${}", reg(rt), rd),
+        Instruction::MTC2 { rt, rd } => format!("mtc2 {}, ${}", reg(rt), rd),
+        Instruction::CFC2 { rt, rd } => format!("cfc2 {}, ${}", reg(rt), rd),
+        Instruction::IMM25 { command } => format!("cop2 {:#x}", command),
+        Instruction::LB { rt, offset, base } => memory_op("lb", rt, offset, base),
+        Instruction::LH { rt, offset, base } => memory_op("lh", rt, offset, base),
+        Instruction::LW { rt, offset, base } => memory_op("lw", rt, offset, base),
+        Instruction::LBU { rt, offset, base } => memory_op("lbu", rt, offset, base),
+        Instruction::LHU { rt, offset, base } => memory_op("lhu", rt, offset, base),
+        Instruction::SB { rt, offset, base } => memory_op("sb", rt, offset, base),
+        Instruction::SH { rt, offset, base } => memory_op("sh", rt, offset, base),
+        Instruction::LWL { rt, offset, base } => memory_op("lwl", rt, offset, base),
+        Instruction::LWR { rt, offset, base } => memory_op("lwr", rt, offset, base),
+        Instruction::SWL { rt, offset, base } => memory_op("swl", rt, offset, base),
+        Instruction::SWR { rt, offset, base } => memory_op("swr", rt, offset, base),
+        Instruction::SW { rt, offset, base } => memory_op("sw", rt, offset, base),
+        Instruction::LWC2 { rt, offset, base } => format!("lwc2 ${}, {}({})", rt, signed_hex(offset as i16 as i32), reg(base)),
+        Instruction::SWC2 { rt, offset, base } => format!("swc2 ${}, {}({})", rt, signed_hex(offset as i16 as i32), reg(base)),
+    }
+}
+
+fn reg(register: u8) -> String {
+    match RegisterNames::from_u8(register) {
+        Some(name) => format!("${}", name),
+        None => format!("${}", register),
+    }
+}
+
+fn signed_hex(value: i32) -> String {
+    if value < 0 {
+        format!("-{:#x}", -(value as i64))
+    } else {
+        format!("{:#x}", value)
+    }
+}
+
+/// Branch offsets are shown in bytes relative to the delay slot
+fn branch_offset(offset: u16) -> String {
+    signed_hex((offset as i16 as i32) << 2)
+}
+
+fn three_reg(name: &str, rd: u8, rs: u8, rt: u8) -> String {
+    format!("{} {}, {}, {}", name, reg(rd), reg(rs), reg(rt))
+}
+
+fn memory_op(name: &str, rt: u8, offset: u16, base: u8) -> String {
+    format!("{} {}, {}({})", name, reg(rt), signed_hex(offset as i16 as i32), reg(base))
+}
+
 #[derive(FromPrimitive)]
 pub enum RegisterNames {
     zero = 0,
@@ -348,7 +456,7 @@ impl Display for RegisterNames {
 
 #[cfg(test)]
 mod instruction_tests {
-    use super::InstructionArgs;
+    use super::{disassemble, InstructionArgs};
     #[test]
     fn test_opcode() {
         let test: u32 = 0b11111100000000000000000000000000;
@@ -396,4 +504,22 @@ mod instruction_tests {
         let test: u32 = 0xFFFFFFF;
         assert_eq!(test.address(), 0x3FFFFFF);
     }
+
+    #[test]
+    fn test_disassemble() {
+        assert_eq!(disassemble(0x25280010), "addiu $t0, $t1, 0x10");
+        assert_eq!(disassemble(0x8FA40004), "lw $a0, 0x4($sp)");
+        assert_eq!(disassemble(0xAFBFFFFC), "sw $ra, -0x4($sp)");
+        assert_eq!(disassemble(0x03E00008), "jr $ra");
+        assert_eq!(disassemble(0x00000000), "nop");
+        assert_eq!(disassemble(0x1509FFFE), "bne $t0, $t1, -0x8");
+        assert_eq!(disassemble(0x0C000040), "jal 0x100");
+        assert_eq!(disassemble(0x04110002), "bgezal $zero, 0x8");
+        assert_eq!(disassemble(0x01095021), "addu $t2, $t0, $t1");
+    }
+
+    #[test]
+    fn test_disassemble_unknown() {
+        assert_eq!(disassemble(0xFC000000), ".word 0xfc000000");
+    }
 }
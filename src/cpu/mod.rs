@@ -1,18 +1,43 @@
 use bit_field::BitField;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
 
 use cop0::Cop0;
 use instruction::{InstructionArgs, NumberHelpers, Instruction, decode_opcode};
 use log::{trace, warn};
 
-use crate::LOGGING;
+use crate::exe::PsxExe;
 use crate::timer::TimerState;
-use crate::{bus::MainBus, cdrom};
+use crate::{bus::{BusError, MainBus}, cdrom};
 
 use self::gte::GTE;
+use self::icache::ICache;
 
 mod cop0;
 mod instruction;
 mod gte;
+mod hle;
+mod icache;
+
+pub use cop0::Cop0Register;
+pub use instruction::disassemble;
+
+/// Reset vector, the first BIOS instruction
+const BIOS_ENTRY: u32 = 0xBFC0_0000;
+
+/// Where the BIOS jumps into the shell once the kernel is set up. A sideloaded executable takes over here
+const SHELL_ENTRY: u32 = 0x8003_0000;
+
+/// Start of the uncached mirror of KSEG0
+const KSEG1_START: u32 = 0xA000_0000;
+
+/// Start of the uncached kernel segment holding the cache control register
+const KSEG2_START: u32 = 0xC000_0000;
+
+/// Cache control register bits
+const CACHE_TAG_TEST: usize = 2;
+const CACHE_ICACHE_ENABLE: usize = 11;
 
 #[derive(Debug, Clone, Copy)]
 pub enum InterruptSource {
@@ -46,11 +71,16 @@ pub enum Exception {
     Int = 0,  //Interrupt
 }
 
-#[derive(Debug)]
+/// Called with the pc and instruction word of every instruction before it executes
+pub type InstructionHook = Box<dyn FnMut(u32, u32) + Send>;
+
+/// Called with the source of every interrupt request latched into I_STAT
+pub type InterruptHook = Box<dyn FnMut(InterruptSource) + Send>;
+
+#[derive(Debug, Clone, Copy)]
 struct LoadDelay {
     register: u8,
     value: u32,
-    cycle_loaded: u32,
 }
 
 pub struct R3000 {
@@ -63,15 +93,26 @@ pub struct R3000 {
     pub main_bus: MainBus,
     delay_slot: u32,
     pub cop0: Cop0,
-    load_delays: Vec<LoadDelay>,
-    i_mask: u32,
-    pub i_status: u32,
+    load_delay: Option<LoadDelay>,
+    pending_load: Option<LoadDelay>,
+    written_reg: Option<u8>,
     pub log: bool,
-    pub load_exe: bool,
+    load_exe: Option<PsxExe>,
     exec_delay: bool,
     last_was_branch: bool,
     gte: GTE,
     pub last_touched_addr: u32,
+    trace_file: Option<BufWriter<File>>,
+    instruction_hook: Option<InstructionHook>,
+    interrupt_hook: Option<InterruptHook>,
+    icache: ICache,
+    bios_hle: bool,
+    /// Text printed through the BIOS TTY functions
+    tty: String,
+    /// Where execution starts after a reset. Normally the BIOS entry point
+    reset_vector: u32,
+    /// Panic on unknown instructions instead of skipping them
+    strict: bool,
 }
 
 impl R3000 {
@@ -86,18 +127,40 @@ impl R3000 {
             main_bus: bus,
             delay_slot: 0,
             cop0: Cop0::new(),
-            load_delays: Vec::new(),
-            i_mask: 0,
-            i_status: 0,
+            load_delay: None,
+            pending_load: None,
+            written_reg: None,
             log: false,
-            load_exe: false,
+            load_exe: None,
             exec_delay: false,
             last_was_branch: false,
             gte: GTE::new(),
             last_touched_addr: 0,
+            trace_file: None,
+            instruction_hook: None,
+            interrupt_hook: None,
+            icache: ICache::new(),
+            bios_hle: false,
+            tty: String::new(),
+            reset_vector: BIOS_ENTRY,
+            strict: true,
         }
     }
-    /// Resets cpu registers to zero and sets program counter to reset vector (0xBFC00000)
+    /// Cold boot. Clears COP0, the GTE and all of the bus devices on top of a normal reset
+    pub fn power_on(&mut self) {
+        self.cop0 = Cop0::new();
+        self.gte = GTE::new();
+        self.icache = ICache::new();
+        self.cycle_count = 0;
+        self.delay_slot = 0;
+        self.exec_delay = false;
+        self.last_was_branch = false;
+        self.written_reg = None;
+        self.main_bus.power_on();
+        self.reset();
+    }
+
+    /// Resets cpu registers to zero and sets program counter to the reset vector (normally 0xBFC00000)
     pub fn reset(&mut self) {
         //Clear registers
         for reg in self.gen_registers.iter_mut() {
@@ -105,136 +168,243 @@ impl R3000 {
         }
         self.hi = 0;
         self.lo = 0;
-        self.pc = 0xBFC00000; // Points to the bios entry point
-        self.cop0
-            .write_reg(12, self.cop0.read_reg(12).set_bit(23, true).clone());
-        self.load_delays = Vec::new();
+        self.pc = self.reset_vector;
+        self.cop0.set_status(*self.cop0.status().set_bit(23, true));
+        self.load_delay = None;
+        self.pending_load = None;
     }
 
-    fn print_string(&mut self, addr: u32) {
-        let val = self.main_bus.read_byte(addr);
-        if val == 0 {
-            //Null, end of string
-            return;
+    /// Starts writing a `pc: instruction` line for every executed instruction to the given file
+    pub fn enable_trace(&mut self, path: &Path) -> io::Result<()> {
+        self.trace_file = Some(BufWriter::new(File::create(path)?));
+        Ok(())
+    }
+
+    /// Stops tracing and flushes anything left in the trace file
+    pub fn disable_trace(&mut self) {
+        if let Some(mut file) = self.trace_file.take() {
+            if let Err(e) = file.flush() {
+                warn!("Failed to flush trace file: {}", e);
+            }
         }
-        print!("{}", std::str::from_utf8(&[val]).unwrap());
-        self.print_string(addr + 1);
     }
 
-    pub fn step_instruction(&mut self, timers: &mut TimerState) {
-        //Fast load exe
+    /// Installs a hook that sees every instruction just before it executes, replacing any previous one
+    pub fn set_instruction_hook(&mut self, hook: InstructionHook) {
+        self.instruction_hook = Some(hook);
+    }
+
+    pub fn clear_instruction_hook(&mut self) {
+        self.instruction_hook = None;
+    }
+
+    /// Installs a hook that sees every external interrupt request, replacing any previous one
+    pub fn set_interrupt_hook(&mut self, hook: InterruptHook) {
+        self.interrupt_hook = Some(hook);
+    }
 
-        if self.load_exe && self.pc == 0xbfc0700c {
-            println!("Jumping to exe...");
-            self.pc = 0x80010000;
+    pub fn clear_interrupt_hook(&mut self) {
+        self.interrupt_hook = None;
+    }
+
+    /// Changes where execution starts on the next reset
+    pub fn set_reset_vector(&mut self, addr: u32) {
+        self.reset_vector = addr;
+    }
+
+    /// With HLE on, the BIOS functions implemented in hle.rs run natively instead of going
+    /// through the BIOS function tables
+    pub fn set_bios_hle(&mut self, enabled: bool) {
+        self.bios_hle = enabled;
+    }
+
+    /// Takes everything printed through the TTY since the last call
+    pub fn take_tty(&mut self) -> String {
+        std::mem::take(&mut self.tty)
+    }
+
+    fn trace(&mut self, pc: u32, instruction: u32) {
+        if let Some(hook) = &mut self.instruction_hook {
+            hook(pc, instruction);
         }
-     
-        if self.pc == 0xB0 {
-            // SYSCALL: Send character to serial port
-            // This catches any characters and prints them to stdout instead
-            if self.read_reg(9) == 0x3D {
-                print!(
-                    "{}",
-                    std::str::from_utf8(&[self.read_reg(4) as u8]).unwrap()
-                );
-            } else {
-                //trace!("SYSCALL B({:#X}) pc: {:#X}", self.read_reg(9), self.current_pc);
+        if let Some(file) = &mut self.trace_file {
+            if let Err(e) = writeln!(file, "{:08x}: {:08x}", pc, instruction) {
+                warn!("Failed to write trace, disabling: {}", e);
+                self.trace_file = None;
             }
         }
+    }
 
-        if self.pc == 0xA0 {
-            //trace!("SYSCALL A({:#X}) pc: {:#X}", self.read_reg(9), self.current_pc);
-            if self.read_reg(9) == 0x40 {
-                panic!("UnhandledException hit!");
-            }
-        }
+    /// Boots through the BIOS as normal, then runs exe instead of the shell
+    pub fn sideload_exe(&mut self, exe: PsxExe) {
+        self.load_exe = Some(exe);
+    }
 
-        if self.pc == 0xC0 {
-            //trace!("SYSCALL C({:#X}) pc: {:#X}", self.read_reg(9), self.current_pc);
+    /// Copies exe into memory and jumps straight to it
+    pub fn run_exe(&mut self, exe: &PsxExe) {
+        self.main_bus.load_bytes(exe.load_address, &exe.data);
+        // Stands in for the FlushCache call the BIOS loader makes
+        self.icache.invalidate_all();
+        self.pc = exe.pc;
+        self.gen_registers[28] = exe.gp;
+        if exe.sp != 0 {
+            self.gen_registers[29] = exe.sp;
+            self.gen_registers[30] = exe.sp;
         }
+    }
 
-        //Check for vblank
-        if self.main_bus.gpu.consume_vblank() {
-            self.fire_external_interrupt(InterruptSource::VBLANK);
+    /// Copies the character of an A0(3Ch) or B0(3Dh) putchar call into the TTY buffer. The call
+    /// still runs through the BIOS as normal
+    fn capture_putchar(&mut self) {
+        let putchar = match self.pc & 0x1FFF_FFFF {
+            0xA0 => 0x3C,
+            0xB0 => 0x3D,
+            _ => return,
         };
+        if self.read_reg(9) == putchar {
+            self.tty.push(self.read_reg(4) as u8 as char);
+        }
+    }
 
-        // Handle interrupts
-        let mut cause = self.cop0.read_reg(13);
-        cause.set_bit(10, self.i_status & self.i_mask != 0);
-        self.cop0.write_reg(13, cause);
-
-
-        if self.cop0.interrupts_enabled() && cause & 0x700 != 0 {
-            self.fire_exception(Exception::Int);
+    pub fn step_instruction(&mut self, timers: &mut TimerState) {
+        if self.pc == SHELL_ENTRY {
+            if let Some(exe) = self.load_exe.take() {
+                trace!("Sideloading exe, jumping to {:#X}", exe.pc);
+                self.run_exe(&exe);
+            }
         }
 
-        
+        if self.bios_hle && self.call_bios_hle() {
+            return;
+        }
 
-        // if self.pc == 0x80015760 {
-        //     println!("a0 {:#X} a1 {:#X}", self.read_reg(4), self.read_reg(5));
-        //     println!("first {:#X} second{:#X}", self.read_bus_word(0x80121CD4, timers), self.read_bus_word(0x80121C90, timers))
-        // }
+        self.capture_putchar();
 
-        if self.pc == 0x80015858  {
-            println!("\nFunc start");
-            unsafe{LOGGING = true;}
-            self.log = true;
+        if self.pc == 0xA0 && self.read_reg(9) == 0x40 {
+            panic!("UnhandledException hit!");
         }
 
-        if self.pc == 0x800158e4 {
-            unsafe{LOGGING = false;}
-            self.log = false;
-            println!("Func end\n");
+        // Handle interrupts
+        self.cop0.set_hardware_interrupt(self.main_bus.interrupts.pending());
+        if self.cop0.should_interrupt() {
+            self.fire_exception(Exception::Int);
         }
 
-        let instruction = self.main_bus.read_word(self.pc);
+        let instruction = match self.fetch(self.pc) {
+            Some(instruction) => instruction,
+            None => return,
+        };
         self.current_pc = self.pc;
         self.pc += 4;
+        self.main_bus.set_log_pc(self.current_pc);
 
         if self.log {
             self.log_instruction(instruction);
         }
+        self.trace(self.current_pc, instruction);
 
         self.exec_delay = false;
         self.last_was_branch = false;
-        for i in (0..self.load_delays.len()).rev() {
-            if self.load_delays[i].cycle_loaded != self.cycle_count {
-                self.write_reg(self.load_delays[i].register, self.load_delays[i].value);
-                self.load_delays.remove(i);
-            }
-        }
-        self.execute_instruction(instruction, timers);
-        self.cycle_count = self.cycle_count.wrapping_add(1);
-
-        if self.main_bus.last_touched_addr == 0x121CA8 {
-            println!("lta pc {:#X} val {:#X}", self.current_pc, self.main_bus.read_word(0x121CA8));
-            self.last_touched_addr = 0;
-        }
+        self.run_with_load_delay(instruction, timers);
 
 
         //Execute branch delay operation
         if self.delay_slot != 0 {
-            let delay_instruction = self.main_bus.read_word(self.delay_slot);
+            let delay_instruction = match self.fetch(self.delay_slot) {
+                Some(instruction) => instruction,
+                None => {
+                    self.delay_slot = 0;
+                    return;
+                }
+            };
             if self.log {
                 self.log_instruction(delay_instruction);
             }
-            //self.trace_file.write(format!("{:08x}: {:08x}\n", self.delay_slot, delay_instruction).as_bytes());
-            //println!("{:08x}: {:08x}", self.delay_slot, delay_instruction);
+            self.trace(self.delay_slot, delay_instruction);
+            self.main_bus.set_log_pc(self.delay_slot);
             self.exec_delay = true;
-            for i in (0..self.load_delays.len()).rev() {
-                if self.load_delays[i].cycle_loaded != self.cycle_count {
-                    self.write_reg(self.load_delays[i].register, self.load_delays[i].value);
-                    self.load_delays.remove(i);
-                }
-            }
-            self.execute_instruction(delay_instruction, timers);
-            self.cycle_count = self.cycle_count.wrapping_add(1);
+            self.run_with_load_delay(delay_instruction, timers);
             self.exec_delay = false;
             self.delay_slot = 0;    
         }
         
     }
 
+    /// Fetches the instruction at addr. Raises AdEL for misaligned addresses, and IBE if nothing
+    /// is mapped there, unless the bus is strict in which case this panics
+    fn fetch(&mut self, addr: u32) -> Option<u32> {
+        if addr & 3 != 0 {
+            warn!("Tried to execute out of alignment at {:#X}", addr);
+            self.current_pc = addr;
+            self.pc = addr.wrapping_add(4);
+            self.fire_address_error(Exception::AdEL, addr);
+            return None;
+        }
+        let cached = addr < KSEG1_START && self.main_bus.cache_control().get_bit(CACHE_ICACHE_ENABLE);
+        if cached {
+            if let Some(instruction) = self.icache.read(addr) {
+                return Some(instruction);
+            }
+        }
+        match self.main_bus.try_read_word(addr) {
+            Ok(instruction) => {
+                if cached {
+                    self.fill_icache_line(addr, instruction);
+                }
+                Some(instruction)
+            }
+            Err(err) => {
+                if self.main_bus.strict() {
+                    panic!("Instruction fetch failed: {}", err);
+                }
+                warn!("Instruction fetch failed: {}", err);
+                self.current_pc = addr;
+                self.pc = addr.wrapping_add(4);
+                self.fire_exception(Exception::IBE);
+                None
+            }
+        }
+    }
+
+    /// A miss refills the line from the missed word to its end
+    fn fill_icache_line(&mut self, addr: u32, instruction: u32) {
+        self.icache.fill(addr, instruction);
+        let mut next = addr + 4;
+        while next & 0xF != 0 {
+            match self.main_bus.try_read_word(next) {
+                Ok(word) => self.icache.fill(next, word),
+                Err(_) => break,
+            }
+            next += 4;
+        }
+    }
+
+    /// With the cache isolated, stores below KSEG2 go to the icache instead of memory
+    fn isolated_cache_write(&mut self, addr: u32, val: u32) {
+        if self.main_bus.cache_control().get_bit(CACHE_TAG_TEST) {
+            self.icache.invalidate(addr);
+        } else {
+            self.icache.write(addr, val);
+        }
+    }
+
+    /// Executes an instruction while retiring the load queued by the previous one.
+    /// The instruction still sees the old register value, and its own write to the same register wins.
+    fn run_with_load_delay(&mut self, instruction: u32, timers: &mut TimerState) {
+        self.pending_load = self.load_delay.take();
+        self.written_reg = None;
+        self.execute_instruction(instruction, timers);
+
+        if let Some(load) = self.pending_load.take() {
+            let overwritten = self.written_reg == Some(load.register)
+                || self.load_delay.is_some_and(|next| next.register == load.register);
+            if !overwritten {
+                self.gen_registers[load.register as usize] = load.value;
+            }
+        }
+        self.cycle_count = self.cycle_count.wrapping_add(1);
+    }
+
     fn log_instruction(&self, instruction: u32) {
         let inst = decode_opcode(instruction).unwrap();
         println!(
@@ -248,24 +418,13 @@ impl R3000 {
     }
 
     pub fn execute_instruction(&mut self, instruction: u32, timers: &mut TimerState) {
-        if self.pc % 4 != 0 || self.delay_slot % 4 != 0 {
-            warn!("Tried to execute out of alignment");
-            self.fire_exception(Exception::AdEL);
-            return;
-        }
-
         match instruction.opcode() {
             0x0 => {
                 //SPECIAL INSTRUCTIONS
                 match instruction.funct() {
                     0x0 => {
-                        //SLL
-                        // if instruction.rt() == 0 {
-                        //     //Actually a NOP
-                        //     return;
-                        // }
+                        //SLL. An all zero word is the canonical NOP, which writes r0 and does nothing
                         self.op_sll(instruction);
-                        //println!("{:#X} << {:#X} = {:#X}", self.read_reg(instruction.rt()), instruction.shamt(), self.read_reg(instruction.rd()));
                     }
 
                     0x2 => {
@@ -405,12 +564,12 @@ impl R3000 {
                         self.op_slt(instruction);
                     }
 
-                    _ => panic!(
+                    _ => self.unknown_instruction(format!(
                         "CPU: Unknown SPECIAL instruction. FUNCT is {0} ({0:#08b}, {0:#X}) PC {1:#X} FULL {2:#X}",
                         instruction.funct(),
-                        self.pc,
+                        self.current_pc,
                         instruction
-                    ),
+                    )),
                 }
             }
 
@@ -441,7 +600,14 @@ impl R3000 {
                         self.last_was_branch = true;
                         self.op_bgezal(instruction)
                     }
-                    _ => (), //psxtest_cpu spams a bunch of invalid instructions, so I'm not printing anything
+                    //psxtest_cpu spams a bunch of invalid instructions, so these are only printed in soft mode
+                    _ if !self.strict => warn!(
+                        "CPU: Unknown test and branch instruction. RT is {:#X} PC {:#X} FULL {:#X}",
+                        instruction.rt(),
+                        self.current_pc,
+                        instruction
+                    ),
+                    _ => (),
                 }
             }
 
@@ -536,13 +702,24 @@ impl R3000 {
                         //RFE
                         self.op_rfe();
                     }
-                    _ => panic!(
-                        "CPU: Unknown COP0 MFC instruction {:#X} ({0:#b}, {0})",
-                        instruction.rs()
-                    ),
+                    _ => self.unknown_instruction(format!(
+                        "CPU: Unknown COP0 MFC instruction {:#X} ({0:#b}, {0}) PC {1:#X}",
+                        instruction.rs(),
+                        self.current_pc
+                    )),
                 }
             }
 
+            0x11 | 0x13 => {
+                //COP1 and COP3 don't exist on the PSX
+                self.fire_coprocessor_unusable(instruction.opcode() as u32 & 0x3);
+            }
+
+            0x12 if !self.cop0.status().get_bit(30) => {
+                //COP2 while the GTE is disabled
+                self.fire_coprocessor_unusable(2);
+            }
+
             0x12 => {
                 //COP2 (GTE) instructions
                 if instruction.get_bit(25) {
@@ -553,8 +730,7 @@ impl R3000 {
                     match instruction.rs() {
                         0x0 => {
                             //MFC2
-                            //This one will just return 0 for now
-                            self.write_reg(instruction.rt(), self.gte.data_register(instruction.rd() as usize));
+                            self.delay_write_reg(instruction.rt(), self.gte.data_register(instruction.rd() as usize));
                         }
     
                         0x6 => {
@@ -571,14 +747,15 @@ impl R3000 {
     
                         0x2 => {
                             //CFC2
-                            self.write_reg(instruction.rt(), self.gte.control_register(instruction.rd() as usize));
+                            self.delay_write_reg(instruction.rt(), self.gte.control_register(instruction.rd() as usize));
                         }
     
-                        _ => panic!(
-                            "CPU: Unknown COP2 MFC instruction {:#X} ({0:#b}, {0}) {:#b}",
+                        _ => self.unknown_instruction(format!(
+                            "CPU: Unknown COP2 MFC instruction {:#X} ({0:#b}, {0}) {:#b} PC {:#X}",
                             instruction.rs(),
-                            instruction
-                        ),
+                            instruction,
+                            self.current_pc
+                        )),
                     }
                 }
             }
@@ -646,40 +823,45 @@ impl R3000 {
                 self.op_sw(instruction, timers);
             }
 
+            0x32 | 0x3A if !self.cop0.status().get_bit(30) => {
+                //GTE transfers while the GTE is disabled
+                self.fire_coprocessor_unusable(2);
+            }
+
             0x32 => {
                 //LWC2
-                let addr = instruction
-                    .immediate_sign_extended()
-                    .wrapping_add(self.read_reg(instruction.rs()));
-                let val = self.read_bus_word(addr, timers);
-                self.gte.set_data_register(instruction.rt() as usize, val);
-
+                self.op_lwc2(instruction, timers);
             }
 
             0x3A => {
                 //SWC2
-                let addr = instruction
-                    .immediate_sign_extended()
-                    .wrapping_add(self.read_reg(instruction.rs()));
-                let val = if instruction.rt() > 31 {
-                    self.gte.control_register(instruction.rt() as usize - 32)
-                } else {
-                    self.gte.data_register(instruction.rt() as usize)
-                };
-                self.write_bus_word(addr, val, timers);
-
+                self.op_swc2(instruction, timers);
             }
 
             
-            _ => panic!(
+            _ => self.unknown_instruction(format!(
                 "CPU: Unknown opcode {0} ({0:#08b}, {0:#X}) PC {1:#X} FULL {2:#X}",
                 instruction.opcode(),
                 self.current_pc,
                 instruction
-            ),
+            )),
         };
     }
 
+    /// Strict mode panics on instructions the decoder doesn't know. Otherwise they're logged
+    /// and skipped like a NOP
+    fn unknown_instruction(&self, message: String) {
+        if self.strict {
+            panic!("{}", message);
+        }
+        warn!("{}. Skipping it", message);
+    }
+
+    /// On by default. Soft mode lets software keep running past unknown instructions
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
     fn op_sw(&mut self, instruction: u32, timers: &mut TimerState) {
         let addr = instruction
             .immediate_sign_extended()
@@ -688,13 +870,27 @@ impl R3000 {
         if addr % 4 != 0 {
             //unaligned address
             trace!("AdES fired by op_sw");
-            self.fire_exception(Exception::AdES);
+            self.fire_address_error(Exception::AdES, addr);
         } else {
             let val = self.read_reg(instruction.rt());
             self.write_bus_word(addr, val, timers);
         };
     }
 
+    fn op_swc2(&mut self, instruction: u32, timers: &mut TimerState) {
+        let addr = instruction
+            .immediate_sign_extended()
+            .wrapping_add(self.read_reg(instruction.rs()));
+
+        if !addr.is_multiple_of(4) {
+            trace!("AdES fired by op_swc2");
+            self.fire_address_error(Exception::AdES, addr);
+        } else {
+            let val = self.gte.data_register(instruction.rt() as usize);
+            self.write_bus_word(addr, val, timers);
+        };
+    }
+
     fn op_swr(&mut self, instruction: u32, timers: &mut TimerState) {
         let addr = instruction
             .immediate_sign_extended()
@@ -739,17 +935,14 @@ impl R3000 {
             .immediate_sign_extended()
             .wrapping_add(self.read_reg(instruction.rs()));
 
-        let word = self.read_bus_word(addr & !3, timers);
+        let word = match self.load_word(addr & !3, timers) {
+            Some(word) => word,
+            None => return,
+        };
 
         // LWR can ignore the load delay, so check if theres an existing load delay and fetch the rt value
         // from there if it exists
-        let mut reg_val = self.read_reg(instruction.rt());
-
-        for ld in &self.load_delays {
-            if ld.register == instruction.rt() {
-                reg_val = ld.value;
-            }
-        }
+        let reg_val = self.in_flight_reg(instruction.rt());
 
         self.delay_write_reg(
             instruction.rt(),
@@ -768,17 +961,14 @@ impl R3000 {
             .immediate_sign_extended()
             .wrapping_add(self.read_reg(instruction.rs()));
 
-        let word = self.read_bus_word(addr & !3, timers);
-        
+        let word = match self.load_word(addr & !3, timers) {
+            Some(word) => word,
+            None => return,
+        };
+
         // LWL can ignore the load delay, so check if theres an existing load delay and fetch the rt value
         // from there if it exists
-        let mut reg_val = self.read_reg(instruction.rt());
-
-        for ld in &self.load_delays {
-            if ld.register == instruction.rt() {
-                reg_val = ld.value;
-            }
-        }
+        let reg_val = self.in_flight_reg(instruction.rt());
 
         self.delay_write_reg(
             instruction.rt(),
@@ -799,14 +989,9 @@ impl R3000 {
         if addr % 2 != 0 {
             //unaligned address
             trace!("AdES fired by op_sh");
-            self.fire_exception(Exception::AdES);
+            self.fire_address_error(Exception::AdES, addr);
         } else {
             let val = (self.read_reg(instruction.rt()) & 0xFFFF) as u16;
-            if addr == 0xD030028 {
-                println!("imm {:#X} rs {:#X} reg {}", instruction.immediate_sign_extended(), self.read_reg(instruction.rs()), instruction.rs());
-                let r = self.read_bus_word(self.read_reg(16), timers);
-                println!("PC {:#X} S0 {:#X} ra {:#X} s0_val {:#X}", self.current_pc, self.read_reg(16), self.read_reg(31), r);
-            };
             self.write_bus_half_word(addr, val, timers);
         };
     }
@@ -824,18 +1009,20 @@ impl R3000 {
             (instruction.immediate_sign_extended()).wrapping_add(self.read_reg(instruction.rs()));
         if addr % 2 != 0 {
             trace!("AdEl fired by op_lhu");
-            self.fire_exception(Exception::AdEL);
+            self.fire_address_error(Exception::AdEL, addr);
         } else {
-            let val = self.read_bus_half_word(addr, timers).zero_extended();
-            self.delay_write_reg(instruction.rt(), val);
+            if let Some(val) = self.load_half_word(addr, timers) {
+                self.delay_write_reg(instruction.rt(), val.zero_extended());
+            }
         };
     }
 
     fn op_lbu(&mut self, instruction: u32) {
         let addr =
             (instruction.immediate_sign_extended()).wrapping_add(self.read_reg(instruction.rs()));
-        let val = self.main_bus.read_byte(addr).zero_extended();
-        self.delay_write_reg(instruction.rt(), val);
+        if let Some(val) = self.load_byte(addr) {
+            self.delay_write_reg(instruction.rt(), val.zero_extended());
+        }
     }
 
     fn op_lw(&mut self, instruction: u32, timers: &mut TimerState) {
@@ -843,10 +1030,24 @@ impl R3000 {
             (instruction.immediate_sign_extended()).wrapping_add(self.read_reg(instruction.rs()));
         if addr % 4 != 0 {
             trace!("AdEl fired by op_lw");
-            self.fire_exception(Exception::AdEL);
+            self.fire_address_error(Exception::AdEL, addr);
+        } else {
+            if let Some(val) = self.load_word(addr, timers) {
+                self.delay_write_reg(instruction.rt(), val);
+            }
+        };
+    }
+
+    fn op_lwc2(&mut self, instruction: u32, timers: &mut TimerState) {
+        let addr =
+            (instruction.immediate_sign_extended()).wrapping_add(self.read_reg(instruction.rs()));
+        if !addr.is_multiple_of(4) {
+            trace!("AdEl fired by op_lwc2");
+            self.fire_address_error(Exception::AdEL, addr);
         } else {
-            let val = self.read_bus_word(addr as u32, timers);
-            self.delay_write_reg(instruction.rt(), val);
+            if let Some(val) = self.load_word(addr, timers) {
+                self.gte.set_data_register(instruction.rt() as usize, val);
+            }
         };
     }
 
@@ -855,24 +1056,26 @@ impl R3000 {
             (instruction.immediate_sign_extended()).wrapping_add(self.read_reg(instruction.rs()));
         if addr % 2 != 0 {
             trace!("AdEl fired by op_lh");
-            self.fire_exception(Exception::AdEL);
+            self.fire_address_error(Exception::AdEL, addr);
         } else {
-            let val = self.read_bus_half_word(addr, timers).sign_extended();
-            self.delay_write_reg(instruction.rt(), val as u32);
+            if let Some(val) = self.load_half_word(addr, timers) {
+                self.delay_write_reg(instruction.rt(), val.sign_extended() as u32);
+            }
         };
     }
 
     fn op_lb(&mut self, instruction: u32) {
         let addr =
             (instruction.immediate_sign_extended()).wrapping_add(self.read_reg(instruction.rs()));
-        let val = self.main_bus.read_byte(addr).sign_extended();
-        self.delay_write_reg(instruction.rt(), val as u32);
+        if let Some(val) = self.load_byte(addr) {
+            self.delay_write_reg(instruction.rt(), val.sign_extended() as u32);
+        }
     }
 
     fn op_rfe(&mut self) {
-        let mode = self.cop0.read_reg(12) & 0x3f;
-        let status = self.cop0.read_reg(12);
-        self.cop0.write_reg(12, (status & !0xf) | (mode >> 2));
+        let status = self.cop0.status();
+        let mode = status & 0x3f;
+        self.cop0.set_status((status & !0xf) | (mode >> 2));
     }
 
     fn op_mfc0(&mut self, instruction: u32) {
@@ -880,8 +1083,13 @@ impl R3000 {
     }
 
     fn op_mtc0(&mut self, instruction: u32) {
-        self.cop0
-            .write_reg(instruction.rd(), self.read_reg(instruction.rt()));
+        let value = self.read_reg(instruction.rt());
+        if instruction.rd() == Cop0Register::Cause as u8 {
+            // Only the two software interrupt bits are writable
+            self.cop0.set_cause((self.cop0.cause() & !0x300) | (value & 0x300));
+        } else {
+            self.cop0.write_reg(instruction.rd(), value);
+        }
     }
 
     fn op_lui(&mut self, instruction: u32) {
@@ -1179,10 +1387,10 @@ impl R3000 {
 
     fn op_jalr(&mut self, instruction: u32) {
         let target = self.read_reg(instruction.rs());
-        self.write_reg(instruction.rd(), self.pc + 4);
+        self.write_reg(instruction.rd(), self.pc.wrapping_add(4));
         if target % 4 != 0 {
             trace!("AdEl fired by op_jalr");
-            self.fire_exception(Exception::AdEL);
+            self.fire_address_error(Exception::AdEL, target);
         } else {
             self.delay_slot = self.pc;
             self.pc = target;
@@ -1193,7 +1401,7 @@ impl R3000 {
         let target = self.read_reg(instruction.rs());
         if target % 4 != 0 {
             trace!("AdEl fired by op_jr");
-            self.fire_exception(Exception::AdEL);
+            self.fire_address_error(Exception::AdEL, target);
         } else {
             self.delay_slot = self.pc;
             self.pc = target;
@@ -1253,23 +1461,21 @@ impl R3000 {
 
 
         if self.delay_slot != 0 {
-            self.cop0.write_reg(13, self.cop0.read_reg(13) | (1 << 31));
-            self.cop0.write_reg(14, self.pc - 8);
+            // EPC points at the branch so it gets run again when the handler returns
+            self.cop0.set_cause(self.cop0.cause() | (1 << 31));
+            self.cop0.set_epc(self.delay_slot.wrapping_sub(4));
         } else {
-            self.cop0.write_reg(13, self.cop0.read_reg(13) & !(1 << 31));
+            self.cop0.set_cause(self.cop0.cause() & !(1 << 31));
             if exception == Exception::Int {
-                self.cop0.write_reg(14, self.pc);
+                self.cop0.set_epc(self.pc);
             } else {
-                self.cop0.write_reg(14, self.pc - 4);
+                self.cop0.set_epc(self.pc - 4);
             }
         }
 
-        let old_status = self.cop0.read_reg(12);
-        self.cop0.write_reg(
-            12,
-            (old_status & !0x3F) | (((old_status & 0x3f) << 2) & 0x3f),
-        );
-        self.pc = if self.cop0.read_reg(12).get_bit(23) {
+        let old_status = self.cop0.status();
+        self.cop0.set_status((old_status & !0x3F) | (((old_status & 0x3f) << 2) & 0x3f));
+        self.pc = if self.cop0.status().get_bit(23) {
             0xBFC0_0180
         } else {
             0x8000_0080
@@ -1278,19 +1484,68 @@ impl R3000 {
         //self.cop0.write_reg(12, self.cop0.read_reg(12) << 4)
     }
 
+    /// Address errors also latch the offending address into BadVaddr
+    fn fire_address_error(&mut self, exception: Exception, addr: u32) {
+        self.cop0.set_bad_vaddr(addr);
+        self.fire_exception(exception);
+    }
+
+    /// Coprocessor unusable also reports which coprocessor was accessed in CAUSE bits 28-29
+    fn fire_coprocessor_unusable(&mut self, coprocessor: u32) {
+        self.fire_exception(Exception::CpU);
+        let cause = self.cop0.cause();
+        self.cop0.set_cause((cause & !(0x3 << 28)) | (coprocessor << 28));
+    }
+
     pub fn fire_external_interrupt(&mut self, source: InterruptSource) {
-        let mask_bit = source.clone() as usize;
-        self.i_status.set_bit(mask_bit, true);
+        self.main_bus.interrupts.request(source);
+        self.cop0.set_hardware_interrupt(self.main_bus.interrupts.pending());
+        if let Some(hook) = &mut self.interrupt_hook {
+            hook(source);
+        }
+    }
+
+    /// Reads for a load instruction. When nothing answers the load raises DBE and gives None,
+    /// so it never reaches its register
+    fn load_word(&mut self, addr: u32, timers: &mut TimerState) -> Option<u32> {
+        match addr & 0x1fffffff {
+            0x1F801100..=0x1F801128 => Some(timers.read_word(addr & 0x1fffffff)),
+            _ => {
+                let result = self.main_bus.load_word(addr);
+                self.data_bus_error(result)
+            }
+        }
+    }
+
+    fn load_half_word(&mut self, addr: u32, timers: &mut TimerState) -> Option<u16> {
+        match addr & 0x1fffffff {
+            0x1F801100..=0x1F801128 => Some(timers.read_half_word(addr & 0x1fffffff)),
+            _ => {
+                let result = self.main_bus.load_half_word(addr);
+                self.data_bus_error(result)
+            }
+        }
+    }
+
+    fn load_byte(&mut self, addr: u32) -> Option<u8> {
+        let result = self.main_bus.load_byte(addr);
+        self.data_bus_error(result)
+    }
+
+    fn data_bus_error<T>(&mut self, result: Result<T, BusError>) -> Option<T> {
+        match result {
+            Ok(value) => Some(value),
+            Err(err) => {
+                warn!("Data load failed: {}", err);
+                self.fire_exception(Exception::DBE);
+                None
+            }
+        }
     }
 
     pub fn read_bus_word(&mut self, addr: u32, timers: &mut TimerState) -> u32 {
         //self.last_touched_addr = addr & 0x1fffffff;
         match addr & 0x1fffffff {
-            0x1F801070 => {
-                //println!("Reading ISTATUS");
-                self.i_status
-            }
-            0x1F801074 => self.i_mask,
             0x1F801100..=0x1F801128 => timers.read_word(addr & 0x1fffffff),
             _ => self.main_bus.read_word(addr),
         }
@@ -1298,57 +1553,34 @@ impl R3000 {
 
     pub fn write_bus_word(&mut self, addr: u32, val: u32, timers: &mut TimerState) {
         self.last_touched_addr = addr & 0x1fffffff;
-        if self.cop0.cache_isolated() {
-            //Cache is isolated, so don't write
+        if self.cop0.cache_isolated() && addr < KSEG2_START {
+            //Cache is isolated, so don't write. KSEG2 never goes through the cache
+            self.isolated_cache_write(addr, val);
             return;
         }
-        
 
         match addr & 0x1fffffff {
-            0x1F801070 => {
-                self.i_status &= val;
-            }
-            0x1F801074 => {
-                //println!("Writing I_MASK val {:#X}", val);
-                self.i_mask = val;
-            }
             0x1F801100..=0x1F801128 => timers.write_word(addr & 0x1fffffff, val),
             _ => self.main_bus.write_word(addr, val),
         };
     }
 
-    fn read_bus_half_word(&mut self, addr: u32, timers: &mut TimerState) -> u16 {
-        //self.last_touched_addr = addr & 0x1fffffff;
-        match addr & 0x1fffffff {
-            0x1F801070 => self.i_status as u16,
-            0x1F801074 => self.i_mask as u16,
-            0x1F801100..=0x1F801128 => timers.read_half_word(addr & 0x1fffffff),
-            _ => self.main_bus.read_half_word(addr),
-        }
-    }
     
     pub fn read_bus_byte(&mut self, addr: u32) -> u8 {
         //self.last_touched_addr = addr & 0x1fffffff;
-        match addr & 0x1fffffff {
-            0x1F801070 => self.i_status as u8,
-            0x1F801072 => (self.i_status >> 8) as u8,
-            0x1F801074 => self.i_mask as u8,
-            0x1F801076 => (self.i_mask >> 8) as u8,
-            _ => self.main_bus.read_byte(addr),
-        }
+        self.main_bus.read_byte(addr)
     }
    
 
     fn write_bus_half_word(&mut self, addr: u32, val: u16, timers: &mut TimerState) {
         self.last_touched_addr = addr & 0x1fffffff;
-        if self.cop0.cache_isolated() {
-            //Cache is isolated, so don't write
+        if self.cop0.cache_isolated() && addr < KSEG2_START {
+            //Cache is isolated, so don't write. KSEG2 never goes through the cache
+            self.isolated_cache_write(addr, val as u32);
             return;
         }
 
         match addr & 0x1fffffff {
-            0x1F801070 => self.i_status &= val as u32,
-            0x1F801074 => self.i_mask = val as u32,
             0x1F801100..=0x1F801128 => timers.write_half_word(addr & 0x1fffffff, val),
             _ => self.main_bus.write_half_word(addr, val),
         };
@@ -1356,15 +1588,12 @@ impl R3000 {
 
     pub fn write_bus_byte(&mut self, addr: u32, val: u8) {
         self.last_touched_addr = addr & 0x1fffffff;
-        if self.cop0.cache_isolated() {
-            //Cache is isolated, so don't write
+        if self.cop0.cache_isolated() && addr < KSEG2_START {
+            //Cache is isolated, so don't write. KSEG2 never goes through the cache
+            self.isolated_cache_write(addr, val as u32);
             return;
         }
-        match addr & 0x1fffffff {
-            0x1F801070 => self.i_status &= val as u32,
-            0x1F801074 => self.i_mask = val as u32,
-            _ => self.main_bus.write_byte(addr, val),
-        };
+        self.main_bus.write_byte(addr, val);
     }
 
     /// Returns the value stored within the given register. Will panic if register_number > 31
@@ -1380,23 +1609,687 @@ impl R3000 {
     fn write_reg(&mut self, register_number: u8, value: u32) {
         match register_number {
             0 => (), //Prevent writing to the zero register
-            _ => self.gen_registers[register_number as usize] = value,
+            _ => {
+                self.gen_registers[register_number as usize] = value;
+                self.written_reg = Some(register_number);
+            }
         }
     }
 
+    /// Queues a register write that only becomes visible after the next instruction (load delay slot)
     fn delay_write_reg(&mut self, register_number: u8, value: u32) {
         if register_number != 0 {
-            //Get rid of old writes to the same register
-            for i in (0..self.load_delays.len()).rev() {
-                if self.load_delays[i].register == register_number {
-                    self.load_delays.remove(i);
-                }
-            }
-            self.load_delays.push(LoadDelay {
+            self.load_delay = Some(LoadDelay {
                 register: register_number,
-                value: value,
-                cycle_loaded: self.cycle_count,
+                value,
             });
         }
     }
+
+    /// Returns the register value including a load that is still in its delay slot
+    fn in_flight_reg(&self, register_number: u8) -> u32 {
+        match self.pending_load {
+            Some(load) if load.register == register_number => load.value,
+            _ => self.read_reg(register_number),
+        }
+    }
+}
+
+#[cfg(test)]
+mod cpu_tests {
+    use super::*;
+    use crate::bios::{Bios, BIOS_SIZE};
+    use crate::gpu::Gpu;
+    use crate::memory::Memory;
+
+    fn test_cpu() -> (R3000, TimerState) {
+        let bus = MainBus::new(Bios::new(vec![0; BIOS_SIZE]).unwrap(), Memory::new(), Gpu::new());
+        let mut cpu = R3000::new(bus);
+        cpu.reset();
+        (cpu, TimerState::new())
+    }
+
+    fn special(rs: u32, rt: u32, rd: u32, funct: u32) -> u32 {
+        (rs << 21) | (rt << 16) | (rd << 11) | funct
+    }
+
+    fn shift(rt: u32, rd: u32, shamt: u32, funct: u32) -> u32 {
+        special(0, rt, rd, funct) | (shamt << 6)
+    }
+
+    #[test]
+    fn test_mult_signed() {
+        let (mut cpu, mut timers) = test_cpu();
+        cpu.gen_registers[8] = -5i32 as u32;
+        cpu.gen_registers[9] = 7;
+        cpu.execute_instruction(special(8, 9, 0, 0x18), &mut timers);
+        let product = ((cpu.hi as u64) << 32) | cpu.lo as u64;
+        assert_eq!(product as i64, -35);
+        assert_eq!(cpu.hi, 0xFFFF_FFFF);
+        assert_eq!(cpu.lo, -35i32 as u32);
+    }
+
+    /// Runs DIV or DIVU on rs / rt and returns (hi, lo)
+    fn divide(funct: u32, rs: u32, rt: u32) -> (u32, u32) {
+        let (mut cpu, mut timers) = test_cpu();
+        cpu.gen_registers[8] = rs;
+        cpu.gen_registers[9] = rt;
+        cpu.execute_instruction(special(8, 9, 0, funct), &mut timers);
+        (cpu.hi, cpu.lo)
+    }
+
+    #[test]
+    fn test_divu_by_zero() {
+        assert_eq!(divide(0x1B, 1234, 0), (1234, 0xFFFF_FFFF));
+        assert_eq!(divide(0x1B, 7, 2), (1, 3));
+    }
+
+    #[test]
+    fn test_div_by_zero_depends_on_sign() {
+        assert_eq!(divide(0x1A, 1234, 0), (1234, 0xFFFF_FFFF));
+        assert_eq!(divide(0x1A, -1234i32 as u32, 0), (-1234i32 as u32, 1));
+        assert_eq!(divide(0x1A, 0, 0), (0, 0xFFFF_FFFF));
+    }
+
+    #[test]
+    fn test_div_overflow() {
+        assert_eq!(divide(0x1A, 0x8000_0000, -1i32 as u32), (0, 0x8000_0000));
+        assert_eq!(divide(0x1A, -7i32 as u32, 2), (-1i32 as u32, -3i32 as u32));
+    }
+
+    #[test]
+    fn test_sub_overflow_traps() {
+        let (mut cpu, mut timers) = test_cpu();
+        cpu.pc = 0x1004;
+        cpu.gen_registers[8] = 0x7FFF_FFFF;
+        cpu.gen_registers[9] = -2i32 as u32;
+        cpu.gen_registers[10] = 0x1234;
+        cpu.execute_instruction(special(8, 9, 10, 0x22), &mut timers);
+        assert_eq!(cpu.pc, 0xBFC0_0180);
+        assert_eq!((cpu.cop0.cause() >> 2) & 0x1F, Exception::Ovf as u32);
+        // Destination register must be left untouched
+        assert_eq!(cpu.gen_registers[10], 0x1234);
+    }
+
+    #[test]
+    fn test_break_sets_cause_code() {
+        let (mut cpu, mut timers) = test_cpu();
+        cpu.pc = 0x1004;
+        cpu.execute_instruction(special(0, 0, 0, 0xD), &mut timers);
+        assert_eq!((cpu.cop0.cause() >> 2) & 0x1F, 9);
+        assert_eq!(cpu.cop0.epc(), 0x1000);
+        assert_eq!(cpu.pc, 0xBFC0_0180);
+    }
+
+    fn load_program(cpu: &mut R3000, addr: u32, program: &[u32]) {
+        for (i, word) in program.iter().enumerate() {
+            cpu.main_bus.write_word(addr + (i as u32 * 4), *word);
+        }
+        cpu.pc = addr;
+    }
+
+    fn immediate(opcode: u32, rs: u32, rt: u32, imm: u16) -> u32 {
+        (opcode << 26) | (rs << 21) | (rt << 16) | imm as u32
+    }
+
+    /// Runs a REGIMM branch at 0x1000 with $t0 = t0, returning the pc and $ra afterwards.
+    /// Taken branches run their delay slot in the same step
+    fn run_regimm(rt: u32, t0: u32) -> (u32, u32) {
+        let (mut cpu, mut timers) = test_cpu();
+        cpu.write_reg(8, t0);
+        load_program(&mut cpu, 0x1000, &[immediate(0x1, 8, rt, 4), 0]);
+        cpu.step_instruction(&mut timers);
+        (cpu.pc, cpu.read_reg(31))
+    }
+
+    #[test]
+    fn test_bgezal_links_whether_taken_or_not() {
+        assert_eq!(run_regimm(0x11, 0), (0x1014, 0x1008));
+        assert_eq!(run_regimm(0x11, 0xFFFF_FFFF), (0x1004, 0x1008));
+    }
+
+    #[test]
+    fn test_bltzal_links_whether_taken_or_not() {
+        assert_eq!(run_regimm(0x10, 0x8000_0000), (0x1014, 0x1008));
+        assert_eq!(run_regimm(0x10, 1), (0x1004, 0x1008));
+        // The plain variants leave $ra alone
+        assert_eq!(run_regimm(0x0, 0x8000_0000), (0x1014, 0));
+        assert_eq!(run_regimm(0x1, 0x8000_0000), (0x1004, 0));
+    }
+
+    #[test]
+    fn test_load_delay_slot_sees_old_value() {
+        let (mut cpu, mut timers) = test_cpu();
+        cpu.main_bus.write_word(0x2000, 0xDEAD_BEEF);
+        cpu.gen_registers[8] = 1;
+        load_program(&mut cpu, 0x1000, &[
+            immediate(0x23, 0, 8, 0x2000), // lw $t0, 0x2000($zero)
+            special(8, 0, 9, 0x21),        // addu $t1, $t0, $zero
+            special(8, 0, 10, 0x21),       // addu $t2, $t0, $zero
+        ]);
+        for _ in 0..3 {
+            cpu.step_instruction(&mut timers);
+        }
+        assert_eq!(cpu.read_reg(9), 1);
+        assert_eq!(cpu.read_reg(10), 0xDEAD_BEEF);
+    }
+
+    #[test]
+    fn test_back_to_back_loads_second_wins() {
+        let (mut cpu, mut timers) = test_cpu();
+        cpu.main_bus.write_word(0x2000, 0x1111_1111);
+        cpu.main_bus.write_word(0x2004, 0x2222_2222);
+        load_program(&mut cpu, 0x1000, &[
+            immediate(0x23, 0, 8, 0x2000), // lw $t0, 0x2000($zero)
+            immediate(0x23, 0, 8, 0x2004), // lw $t0, 0x2004($zero)
+            special(8, 0, 9, 0x21),        // addu $t1, $t0, $zero
+            0,
+        ]);
+        for _ in 0..4 {
+            cpu.step_instruction(&mut timers);
+        }
+        assert_eq!(cpu.read_reg(9), 0);
+        assert_eq!(cpu.read_reg(8), 0x2222_2222);
+    }
+
+    #[test]
+    fn test_write_in_load_delay_slot_wins() {
+        let (mut cpu, mut timers) = test_cpu();
+        cpu.main_bus.write_word(0x2000, 0xDEAD_BEEF);
+        load_program(&mut cpu, 0x1000, &[
+            immediate(0x23, 0, 8, 0x2000), // lw $t0, 0x2000($zero)
+            immediate(0x09, 0, 8, 5),      // addiu $t0, $zero, 5
+            0,
+        ]);
+        for _ in 0..3 {
+            cpu.step_instruction(&mut timers);
+        }
+        assert_eq!(cpu.read_reg(8), 5);
+    }
+
+    #[test]
+    fn test_lwl_lwr_merge_in_flight_load() {
+        let (mut cpu, mut timers) = test_cpu();
+        cpu.main_bus.write_word(0x2000, 0x4433_2211);
+        cpu.main_bus.write_word(0x2004, 0x8877_6655);
+        load_program(&mut cpu, 0x1000, &[
+            immediate(0x22, 0, 8, 0x2004), // lwl $t0, 0x2004($zero)
+            immediate(0x26, 0, 8, 0x2001), // lwr $t0, 0x2001($zero)
+            0,
+        ]);
+        for _ in 0..3 {
+            cpu.step_instruction(&mut timers);
+        }
+        assert_eq!(cpu.read_reg(8), 0x5544_3322);
+    }
+
+    #[test]
+    fn test_trace_file() {
+        let (mut cpu, mut timers) = test_cpu();
+        let path = std::env::temp_dir().join(format!("psx_emu_trace_test_{}.txt", std::process::id()));
+        cpu.enable_trace(&path).unwrap();
+        load_program(&mut cpu, 0x1000, &[immediate(0x09, 0, 8, 5), 0]);
+        cpu.step_instruction(&mut timers);
+        cpu.step_instruction(&mut timers);
+        cpu.disable_trace();
+
+        let trace = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(trace, "00001000: 24080005\n00001004: 00000000\n");
+    }
+
+    #[test]
+    fn test_instruction_hook_counts_instructions() {
+        use std::sync::{Arc, Mutex};
+
+        let (mut cpu, mut timers) = test_cpu();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let hook_seen = seen.clone();
+        cpu.set_instruction_hook(Box::new(move |pc, instruction| {
+            hook_seen.lock().unwrap().push((pc, instruction));
+        }));
+        load_program(&mut cpu, 0x1000, &[
+            immediate(0x4, 0, 0, 0x10), // beq $zero, $zero, 0x10
+            immediate(0x09, 0, 8, 5),   // addiu $t0, $zero, 5
+        ]);
+        cpu.step_instruction(&mut timers);
+
+        // The delay slot goes through the hook too
+        assert_eq!(*seen.lock().unwrap(), vec![
+            (0x1000, immediate(0x4, 0, 0, 0x10)),
+            (0x1004, immediate(0x09, 0, 8, 5)),
+        ]);
+
+        cpu.clear_instruction_hook();
+        cpu.step_instruction(&mut timers);
+        assert_eq!(seen.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_gte_transfer_round_trip() {
+        let (mut cpu, mut timers) = test_cpu();
+        cpu.cop0.set_status(cpu.cop0.status() | (1 << 30));
+        cpu.main_bus.write_word(0x2000, 0x1234_5678);
+        load_program(&mut cpu, 0x1000, &[
+            immediate(0x32, 0, 24, 0x2000), // lwc2 $24, 0x2000($zero) (MAC0)
+            immediate(0x3A, 0, 24, 0x2004), // swc2 $24, 0x2004($zero)
+        ]);
+        cpu.step_instruction(&mut timers);
+        assert_eq!(cpu.gte.data_register(24), 0x1234_5678);
+        cpu.step_instruction(&mut timers);
+        assert_eq!(cpu.main_bus.read_word(0x2004), 0x1234_5678);
+
+        load_program(&mut cpu, 0x3000, &[immediate(0x3A, 0, 24, 0x2006)]);
+        cpu.step_instruction(&mut timers);
+        assert_eq!((cpu.cop0.cause() >> 2) & 0x1F, Exception::AdES as u32);
+        assert_eq!(cpu.cop0.bad_vaddr(), 0x2006);
+    }
+
+    #[test]
+    fn test_unusable_coprocessors() {
+        let (mut cpu, mut timers) = test_cpu();
+        load_program(&mut cpu, 0x1000, &[0x4400_0000]); // mfc1 $zero, $0
+        cpu.step_instruction(&mut timers);
+        let cause = cpu.cop0.cause();
+        assert_eq!((cause >> 2) & 0x1F, Exception::CpU as u32);
+        assert_eq!((cause >> 28) & 0x3, 1);
+        assert_eq!(cpu.cop0.epc(), 0x1000);
+        assert_eq!(cpu.pc, 0xBFC0_0180);
+
+        // The GTE only answers once CU2 is set
+        load_program(&mut cpu, 0x2000, &[0x4808_4800]); // mfc2 $t0, $9
+        cpu.step_instruction(&mut timers);
+        assert_eq!((cpu.cop0.cause() >> 28) & 0x3, 2);
+        assert_eq!(cpu.cop0.epc(), 0x2000);
+
+        // So do LWC2 and SWC2, which leave the GTE and memory alone
+        cpu.main_bus.write_word(0x2100, 0x1234_5678);
+        load_program(&mut cpu, 0x2000, &[
+            immediate(0x32, 0, 24, 0x2100), // lwc2 $24, 0x2100($zero)
+            immediate(0x3A, 0, 24, 0x2104), // swc2 $24, 0x2104($zero)
+        ]);
+        for pc in [0x2000, 0x2004].iter() {
+            cpu.pc = *pc;
+            cpu.step_instruction(&mut timers);
+            assert_eq!((cpu.cop0.cause() >> 2) & 0x1F, Exception::CpU as u32);
+            assert_eq!((cpu.cop0.cause() >> 28) & 0x3, 2);
+            assert_eq!(cpu.cop0.epc(), *pc);
+        }
+        assert_eq!(cpu.gte.data_register(24), 0);
+        assert_eq!(cpu.main_bus.read_word(0x2104), 0);
+
+        cpu.cop0.set_status(cpu.cop0.status() | (1 << 30));
+        load_program(&mut cpu, 0x3000, &[0x4808_4800, 0]);
+        cpu.step_instruction(&mut timers);
+        cpu.step_instruction(&mut timers);
+        assert_eq!(cpu.pc, 0x3008);
+    }
+
+    #[test]
+    fn test_unmapped_fetch_raises_bus_error() {
+        let (mut cpu, mut timers) = test_cpu();
+        cpu.pc = 0x1F10_0000;
+        cpu.step_instruction(&mut timers);
+        assert_eq!((cpu.cop0.cause() >> 2) & 0x1F, Exception::IBE as u32);
+        assert_eq!(cpu.cop0.epc(), 0x1F10_0000);
+        assert_eq!(cpu.pc, 0xBFC0_0180);
+    }
+
+    #[test]
+    fn test_unmapped_load_raises_bus_error() {
+        let (mut cpu, mut timers) = test_cpu();
+        cpu.gen_registers[8] = 0x55;
+        load_program(&mut cpu, 0x1000, &[
+            immediate(0xF, 0, 9, 0x1F90), // lui $t1, 0x1F90
+            immediate(0x23, 9, 8, 0),     // lw $t0, 0($t1)
+            0,
+        ]);
+        cpu.step_instruction(&mut timers);
+        cpu.step_instruction(&mut timers);
+        assert_eq!((cpu.cop0.cause() >> 2) & 0x1F, Exception::DBE as u32);
+        assert_eq!(cpu.cop0.epc(), 0x1004);
+        assert_eq!(cpu.pc, 0xBFC0_0180);
+        cpu.step_instruction(&mut timers);
+        assert_eq!(cpu.gen_registers[8], 0x55);
+    }
+
+    #[test]
+    fn test_syscall_in_delay_slot() {
+        let (mut cpu, mut timers) = test_cpu();
+        load_program(&mut cpu, 0x1000, &[
+            immediate(0x4, 0, 0, 0x10), // beq $zero, $zero, 0x10
+            special(0, 0, 0, 0xC),      // syscall
+        ]);
+        cpu.step_instruction(&mut timers);
+
+        let cause = cpu.cop0.cause();
+        assert_eq!((cause >> 2) & 0x1F, Exception::Sys as u32);
+        assert!(cause.get_bit(31));
+        assert_eq!(cpu.cop0.epc(), 0x1000);
+        assert_eq!(cpu.pc, 0xBFC0_0180);
+
+        // Exceptions outside of a delay slot clear BD again
+        load_program(&mut cpu, 0x2000, &[special(0, 0, 0, 0xC)]);
+        cpu.step_instruction(&mut timers);
+        assert!(!cpu.cop0.cause().get_bit(31));
+        assert_eq!(cpu.cop0.epc(), 0x2000);
+    }
+
+    #[test]
+    fn test_software_interrupt_dispatches_when_unmasked() {
+        let (mut cpu, mut timers) = test_cpu();
+        let status = cpu.cop0.status() | 1;
+        cpu.cop0.set_status(status);
+        let mtc0_cause = (0x10 << 26) | (0x4 << 21) | (8 << 16) | (13 << 11);
+        // ori $t0, $zero, 0x17C ; mtc0 $t0, $13
+        load_program(&mut cpu, 0x1000, &[immediate(0xD, 0, 8, 0x17C), mtc0_cause, 0]);
+        for _ in 0..3 {
+            cpu.step_instruction(&mut timers);
+        }
+        // Only IP0 took the write, and it's masked off
+        assert_eq!(cpu.cop0.cause(), 0x100);
+        assert_eq!(cpu.pc, 0x100C);
+
+        cpu.cop0.set_status(status | 0x100);
+        cpu.step_instruction(&mut timers);
+        assert_eq!((cpu.cop0.cause() >> 2) & 0x1F, Exception::Int as u32);
+        assert_eq!(cpu.cop0.epc(), 0x100C);
+        assert_eq!(cpu.pc & 0xFFFF_FF00, 0xBFC0_0100);
+    }
+
+    #[test]
+    fn test_external_interrupt_sets_ip2() {
+        let (mut cpu, _) = test_cpu();
+        cpu.fire_external_interrupt(InterruptSource::VBLANK);
+        assert_eq!(cpu.cop0.interrupt_pending(), 0);
+        cpu.main_bus.write_word(0x1F80_1074, 1);
+        cpu.fire_external_interrupt(InterruptSource::VBLANK);
+        assert_eq!(cpu.cop0.interrupt_pending(), 0x4);
+    }
+
+    #[test]
+    fn test_nested_exceptions_restore_status_stack() {
+        let (mut cpu, mut timers) = test_cpu();
+        // BEV set, user mode with interrupts enabled in the current slot
+        cpu.cop0.set_status(0x0040_0003);
+
+        load_program(&mut cpu, 0x1000, &[special(0, 0, 0, 0xC)]);
+        cpu.step_instruction(&mut timers);
+        assert_eq!(cpu.cop0.status(), 0x0040_000C);
+
+        load_program(&mut cpu, 0x2000, &[special(0, 0, 0, 0xC)]);
+        cpu.step_instruction(&mut timers);
+        assert_eq!(cpu.cop0.status(), 0x0040_0030);
+
+        // rfe pops one level at a time and leaves the pc alone
+        let rfe = (0x10 << 26) | (0x10 << 21) | 0x10;
+        load_program(&mut cpu, 0x3000, &[rfe, rfe]);
+        cpu.step_instruction(&mut timers);
+        assert_eq!(cpu.cop0.status(), 0x0040_003C);
+        assert_eq!(cpu.pc, 0x3004);
+        cpu.step_instruction(&mut timers);
+        assert_eq!(cpu.cop0.status(), 0x0040_003F);
+        assert_eq!(cpu.pc, 0x3008);
+    }
+
+    #[test]
+    fn test_unaligned_lw_raises_address_error() {
+        let (mut cpu, mut timers) = test_cpu();
+        cpu.gen_registers[8] = 0x2000;
+        cpu.gen_registers[9] = 0x1234;
+        load_program(&mut cpu, 0x1000, &[immediate(0x23, 8, 9, 0x2)]); // lw $t1, 2($t0)
+        cpu.step_instruction(&mut timers);
+
+        assert_eq!((cpu.cop0.cause() >> 2) & 0x1F, Exception::AdEL as u32);
+        assert_eq!(cpu.cop0.bad_vaddr(), 0x2002);
+        assert_eq!(cpu.cop0.epc(), 0x1000);
+        assert_eq!(cpu.pc, 0xBFC0_0180);
+        // The load never happens
+        cpu.step_instruction(&mut timers);
+        assert_eq!(cpu.gen_registers[9], 0x1234);
+    }
+
+    #[test]
+    fn test_unaligned_sh_raises_address_error() {
+        let (mut cpu, mut timers) = test_cpu();
+        cpu.gen_registers[8] = 0x2001;
+        load_program(&mut cpu, 0x1000, &[immediate(0x29, 8, 9, 0x0)]); // sh $t1, 0($t0)
+        cpu.step_instruction(&mut timers);
+        assert_eq!((cpu.cop0.cause() >> 2) & 0x1F, Exception::AdES as u32);
+        assert_eq!(cpu.cop0.bad_vaddr(), 0x2001);
+    }
+
+    #[test]
+    fn test_unaligned_jump_register_raises_address_error() {
+        for funct in [0x8, 0x9].iter() {
+            let (mut cpu, mut timers) = test_cpu();
+            cpu.gen_registers[8] = 0x2002;
+            // jr $t0 / jalr $ra, $t0, and a delay slot that must not run
+            load_program(&mut cpu, 0x1000, &[special(8, 0, 31, *funct), immediate(0x9, 0, 9, 1)]);
+            cpu.step_instruction(&mut timers);
+
+            assert_eq!((cpu.cop0.cause() >> 2) & 0x1F, Exception::AdEL as u32);
+            assert_eq!(cpu.cop0.bad_vaddr(), 0x2002);
+            assert_eq!(cpu.cop0.epc(), 0x1000);
+            assert_eq!(cpu.pc, 0xBFC0_0180);
+            assert_eq!(cpu.gen_registers[9], 0);
+            // JALR still links
+            let link = if *funct == 0x9 { 0x1008 } else { 0 };
+            assert_eq!(cpu.gen_registers[31], link);
+        }
+    }
+
+    #[test]
+    fn test_soft_mode_skips_unknown_instructions() {
+        let (mut cpu, mut timers) = test_cpu();
+        cpu.set_strict(false);
+        load_program(&mut cpu, 0x1000, &[
+            0xFC00_0000, // Opcode 0x3F
+            special(0, 0, 0, 0x3F),
+            immediate(0x1, 0, 0x5, 0x10), // REGIMM with rt 5
+            immediate(0xD, 0, 8, 1), // ori $t0, $zero, 1
+        ]);
+        for pc in [0x1004, 0x1008, 0x100C].iter() {
+            cpu.step_instruction(&mut timers);
+            assert_eq!(cpu.pc, *pc);
+        }
+        cpu.step_instruction(&mut timers);
+        assert_eq!(cpu.gen_registers[8], 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "Unknown opcode")]
+    fn test_strict_mode_panics_on_unknown_opcode() {
+        let (mut cpu, mut timers) = test_cpu();
+        load_program(&mut cpu, 0x1000, &[0xFC00_0000]);
+        cpu.step_instruction(&mut timers);
+    }
+
+    #[test]
+    fn test_unaligned_fetch_raises_address_error() {
+        let (mut cpu, mut timers) = test_cpu();
+        cpu.pc = 0x1002;
+        cpu.step_instruction(&mut timers);
+        assert_eq!((cpu.cop0.cause() >> 2) & 0x1F, Exception::AdEL as u32);
+        assert_eq!(cpu.cop0.bad_vaddr(), 0x1002);
+        assert_eq!(cpu.cop0.epc(), 0x1002);
+    }
+
+    #[test]
+    fn test_isolated_cache_writes_are_dropped() {
+        let (mut cpu, mut timers) = test_cpu();
+        cpu.write_bus_word(0x8000_0100, 0x1234_5678, &mut timers);
+
+        // The BIOS enables the icache, isolates it, and zeroes every line by storing over low RAM
+        cpu.write_bus_word(0xFFFE_0130, 0x0000_0804, &mut timers);
+        assert_eq!(cpu.main_bus.cache_control(), 0x804);
+        cpu.cop0.set_status(0x0001_0000);
+        for addr in (0x8000_0000..0x8000_1000).step_by(4) {
+            cpu.write_bus_word(addr, 0, &mut timers);
+        }
+        cpu.write_bus_half_word(0x8000_0104, 0xFFFF, &mut timers);
+        cpu.write_bus_byte(0x8000_0108, 0xFF);
+        cpu.write_bus_word(0xFFFE_0130, 0x0001_E988, &mut timers);
+        cpu.cop0.set_status(0);
+
+        assert_eq!(cpu.read_bus_word(0x8000_0100, &mut timers), 0x1234_5678);
+        assert_eq!(cpu.read_bus_word(0x8000_0104, &mut timers), 0);
+        assert_eq!(cpu.main_bus.cache_control(), 0x1_E988);
+
+        // Writes land again once isolation is off
+        cpu.write_bus_word(0x8000_0100, 0xCAFE_F00D, &mut timers);
+        assert_eq!(cpu.read_bus_word(0x8000_0100, &mut timers), 0xCAFE_F00D);
+    }
+
+    #[test]
+    fn test_icache_holds_stale_instructions_until_invalidated() {
+        let (mut cpu, mut timers) = test_cpu();
+        let run_at = |cpu: &mut R3000, timers: &mut TimerState, pc: u32| {
+            cpu.pc = pc;
+            cpu.step_instruction(timers);
+            cpu.read_reg(1)
+        };
+        cpu.write_bus_word(0xFFFE_0130, 0x800, &mut timers);
+        load_program(&mut cpu, 0x8000_1000, &[immediate(0x09, 0, 1, 1)]);
+        assert_eq!(run_at(&mut cpu, &mut timers, 0x8000_1000), 1);
+
+        // Memory changes underneath the cache, but KSEG1 bypasses it
+        load_program(&mut cpu, 0x8000_1000, &[immediate(0x09, 0, 1, 2)]);
+        assert_eq!(run_at(&mut cpu, &mut timers, 0x8000_1000), 1);
+        assert_eq!(run_at(&mut cpu, &mut timers, 0xA000_1000), 2);
+
+        // Isolated stores write straight into the cached line
+        cpu.cop0.set_status(0x0001_0000);
+        cpu.write_bus_word(0x8000_1000, immediate(0x09, 0, 1, 3), &mut timers);
+        cpu.cop0.set_status(0);
+        assert_eq!(run_at(&mut cpu, &mut timers, 0x8000_1000), 3);
+        assert_eq!(cpu.read_bus_word(0x8000_1000, &mut timers), immediate(0x09, 0, 1, 2));
+
+        // Flushing the line the way the BIOS does picks up the new instruction
+        cpu.write_bus_word(0xFFFE_0130, 0x804, &mut timers);
+        cpu.cop0.set_status(0x0001_0000);
+        cpu.write_bus_word(0x8000_1000, 0, &mut timers);
+        cpu.cop0.set_status(0);
+        cpu.write_bus_word(0xFFFE_0130, 0x800, &mut timers);
+        assert_eq!(run_at(&mut cpu, &mut timers, 0x8000_1000), 2);
+    }
+
+    #[test]
+    fn test_immediate_shifts() {
+        let (mut cpu, mut timers) = test_cpu();
+        cpu.gen_registers[8] = 0x8000_0001;
+        let cases = [
+            (0x0, 0, 0x8000_0001),
+            (0x0, 4, 0x0000_0010),
+            (0x0, 31, 0x8000_0000),
+            (0x2, 0, 0x8000_0001),
+            (0x2, 4, 0x0800_0000),
+            (0x2, 31, 0x0000_0001),
+            (0x3, 0, 0x8000_0001),
+            (0x3, 4, 0xF800_0000),
+            (0x3, 31, 0xFFFF_FFFF),
+        ];
+        for (funct, shamt, expected) in cases.iter() {
+            cpu.gen_registers[9] = 0xDEAD_BEEF;
+            cpu.execute_instruction(shift(8, 9, *shamt, *funct), &mut timers);
+            assert_eq!(cpu.gen_registers[9], *expected, "funct {:#X} by {}", funct, shamt);
+        }
+
+        // A positive value shifts in zeroes either way
+        cpu.gen_registers[8] = 0x4000_0000;
+        cpu.execute_instruction(shift(8, 9, 30, 0x3), &mut timers);
+        assert_eq!(cpu.gen_registers[9], 1);
+
+        // The zero word is a NOP, and r0 stays zero even when it's the destination
+        cpu.execute_instruction(0, &mut timers);
+        cpu.execute_instruction(shift(8, 0, 1, 0x0), &mut timers);
+        assert_eq!(cpu.read_reg(0), 0);
+    }
+
+    #[test]
+    fn test_variable_shifts_mask_the_amount() {
+        let (mut cpu, mut timers) = test_cpu();
+        cpu.gen_registers[8] = 0x8000_0001;
+        let cases = [
+            (0x4, 0, 0x8000_0001),
+            (0x4, 31, 0x8000_0000),
+            (0x4, 33, 0x0000_0002),
+            (0x6, 31, 0x0000_0001),
+            (0x6, 0xFFFF_FFE4, 0x0800_0000),
+            (0x7, 31, 0xFFFF_FFFF),
+            (0x7, 32, 0x8000_0001),
+            (0x7, 36, 0xF800_0000),
+        ];
+        for (funct, amount, expected) in cases.iter() {
+            cpu.gen_registers[10] = *amount;
+            cpu.execute_instruction(special(10, 8, 9, *funct), &mut timers);
+            assert_eq!(cpu.gen_registers[9], *expected, "funct {:#X} by {:#X}", funct, amount);
+        }
+    }
+
+    fn write_c_string(cpu: &mut R3000, addr: u32, string: &str) {
+        for (i, byte) in string.bytes().chain(std::iter::once(0)).enumerate() {
+            cpu.main_bus.write_byte(addr + i as u32, byte);
+        }
+    }
+
+    /// Sets up a call to a BIOS function that returns to 0x80001000
+    fn bios_call(cpu: &mut R3000, vector: u32, function: u32, args: &[u32]) {
+        for (i, arg) in args.iter().enumerate() {
+            cpu.gen_registers[4 + i] = *arg;
+        }
+        cpu.gen_registers[9] = function;
+        cpu.gen_registers[31] = 0x8000_1000;
+        cpu.pc = vector;
+    }
+
+    #[test]
+    fn test_hle_printf() {
+        let (mut cpu, mut timers) = test_cpu();
+        cpu.set_bios_hle(true);
+        let format = "%s=%04x|%-3d|%c%%|%d\n";
+        write_c_string(&mut cpu, 0x8000_2000, format);
+        write_c_string(&mut cpu, 0x8000_2100, "hp");
+        // The fifth and sixth arguments go on the stack, past the home space for a0-a3
+        cpu.gen_registers[29] = 0x8000_3000;
+        cpu.main_bus.write_word(0x8000_3010, 'A' as u32);
+        cpu.main_bus.write_word(0x8000_3014, -5i32 as u32);
+
+        bios_call(&mut cpu, 0xA0, 0x3F, &[0x8000_2000, 0x8000_2100, 0xBE, 7]);
+        cpu.step_instruction(&mut timers);
+        let expected = "hp=00be|7  |A%|-5\n";
+        assert_eq!(cpu.take_tty(), expected);
+        assert_eq!(cpu.read_reg(2), expected.len() as u32);
+        assert_eq!(cpu.pc, 0x8000_1000);
+        assert!(cpu.take_tty().is_empty());
+    }
+
+    #[test]
+    fn test_hle_putchar_memcpy_and_fallthrough() {
+        let (mut cpu, mut timers) = test_cpu();
+        cpu.set_bios_hle(true);
+        bios_call(&mut cpu, 0xB0, 0x3D, &['x' as u32]);
+        cpu.step_instruction(&mut timers);
+        assert_eq!(cpu.take_tty(), "x");
+
+        write_c_string(&mut cpu, 0x8000_2000, "copy");
+        bios_call(&mut cpu, 0xA0, 0x2A, &[0x8000_2100, 0x8000_2000, 5]);
+        cpu.step_instruction(&mut timers);
+        assert_eq!(cpu.read_reg(2), 0x8000_2100);
+        bios_call(&mut cpu, 0xA0, 0x1B, &[0x8000_2100]);
+        cpu.step_instruction(&mut timers);
+        assert_eq!(cpu.read_reg(2), 4);
+
+        // Anything unimplemented runs the BIOS's own code at the vector
+        bios_call(&mut cpu, 0xC0, 0x00, &[]);
+        cpu.step_instruction(&mut timers);
+        assert_eq!(cpu.pc, 0xC4);
+
+        // Without HLE the character is still captured, but the BIOS code runs as well
+        cpu.set_bios_hle(false);
+        bios_call(&mut cpu, 0xA0, 0x3C, &['y' as u32]);
+        cpu.step_instruction(&mut timers);
+        assert_eq!(cpu.pc, 0xA4);
+        assert_eq!(cpu.take_tty(), "y");
+    }
 }
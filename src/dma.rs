@@ -1,6 +1,7 @@
 use crate::cpu::{InterruptSource, R3000};
+use crate::memory::RAM_MASK;
 use bit_field::BitField;
-use log::{error, info, trace};
+use log::{error, info, trace, warn};
 
 const NUM_CHANNELS: usize = 7;
 
@@ -77,6 +78,37 @@ impl Channel {
     }
 }
 
+/// Register values of one DMA channel, for debuggers
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct DmaChannelSnapshot {
+    pub name: &'static str,
+    /// D#_MADR
+    pub base_addr: u32,
+    /// D#_BCR
+    pub block: u32,
+    /// D#_CHCR
+    pub control: u32,
+}
+
+impl DmaChannelSnapshot {
+    pub fn from_ram(&self) -> bool {
+        self.control.get_bit(0)
+    }
+
+    /// 0 transfers everything at once, 1 goes block by block and 2 follows a linked list
+    pub fn sync_mode(&self) -> u32 {
+        self.control.get_bits(9..=10)
+    }
+
+    pub fn busy(&self) -> bool {
+        self.control.get_bit(24)
+    }
+
+    pub fn triggered(&self) -> bool {
+        self.control.get_bit(28)
+    }
+}
+
 pub struct DMAState {
     channels: [Channel; NUM_CHANNELS],
     control: u32,
@@ -168,6 +200,27 @@ impl DMAState {
         };
     }
 
+    /// None if channel_num isn't one of the 7 channels
+    pub fn channel_snapshot(&self, channel_num: usize) -> Option<DmaChannelSnapshot> {
+        let channel = self.channels.get(channel_num)?;
+        Some(DmaChannelSnapshot {
+            name: DMA_CHANNEL_NAMES[channel_num],
+            base_addr: channel.base_addr,
+            block: channel.block,
+            control: channel.control,
+        })
+    }
+
+    /// DPCR
+    pub fn control(&self) -> u32 {
+        self.control
+    }
+
+    /// DICR
+    pub fn interrupt(&self) -> u32 {
+        self.interrupt
+    }
+
     pub fn update_master_flag(&mut self) {
         let should_flag = self.interrupt.get_bit(15)
             || (self.interrupt.get_bit(23)
@@ -284,7 +337,6 @@ pub fn execute_dma_cycle(cpu: &mut R3000) {
                     }
                     0x1000200 => {
                         //VramRead
-                        //TODO: Implement properly
                         trace!("VRAM read");
 
                         let entries = (cpu.main_bus.dma.channels[num].block >> 16) & 0xFFFF;
@@ -292,10 +344,10 @@ pub fn execute_dma_cycle(cpu: &mut R3000) {
                         let base_addr = cpu.main_bus.dma.channels[num].base_addr & 0xFFFFFF;
                         for i in 0..entries {
                             for j in 0..block_size {
-                                //Lets just write all zeros for now
+                                let packet = cpu.main_bus.gpu.read_word_gp0();
                                 cpu.main_bus.write_word(
                                     base_addr + ((i * block_size) * 4) + (j * 4),
-                                    0xFFFFFFFF,
+                                    packet,
                                 );
                             }
                         }
@@ -316,15 +368,28 @@ pub fn execute_dma_cycle(cpu: &mut R3000) {
             }
 
             3 => {
-                let words = (cpu.main_bus.dma.channels[num].block) & 0xFFFF;
+                //CDROM
+                let block = cpu.main_bus.dma.channels[num].block;
+                let sync_mode = cpu.main_bus.dma.channels[num].control.get_bits(9..=10);
+                let words = match sync_mode {
+                    // A block count of 0 means the largest possible transfer
+                    0 => if block & 0xFFFF == 0 { 0x10000 } else { block & 0xFFFF },
+                    _ => (block & 0xFFFF) * ((block >> 16) & 0xFFFF),
+                };
                 let base_addr = (cpu.main_bus.dma.channels[num].base_addr & 0xFFFFFF) as usize;
-                let data = cpu.main_bus.cd_drive.sector_data_take();
+                let mut data = cpu.main_bus.cd_drive.sector_data_take(words as usize * 4);
+                if data.len() < words as usize * 4 {
+                    warn!("CD DMA wanted {} words but only {} bytes were buffered", words, data.len());
+                    data.resize(words as usize * 4, 0);
+                }
 
                 trace!("Words {} base_addr {:#X}", words, base_addr);
-                if base_addr <= 0x121CA8 && base_addr + (words * 4) as usize >= 0x121CA8 {
-                    println!("CD DMA thing touched it");
+                for (offset, byte) in data.iter().enumerate() {
+                    cpu.main_bus.memory.write_byte((base_addr + offset) as u32 & RAM_MASK, *byte);
+                }
+                if sync_mode == 1 {
+                    cpu.main_bus.dma.channels[num].base_addr += words * 4;
                 }
-                cpu.main_bus.memory.data[base_addr..(base_addr + (words * 4) as usize)].copy_from_slice(data);
                 cpu.main_bus.dma.channels[num].complete();
                 cpu.main_bus.dma.raise_irq(num);
                 if cpu.main_bus.dma.irq_channel_enabled(num) {
@@ -332,12 +397,27 @@ pub fn execute_dma_cycle(cpu: &mut R3000) {
                 } else {
                     trace!("DMA IRQ Rejected");
                     trace!("DICR: {:#X}", cpu.main_bus.dma.interrupt);
-                }     
+                }
             }
 
             4 => {
                 //SPU
-                //Lets just ignore this one for now
+                let block_size = cpu.main_bus.dma.channels[num].block & 0xFFFF;
+                let blocks = ((cpu.main_bus.dma.channels[num].block >> 16) & 0xFFFF).max(1);
+                let base_addr = cpu.main_bus.dma.channels[num].base_addr & 0xFFFFFC;
+                let to_spu = cpu.main_bus.dma.channels[num].control.get_bit(0);
+                trace!("SPU DMA to_spu {} block size {} blocks {} base {:#X}", to_spu, block_size, blocks, base_addr);
+                for i in 0..(block_size * blocks) {
+                    let addr = base_addr + i * 4;
+                    if to_spu {
+                        let word = cpu.main_bus.read_word(addr);
+                        cpu.main_bus.spu.dma_write_word(word);
+                    } else {
+                        let word = cpu.main_bus.spu.dma_read_word();
+                        cpu.main_bus.write_word(addr, word);
+                    }
+                }
+                cpu.main_bus.dma.channels[num].base_addr += block_size * blocks * 4;
                 cpu.main_bus.dma.channels[num].complete();
                 cpu.main_bus.dma.raise_irq(num);
                 if cpu.main_bus.dma.irq_channel_enabled(num) {
@@ -0,0 +1,120 @@
+use std::fmt;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+/// The header takes up the first 2KiB, the program body follows it
+pub const HEADER_SIZE: usize = 0x800;
+const MAGIC: &[u8; 8] = b"PS-X EXE";
+
+/// Errors that can occur while parsing a PS-EXE
+#[derive(Debug, PartialEq)]
+pub enum ExeError {
+    TooShort { got: usize },
+    BadMagic,
+    Truncated { expected: usize, got: usize },
+}
+
+impl fmt::Display for ExeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExeError::TooShort { got } => write!(
+                f,
+                "Executable is {} bytes, too short for the {} byte header",
+                got, HEADER_SIZE
+            ),
+            ExeError::BadMagic => write!(f, "Executable is missing the \"PS-X EXE\" magic"),
+            ExeError::Truncated { expected, got } => write!(
+                f,
+                "Executable header says the body is {} bytes, but only {} are there",
+                expected, got
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ExeError {}
+
+/// A parsed PS-EXE, the format homebrew and test programs are distributed in
+#[derive(Debug, Clone)]
+pub struct PsxExe {
+    pub pc: u32,
+    pub gp: u32,
+    pub load_address: u32,
+    /// Initial sp/fp. The header leaves this as 0 when the program sets up its own stack
+    pub sp: u32,
+    pub data: Vec<u8>,
+}
+
+impl PsxExe {
+    pub fn parse(exe: &[u8]) -> Result<Self, ExeError> {
+        if exe.len() < HEADER_SIZE {
+            return Err(ExeError::TooShort { got: exe.len() });
+        }
+        if &exe[0..8] != MAGIC {
+            return Err(ExeError::BadMagic);
+        }
+
+        let file_size = LittleEndian::read_u32(&exe[0x1C..0x20]) as usize;
+        let body = &exe[HEADER_SIZE..];
+        if body.len() < file_size {
+            return Err(ExeError::Truncated {
+                expected: file_size,
+                got: body.len(),
+            });
+        }
+
+        let sp_base = LittleEndian::read_u32(&exe[0x30..0x34]);
+        let sp_offset = LittleEndian::read_u32(&exe[0x34..0x38]);
+
+        Ok(Self {
+            pc: LittleEndian::read_u32(&exe[0x10..0x14]),
+            gp: LittleEndian::read_u32(&exe[0x14..0x18]),
+            load_address: LittleEndian::read_u32(&exe[0x18..0x1C]),
+            sp: if sp_base == 0 { 0 } else { sp_base.wrapping_add(sp_offset) },
+            data: body[..file_size].to_vec(),
+        })
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod exe_tests {
+    use super::*;
+
+    pub(crate) fn build_exe(pc: u32, load_address: u32, sp: u32, body: &[u8]) -> Vec<u8> {
+        let mut exe = vec![0; HEADER_SIZE];
+        exe[0..8].copy_from_slice(MAGIC);
+        LittleEndian::write_u32(&mut exe[0x10..0x14], pc);
+        LittleEndian::write_u32(&mut exe[0x14..0x18], 0x8001_8000);
+        LittleEndian::write_u32(&mut exe[0x18..0x1C], load_address);
+        LittleEndian::write_u32(&mut exe[0x1C..0x20], body.len() as u32);
+        LittleEndian::write_u32(&mut exe[0x30..0x34], sp);
+        LittleEndian::write_u32(&mut exe[0x34..0x38], 0x10);
+        exe.extend_from_slice(body);
+        exe
+    }
+
+    #[test]
+    fn test_parse_header() {
+        let exe = PsxExe::parse(&build_exe(0x8001_0000, 0x8001_0000, 0x801F_FF00, &[1, 2, 3, 4])).unwrap();
+        assert_eq!(exe.pc, 0x8001_0000);
+        assert_eq!(exe.gp, 0x8001_8000);
+        assert_eq!(exe.load_address, 0x8001_0000);
+        assert_eq!(exe.sp, 0x801F_FF10);
+        assert_eq!(exe.data, vec![1, 2, 3, 4]);
+
+        assert_eq!(PsxExe::parse(&build_exe(0, 0, 0, &[])).unwrap().sp, 0);
+    }
+
+    #[test]
+    fn test_rejects_bad_executables() {
+        assert_eq!(PsxExe::parse(&[0; 16]).unwrap_err(), ExeError::TooShort { got: 16 });
+
+        let mut exe = build_exe(0, 0, 0, &[0; 8]);
+        assert_eq!(
+            PsxExe::parse(&exe[..HEADER_SIZE + 4]).unwrap_err(),
+            ExeError::Truncated { expected: 8, got: 4 }
+        );
+        exe[0] = b'X';
+        assert_eq!(PsxExe::parse(&exe).unwrap_err(), ExeError::BadMagic);
+    }
+}
@@ -5,6 +5,19 @@ use log::{error, trace};
 
 const CYCLES_PER_SCANLINE: u32 = 3413;
 const TOTAL_SCANLINES: u32 = 263;
+const PAL_CYCLES_PER_SCANLINE: u32 = 3406;
+const PAL_TOTAL_SCANLINES: u32 = 314;
+
+/// What GP1 0x10 reports as the GPU version. 2 is the newer 208 pin GPU, the 160 pin one
+/// doesn't answer request 0x7 at all
+const GPU_VERSION: u32 = 2;
+
+/// VRAM is laid out as 1024x512 16 bit pixels
+pub const VRAM_WIDTH: u16 = 1024;
+pub const VRAM_HEIGHT: u16 = 512;
+
+/// Largest internal resolution multiplier. An 8x VRAM is already 64MiB
+pub const MAX_INTERNAL_SCALE: u32 = 8;
 
 #[derive(Copy, Clone, Debug)]
 enum TextureColorMode {
@@ -13,6 +26,20 @@ enum TextureColorMode {
     FifteenBit,
 }
 
+/// Video timing standard, selected by GP1 0x08 bit 3
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum VideoMode {
+    Ntsc,
+    Pal,
+}
+
+/// Which half of an interlaced frame was scanned out. Progressive video is always even
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Field {
+    Even,
+    Odd,
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub struct Resolution {
     pub height: u32,
@@ -44,16 +71,6 @@ impl Point {
         }
     }
 
-    fn from_word_with_offset(word: u32, color: u16, offset: Point) -> Self {
-        Self {
-            x: ((word & 0xFFFF) as i32 + offset.x as i32) as i16,
-            y: (((word >> 16) & 0xFFFF) as i32 + offset.y as i32) as i16,
-            color,
-            tex_x: 0,
-            tex_y: 0,
-        }
-    }
-
     fn from_components(x: i16, y: i16, color: u16) -> Self {
         Self {
             x,
@@ -75,21 +92,67 @@ impl Point {
     }
 }
 
+/// Rectangle being streamed between the CPU and VRAM one halfword at a time
+#[derive(Copy, Clone, Debug)]
+struct VramTransfer {
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+    index: u32,
+}
+
+impl VramTransfer {
+    fn from_words(position: u32, size: u32) -> Self {
+        Self {
+            x: position & 0x3FF,
+            y: (position >> 16) & 0x1FF,
+            width: ((size & 0xFFFF).wrapping_sub(1) & 0x3FF) + 1,
+            height: ((size >> 16).wrapping_sub(1) & 0x1FF) + 1,
+            index: 0,
+        }
+    }
+
+    /// VRAM address of the next pixel, wrapping around the edges of VRAM
+    fn next_address(&mut self) -> usize {
+        let x = (self.x + self.index % self.width) & 0x3FF;
+        let y = (self.y + self.index / self.width) & 0x1FF;
+        self.index += 1;
+        point_to_address(x, y) as usize
+    }
+
+    fn done(&self) -> bool {
+        self.index >= self.width * self.height
+    }
+}
+
 pub struct Gpu {
     vram: Vec<u16>,
     status_reg: u32,
     pixel_count: u32,
-    enabled: bool,
+    display_enabled: bool,
     gp0_buffer: Vec<u32>,
     color_depth: ColorDepth,
+    cpu_transfer: Option<VramTransfer>,
+    vram_read: Option<VramTransfer>,
+    gpuread_latch: u32,
 
     texpage_x_base: u16,
     texpage_y_base: u16,
     texmode: TextureColorMode,
     palette_x: u16,
     palette_y: u16,
-    blend_enabled: bool,
-    blend_color: u16,
+    semi_transparency: u8,
+    set_mask_bit: bool,
+    check_mask_bit: bool,
+    dma_direction: u32,
+    raw_texture: bool,
+    texture_color: u32,
+    /// GP0 0xE2 fields, in 8 pixel units
+    tex_window_mask_x: u8,
+    tex_window_mask_y: u8,
+    tex_window_offset_x: u8,
+    tex_window_offset_y: u8,
 
     draw_area_tl_point: Point,
     draw_area_br_point: Point,
@@ -100,12 +163,24 @@ pub struct Gpu {
     hblank_consumed: bool,
     show_frame: bool,
     frame_ready: bool,
+    odd_field: bool,
+    last_field: Field,
 
     display_h_res: u32,
     display_v_res: u32,
+    display_mode: u32,
+    display_origin_x: u32,
+    display_origin_y: u32,
 
     ntsc_y1: u32,
     ntsc_y2: u32,
+
+    /// Internal resolution multiplier. Above 1, primitives are also drawn into scaled_vram,
+    /// which is what gets displayed. Transfers and texture lookups stay on the native VRAM
+    scale: u32,
+    scaled_vram: Vec<u16>,
+    /// Scale of the buffer the current drawing pass targets
+    pass_scale: u32,
 }
 
 impl Gpu {
@@ -114,17 +189,28 @@ impl Gpu {
             vram: vec![0; 1_048_576 / 2],
             status_reg: 0x1C000000,
             pixel_count: 0,
-            enabled: false,
+            display_enabled: true,
             gp0_buffer: Vec::new(),
             color_depth: ColorDepth::Reduced,
+            cpu_transfer: None,
+            vram_read: None,
+            gpuread_latch: 0,
 
             texpage_x_base: 0,
             texpage_y_base: 0,
             texmode: TextureColorMode::FifteenBit,
             palette_x: 0,
             palette_y: 0,
-            blend_enabled: false,
-            blend_color: 0xFFFF,
+            semi_transparency: 0,
+            set_mask_bit: false,
+            check_mask_bit: false,
+            dma_direction: 0,
+            raw_texture: false,
+            texture_color: 0x808080,
+            tex_window_mask_x: 0,
+            tex_window_mask_y: 0,
+            tex_window_offset_x: 0,
+            tex_window_offset_y: 0,
 
             draw_area_tl_point: Point::from_components(0, 0, 0),
             draw_area_br_point: Point::from_components(0, 0, 0),
@@ -135,21 +221,37 @@ impl Gpu {
             hblank_consumed: false,
             show_frame: false,
             frame_ready: false,
+            odd_field: false,
+            last_field: Field::Even,
 
             display_h_res: 640,
             display_v_res: 480,
+            display_mode: 0,
+            display_origin_x: 0,
+            display_origin_y: 0,
 
             ntsc_y1: 16,
             ntsc_y2: 256,
+
+            scale: 1,
+            scaled_vram: Vec::new(),
+            pass_scale: 1,
         }
     }
 
     //Only reseting the big stuff. This will probably bite me later
     pub fn reset(&mut self) {
         self.vram = vec![0; 1_048_576 / 2];
+        for pixel in self.scaled_vram.iter_mut() {
+            *pixel = 0;
+        }
         self.status_reg = 0x1C000000;
         self.gp0_buffer = Vec::new();
+        self.cpu_transfer = None;
+        self.vram_read = None;
         self.pixel_count = 0;
+        self.odd_field = false;
+        self.last_field = Field::Even;
     }
 
     pub fn read_status_register(&mut self) -> u32 {
@@ -158,6 +260,9 @@ impl Gpu {
 
         stat |= (self.texpage_x_base) as u32;
         stat |= (self.texpage_y_base << 4) as u32;
+        stat |= (self.semi_transparency as u32) << 5;
+        stat.set_bit(11, self.set_mask_bit);
+        stat.set_bit(12, self.check_mask_bit);
 
         stat |= match self.texmode {
             TextureColorMode::FourBit => 0,
@@ -165,18 +270,62 @@ impl Gpu {
             TextureColorMode::FifteenBit => 2,
         } << 7;
 
-        stat |= 0x1C000000;
+        // GP1 0x08 display mode bits
+        stat |= (self.display_mode & 0x3F) << 17;
+        stat.set_bit(16, self.display_mode.get_bit(6));
+        stat.set_bit(14, self.display_mode.get_bit(7));
+
+        stat.set_bit(23, !self.display_enabled);
+
+        // Bit 13 is stuck on without interlace, bit 31 reads as even during vblank
+        stat.set_bit(13, !self.interlaced() || self.odd_field);
+        stat.set_bit(31, self.interlaced() && self.odd_field && !self.is_vblank());
+
+        // Commands run instantly, so the GPU only holds off new commands while it's still
+        // collecting parameters or pixel data. It can always take the next word of a block
+        let ready_for_command = self.gp0_buffer.is_empty() && self.cpu_transfer.is_none();
+        let ready_to_send = self.vram_read.is_some();
+        stat.set_bit(26, ready_for_command);
+        stat.set_bit(27, ready_to_send);
+        stat.set_bit(28, true);
+
+        stat |= self.dma_direction << 29;
+        stat.set_bit(25, match self.dma_direction {
+            0 => false,
+            1 | 2 => true,
+            _ => ready_to_send,
+        });
 
 
         stat
     }
 
+    /// Reads GPUREAD. While a VRAM->CPU transfer is running each read returns the next two pixels,
+    /// otherwise the last value is returned again
     pub fn read_word_gp0(&mut self) -> u32 {
-        //println!("Reading gp0");
-        0x0 as u32
+        if let Some(mut transfer) = self.vram_read.take() {
+            let mut word = 0;
+            for shift in [0, 16].iter() {
+                if transfer.done() {
+                    break;
+                }
+                let address = transfer.next_address();
+                word |= (self.vram[address] as u32) << shift;
+            }
+            self.gpuread_latch = word;
+            if !transfer.done() {
+                self.vram_read = Some(transfer);
+            }
+        }
+        self.gpuread_latch
     }
 
     pub fn send_gp0_command(&mut self, value: u32) {
+        if self.cpu_transfer.is_some() {
+            self.write_transfer_word(value);
+            return;
+        }
+
         self.gp0_push(value);
 
         let command = self.gp0_buffer[0];
@@ -192,17 +341,17 @@ impl Gpu {
                             return;
                         }
 
-                        let x1 = self.gp0_buffer[1] & 0xFFFF;
-                        let y1 = (self.gp0_buffer[1] >> 16) & 0xFFFF;
-                        let x2 = ((self.gp0_buffer[2] & 0xFFFF) + x1);
-                        let y2 = (((self.gp0_buffer[2] >> 16) & 0xFFFF) + y1);
-                        self.draw_solid_box(
-                            x1,
-                            y1,
-                            x2,
-                            y2,
-                            b24color_to_b15color(self.gp0_buffer[0] & 0x1FFFFFF) + 1,
-                            false,
+                        // X is aligned down and the width rounded up to 16 pixels
+                        let x = self.gp0_buffer[1] & 0x3F0;
+                        let y = (self.gp0_buffer[1] >> 16) & 0x1FF;
+                        let width = ((self.gp0_buffer[2] & 0x3FF) + 0xF) & !0xF;
+                        let height = (self.gp0_buffer[2] >> 16) & 0x1FF;
+                        self.fill_rectangle(
+                            x,
+                            y,
+                            width,
+                            height,
+                            b24color_to_b15color(self.gp0_buffer[0] & 0xFFFFFF),
                         );
                     }
                     _ => {
@@ -213,10 +362,6 @@ impl Gpu {
 
             0x1 => {
                 //Render Polygon
-
-                // If the polygon is textured or gouraud shaded, lets just lock up the emulator.
-                // I only want to test flat shaded polygons right now
-
                 let is_gouraud = command.get_bit(28);
                 let is_textured = command.get_bit(26);
                 let is_quad = command.get_bit(27);
@@ -232,309 +377,142 @@ impl Gpu {
                     return;
                 }
 
-                let fill = b24color_to_b15color(self.gp0_buffer[0] & 0x1FFFFFF);
-                self.blend_enabled = self.gp0_buffer[0].get_bit(24);
-                self.blend_color = fill;
-                if is_quad {
-                    if is_textured && is_gouraud {
-                        //Should be blending in colors. Do that later
-                        //println!("Tried to try draw texture blended quad!");
-                    } else if is_textured {
-                        //println!("GPU: Tex quad");
-                        let points: Vec<Point> = vec![
-                            Point::new_textured_point(
-                                self.gp0_buffer[1],
-                                ((self.gp0_buffer[2] >> 8) & 0xFF) as i16,
-                                (self.gp0_buffer[2] & 0xFF) as i16,
-                            ),
-                            Point::new_textured_point(
-                                self.gp0_buffer[3],
-                                ((self.gp0_buffer[4] >> 8) & 0xFF) as i16,
-                                (self.gp0_buffer[4] & 0xFF) as i16,
-                            ),
-                            Point::new_textured_point(
-                                self.gp0_buffer[5],
-                                ((self.gp0_buffer[6] >> 8) & 0xFF) as i16,
-                                (self.gp0_buffer[6] & 0xFF) as i16,
-                            ),
-                            Point::new_textured_point(
-                                self.gp0_buffer[7],
-                                ((self.gp0_buffer[8] >> 8) & 0xFF) as i16,
-                                (self.gp0_buffer[8] & 0xFF) as i16,
-                            ),
-                        ];
-
-                        self.palette_x = ((self.gp0_buffer[2] >> 16) & 0x3F) as u16;
-                        self.palette_y = ((self.gp0_buffer[2] >> 22) & 0x1FF) as u16;
-                        self.texpage_x_base = ((self.gp0_buffer[4] >> 16) & 0xF) as u16;
-                        self.texpage_y_base = ((self.gp0_buffer[4] >> 20) & 0x1) as u16;
-                        self.blend_color = fill;
-
-                        self.draw_textured_quad(&points, command.get_bit(25));
-                    } else if is_gouraud {
-                        //println!("GPU: gouraud quad");
-                        let points: Vec<Point> = vec![
-                            Point::from_word(self.gp0_buffer[1], fill),
-                            Point::from_word(
-                                self.gp0_buffer[3],
-                                b24color_to_b15color(self.gp0_buffer[2]),
-                            ),
-                            Point::from_word(
-                                self.gp0_buffer[5],
-                                b24color_to_b15color(self.gp0_buffer[4]),
-                            ),
-                            Point::from_word(
-                                self.gp0_buffer[7],
-                                b24color_to_b15color(self.gp0_buffer[6]),
-                            ),
-                        ];
-                        self.draw_shaded_quad(&points, command.get_bit(25));
+                // Every vertex is an optional color word, the position, then an optional texcoord word.
+                // The first vertex's color lives in the command word itself
+                let stride = 1 + is_gouraud as usize + is_textured as usize;
+                let vertex_word = |i: usize| 1 + i * stride;
+                let colors: Vec<u32> = (0..verts)
+                    .map(|i| {
+                        let word = if is_gouraud { i * stride } else { 0 };
+                        self.gp0_buffer[word] & 0xFFFFFF
+                    })
+                    .collect();
+                let points: Vec<Point> = (0..verts)
+                    .map(|i| {
+                        let word = self.gp0_buffer[vertex_word(i)];
+                        if is_textured {
+                            let uv = self.gp0_buffer[vertex_word(i) + 1];
+                            Point::new_textured_point(word, ((uv >> 8) & 0xFF) as i16, (uv & 0xFF) as i16)
+                        } else {
+                            Point::from_word(word, 0)
+                        }
+                    })
+                    .collect();
+
+                let transparent = command.get_bit(25);
+                self.raw_texture = self.gp0_buffer[0].get_bit(24);
+                self.texture_color = colors[0];
+                if is_textured {
+                    self.set_clut(self.gp0_buffer[vertex_word(0) + 1]);
+                    self.set_polygon_texpage(self.gp0_buffer[vertex_word(1) + 1]);
+                    let shading = if is_gouraud { Some(&colors[..]) } else { None };
+                    if is_quad {
+                        self.draw_textured_quad(&points, shading, transparent);
                     } else {
-                        let points: Vec<Point> = vec![
-                            Point::from_word(self.gp0_buffer[1], 0),
-                            Point::from_word(self.gp0_buffer[2], 0),
-                            Point::from_word(self.gp0_buffer[3], 0),
-                            Point::from_word(self.gp0_buffer[4], 0),
-                        ];
-                        self.draw_solid_quad(&points, fill, command.get_bit(25));
-                    };
+                        self.draw_textured_triangle(&points, shading, transparent);
+                    }
+                } else if is_gouraud {
+                    if is_quad {
+                        self.draw_shaded_quad(&points, &colors, transparent);
+                    } else {
+                        self.draw_shaded_triangle(&points, &colors, transparent);
+                    }
                 } else {
-                    if is_gouraud && is_textured {
-                        //println!("Tried to try draw texture blended tri! Queue {:?}", self.gp0_buffer);
-                    } else if is_textured {
-                        //println!("GPU: Tex tri");
-                        let points: Vec<Point> = vec![
-                            Point::new_textured_point(
-                                self.gp0_buffer[1],
-                                ((self.gp0_buffer[2] >> 8) & 0xFF) as i16,
-                                (self.gp0_buffer[2] & 0xFF) as i16,
-                            ),
-                            Point::new_textured_point(
-                                self.gp0_buffer[3],
-                                ((self.gp0_buffer[4] >> 8) & 0xFF) as i16,
-                                (self.gp0_buffer[4] & 0xFF) as i16,
-                            ),
-                            Point::new_textured_point(
-                                self.gp0_buffer[5],
-                                ((self.gp0_buffer[6] >> 8) & 0xFF) as i16,
-                                (self.gp0_buffer[6] & 0xFF) as i16,
-                            ),
-                        ];
-                        ////println!("{:?}", points);
-                        self.palette_x = ((self.gp0_buffer[2] >> 16) & 0x3F) as u16;
-                        self.palette_y = ((self.gp0_buffer[2] >> 22) & 0x1FF) as u16;
-                        //println!("palx {}", self.palette_x);
-                        self.texpage_x_base = ((self.gp0_buffer[4] >> 16) & 0xF) as u16;
-                        self.texpage_y_base = ((self.gp0_buffer[4] >> 20) & 0x1) as u16;
-                        // self.blend_color = if fill == 0 {
-                        //     0xFFFF
-                        // } else {
-                        //     fill
-                        // };
-                        self.blend_color = fill;
-                        self.draw_textured_triangle(&points, command.get_bit(25));
-                    } else if is_gouraud {
-                        //println!("GPU: gouraud tri");
-                        let points: Vec<Point> = vec![
-                            Point::from_word(self.gp0_buffer[1], fill),
-                            Point::from_word(
-                                self.gp0_buffer[3],
-                                b24color_to_b15color(self.gp0_buffer[2]),
-                            ),
-                            Point::from_word(
-                                self.gp0_buffer[5],
-                                b24color_to_b15color(self.gp0_buffer[4]),
-                            ),
-                        ];
-                        ////println!("{:?}", points);
-                        self.draw_shaded_triangle(&points, command.get_bit(25));
+                    let fill = b24color_to_b15color(colors[0]);
+                    if is_quad {
+                        self.draw_solid_quad(&points, fill, transparent);
                     } else {
-                        let points: Vec<Point> = vec![
-                            Point::from_word(self.gp0_buffer[1], 0),
-                            Point::from_word(self.gp0_buffer[2], 0),
-                            Point::from_word(self.gp0_buffer[3], 0),
-                        ];
-                        self.draw_solid_triangle(&points, fill, command.get_bit(25));
+                        self.draw_solid_triangle(&points, fill, transparent);
                     }
                 }
             }
 
             0x2 => {
                 //Render line
-                if command.get_bit(27) {
-                    ////println!("{:?}", self.gp0_buffer);
-                    if (self.gp0_buffer[self.gp0_buffer.len() - 1] & 0xF000F000) != 0x50005000 {
+                let is_gouraud = command.get_bit(28);
+                let transparent = command.get_bit(25);
+                let len = self.gp0_buffer.len();
+                let vertices = if command.get_bit(27) {
+                    // Gouraud polylines pass the terminator in place of the next color
+                    let min_len = if is_gouraud { 5 } else { 4 };
+                    if len < min_len
+                        || (is_gouraud && len & 1 == 0)
+                        || (self.gp0_buffer[len - 1] & 0xF000F000) != 0x50005000
+                    {
                         //Wait until terminating vertex
                         return;
                     }
-                    //TODO draw polyline
+                    if is_gouraud { (len - 1) / 2 } else { len - 2 }
                 } else {
-                    if self.gp0_buffer.len() < (3 + if command.get_bit(28) { 2 } else { 0 }) {
+                    if len < (3 + if is_gouraud { 1 } else { 0 }) {
                         //Not enough commands
                         return;
                     }
+                    2
+                };
 
-                    //TODO draw line
+                let points = self.line_points(vertices, is_gouraud);
+                for segment in points.windows(2) {
+                    self.draw_line(segment[0], segment[1], transparent);
                 }
             }
 
             0x3 => {
                 //Render Rectangle
-
                 let size = (command >> 27) & 0x3;
+                let is_textured = command.get_bit(26);
 
-                let length =
-                    2 + if size == 0 { 1 } else { 0 } + if command.get_bit(26) { 1 } else { 0 };
+                let length = 2 + if size == 0 { 1 } else { 0 } + if is_textured { 1 } else { 0 };
 
                 if self.gp0_buffer.len() < length {
                     //Not enough commands
                     return;
                 }
 
-                match size {
-                    0b01 => {
-                        //println!("GPU: Single point");
-                        //Draw single pixel
-                        let point = Point::from_word(self.gp0_buffer[1], 0);
-
-                        let address = point_to_address(point.x as u32, point.y as u32) as usize;
-                        let color = if command.get_bit(25) {
-                            //Transparent
-                            alpha_composite(
-                                self.vram[address],
-                                b24color_to_b15color(self.gp0_buffer[0] & 0x1FFFFFF),
-                            )
-                        } else {
-                            b24color_to_b15color(self.gp0_buffer[0] & 0x1FFFFFF)
-                        };
-                        self.vram[address] = color;
-                    }
-
-                    0b0 => {
-                        //Draw variable size
-                        if command.get_bit(26) {
-                            //println!("GPU: Tex box");
-                            let tl_point = Point::new_textured_point(
-                                self.gp0_buffer[1],
-                                ((self.gp0_buffer[2] >> 8) & 0xFF) as i16,
-                                (self.gp0_buffer[2] & 0xFF) as i16,
-                            );
-
-                            let size = Point::from_word(self.gp0_buffer[3], 0);
-
-                            self.palette_x = ((self.gp0_buffer[2] >> 16) & 0x3F) as u16;
-                            self.palette_y = ((self.gp0_buffer[2] >> 22) & 0x1FF) as u16;
-
-                            self.draw_textured_box(&tl_point, size.x, size.y, command.get_bit(25));
-                        } else {
-                            //println!("GPU: solid box");
-                            let tl_point = Point::from_word(self.gp0_buffer[1], 0);
-                            let br_point =
-                                Point::from_word_with_offset(self.gp0_buffer[2], 0, tl_point);
-
-                            //println!("tl: {:?} br: {:?}", tl_point, br_point);
-
-                            self.draw_solid_box(
-                                (tl_point.x + self.draw_offset.x) as u32,
-                                (tl_point.y + self.draw_offset.y) as u32,
-                                (br_point.x + self.draw_offset.x) as u32,
-                                (br_point.y + self.draw_offset.y) as u32,
-                                b24color_to_b15color(self.gp0_buffer[0] & 0x1FFFFFF),
-                                command.get_bit(25),
-                            );
-                        }
-                    }
-
-                    0b10 => {
-                        //8x8 sprite
-                        //println!("GPU: 8x8 sprite");
-                        if command.get_bit(26) {
-                            let tl_point = Point::new_textured_point(
-                                self.gp0_buffer[1],
-                                ((self.gp0_buffer[2] >> 8) & 0xFF) as i16,
-                                (self.gp0_buffer[2] & 0xFF) as i16,
-                            );
-
-                            let size = Point::from_components(8, 8, 0);
-
-                            self.palette_x = ((self.gp0_buffer[2] >> 16) & 0x3F) as u16;
-                            self.palette_y = ((self.gp0_buffer[2] >> 22) & 0x1FF) as u16;
-
-                            self.draw_textured_box(&tl_point, size.x, size.y, command.get_bit(25));
-                        } else {
-                            let x1 = self.gp0_buffer[1] & 0xFFFF;
-                            let y1 = (self.gp0_buffer[1] >> 16) & 0xFFFF;
-                            self.draw_solid_box(
-                                x1,
-                                y1,
-                                x1 + 8,
-                                y1 + 8,
-                                b24color_to_b15color(self.gp0_buffer[0] & 0x1FFFFFF),
-                                command.get_bit(25),
-                            );
-                        }
-                    }
-
-                    0b11 => {
-                        //16x16 sprite
-                        //println!("GPU: 16x16 sprite");
-                        if command.get_bit(26) {
-                            let tl_point = Point::new_textured_point(
-                                self.gp0_buffer[1],
-                                ((self.gp0_buffer[2] >> 8) & 0xFF) as i16,
-                                (self.gp0_buffer[2] & 0xFF) as i16,
-                            );
-
-                            let size = Point::from_components(16, 16, 0);
-
-                            self.palette_x = ((self.gp0_buffer[2] >> 16) & 0x3F) as u16;
-                            self.palette_y = ((self.gp0_buffer[2] >> 22) & 0x1FF) as u16;
-
-                            self.draw_textured_box(&tl_point, size.x, size.y, command.get_bit(25));
-                        } else {
-                            let x1 = self.gp0_buffer[1] & 0xFFFF;
-                            let y1 = (self.gp0_buffer[1] >> 16) & 0xFFFF;
-                            self.draw_solid_box(
-                                x1,
-                                y1,
-                                x1 + 16,
-                                y1 + 16,
-                                b24color_to_b15color(self.gp0_buffer[0] & 0x1FFFFFF),
-                                command.get_bit(25),
-                            );
-                        }
+                let (width, height) = match size {
+                    0b00 => {
+                        let size_word = self.gp0_buffer[length - 1];
+                        ((size_word & 0x3FF) as i16, ((size_word >> 16) & 0x1FF) as i16)
                     }
+                    0b01 => (1, 1),
+                    0b10 => (8, 8),
+                    _ => (16, 16),
+                };
 
-                    _ => {
-                        //Lets do nothing with the others
-                        //println!("Invalid size rect");
-                    }
+                let position = Point::from_word(self.gp0_buffer[1], 0);
+                let x = position.x.wrapping_add(self.draw_offset.x);
+                let y = position.y.wrapping_add(self.draw_offset.y);
+                let transparent = command.get_bit(25);
+
+                if is_textured {
+                    // Rectangles sample from the texpage set by GP0 0xE1
+                    let uv = self.gp0_buffer[2];
+                    self.set_clut(uv);
+                    self.raw_texture = command.get_bit(24);
+                    self.texture_color = command & 0xFFFFFF;
+                    let tl_point = Point {
+                        x,
+                        y,
+                        color: 0,
+                        tex_x: (uv & 0xFF) as i16,
+                        tex_y: ((uv >> 8) & 0xFF) as i16,
+                    };
+                    self.draw_textured_rectangle(&tl_point, width, height, transparent);
+                } else {
+                    let fill = b24color_to_b15color(command & 0xFFFFFF);
+                    self.draw_solid_rectangle(x, y, width, height, fill, transparent);
                 }
             }
 
             0x4 => {
                 //VRAM to VRAM blit
-                //println!("GPU: VRAM -> VRAM blit");
                 if self.gp0_buffer.len() < 4 {
                     //Not enough commands
                     return;
                 }
-                //println!("Running VRAM to VRAM transfer");
-                let x_source = self.gp0_buffer[1] & 0xFFFF;
-                let y_source = (self.gp0_buffer[1] >> 16) & 0xFFFF;
-                let x_dest = self.gp0_buffer[2] & 0xFFFF;
-                let y_dest = (self.gp0_buffer[2] >> 16) & 0xFFFF;
-                let mut width = self.gp0_buffer[3] & 0xFFFF;
-                let mut height = (self.gp0_buffer[3] >> 16) & 0xFFFF;
-
-                if width == 0 {width = 1024};
-                if height == 0 {height = 512};
-
-                if width == 0 || height == 0 {
-                    panic!("0 width or height! w {} h {}", width, height);
-                }
-
-                self.copy_rectangle(x_source, y_source, x_dest, y_dest, width, height);
+                let source = VramTransfer::from_words(self.gp0_buffer[1], self.gp0_buffer[3]);
+                let dest = VramTransfer::from_words(self.gp0_buffer[2], self.gp0_buffer[3]);
+                self.copy_rectangle(source, dest);
             }
             0x5 => {
                 //CPU To VRAM
@@ -542,29 +520,11 @@ impl Gpu {
                     //Not enough for the header
                     return;
                 }
-                let mut width = ((self.gp0_buffer[2] & 0xFFFF) as i16);
-                let mut height = (((self.gp0_buffer[2] >> 16) & 0xFFFF) as i16);
-                if width == 0 {width = 1024};
-                if height == 0 {height = 512};
-                let length = (((width) * height + if width % 2 != 0 {1} else {0}) / 2) + 3;
-                if self.gp0_buffer.len() < length as usize {
-                    //Not enough commands
-                    return;
-                }
-
-                let base_x = ((self.gp0_buffer[1] & 0xFFFF) as i16);
-                let base_y = ((self.gp0_buffer[1] >> 16) & 0xFFFF) as i16;
-
-
-                for index in 3..(length) {
-                    let p2 = ((self.gp0_buffer[index as usize] >> 16) & 0xFFFF) as u16;
-                    let p1 = (self.gp0_buffer[index as usize] & 0xFFFF) as u16;
-                    let x = base_x + (((index - 3) * 2) % (width));
-                    let y = base_y + (((index - 3) * 2) / (width));
-                    let addr = point_to_address(x as u32, y as u32);
-                    self.vram[addr as usize] = p1;
-                    self.vram[(addr + 1) as usize] = p2;
-                }
+                // Pixel data words are consumed by write_transfer_word until the rectangle is full
+                self.cpu_transfer = Some(VramTransfer::from_words(
+                    self.gp0_buffer[1],
+                    self.gp0_buffer[2],
+                ));
             }
 
             0x6 => {
@@ -572,15 +532,11 @@ impl Gpu {
                 if self.gp0_buffer.len() < 3 {
                     return;
                 }
-
-                let width = (self.gp0_buffer[2] & 0xFFFF) as u32;
-                let height = (((self.gp0_buffer[2] >> 16) & 0xFFFF) as u32) * 2;
-
-                if width == 0 || height == 0 {
-                    panic!("0 width or height! w {} h {}", width, height);
-                }
-                trace!("VRAM to CPU")
-                //Lets ignore this one for now
+                trace!("VRAM to CPU");
+                self.vram_read = Some(VramTransfer::from_words(
+                    self.gp0_buffer[1],
+                    self.gp0_buffer[2],
+                ));
             }
             0x7 => {
                 //Env commands
@@ -589,6 +545,7 @@ impl Gpu {
                         //Draw Mode Setting
                         self.texpage_x_base = (command & 0xF) as u16;
                         self.texpage_y_base = if command.get_bit(4) { 1 } else { 0 };
+                        self.semi_transparency = ((command >> 5) & 0x3) as u8;
                         self.texmode = match (command >> 7) & 0x3 {
                             0 => TextureColorMode::FourBit,
                             1 => TextureColorMode::EightBit,
@@ -598,6 +555,14 @@ impl Gpu {
                         };
                     }
 
+                    0xE2 => {
+                        //Texture Window Setting
+                        self.tex_window_mask_x = (command & 0x1F) as u8;
+                        self.tex_window_mask_y = ((command >> 5) & 0x1F) as u8;
+                        self.tex_window_offset_x = ((command >> 10) & 0x1F) as u8;
+                        self.tex_window_offset_y = ((command >> 15) & 0x1F) as u8;
+                    }
+
                     0xE3 => {
                         //Set Drawing Area Top Left
                         self.draw_area_tl_point = Point::from_components(
@@ -620,11 +585,17 @@ impl Gpu {
 
                     0xE5 => {
                         //Set Drawing Offset
-                        let x = (command & 0x7FF) as i16;
-                        let y = ((command >> 11) & 0x7FF) as i16;
+                        let x = sign_extend_11(command);
+                        let y = sign_extend_11(command >> 11);
                         self.draw_offset = Point::from_components(x, y, 0);
                     }
 
+                    0xE6 => {
+                        //Mask Bit Setting
+                        self.set_mask_bit = command.get_bit(0);
+                        self.check_mask_bit = command.get_bit(1);
+                    }
+
              
 
                     
@@ -650,22 +621,53 @@ impl Gpu {
         //println!("GP1 Command {:#X} parameter {:#X}", command.command(), command.parameter());
         match command.command() {
             0x0 => {
-                //Reset GPU
-                self.enabled = false;
-                self.status_reg = 0;
-                self.pixel_count = 0;
-                self.vram = vec![0; 1_048_576 / 2];
+                //Reset GPU. VRAM and the video mode are left alone. Hardware goes back to NTSC
+                //here, but the mode stands in for the console region set by set_video_mode, and
+                //software run without a PAL BIOS may never write GP1 0x08 to pick PAL again
+                self.send_gp1_command(0x0100_0000);
+                self.send_gp1_command(0x0300_0001);
+                self.send_gp1_command(0x0400_0000);
+                self.send_gp1_command(0x0500_0000);
+                self.send_gp1_command(0x0704_0010);
+                self.send_gp1_command(0x0800_0000 | (self.display_mode & 0x8));
+
+                self.texpage_x_base = 0;
+                self.texpage_y_base = 0;
+                self.semi_transparency = 0;
+                self.set_mask_bit = false;
+                self.check_mask_bit = false;
+                self.texmode = TextureColorMode::FourBit;
+                self.draw_area_tl_point = Point::from_components(0, 0, 0);
+                self.draw_area_br_point = Point::from_components(0, 0, 0);
+                self.draw_offset = Point::from_components(0, 0, 0);
             }
 
             0x1 => {
                 //Reset Command buffer
                 self.gp0_buffer.clear();
+                self.cpu_transfer = None;
             }
 
             // 0x2 => {
             //     self.show_frame = true;
             // }
 
+            0x3 => {
+                //Display enable. Bit 0 set turns the display off
+                self.display_enabled = !command.get_bit(0);
+            }
+
+            0x4 => {
+                //DMA direction. 0 off, 1 FIFO, 2 CPU to GP0, 3 GPUREAD to CPU
+                self.dma_direction = command & 0x3;
+            }
+
+            0x5 => {
+                //Start of display area in VRAM
+                self.display_origin_x = command.get_bits(0..10);
+                self.display_origin_y = command.get_bits(10..19);
+            }
+
             0x6 => {
                 //Horizontal Display Range
                 //Ignore this one for now
@@ -679,6 +681,7 @@ impl Gpu {
 
             0x8 => {
                 //Display mode
+                self.display_mode = command & 0xFF;
                 self.display_h_res = {
                     if command.get_bit(6) {
                         368
@@ -706,8 +709,26 @@ impl Gpu {
             }
 
             0x10 => {
-                //Get gpu information
-                //Ignoring this too
+                //Get gpu information. The result is read back through GPUREAD
+                let draw_area_word =
+                    |point: &Point| (point.x as u32 & 0x3FF) | (point.y as u32 & 0x3FF) << 10;
+                match command.parameter() & 0x7 {
+                    0x2 => {
+                        self.gpuread_latch = self.tex_window_mask_x as u32
+                            | (self.tex_window_mask_y as u32) << 5
+                            | (self.tex_window_offset_x as u32) << 10
+                            | (self.tex_window_offset_y as u32) << 15;
+                    }
+                    0x3 => self.gpuread_latch = draw_area_word(&self.draw_area_tl_point),
+                    0x4 => self.gpuread_latch = draw_area_word(&self.draw_area_br_point),
+                    0x5 => {
+                        self.gpuread_latch = (self.draw_offset.x as u32 & 0x7FF)
+                            | (self.draw_offset.y as u32 & 0x7FF) << 11;
+                    }
+                    0x7 => self.gpuread_latch = GPU_VERSION,
+                    // The rest leave GPUREAD alone
+                    _ => (),
+                }
             }
             _ => error!(
                 "Unknown gp1 command {:#X} parameter {}!",
@@ -720,24 +741,73 @@ impl Gpu {
     pub fn execute_cycle(&mut self) {
         self.pixel_count += 1;
 
-        if self.pixel_count % CYCLES_PER_SCANLINE == 0 {
+        if self.pixel_count.is_multiple_of(self.cycles_per_scanline()) {
             self.hblank_consumed = false;
         }
 
-        if self.pixel_count > CYCLES_PER_SCANLINE * TOTAL_SCANLINES {
+        if self.pixel_count > self.cycles_per_scanline() * self.scanlines_per_frame() {
             self.pixel_count = 0;
             self.vblank_consumed = false;
-            self.frame_ready = true;
+            // A 480 line frame is drawn as two fields, so it only finishes after the odd one
+            let finished_odd = self.odd_field;
+            self.last_field = if finished_odd { Field::Odd } else { Field::Even };
+            self.odd_field = self.interlaced() && !self.odd_field;
+            self.frame_ready = self.display_v_res != 480 || !self.interlaced() || finished_odd;
             trace!("VBLANK DONE");
         }
     }
 
+    /// GP1 0x08 bit 5
+    fn interlaced(&self) -> bool {
+        self.display_mode.get_bit(5)
+    }
+
+    /// GP1 0x08 bit 3
+    pub fn is_pal(&self) -> bool {
+        self.display_mode.get_bit(3)
+    }
+
+    pub fn video_mode(&self) -> VideoMode {
+        if self.is_pal() {
+            VideoMode::Pal
+        } else {
+            VideoMode::Ntsc
+        }
+    }
+
+    /// Switches the timing standard, as if GP1 0x08 had changed only bit 3. Unlike on hardware,
+    /// a GP1 reset keeps it
+    pub fn set_video_mode(&mut self, mode: VideoMode) {
+        self.display_mode.set_bit(3, mode == VideoMode::Pal);
+    }
+
+    /// Scanlines in one field for the current video mode, vblank included
+    pub fn scanlines_per_frame(&self) -> u32 {
+        match self.video_mode() {
+            VideoMode::Ntsc => TOTAL_SCANLINES,
+            VideoMode::Pal => PAL_TOTAL_SCANLINES,
+        }
+    }
+
+    /// Gpu cycles per scanline, hblank included
+    fn cycles_per_scanline(&self) -> u32 {
+        match self.video_mode() {
+            VideoMode::Ntsc => CYCLES_PER_SCANLINE,
+            VideoMode::Pal => PAL_CYCLES_PER_SCANLINE,
+        }
+    }
+
+    /// The field that was scanned out last
+    pub fn last_field(&self) -> Field {
+        self.last_field
+    }
+
     pub fn is_vblank(&self) -> bool {
-        self.pixel_count > CYCLES_PER_SCANLINE * (self.ntsc_y2 - self.ntsc_y1)
+        self.pixel_count > self.cycles_per_scanline() * (self.ntsc_y2 - self.ntsc_y1)
     }
 
     pub fn is_hblank(&self) -> bool {
-        self.pixel_count % CYCLES_PER_SCANLINE > self.display_h_res
+        self.pixel_count % self.cycles_per_scanline() > self.display_h_res
     }
 
     pub fn resolution(&self) -> Resolution {
@@ -747,6 +817,68 @@ impl Gpu {
         }
     }
 
+    /// Whether GP1 0x03 has the display turned on
+    pub fn display_enabled(&self) -> bool {
+        self.display_enabled
+    }
+
+    /// Top left corner of the displayed area in VRAM, set by GP1 0x05
+    pub fn display_origin(&self) -> (u32, u32) {
+        (self.display_origin_x, self.display_origin_y)
+    }
+
+    /// The displayed part of VRAM converted to RGBA8888, row by row at the current resolution
+    /// times the internal scale. A disabled display is all black
+    pub fn framebuffer_rgba(&self) -> Vec<u8> {
+        let scale = self.scale;
+        let width = self.display_h_res * scale;
+        let height = self.display_v_res * scale;
+        if !self.display_enabled {
+            return [0, 0, 0, 0xFF].repeat((width * height) as usize);
+        }
+        let mut framebuffer = Vec::with_capacity((width * height * 4) as usize);
+
+        for row in 0..height {
+            let y = (self.display_origin_y + row / scale) & 0x1FF;
+            for col in 0..width {
+                let [r, g, b] = match self.color_depth {
+                    ColorDepth::Reduced => {
+                        let x = self.display_origin_x * scale + col;
+                        bgr555_to_rgb888(self.scaled_pixel(x, self.display_origin_y * scale + row))
+                    }
+                    ColorDepth::Full => {
+                        // 24 bit pixels are packed as 3 bytes each across the 16 bit VRAM words.
+                        // Nothing draws in 24 bit mode, so upscaling just repeats the native pixels
+                        let byte = self.display_origin_x * 2 + (col / scale) * 3;
+                        [
+                            self.vram_byte(byte, y),
+                            self.vram_byte(byte + 1, y),
+                            self.vram_byte(byte + 2, y),
+                        ]
+                    }
+                };
+                // The mask bit is ignored, everything on screen is opaque
+                framebuffer.extend_from_slice(&[r, g, b, 0xFF]);
+            }
+        }
+        framebuffer
+    }
+
+    /// A pixel of the displayed VRAM, with x and y in internal resolution pixels
+    fn scaled_pixel(&self, x: u32, y: u32) -> u16 {
+        if self.scale == 1 {
+            return self.vram[point_to_address(x & 0x3FF, y & 0x1FF) as usize];
+        }
+        let width = VRAM_WIDTH as u32 * self.scale;
+        let height = VRAM_HEIGHT as u32 * self.scale;
+        self.scaled_vram[((y % height) * width + (x % width)) as usize]
+    }
+
+    fn vram_byte(&self, byte: u32, y: u32) -> u8 {
+        let pixel = self.vram[point_to_address((byte / 2) & 0x3FF, y) as usize];
+        (pixel >> ((byte & 1) * 8)) as u8
+    }
+
     pub fn consume_vblank(&mut self) -> bool {
         if !self.vblank_consumed && self.is_vblank() {
             trace!("VBLANK consumed");
@@ -779,6 +911,41 @@ impl Gpu {
         &self.vram
     }
 
+    /// Direct access to VRAM. Writes through this skip the mask bit settings, and don't reach
+    /// the upscaled VRAM until the next set_internal_scale
+    pub fn get_vram_mut(&mut self) -> &mut [u16] {
+        &mut self.vram
+    }
+
+    /// Sets the internal resolution multiplier, clamped to 1..=MAX_INTERNAL_SCALE. The upscaled
+    /// VRAM is rebuilt by repeating every native pixel, so drawing detail above 1x is lost
+    pub fn set_internal_scale(&mut self, factor: u32) {
+        if factor == 0 || factor > MAX_INTERNAL_SCALE {
+            error!("GPU: Internal scale {} out of range, clamping", factor);
+        }
+        self.scale = factor.clamp(1, MAX_INTERNAL_SCALE);
+        self.scaled_vram = Vec::new();
+        if self.scale > 1 {
+            let width = VRAM_WIDTH as u32 * self.scale;
+            let height = VRAM_HEIGHT as u32 * self.scale;
+            self.scaled_vram = (0..width * height)
+                .map(|i| {
+                    let (x, y) = ((i % width) / self.scale, (i / width) / self.scale);
+                    self.vram[point_to_address(x, y) as usize]
+                })
+                .collect();
+        }
+    }
+
+    pub fn internal_scale(&self) -> u32 {
+        self.scale
+    }
+
+    /// The VRAM primitives are drawn into at the internal scale. Empty at 1x
+    pub fn get_scaled_vram(&self) -> &[u16] {
+        &self.scaled_vram
+    }
+
     ///Returns irq status. If true, function will return true then clear irq status
     pub fn consume_irq(&mut self) -> bool {
         if self.irq_fired {
@@ -789,6 +956,23 @@ impl Gpu {
         }
     }
 
+    /// Writes the two pixels packed in a CPU->VRAM data word. If the pixel count is odd,
+    /// the upper half of the last word is padding
+    fn write_transfer_word(&mut self, word: u32) {
+        if let Some(mut transfer) = self.cpu_transfer.take() {
+            for pixel in [word as u16, (word >> 16) as u16].iter() {
+                if transfer.done() {
+                    break;
+                }
+                let address = transfer.next_address();
+                self.write_vram(address, *pixel);
+            }
+            if !transfer.done() {
+                self.cpu_transfer = Some(transfer);
+            }
+        }
+    }
+
     fn gp0_push(&mut self, val: u32) {
         self.gp0_buffer.push(val);
     }
@@ -797,394 +981,335 @@ impl Gpu {
         self.gp0_buffer.clear();
     }
 
-    fn copy_horizontal_line(
-        &mut self,
-        x_source: u32,
-        y_source: u32,
-        x_dest: u32,
-        y_dest: u32,
-        width: u32,
-    ) {
-        for x_offset in 0..=width {
-            let val =
-                self.vram[(point_to_address(x_source + x_offset, y_source) as usize) % 524288];
-            let addr = point_to_address(x_dest + x_offset, y_dest) as usize;
-            self.vram[addr % 524288] = val;
+    /// Copies one VRAM rectangle to another, wrapping around the edges of VRAM.
+    /// The whole source is read before anything is written so overlapping copies come out intact
+    fn copy_rectangle(&mut self, mut source: VramTransfer, mut dest: VramTransfer) {
+        let mut pixels = Vec::with_capacity((source.width * source.height) as usize);
+        while !source.done() {
+            pixels.push(self.vram[source.next_address()]);
+        }
+        for pixel in pixels {
+            let address = dest.next_address();
+            self.write_vram(address, pixel);
         }
     }
 
-    fn copy_rectangle(
-        &mut self,
-        x_source: u32,
-        y_source: u32,
-        x_dest: u32,
-        y_dest: u32,
-        width: u32,
-        height: u32,
-    ) {
-        for y_offset in 0..height {
-            self.copy_horizontal_line(
-                x_source,
-                y_source + y_offset,
-                x_dest,
-                y_dest + y_offset,
-                width,
-            );
+    /// Stores a transferred pixel, following the GP0 0xE6 mask settings. Pixels with the mask
+    /// bit set are protected while check_mask_bit is on. The upscaled VRAM gets a block of copies
+    fn write_vram(&mut self, address: usize, pixel: u16) {
+        if self.check_mask_bit && self.vram[address].get_bit(15) {
+            return;
+        }
+        self.vram[address] = pixel | ((self.set_mask_bit as u16) << 15);
+        if self.scale > 1 {
+            let (x, y) = ((address & 0x3FF) as u32, (address >> 10) as u32);
+            let width = VRAM_WIDTH as u32 * self.scale;
+            for row in y * self.scale..(y + 1) * self.scale {
+                for col in x * self.scale..(x + 1) * self.scale {
+                    self.scaled_vram[(row * width + col) as usize] = self.vram[address];
+                }
+            }
         }
     }
 
-    fn draw_horizontal_line(&mut self, x1: u32, x2: u32, y: u32, fill: u16, transparent: bool) {
-        
-        for x in x1..x2 {
-            if self.out_of_draw_area(&Point::from_components(x as i16, y as i16, 0)) {
-                continue;
-            }
-            let address = point_to_address(x, y) as usize;
-            let color = if transparent {
-                alpha_composite(self.vram[address % 524288], fill)
-            } else {
-                fill
-            };
-            if fill != 0 {
-                self.vram[address % 524288] = color;
-            }
+    /// Runs a drawing routine once for the native VRAM and, with upscaling on, once more for
+    /// the upscaled one. Routines scale their coordinates by pass_scale
+    fn for_each_target<F>(&mut self, mut draw: F)
+    where
+        F: FnMut(&mut Self),
+    {
+        draw(self);
+        if self.scale > 1 {
+            self.pass_scale = self.scale;
+            draw(self);
+            self.pass_scale = 1;
         }
     }
 
-    fn draw_horizontal_line_shaded(
-        &mut self,
-        x1: i16,
-        x2: i16,
-        y: i16,
-        x1_color: u16,
-        x2_color: u16,
-        transparent: bool,
-    ) {
-        let (start, end, start_color, end_color) = if x1 > x2 {
-            (x2, x1, x2_color, x1_color)
+    /// Address of a pixel in the buffer the current pass draws into, wrapping around its edges
+    fn target_address(&self, x: u32, y: u32) -> usize {
+        if self.pass_scale == 1 {
+            point_to_address(x, y) as usize % 524288
         } else {
-            (x1, x2, x1_color, x2_color)
-        };
-        for x in start..end {
-            if self.out_of_draw_area(&Point::from_components(x, y, 0)) {
-                continue;
-            }
-            let address = point_to_address(x as u32, y as u32) as usize;
-            let fill = lerp_color(start_color, end_color, start, end, x);
-            ////println!("x {} end {} fill {:#X}", x, end, fill);
-            let color = if transparent {
-                alpha_composite(self.vram[address % 524288], fill)
-            } else {
-                fill
-            };
-            if fill != 0 {
-                self.vram[address % 524288] = color;
-            }
+            let width = VRAM_WIDTH as u32 * self.pass_scale;
+            ((y * width + x) as usize) % self.scaled_vram.len()
         }
     }
-    fn out_of_draw_area(&self, test_point: &Point) -> bool {
-        !(test_point.x > self.draw_area_tl_point.x
-            && test_point.x < self.draw_area_br_point.x
-            && test_point.y > self.draw_area_tl_point.y
-            && test_point.y < self.draw_area_br_point.y)
+
+    fn target_vram(&mut self) -> &mut Vec<u16> {
+        if self.pass_scale == 1 {
+            &mut self.vram
+        } else {
+            &mut self.scaled_vram
+        }
     }
 
-    fn draw_horizontal_line_textured(
-        &mut self,
-        x1: i16,
-        x2: i16,
-        y: i16,
-        y1_tex: i16,
-        y2_tex: i16,
-        x1_tex: i16,
-        x2_tex: i16,
-        transparent: bool,
-    ) {
-        let (start, end) = if x1 > x2 { (x2, x1) } else { (x1, x2) };
-        ////println!("x1: {} y1: {} x2: {} y2: {}", x1_tex, y1_tex, x2_tex, y2_tex);
-        for x in start..end {
-            if self.out_of_draw_area(&Point::from_components(x, y, 0)) {
-                continue;
-            }
+    /// Vertices of the line command in the buffer, moved by the drawing offset. Monochrome
+    /// lines take their color from the command word, gouraud lines have one before every vertex
+    fn line_points(&self, count: usize, is_gouraud: bool) -> Vec<Point> {
+        let flat = b24color_to_b15color(self.gp0_buffer[0] & 0xFFFFFF);
+        (0..count)
+            .map(|i| {
+                let (color, vertex) = if is_gouraud {
+                    (b24color_to_b15color(self.gp0_buffer[i * 2] & 0xFFFFFF), self.gp0_buffer[i * 2 + 1])
+                } else {
+                    (flat, self.gp0_buffer[i + 1])
+                };
+                self.offset_point(Point::from_word(vertex, color))
+            })
+            .collect()
+    }
 
-            let address = point_to_address(x as u32, y as u32) as usize;
+    /// Bresenham line including both endpoints, clipped to the drawing area. The color is
+    /// interpolated along the major axis. Lines too long to fit in VRAM are skipped like on hardware
+    fn draw_line(&mut self, start: Point, end: Point, transparent: bool) {
+        self.for_each_target(|gpu| {
+            let scale = gpu.pass_scale;
+            gpu.draw_line_scaled(scale_point(start, scale), scale_point(end, scale), transparent);
+        });
+    }
 
-            let fill = self.get_texel(
-                lerp_coords(x1_tex, x2_tex, start, end, x),
-                lerp_coords(y1_tex, y2_tex, start, end, x),
-            );
-            //let fill = 0xFFFF;
-            ////println!("x {} end {} fill {:#X}", x, end, fill);
+    fn draw_line_scaled(&mut self, start: Point, end: Point, transparent: bool) {
+        let dx = (end.x as i32 - start.x as i32).abs();
+        let dy = (end.y as i32 - start.y as i32).abs();
+        let scale = self.pass_scale as i32;
+        if dx >= 1024 * scale || dy >= 512 * scale {
+            return;
+        }
+        let step_x = if start.x < end.x { 1 } else { -1 };
+        let step_y = if start.y < end.y { 1 } else { -1 };
+        let steps = dx.max(dy);
+
+        let (mut x, mut y) = (start.x as i32, start.y as i32);
+        let mut error = dx - dy;
+        for step in 0..=steps {
+            let point = Point::from_components(x as i16, y as i16, 0);
+            if !self.out_of_draw_area(&point) {
+                let color = if steps == 0 {
+                    start.color
+                } else {
+                    lerp_color(start.color, end.color, 0, steps as i16, step as i16)
+                };
+                self.plot_pixel(x as u32, y as u32, color, transparent);
+            }
 
-            let color = if transparent {
-                alpha_composite(self.vram[address % 524288], fill)
-            } else {
-                fill
-            };
-            if fill != 0 {
-                self.vram[address % 524288] = color;
+            let doubled = error * 2;
+            if doubled > -dy {
+                error -= dy;
+                x += step_x;
+            }
+            if doubled < dx {
+                error += dx;
+                y += step_y;
             }
         }
     }
 
-    fn draw_solid_box(&mut self, x1: u32, y1: u32, x2: u32, y2: u32, fill: u16, transparent: bool) {
-        for y in y1..y2 {
-            self.draw_horizontal_line(x1, x2, y, fill, transparent);
-        }
+    /// The drawing area as (left, top, right, bottom) in pixels of the current pass. Every
+    /// native pixel covers pass_scale pixels in each direction
+    fn draw_area_bounds(&self) -> (i16, i16, i16, i16) {
+        let scale = self.pass_scale as i16;
+        (
+            self.draw_area_tl_point.x.wrapping_mul(scale),
+            self.draw_area_tl_point.y.wrapping_mul(scale),
+            self.draw_area_br_point.x.wrapping_mul(scale).wrapping_add(scale - 1),
+            self.draw_area_br_point.y.wrapping_mul(scale).wrapping_add(scale - 1),
+        )
     }
 
-    fn draw_textured_box(&mut self, tl_point: &Point, width: i16, height: i16, transparent: bool) {
-        for offset in 0..height {
-            self.draw_horizontal_line_textured(
-                tl_point.x,
-                tl_point.x + width,
-                tl_point.y + offset,
-                tl_point.tex_y + offset,
-                tl_point.tex_y + offset,
-                tl_point.tex_x,
-                tl_point.tex_x + width,
-                transparent,
-            )
-        }
+    fn out_of_draw_area(&self, test_point: &Point) -> bool {
+        let (left, top, right, bottom) = self.draw_area_bounds();
+        !(test_point.x >= left && test_point.x <= right && test_point.y >= top && test_point.y <= bottom)
     }
 
-    fn draw_solid_flat_bottom_triangle(
-        &mut self,
-        p1: Point,
-        p2: Point,
-        p3: Point,
-        fill: u16,
-        transparent: bool,
-    ) {
-        let invslope1 = (p2.x - p1.x) as f32 / (p2.y - p1.y) as f32;
-        let invslope2 = (p3.x - p1.x) as f32 / (p3.y - p1.y) as f32;
-
-        let mut curx1 = p1.x as f32;
-        let mut curx2 = p1.x as f32;
-
-        for scanline in p1.y..p2.y {
-            self.draw_horizontal_line(
-                curx1 as u32,
-                curx2 as u32,
-                scanline as u32,
-                fill,
-                transparent,
-            );
-            curx1 = curx1 + invslope1;
-            curx2 = curx2 + invslope2;
-        }
+    /// Fills a VRAM rectangle for GP0 0x02, wrapping around the edges of VRAM. Unlike drawing,
+    /// this ignores the drawing area and the mask settings
+    fn fill_rectangle(&mut self, x: u32, y: u32, width: u32, height: u32, fill: u16) {
+        self.for_each_target(|gpu| {
+            let scale = gpu.pass_scale;
+            for row in y * scale..(y + height) * scale {
+                for col in x * scale..(x + width) * scale {
+                    let address = gpu.target_address(col % (VRAM_WIDTH as u32 * scale), row);
+                    gpu.target_vram()[address] = fill;
+                }
+            }
+        });
     }
 
-    fn draw_solid_flat_top_triangle(
+    /// Fills a rectangle clipped to the drawing area
+    fn draw_solid_rectangle(
         &mut self,
-        p1: Point,
-        p2: Point,
-        p3: Point,
+        x: i16,
+        y: i16,
+        width: i16,
+        height: i16,
         fill: u16,
         transparent: bool,
     ) {
-        let invslope1 = (p3.x - p1.x) as f32 / (p3.y - p1.y) as f32;
-        let invslope2 = (p3.x - p2.x) as f32 / (p3.y - p2.y) as f32;
-
-        let mut curx1 = p3.x as f32;
-        let mut curx2 = p3.x as f32;
-
-        for scanline in (p1.y..=p3.y).rev() {
-            self.draw_horizontal_line(
-                (curx1 + 0.5) as u32,
-                (curx2 + 0.5) as u32,
-                scanline as u32,
-                fill,
-                transparent,
-            );
-            curx1 = curx1 - invslope1;
-            curx2 = curx2 - invslope2;
-        }
+        self.for_each_target(|gpu| {
+            let scale = gpu.pass_scale as i16;
+            let (x, y) = (x.wrapping_mul(scale), y.wrapping_mul(scale));
+            for row in y..y.saturating_add(height * scale) {
+                for col in x..x.saturating_add(width * scale) {
+                    if col < 0 || row < 0 || gpu.out_of_draw_area(&Point::from_components(col, row, 0)) {
+                        continue;
+                    }
+                    gpu.plot_pixel(col as u32, row as u32, fill, transparent);
+                }
+            }
+        });
     }
 
-    fn draw_shaded_flat_bottom_triangle(
-        &mut self,
-        p1: Point,
-        p2: Point,
-        p3: Point,
-        transparent: bool,
-    ) {
-        let invslope1 = (p2.x - p1.x) as f32 / (p2.y - p1.y) as f32;
-        let invslope2 = (p3.x - p1.x) as f32 / (p3.y - p1.y) as f32;
-
-        let mut curx1 = p1.x as f32;
-        let mut curx2 = p1.x as f32;
-
-        for scanline in p1.y..p3.y {
-            let curx1_color = lerp_color(p1.color, p2.color, p1.y, p3.y, scanline);
-            let curx2_color = lerp_color(p1.color, p3.color, p1.y, p3.y, scanline);
-            self.draw_horizontal_line_shaded(
-                curx1 as i16,
-                curx2 as i16,
-                scanline,
-                curx1_color,
-                curx2_color,
-                transparent,
-            );
-            curx1 = curx1 + invslope1;
-            curx2 = curx2 + invslope2;
-        }
+    /// Copies a rectangle of texels out of the current texpage, starting from the texture
+    /// coordinates of tl_point. Same transparency rules as textured polygons
+    fn draw_textured_rectangle(&mut self, tl_point: &Point, width: i16, height: i16, transparent: bool) {
+        let tl_point = *tl_point;
+        self.for_each_target(|gpu| {
+            let scale = gpu.pass_scale as i16;
+            let origin = scale_point(tl_point, gpu.pass_scale);
+            for row in 0..height * scale {
+                for col in 0..width * scale {
+                    let point = Point::from_components(origin.x.wrapping_add(col), origin.y.wrapping_add(row), 0);
+                    if point.x < 0 || point.y < 0 || gpu.out_of_draw_area(&point) {
+                        continue;
+                    }
+                    let texel = gpu.get_texel(tl_point.tex_x + col / scale, tl_point.tex_y + row / scale);
+                    if texel == 0 {
+                        continue;
+                    }
+                    let color = gpu.shade_texel(texel, gpu.texture_color);
+                    gpu.plot_pixel(point.x as u32, point.y as u32, color, transparent && texel.get_bit(15));
+                }
+            }
+        });
     }
 
-    fn draw_shaded_flat_top_triangle(
-        &mut self,
-        p1: Point,
-        p2: Point,
-        p3: Point,
-        transparent: bool,
-    ) {
-        let invslope1 = (p3.x - p1.x) as f32 / (p3.y - p1.y) as f32;
-        let invslope2 = (p3.x - p2.x) as f32 / (p3.y - p2.y) as f32;
-
-        let mut curx1 = p3.x as f32;
-        let mut curx2 = p3.x as f32;
-
-        for scanline in (p1.y..=p3.y).rev() {
-            let curx1_color = lerp_color(p1.color, p3.color, p1.y, p3.y, scanline);
-            let curx2_color = lerp_color(p2.color, p3.color, p1.y, p3.y, scanline);
-            self.draw_horizontal_line_shaded(
-                (curx1 + 0.5) as i16,
-                (curx2 + 0.5) as i16,
-                scanline,
-                curx1_color,
-                curx2_color,
-                transparent,
-            );
-            curx1 = curx1 - invslope1;
-            curx2 = curx2 - invslope2;
+    /// Walks every pixel of a triangle by testing its bounding box against the three edge functions.
+    /// Pixels on bottom and right edges are left out so neighbouring triangles don't overlap.
+    /// plot gets the pixel along with the barycentric weights of each vertex and their sum
+    fn rasterize_triangle<F>(&mut self, points: &[Point], mut plot: F)
+    where
+        F: FnMut(&mut Self, i16, i16, [i64; 3], i64),
+    {
+        let (p0, mut p1, mut p2) = (points[0], points[1], points[2]);
+        let mut area = edge_function(&p0, &p1, &p2);
+        if area == 0 {
+            return;
+        }
+        let swapped = area < 0;
+        if swapped {
+            std::mem::swap(&mut p1, &mut p2);
+            area = -area;
         }
-    }
 
-    fn draw_textured_flat_bottom_triangle(
-        &mut self,
-        p1: Point,
-        p2: Point,
-        p3: Point,
-        transparent: bool,
-    ) {
-        let invslope1 = (p2.x - p1.x) as f32 / (p2.y - p1.y) as f32;
-        let invslope2 = (p3.x - p1.x) as f32 / (p3.y - p1.y) as f32;
-
-        let mut curx1 = p1.x as f32;
-        let mut curx2 = p1.x as f32;
-
-        for scanline in p1.y..p3.y {
-            let y1 = lerp_coords(p1.tex_y, p3.tex_y, p1.y, p3.y, scanline);
-            let y2 = lerp_coords(p1.tex_y, p2.tex_y, p1.y, p3.y, scanline);
-            let x1 = lerp_coords(p1.tex_x, p3.tex_x, p1.y, p3.y, scanline);
-            let x2 = lerp_coords(p1.tex_x, p2.tex_x, p1.y, p3.y, scanline);
-            self.draw_horizontal_line_textured(
-                curx1 as i16,
-                curx2 as i16,
-                scanline,
-                y1,
-                y2,
-                x1,
-                x2,
-                transparent,
-            );
-            curx1 = curx1 + invslope1;
-            curx2 = curx2 + invslope2;
+        let (left, top, right, bottom) = self.draw_area_bounds();
+        let scale = self.pass_scale as i16;
+        let min_x = p0.x.min(p1.x).min(p2.x).max(left).max(0);
+        let max_x = p0.x.max(p1.x).max(p2.x).min(right).min(VRAM_WIDTH as i16 * scale - 1);
+        let min_y = p0.y.min(p1.y).min(p2.y).max(top).max(0);
+        let max_y = p0.y.max(p1.y).max(p2.y).min(bottom).min(VRAM_HEIGHT as i16 * scale - 1);
+
+        let bias0 = edge_bias(&p1, &p2);
+        let bias1 = edge_bias(&p2, &p0);
+        let bias2 = edge_bias(&p0, &p1);
+
+        for y in min_y..=max_y {
+            for x in min_x..=max_x {
+                let p = Point::from_components(x, y, 0);
+                let w0 = edge_function(&p1, &p2, &p);
+                let w1 = edge_function(&p2, &p0, &p);
+                let w2 = edge_function(&p0, &p1, &p);
+                if w0 + bias0 >= 0 && w1 + bias1 >= 0 && w2 + bias2 >= 0 {
+                    // Hand the weights back in the caller's vertex order
+                    let weights = if swapped { [w0, w2, w1] } else { [w0, w1, w2] };
+                    plot(self, x, y, weights, area);
+                }
+            }
         }
     }
 
-    fn draw_textured_flat_top_triangle(
-        &mut self,
-        p1: Point,
-        p2: Point,
-        p3: Point,
-        transparent: bool,
-    ) {
-        let invslope1 = (p3.x - p1.x) as f32 / (p3.y - p1.y) as f32;
-        let invslope2 = (p3.x - p2.x) as f32 / (p3.y - p2.y) as f32;
-
-        let mut curx1 = p3.x as f32;
-        let mut curx2 = p3.x as f32;
-
-        for scanline in (p1.y..=p3.y).rev() {
-            let y1 = lerp_coords(p1.tex_y, p3.tex_y, p1.y, p3.y, scanline);
-            let y2 = lerp_coords(p2.tex_y, p3.tex_y, p1.y, p3.y, scanline);
-            let x1 = lerp_coords(p1.tex_x, p3.tex_x, p1.y, p3.y, scanline);
-            let x2 = lerp_coords(p2.tex_x, p3.tex_x, p1.y, p3.y, scanline);
-            self.draw_horizontal_line_textured(
-                (curx1 + 0.5) as i16,
-                (curx2 + 0.5) as i16,
-                scanline,
-                y1,
-                y2,
-                x1,
-                x2,
-                transparent,
-            );
-            curx1 = curx1 - invslope1;
-            curx2 = curx2 - invslope2;
+    /// Moves a vertex by the drawing offset from GP0 0xE5
+    fn offset_point(&self, point: Point) -> Point {
+        Point {
+            x: point.x.wrapping_add(self.draw_offset.x),
+            y: point.y.wrapping_add(self.draw_offset.y),
+            ..point
         }
     }
 
-    fn draw_solid_triangle(&mut self, points: &[Point], fill: u16, transparent: bool) {
-        let mut sp = points.to_vec();
-        sp.sort_by_key(|p| p.y);
+    fn offset_points(&self, points: &[Point]) -> Vec<Point> {
+        points.iter().map(|point| self.offset_point(*point)).collect()
+    }
 
-        if sp[1].y == sp[2].y {
-            self.draw_solid_flat_bottom_triangle(sp[0], sp[1], sp[2], fill, transparent);
-        } else if sp[0].y == sp[1].y {
-            self.draw_solid_flat_top_triangle(sp[0], sp[1], sp[2], fill, transparent);
-        } else {
-            let bound_x = (sp[0].x
-                + ((sp[1].y - sp[0].y) as f32 / (sp[2].y - sp[0].y) as f32) as i16
-                    * (sp[2].x - sp[0].x)) as i16;
-            let bound_point = Point::from_components(bound_x, sp[1].y, 0);
-            self.draw_solid_flat_bottom_triangle(sp[0], sp[1], bound_point, fill, transparent);
-            self.draw_solid_flat_top_triangle(sp[1], bound_point, sp[2], fill, transparent);
-        }
+    /// Rasterizes a triangle for every render target, with the vertices moved by the drawing offset
+    fn rasterize_each_target<F>(&mut self, points: &[Point], mut plot: F)
+    where
+        F: FnMut(&mut Self, i16, i16, [i64; 3], i64),
+    {
+        let points = self.offset_points(points);
+        self.for_each_target(|gpu| {
+            let scaled: Vec<Point> = points.iter().map(|p| scale_point(*p, gpu.pass_scale)).collect();
+            gpu.rasterize_triangle(&scaled, &mut plot);
+        });
     }
 
-    fn draw_shaded_triangle(&mut self, points: &[Point], transparent: bool) {
-        let mut sp = points.to_vec();
-        sp.sort_by_key(|p| p.y);
+    fn draw_solid_triangle(&mut self, points: &[Point], fill: u16, transparent: bool) {
+        self.rasterize_each_target(points, |gpu, x, y, _, _| {
+            gpu.plot_pixel(x as u32, y as u32, fill, transparent);
+        });
+    }
 
-        if sp[1].y == sp[2].y {
-            self.draw_shaded_flat_bottom_triangle(sp[0], sp[1], sp[2], transparent);
-        } else if sp[0].y == sp[1].y {
-            self.draw_shaded_flat_top_triangle(sp[0], sp[1], sp[2], transparent);
-        } else {
-            let bound_x = (sp[0].x
-                + ((sp[1].y - sp[0].y) as f32 / (sp[2].y - sp[0].y) as f32) as i16
-                    * (sp[2].x - sp[0].x)) as i16;
-            let bound_point = Point::from_components(bound_x, sp[1].y, sp[2].color);
-            self.draw_shaded_flat_bottom_triangle(sp[0], bound_point, sp[1], transparent);
-            self.draw_shaded_flat_top_triangle(sp[1], bound_point, sp[2], transparent);
+    /// Blends and stores a drawn pixel in the current pass's VRAM, following the GP0 0xE6 mask
+    /// settings like write_vram
+    fn plot_pixel(&mut self, x: u32, y: u32, fill: u16, transparent: bool) {
+        let address = self.target_address(x, y);
+        let (semi_transparency, check_mask_bit) = (self.semi_transparency, self.check_mask_bit);
+        let mask = (self.set_mask_bit as u16) << 15;
+        let vram = self.target_vram();
+        if check_mask_bit && vram[address].get_bit(15) {
+            return;
         }
+        let color = if transparent {
+            blend(semi_transparency, vram[address], fill)
+        } else {
+            fill
+        };
+        vram[address] = color | mask;
     }
 
-    fn draw_textured_triangle(&mut self, points: &[Point], transparent: bool) {
-        let mut sp = points.to_vec();
-        sp.sort_by_key(|p| p.y);
+    /// Draws a triangle with each vertex's 24 bit color blended across it by the barycentric
+    /// weights. The channels are interpolated at 8 bits and only cut down to 5 at the end
+    fn draw_shaded_triangle(&mut self, points: &[Point], colors: &[u32], transparent: bool) {
+        self.rasterize_each_target(points, |gpu, x, y, weights, area| {
+            let fill = b24color_to_b15color(interpolate_color(colors, weights, area));
+            gpu.plot_pixel(x as u32, y as u32, fill, transparent);
+        });
+    }
 
-        if sp[1].y == sp[2].y {
-            self.draw_textured_flat_bottom_triangle(sp[0], sp[1], sp[2], transparent);
-        } else if sp[0].y == sp[1].y {
-            self.draw_textured_flat_top_triangle(sp[0], sp[1], sp[2], transparent);
-        } else {
-            let progress =
-                sp[0].x + ((sp[1].y - sp[0].y) as f32 / (sp[2].y - sp[0].y) as f32) as i16;
-            let bound_x = progress * ((sp[2].x - sp[0].x) as i16);
-            let bound_point = Point {
-                x: bound_x,
-                y: sp[1].y,
-                color: 0,
-                tex_x: lerp_coords(sp[0].tex_x, sp[1].tex_x, sp[0].y, sp[1].y, progress),
-                tex_y: lerp_coords(sp[0].tex_y, sp[1].tex_y, sp[0].y, sp[1].y, progress),
+    /// Draws a triangle sampled from the current texpage and CLUT. Fully black texels are
+    /// skipped, and only texels with bit 15 set are blended when the command is semi-transparent.
+    /// Gouraud textured polygons pass their vertex colors to modulate the texels with
+    fn draw_textured_triangle(&mut self, points: &[Point], colors: Option<&[u32]>, transparent: bool) {
+        let tex: Vec<(i64, i64)> = points
+            .iter()
+            .map(|p| (p.tex_x as i64, p.tex_y as i64))
+            .collect();
+        self.rasterize_each_target(points, |gpu, x, y, weights, area| {
+            let interpolate = |coord: fn(&(i64, i64)) -> i64| {
+                (weights[0] * coord(&tex[0]) + weights[1] * coord(&tex[1]) + weights[2] * coord(&tex[2]))
+                    / area
             };
-
-            self.draw_textured_flat_bottom_triangle(sp[0], bound_point, sp[1], transparent);
-            self.draw_textured_flat_top_triangle(sp[1], bound_point, sp[2], transparent);
-        }
+            let texel = gpu.get_texel(interpolate(|t| t.0) as i16, interpolate(|t| t.1) as i16);
+            if texel == 0 {
+                return;
+            }
+            let shade = match colors {
+                Some(colors) => interpolate_color(colors, weights, area),
+                None => gpu.texture_color,
+            };
+            let color = gpu.shade_texel(texel, shade);
+            gpu.plot_pixel(x as u32, y as u32, color, transparent && texel.get_bit(15));
+        });
     }
 
     fn draw_solid_quad(&mut self, points: &[Point], fill: u16, transparent: bool) {
@@ -1192,62 +1317,125 @@ impl Gpu {
         self.draw_solid_triangle(&points[1..4], fill, transparent);
     }
 
-    fn draw_shaded_quad(&mut self, points: &[Point], transparent: bool) {
-        self.draw_shaded_triangle(&points[0..3], transparent);
-        self.draw_shaded_triangle(&points[1..4], transparent);
+    fn draw_shaded_quad(&mut self, points: &[Point], colors: &[u32], transparent: bool) {
+        self.draw_shaded_triangle(&points[0..3], &colors[0..3], transparent);
+        self.draw_shaded_triangle(&points[1..4], &colors[1..4], transparent);
+    }
+
+    fn draw_textured_quad(&mut self, points: &[Point], colors: Option<&[u32]>, transparent: bool) {
+        self.draw_textured_triangle(&points[0..3], colors.map(|c| &c[0..3]), transparent);
+        self.draw_textured_triangle(&points[1..4], colors.map(|c| &c[1..4]), transparent);
+    }
+
+    /// CLUT attribute from the upper half of a texcoord word, in units of 16 pixels horizontally
+    fn set_clut(&mut self, word: u32) {
+        self.palette_x = ((word >> 16) & 0x3F) as u16;
+        self.palette_y = ((word >> 22) & 0x1FF) as u16;
     }
 
-    fn draw_textured_quad(&mut self, points: &[Point], transparent: bool) {
-        self.draw_textured_triangle(&points[0..3], transparent);
-        self.draw_textured_triangle(&[points[1], points[3], points[2]], transparent);
+    /// Polygons carry their own texpage in the upper half of the second texcoord word,
+    /// laid out the same as the low bits of GP0 0xE1
+    fn set_polygon_texpage(&mut self, word: u32) {
+        let page = word >> 16;
+        self.texpage_x_base = (page & 0xF) as u16;
+        self.texpage_y_base = ((page >> 4) & 0x1) as u16;
+        self.semi_transparency = ((page >> 5) & 0x3) as u8;
+        self.texmode = match (page >> 7) & 0x3 {
+            0 => TextureColorMode::FourBit,
+            1 => TextureColorMode::EightBit,
+            _ => TextureColorMode::FifteenBit,
+        };
     }
 
+    /// Modulates a texel by a 24 bit color unless the command asked for a raw texture.
+    /// A color of 0x80 leaves the channel untouched
+    fn shade_texel(&self, texel: u16, color: u32) -> u16 {
+        if self.raw_texture {
+            return texel;
+        }
+        let modulate = |channel: u16, shift: u32| {
+            let factor = (color >> shift) & 0xFF;
+            ((channel as u32 * factor) >> 7).min(0x1F) as u16
+        };
+        let (b, g, r) = b15_to_rgb(texel);
+        (texel & 0x8000)
+            | (modulate(b as u16, 16) << 10)
+            | (modulate(g as u16, 8) << 5)
+            | modulate(r as u16, 0)
+    }
+
+    /// Reads the raw texel at (x, y) inside the current texpage, looking it up in the CLUT for
+    /// the indexed modes. Texture coordinates wrap at 256, then repeat inside the texture window
     fn get_texel(&self, x: i16, y: i16) -> u16 {
-        //TODO inline variables. Just did this because I'm lazy
-        let page_x = self.texpage_x_base;
-        let page_y = self.texpage_y_base;
-        let clut_x = self.palette_x;
-        let clut_y = self.palette_y;
-        let size = self.texmode;
-
-        let pixel_val = match size {
+        let x = apply_texture_window((x as u32) & 0xFF, self.tex_window_mask_x, self.tex_window_offset_x);
+        let y = apply_texture_window((y as u32) & 0xFF, self.tex_window_mask_y, self.tex_window_offset_y);
+        let page_x = self.texpage_x_base as u32 * 64;
+        let page_y = self.texpage_y_base as u32 * 256;
+        let clut_x = self.palette_x as u32 * 16;
+        let clut_y = self.palette_y as u32;
+
+        let clut = |index: u32| self.vram[point_to_address((clut_x + index) & 0x3FF, clut_y) as usize];
+
+        match self.texmode {
             TextureColorMode::FifteenBit => {
-                self.vram[point_to_address(
-                    ((page_x * 64) as u32 + x as u32) as u32,
-                    ((page_y * 256) as u32 + y as u32) as u32,
-                ) as usize]
+                self.vram[point_to_address((page_x + x) & 0x3FF, page_y + y) as usize]
             }
             TextureColorMode::EightBit => {
-                let value = self.vram[point_to_address(
-                    (page_x * 64) as u32 + (x / 2) as u32,
-                    (page_y * 256) as u32 + y as u32,
-                ) as usize];
-                let clut_index = (value >> (x % 2) * 8) & 0xF;
-                self.vram
-                    [point_to_address((clut_x * 16 + clut_index) as u32, clut_y as u32) as usize]
+                let value = self.vram[point_to_address((page_x + x / 2) & 0x3FF, page_y + y) as usize];
+                clut(((value >> ((x % 2) * 8)) & 0xFF) as u32)
             }
             TextureColorMode::FourBit => {
-                let value = self.vram[(point_to_address(
-                    (page_x * 64) as u32 + (x / 4) as u32,
-                    (page_y * 256) as u32 + y as u32,
-                ) as usize) % 524288];
-                let (clut_index, _) = (value.overflowing_shr(((x % 4) * 4) as u32));
-                self.vram
-                    [point_to_address((clut_x * 16 + (clut_index & 0xF)) as u32, clut_y as u32) as usize]
+                let value = self.vram[point_to_address((page_x + x / 4) & 0x3FF, page_y + y) as usize];
+                clut(((value >> ((x % 4) * 4)) & 0xF) as u32)
             }
-        };
-        if self.blend_enabled {
-            pixel_val & self.blend_color
-        } else {
-            pixel_val
         }
     }
 }
 
+/// Masked coordinate bits are replaced by the offset's, so the texture repeats every mask * 8 texels
+fn apply_texture_window(coord: u32, mask: u8, offset: u8) -> u32 {
+    let mask = mask as u32 * 8;
+    let offset = offset as u32 * 8;
+    (coord & !mask) | (offset & mask)
+}
+
+/// Moves a point onto a VRAM scale times the native size. Texture coordinates stay native
+fn scale_point(point: Point, scale: u32) -> Point {
+    Point {
+        x: point.x.wrapping_mul(scale as i16),
+        y: point.y.wrapping_mul(scale as i16),
+        ..point
+    }
+}
+
+/// Sign extends the 11 bit field in the low bits of value
+fn sign_extend_11(value: u32) -> i16 {
+    (((value & 0x7FF) as u16) << 5) as i16 >> 5
+}
+
 fn point_to_address(x: u32, y: u32) -> u32 {
     ((1024) as u32 * y).wrapping_add(x)
 }
 
+/// Twice the signed area of the triangle a, b, p. Positive when p is on the inside of edge a -> b.
+/// It's i64 because the weights get multiplied by colors and texture coordinates, and an
+/// upscaled full screen triangle already comes close to the i32 limit here
+fn edge_function(a: &Point, b: &Point, p: &Point) -> i64 {
+    (b.x as i64 - a.x as i64) * (p.y as i64 - a.y as i64)
+        - (b.y as i64 - a.y as i64) * (p.x as i64 - a.x as i64)
+}
+
+/// Top-left fill rule. Pixels exactly on an edge are only drawn if it's a top or left edge
+fn edge_bias(a: &Point, b: &Point) -> i64 {
+    let dy = b.y as i64 - a.y as i64;
+    let dx = b.x as i64 - a.x as i64;
+    if dy < 0 || (dy == 0 && dx > 0) {
+        0
+    } else {
+        -1
+    }
+}
+
 fn b24color_to_b15color(color: u32) -> u16 {
     let r = ((color >> 16) & 0xFF) / 8;
     let g = ((color >> 8) & 0xFF) / 8;
@@ -1255,6 +1443,20 @@ fn b24color_to_b15color(color: u32) -> u16 {
     ((r << 10) | (g << 5) | b) as u16
 }
 
+/// Blends three 24 bit vertex colors channel by channel using a pixel's barycentric weights
+fn interpolate_color(colors: &[u32], weights: [i64; 3], area: i64) -> u32 {
+    (0..3).fold(0, |color, channel| {
+        let shift = channel * 8;
+        let value = colors
+            .iter()
+            .zip(weights.iter())
+            .map(|(c, w)| ((c >> shift) & 0xFF) as i64 * w)
+            .sum::<i64>()
+            / area;
+        color | ((value as u32 & 0xFF) << shift)
+    })
+}
+
 fn b15_to_rgb(color: u16) -> (u8, u8, u8) {
     (
         ((color >> 10) & 0x1F) as u8,
@@ -1263,6 +1465,16 @@ fn b15_to_rgb(color: u16) -> (u8, u8, u8) {
     )
 }
 
+/// Expands a VRAM pixel to 8 bits per channel, as [r, g, b]
+fn bgr555_to_rgb888(pixel: u16) -> [u8; 3] {
+    let expand = |c: u16| ((c << 3) | (c >> 2)) as u8;
+    [
+        expand(pixel & 0x1F),
+        expand((pixel >> 5) & 0x1F),
+        expand((pixel >> 10) & 0x1F),
+    ]
+}
+
 fn rgb_to_b15(r: u8, g: u8, b: u8) -> u16 {
     ((r as u16) << 10) | ((g as u16) << 5) | (b as u16)
 }
@@ -1283,15 +1495,23 @@ fn lerp_color(y0: u16, y1: u16, x0: i16, x1: i16, x: i16) -> u16 {
     //(y0 as f32 + ((y1 - y0) as f32 * ((x - x0) as f32 / (x1 - x0) as f32))) as u16
 }
 
-fn lerp_coords(y0: i16, y1: i16, x0: i16, x1: i16, x: i16) -> i16 {
-    (y0 as f32 + ((y1 as i32 - y0 as i32) as f32 * ((x - x0) as f32 / (x1 - x0) as f32))) as i16
-}
-
-//TODO Make colors more accurate
-fn alpha_composite(background_color: u16, alpha_color: u16) -> u16 {
+/// Semi-transparency equations, per channel and saturated to 0..=31:
+/// 0 is B/2 + F/2, 1 is B + F, 2 is B - F and 3 is B + F/4.
+/// The result keeps the mask bit of the incoming pixel
+fn blend(mode: u8, background_color: u16, foreground_color: u16) -> u16 {
     let (b_r, b_g, b_b) = b15_to_rgb(background_color);
-    let (a_r, a_g, a_b) = b15_to_rgb(alpha_color);
-    rgb_to_b15(a_r + b_r, a_g + b_g, a_b + b_b)
+    let (f_r, f_g, f_b) = b15_to_rgb(foreground_color);
+    let channel = |b: u8, f: u8| {
+        let (b, f) = (b as i32, f as i32);
+        let value = match mode & 0x3 {
+            0 => (b + f) / 2,
+            1 => b + f,
+            2 => b - f,
+            _ => b + f / 4,
+        };
+        value.clamp(0, 0x1F) as u8
+    };
+    (foreground_color & 0x8000) | rgb_to_b15(channel(b_r, f_r), channel(b_g, f_g), channel(b_b, f_b))
 }
 
 //Helper trait + impl
@@ -1327,4 +1547,765 @@ mod tests {
     fn test_lerp_color_negative() {
         assert_eq!(15, lerp_color(20, 10, 100, 200, 150));
     }
+
+    fn set_draw_area(gpu: &mut Gpu, x1: u32, y1: u32, x2: u32, y2: u32) {
+        gpu.send_gp0_command(0xE300_0000 | (y1 << 10) | x1);
+        gpu.send_gp0_command(0xE400_0000 | (y2 << 10) | x2);
+    }
+
+    #[test]
+    fn test_flat_triangles_fill_rectangle() {
+        let mut gpu = Gpu::new();
+        set_draw_area(&mut gpu, 0, 0, 1023, 511);
+
+        // Two monochrome triangles sharing the diagonal of a 10x10 square at (20, 30)
+        for verts in [[(20, 30), (30, 30), (20, 40)], [(30, 30), (30, 40), (20, 40)]].iter() {
+            gpu.send_gp0_command(0x2000_00FF); // Red
+            for (x, y) in verts.iter() {
+                gpu.send_gp0_command((y << 16) | x);
+            }
+        }
+
+        let vram = gpu.get_vram();
+        let drawn = vram.iter().filter(|p| **p != 0).count();
+        assert_eq!(drawn, 100);
+        assert_eq!(vram[point_to_address(20, 30) as usize], 0x1F);
+        assert_eq!(vram[point_to_address(29, 39) as usize], 0x1F);
+        assert_eq!(vram[point_to_address(30, 35) as usize], 0);
+        assert_eq!(vram[point_to_address(25, 40) as usize], 0);
+    }
+
+    /// Draws a 10x10 square out of two triangles, then an 8x8 rectangle, into a fresh GPU
+    fn render_square_and_sprite(scale: u32) -> Gpu {
+        let mut gpu = Gpu::new();
+        gpu.set_internal_scale(scale);
+        set_draw_area(&mut gpu, 0, 0, 1023, 511);
+        for verts in [[(20, 30), (30, 30), (20, 40)], [(30, 30), (30, 40), (20, 40)]].iter() {
+            gpu.send_gp0_command(0x2000_00FF);
+            for (x, y) in verts.iter() {
+                gpu.send_gp0_command((y << 16) | x);
+            }
+        }
+        gpu.send_gp0_command(0x7000_FF00);
+        gpu.send_gp0_command((100 << 16) | 100);
+        gpu
+    }
+
+    #[test]
+    fn test_internal_scale_renders_more_pixels() {
+        let native = render_square_and_sprite(1);
+        let native_drawn = native.get_vram().iter().filter(|p| **p != 0).count();
+        assert_eq!(native_drawn, 164);
+        assert!(native.get_scaled_vram().is_empty());
+
+        let upscaled = render_square_and_sprite(2);
+        let scaled_drawn = upscaled.get_scaled_vram().iter().filter(|p| **p != 0).count();
+        assert_eq!(scaled_drawn, native_drawn * 4);
+        // The native VRAM is drawn the same as without upscaling
+        assert_eq!(upscaled.get_vram(), native.get_vram());
+
+        let mut gpu = upscaled;
+        assert_eq!(gpu.framebuffer_rgba().len(), native.framebuffer_rgba().len() * 4);
+
+        // Transfers land at native resolution and are repeated across the upscaled pixels
+        upload(&mut gpu, 0, 0, 1, 1, &[0x7FFF]);
+        assert_eq!(gpu.get_vram()[0], 0x7FFF);
+        assert_eq!(&gpu.get_scaled_vram()[..3], &[0x7FFF, 0x7FFF, 0]);
+        assert_eq!(gpu.get_scaled_vram()[2048 + 1], 0x7FFF);
+    }
+
+    #[test]
+    fn test_flat_triangle_clipped_to_draw_area() {
+        let mut gpu = Gpu::new();
+        gpu.vram[point_to_address(4, 4) as usize] = 0x7FFF;
+        set_draw_area(&mut gpu, 4, 4, 5, 5);
+
+        // Black is still drawn
+        gpu.send_gp0_command(0x2000_0000);
+        gpu.send_gp0_command(0);
+        gpu.send_gp0_command(100);
+        gpu.send_gp0_command(100 << 16);
+        assert_eq!(gpu.vram[point_to_address(4, 4) as usize], 0);
+
+        gpu.send_gp0_command(0x2000_FF00); // Green
+        gpu.send_gp0_command(0);
+        gpu.send_gp0_command(100);
+        gpu.send_gp0_command(100 << 16);
+
+        let drawn: Vec<usize> = (0..gpu.vram.len()).filter(|i| gpu.vram[*i] != 0).collect();
+        let expected: Vec<usize> = [(4, 4), (5, 4), (4, 5), (5, 5)]
+            .iter()
+            .map(|(x, y)| point_to_address(*x, *y) as usize)
+            .collect();
+        assert_eq!(drawn, expected);
+        assert_eq!(gpu.vram[expected[0]], 0x1F << 5);
+    }
+
+    #[test]
+    fn test_gouraud_triangle_blends_vertex_colors() {
+        let mut gpu = Gpu::new();
+        set_draw_area(&mut gpu, 0, 0, 1023, 511);
+        gpu.send_gp0_command(0x3000_00FF); // Red
+        gpu.send_gp0_command(0);
+        gpu.send_gp0_command(0x0000_FF00); // Green
+        gpu.send_gp0_command(30);
+        gpu.send_gp0_command(0x00FF_0000); // Blue
+        gpu.send_gp0_command(30 << 16);
+
+        // The centroid gets a third of each color
+        assert_eq!(gpu.vram[point_to_address(10, 10) as usize], (10 << 10) | (10 << 5) | 10);
+        assert_eq!(gpu.vram[point_to_address(0, 0) as usize], 0x1F);
+        assert!(gpu.gp0_buffer.is_empty());
+    }
+
+    #[test]
+    fn test_full_screen_triangles_at_max_scale() {
+        let mut gpu = Gpu::new();
+        gpu.set_internal_scale(MAX_INTERNAL_SCALE);
+        set_draw_area(&mut gpu, 0, 0, 1023, 511);
+        let corners = [0, 639, (479 << 16)];
+        gpu.send_gp0_command(0x3000_00FF); // Gouraud, red to green to blue
+        for (color, vertex) in [0x0000_00FFu32, 0x0000_FF00, 0x00FF_0000].iter().zip(corners.iter()) {
+            if *color != 0xFF {
+                gpu.send_gp0_command(*color);
+            }
+            gpu.send_gp0_command(*vertex);
+        }
+        assert_eq!(gpu.vram[point_to_address(0, 0) as usize], 0x1F);
+        assert_eq!(gpu.get_scaled_vram()[0], 0x1F);
+        let scaled_width = VRAM_WIDTH as usize * MAX_INTERNAL_SCALE as usize;
+        assert_ne!(gpu.get_scaled_vram()[478 * 8 * scaled_width + 8], 0);
+
+        // Textured triangles interpolate their texture coordinates with the same weights
+        gpu.send_gp0_command(0x2480_8080);
+        for vertex in corners.iter() {
+            gpu.send_gp0_command(*vertex);
+            gpu.send_gp0_command(0);
+        }
+        assert_eq!(gpu.get_scaled_vram()[0], 0x1F);
+    }
+
+    #[test]
+    fn test_cpu_to_vram_transfer() {
+        let mut gpu = Gpu::new();
+        gpu.send_gp0_command(0xA000_0000);
+        gpu.send_gp0_command((20 << 16) | 10);
+        gpu.send_gp0_command((2 << 16) | 2);
+        gpu.send_gp0_command(0x2222_1111);
+        gpu.send_gp0_command(0x4444_3333);
+
+        assert_eq!(gpu.vram[point_to_address(10, 20) as usize], 0x1111);
+        assert_eq!(gpu.vram[point_to_address(11, 20) as usize], 0x2222);
+        assert_eq!(gpu.vram[point_to_address(10, 21) as usize], 0x3333);
+        assert_eq!(gpu.vram[point_to_address(11, 21) as usize], 0x4444);
+
+        // The transfer is over, so this is a regular command again
+        gpu.send_gp0_command(0xE300_0000 | (5 << 10) | 5);
+        assert_eq!(gpu.draw_area_tl_point.x, 5);
+    }
+
+    #[test]
+    fn test_cpu_to_vram_odd_size_wraps() {
+        let mut gpu = Gpu::new();
+        gpu.send_gp0_command(0xA000_0000);
+        gpu.send_gp0_command((511 << 16) | 1023);
+        gpu.send_gp0_command((1 << 16) | 3);
+        gpu.send_gp0_command(0x0002_0001);
+        gpu.send_gp0_command(0xFFFF_0003); // Upper half is padding
+
+        assert_eq!(gpu.vram[point_to_address(1023, 511) as usize], 1);
+        assert_eq!(gpu.vram[point_to_address(0, 511) as usize], 2);
+        assert_eq!(gpu.vram[point_to_address(1, 511) as usize], 3);
+        assert_eq!(gpu.vram[point_to_address(2, 511) as usize], 0);
+        assert!(gpu.cpu_transfer.is_none());
+    }
+
+    #[test]
+    fn test_vram_to_cpu_readback() {
+        let mut gpu = Gpu::new();
+        gpu.send_gp0_command(0xA000_0000);
+        gpu.send_gp0_command((100 << 16) | 200);
+        gpu.send_gp0_command((2 << 16) | 3);
+        gpu.send_gp0_command(0x0002_0001);
+        gpu.send_gp0_command(0x0004_0003);
+        gpu.send_gp0_command(0x0006_0005);
+        assert!(!gpu.read_status_register().get_bit(27));
+
+        gpu.send_gp0_command(0xC000_0000);
+        gpu.send_gp0_command((101 << 16) | 200);
+        gpu.send_gp0_command((1 << 16) | 3);
+        assert!(gpu.read_status_register().get_bit(27));
+
+        // Reading back only the second row leaves the last word half empty
+        assert_eq!(gpu.read_word_gp0(), 0x0005_0004);
+        assert_eq!(gpu.read_word_gp0(), 0x0000_0006);
+        assert!(!gpu.read_status_register().get_bit(27));
+    }
+
+    #[test]
+    fn test_display_mode() {
+        let mut gpu = Gpu::new();
+        gpu.send_gp1_command(0x0800_0001);
+        assert_eq!(gpu.resolution(), Resolution { width: 320, height: 240 });
+
+        // 640x480 interlaced PAL
+        gpu.send_gp1_command(0x0800_002F);
+        assert_eq!(gpu.resolution(), Resolution { width: 640, height: 480 });
+        let stat = gpu.read_status_register();
+        assert_eq!(stat.get_bits(17..23), 0x2F);
+
+        // 368 wide overrides the other horizontal bits, and 480 lines needs interlace
+        gpu.send_gp1_command(0x0800_0044);
+        assert_eq!(gpu.resolution(), Resolution { width: 368, height: 240 });
+        assert!(gpu.read_status_register().get_bit(16));
+    }
+
+    #[test]
+    fn test_framebuffer_rgba() {
+        let mut gpu = Gpu::new();
+        gpu.send_gp1_command(0x0800_0000); // 256x240, 15 bit
+        gpu.send_gp1_command(0x0500_0000 | (16 << 10) | 8); // Display starts at (8, 16)
+        gpu.vram[point_to_address(8, 16) as usize] = 0x001F; // Red
+        gpu.vram[point_to_address(9, 16) as usize] = 0x83E0; // Green with the mask bit
+        gpu.vram[point_to_address(8, 17) as usize] = 0x7C00; // Blue
+
+        let framebuffer = gpu.framebuffer_rgba();
+        assert_eq!(framebuffer.len(), 256 * 240 * 4);
+        assert_eq!(&framebuffer[0..8], &[0xFF, 0, 0, 0xFF, 0, 0xFF, 0, 0xFF]);
+        assert_eq!(&framebuffer[256 * 4..256 * 4 + 4], &[0, 0, 0xFF, 0xFF]);
+        assert_eq!(&framebuffer[8..12], &[0, 0, 0, 0xFF]);
+    }
+
+    #[test]
+    fn test_framebuffer_rgba_24_bit() {
+        let mut gpu = Gpu::new();
+        gpu.send_gp1_command(0x0800_0010); // 256x240, 24 bit
+        // Two pixels (0x11, 0x22, 0x33) and (0x44, 0x55, 0x66) packed into three halfwords
+        gpu.vram[0] = 0x2211;
+        gpu.vram[1] = 0x4433;
+        gpu.vram[2] = 0x6655;
+
+        let framebuffer = gpu.framebuffer_rgba();
+        assert_eq!(&framebuffer[0..8], &[0x11, 0x22, 0x33, 0xFF, 0x44, 0x55, 0x66, 0xFF]);
+    }
+
+    #[test]
+    fn test_gp1_display_enable() {
+        let mut gpu = Gpu::new();
+        gpu.vram[0] = 0x001F;
+        assert!(gpu.display_enabled());
+        assert!(!gpu.read_status_register().get_bit(23));
+
+        gpu.send_gp1_command(0x0300_0001);
+        assert!(!gpu.display_enabled());
+        assert!(gpu.read_status_register().get_bit(23));
+        assert!(gpu.framebuffer_rgba().chunks(4).all(|p| p == [0, 0, 0, 0xFF]));
+
+        gpu.send_gp1_command(0x0300_0000);
+        assert!(gpu.display_enabled());
+        assert_eq!(&gpu.framebuffer_rgba()[0..4], &[0xFF, 0, 0, 0xFF]);
+    }
+
+    #[test]
+    fn test_gp1_display_area_start() {
+        let mut gpu = Gpu::new();
+        gpu.send_gp1_command(0x0500_0000 | (0x1FF << 10) | 0x3FF);
+        assert_eq!(gpu.display_origin(), (0x3FF, 0x1FF));
+        gpu.send_gp1_command(0x0500_0000 | (100 << 10) | 320);
+        assert_eq!(gpu.display_origin(), (320, 100));
+    }
+
+    #[test]
+    fn test_gp1_reset_command_buffer() {
+        let mut gpu = Gpu::new();
+        gpu.send_gp0_command(0xA000_0000); // CPU->VRAM copy, missing its parameters
+        gpu.send_gp1_command(0x0100_0000);
+
+        // A fresh command goes through instead of being read as parameters
+        gpu.send_gp0_command(0xE500_0000 | (8 << 11) | 4);
+        assert_eq!(gpu.draw_offset.x, 4);
+        assert_eq!(gpu.draw_offset.y, 8);
+    }
+
+    #[test]
+    fn test_gp1_reset() {
+        let mut gpu = Gpu::new();
+        gpu.vram[10] = 0x1234;
+        gpu.send_gp1_command(0x0800_0013);
+        gpu.send_gp1_command(0x0500_0000 | (16 << 10) | 8);
+        gpu.send_gp0_command(0xE100_0105);
+        gpu.send_gp0_command(0xA000_0000);
+
+        gpu.send_gp1_command(0x0000_0000);
+        assert!(!gpu.display_enabled());
+        assert_eq!(gpu.display_origin(), (0, 0));
+        assert_eq!(gpu.resolution(), Resolution { width: 256, height: 240 });
+        assert_eq!(gpu.read_status_register() & 0x7FFF_FFFF, 0x1480_2000);
+        assert!(gpu.gp0_buffer.is_empty());
+        assert_eq!(gpu.vram[10], 0x1234);
+    }
+
+    fn upload(gpu: &mut Gpu, x: u32, y: u32, width: u32, height: u32, words: &[u32]) {
+        gpu.send_gp0_command(0xA000_0000);
+        gpu.send_gp0_command((y << 16) | x);
+        gpu.send_gp0_command((height << 16) | width);
+        for word in words {
+            gpu.send_gp0_command(*word);
+        }
+    }
+
+    /// Draws a 4x4 quad at (10, 10) textured with a 4bpp texture at (64, 0), where each
+    /// texel's CLUT index is its u coordinate. The CLUT at (0, 100) is clear, red, green, blue
+    fn draw_clut_quad(gpu: &mut Gpu, command: u32) {
+        draw_quad_with_clut(gpu, command, &[0x001F_0000, 0x7C00_03E0]);
+    }
+
+    fn draw_quad_with_clut(gpu: &mut Gpu, command: u32, clut_words: &[u32]) {
+        set_draw_area(gpu, 0, 0, 1023, 511);
+        upload(gpu, 64, 0, 1, 4, &[0x3210_3210, 0x3210_3210]);
+        upload(gpu, 0, 100, 4, 1, clut_words);
+
+        let clut = (100 << 6) << 16;
+        let texpage = 1 << 16;
+        gpu.send_gp0_command(command);
+        for (i, (x, y)) in [(10, 10), (14, 10), (10, 14), (14, 14)].iter().enumerate() {
+            let (u, v) = (x - 10, y - 10);
+            gpu.send_gp0_command((y << 16) | x);
+            let attribute = match i {
+                0 => clut,
+                1 => texpage,
+                _ => 0,
+            };
+            gpu.send_gp0_command(attribute | (v << 8) | u);
+        }
+    }
+
+    #[test]
+    fn test_textured_quad_4bit_clut() {
+        let mut gpu = Gpu::new();
+        draw_clut_quad(&mut gpu, 0x2D00_0000); // Raw texture
+
+        for y in 10..14 {
+            // Index 0 is fully transparent
+            assert_eq!(gpu.vram[point_to_address(10, y) as usize], 0);
+            assert_eq!(gpu.vram[point_to_address(11, y) as usize], 0x001F);
+            assert_eq!(gpu.vram[point_to_address(12, y) as usize], 0x03E0);
+            assert_eq!(gpu.vram[point_to_address(13, y) as usize], 0x7C00);
+            assert_eq!(gpu.vram[point_to_address(14, y) as usize], 0);
+        }
+        assert_eq!(gpu.vram[point_to_address(11, 14) as usize], 0);
+    }
+
+    #[test]
+    fn test_textured_quad_modulation() {
+        let mut gpu = Gpu::new();
+        draw_clut_quad(&mut gpu, 0x2C40_4080); // Full red, half green and blue
+        assert_eq!(gpu.vram[point_to_address(11, 10) as usize], 0x001F);
+        assert_eq!(gpu.vram[point_to_address(12, 10) as usize], 0x01E0);
+        assert_eq!(gpu.vram[point_to_address(13, 10) as usize], 0x3C00);
+    }
+
+    #[test]
+    fn test_blend_equations() {
+        let background = 0x1F | (10 << 5) | (4 << 10);
+        let foreground = 0x8000 | 0x1F | (6 << 5) | (8 << 10);
+
+        assert_eq!(blend(0, background, foreground), 0x8000 | 0x1F | (8 << 5) | (6 << 10));
+        // B + F saturates instead of carrying into the next channel
+        assert_eq!(blend(1, background, foreground), 0x8000 | 0x1F | (16 << 5) | (12 << 10));
+        // B - F bottoms out at 0
+        assert_eq!(blend(2, background, foreground), 0x8000 | (4 << 5));
+        assert_eq!(blend(3, background, foreground), 0x1F | (11 << 5) | (6 << 10) | 0x8000);
+        assert_eq!(blend(1, 0x7FFF, 0x7FFF), 0x7FFF);
+    }
+
+    #[test]
+    fn test_semi_transparent_triangle_uses_texpage_mode() {
+        let mut gpu = Gpu::new();
+        set_draw_area(&mut gpu, 0, 0, 1023, 511);
+        gpu.vram[point_to_address(1, 1) as usize] = 0x10 << 5;
+        gpu.send_gp0_command(0xE100_0000 | (2 << 5)); // B - F
+        assert_eq!((gpu.read_status_register() >> 5) & 0x3, 2);
+
+        gpu.send_gp0_command(0x2200_4000); // Semi-transparent, green 0x40
+        gpu.send_gp0_command(0);
+        gpu.send_gp0_command(8);
+        gpu.send_gp0_command(8 << 16);
+        assert_eq!(gpu.vram[point_to_address(1, 1) as usize], 0x08 << 5);
+        assert_eq!(gpu.vram[point_to_address(2, 2) as usize], 0);
+    }
+
+    #[test]
+    fn test_textured_stp_bit_selects_blending() {
+        let mut gpu = Gpu::new();
+        for y in 10..14 {
+            for x in 10..14 {
+                gpu.vram[point_to_address(x, y) as usize] = 0x001F;
+            }
+        }
+        // Make blue semi-transparent, mode 0 comes from the polygon's texpage
+        draw_quad_with_clut(&mut gpu, 0x2F00_0000, &[0x001F_0000, 0xFC00_03E0]);
+        assert_eq!(gpu.vram[point_to_address(10, 10) as usize], 0x001F);
+        assert_eq!(gpu.vram[point_to_address(12, 10) as usize], 0x03E0);
+        assert_eq!(gpu.vram[point_to_address(13, 10) as usize], 0x8000 | (0xF << 10) | 0xF);
+    }
+
+    #[test]
+    fn test_monochrome_16x16_rectangle() {
+        let mut gpu = Gpu::new();
+        set_draw_area(&mut gpu, 0, 0, 1023, 35);
+        gpu.send_gp0_command(0x7800_00FF); // Red
+        gpu.send_gp0_command((30 << 16) | 20);
+        // Fixed size rectangles don't take a size word, this is already the next command
+        gpu.send_gp0_command(0xE500_0000);
+
+        let drawn: Vec<usize> = (0..gpu.vram.len()).filter(|i| gpu.vram[*i] != 0).collect();
+        assert_eq!(drawn.len(), 16 * 6);
+        assert_eq!(gpu.vram[point_to_address(20, 30) as usize], 0x1F);
+        assert_eq!(gpu.vram[point_to_address(35, 35) as usize], 0x1F);
+        assert_eq!(gpu.vram[point_to_address(36, 30) as usize], 0);
+        // Clipped by the drawing area
+        assert_eq!(gpu.vram[point_to_address(20, 36) as usize], 0);
+    }
+
+    #[test]
+    fn test_draw_offset_moves_polygons() {
+        let mut gpu = Gpu::new();
+        set_draw_area(&mut gpu, 0, 0, 1023, 511);
+        gpu.send_gp0_command(0xE500_0000 | (50 << 11) | 100);
+        gpu.send_gp0_command(0x2800_00FF); // Red 4x4 quad at the origin
+        for (x, y) in [(0, 0), (4, 0), (0, 4), (4, 4)].iter() {
+            gpu.send_gp0_command((y << 16) | x);
+        }
+        assert_eq!(gpu.vram[point_to_address(100, 50) as usize], 0x1F);
+        assert_eq!(gpu.vram[point_to_address(103, 53) as usize], 0x1F);
+        assert_eq!(gpu.vram.iter().filter(|p| **p != 0).count(), 16);
+
+        // Negative offsets are sign extended from 11 bits
+        gpu.send_gp0_command(0xE500_0000 | (0x7FE << 11) | 0x7FC);
+        assert_eq!((gpu.draw_offset.x, gpu.draw_offset.y), (-4, -2));
+        gpu.send_gp0_command(0x6800_F800); // 1x1 green rectangle
+        gpu.send_gp0_command((12 << 16) | 14);
+        assert_eq!(gpu.vram[point_to_address(10, 10) as usize], 0x1F << 5);
+    }
+
+    #[test]
+    fn test_variable_size_rectangle_uses_draw_offset() {
+        let mut gpu = Gpu::new();
+        set_draw_area(&mut gpu, 0, 0, 1023, 511);
+        gpu.send_gp0_command(0xE500_0000 | (2 << 11) | 5);
+        gpu.send_gp0_command(0x6000_0000); // Black is drawn too
+        gpu.send_gp0_command(0);
+        gpu.send_gp0_command((2 << 16) | 3);
+        gpu.vram.iter_mut().for_each(|p| *p = 0x7FFF);
+        gpu.send_gp0_command(0x6000_0000);
+        gpu.send_gp0_command(0);
+        gpu.send_gp0_command((2 << 16) | 3);
+
+        let drawn: Vec<usize> = (0..gpu.vram.len()).filter(|i| gpu.vram[*i] == 0).collect();
+        let expected: Vec<usize> = [(5, 2), (6, 2), (7, 2), (5, 3), (6, 3), (7, 3)]
+            .iter()
+            .map(|(x, y)| point_to_address(*x, *y) as usize)
+            .collect();
+        assert_eq!(drawn, expected);
+    }
+
+    #[test]
+    fn test_textured_8x8_sprite() {
+        let mut gpu = Gpu::new();
+        set_draw_area(&mut gpu, 0, 0, 1023, 511);
+        // 4bpp texture at (64, 0) where each texel's CLUT index is its u coordinate,
+        // and a CLUT mapping index n to red level n
+        upload(&mut gpu, 64, 0, 2, 8, &[0x7654_3210; 8]);
+        upload(&mut gpu, 0, 100, 8, 1, &[0x0001_0000, 0x0003_0002, 0x0005_0004, 0x0007_0006]);
+        gpu.send_gp0_command(0xE100_0001); // Texpage at x = 64, 4 bit
+
+        gpu.send_gp0_command(0x7500_0000); // Raw texture
+        gpu.send_gp0_command((30 << 16) | 20);
+        gpu.send_gp0_command((100 << 6) << 16);
+
+        for y in 30..38 {
+            for x in 20..28 {
+                assert_eq!(gpu.vram[point_to_address(x, y) as usize], (x - 20) as u16);
+            }
+        }
+        assert_eq!(gpu.vram[point_to_address(28, 30) as usize], 0);
+        assert_eq!(gpu.vram[point_to_address(20, 38) as usize], 0);
+    }
+
+    #[test]
+    fn test_texture_window_repeats_tile() {
+        let mut gpu = Gpu::new();
+        set_draw_area(&mut gpu, 0, 0, 1023, 511);
+        // 15 bit texture at (64, 0) where texel u is u + 1
+        let row: Vec<u32> = (0..8).map(|i| ((2 * i + 2) << 16) | (2 * i + 1)).collect();
+        upload(&mut gpu, 64, 0, 16, 1, &row);
+        gpu.send_gp0_command(0xE100_0101); // Texpage at x = 64, 15 bit
+        // An 8 texel window starting at u = 8
+        gpu.send_gp0_command(0xE200_0000 | (1 << 10) | 1);
+
+        gpu.send_gp0_command(0x6500_0000); // Raw texture, variable size
+        gpu.send_gp0_command(0);
+        gpu.send_gp0_command(0);
+        gpu.send_gp0_command((1 << 16) | 16);
+
+        let drawn: Vec<u16> = (0..16).map(|x| gpu.vram[point_to_address(x, 0) as usize]).collect();
+        let expected: Vec<u16> = (0..16).map(|u| 9 + (u & 7)).collect();
+        assert_eq!(drawn, expected);
+    }
+
+    #[test]
+    fn test_vram_to_vram_copy() {
+        let mut gpu = Gpu::new();
+        for y in 0..4 {
+            for x in 0..4 {
+                gpu.vram[point_to_address(10 + x, 20 + y) as usize] = (y * 4 + x + 1) as u16;
+            }
+        }
+
+        gpu.send_gp0_command(0x8000_0000);
+        gpu.send_gp0_command((20 << 16) | 10);
+        gpu.send_gp0_command((100 << 16) | 1022); // Wraps around the right edge
+        gpu.send_gp0_command((4 << 16) | 4);
+
+        for y in 0..4 {
+            for x in 0..4 {
+                let expected = (y * 4 + x + 1) as u16;
+                assert_eq!(gpu.vram[point_to_address(10 + x, 20 + y) as usize], expected);
+                let dest_x = (1022 + x) & 0x3FF;
+                assert_eq!(gpu.vram[point_to_address(dest_x, 100 + y) as usize], expected);
+            }
+        }
+        assert_eq!(gpu.vram.iter().filter(|p| **p != 0).count(), 32);
+    }
+
+    #[test]
+    fn test_vram_to_vram_copy_overlapping() {
+        let mut gpu = Gpu::new();
+        for x in 0..4 {
+            gpu.vram[point_to_address(x, 0) as usize] = x as u16 + 1;
+            gpu.vram[point_to_address(x, 1) as usize] = x as u16 + 5;
+        }
+        // Shift the 4x2 block one pixel right and down over itself
+        gpu.send_gp0_command(0x8000_0000);
+        gpu.send_gp0_command(0);
+        gpu.send_gp0_command((1 << 16) | 1);
+        gpu.send_gp0_command((2 << 16) | 4);
+
+        let row = |gpu: &Gpu, y| (1..5).map(|x| gpu.vram[point_to_address(x, y) as usize]).collect::<Vec<u16>>();
+        assert_eq!(row(&gpu, 1), vec![1, 2, 3, 4]);
+        assert_eq!(row(&gpu, 2), vec![5, 6, 7, 8]);
+    }
+
+    #[test]
+    fn test_vram_to_vram_copy_mask_bits() {
+        let mut gpu = Gpu::new();
+        gpu.vram[0] = 0x1234;
+        gpu.vram[1] = 0x5678;
+        gpu.vram[point_to_address(1, 10) as usize] = 0x8000;
+
+        gpu.send_gp0_command(0xE600_0003); // Set and check the mask bit
+        assert_eq!((gpu.read_status_register() >> 11) & 0x3, 0x3);
+        gpu.send_gp0_command(0x8000_0000);
+        gpu.send_gp0_command(0);
+        gpu.send_gp0_command(10 << 16);
+        gpu.send_gp0_command((1 << 16) | 2);
+
+        assert_eq!(gpu.vram[point_to_address(0, 10) as usize], 0x9234);
+        assert_eq!(gpu.vram[point_to_address(1, 10) as usize], 0x8000);
+    }
+
+    #[test]
+    fn test_status_ready_for_words_during_gp0_parameters() {
+        let mut gpu = Gpu::new();
+        // A flat quad takes a color and four vertices
+        gpu.send_gp0_command(0x2800_0000);
+        for vertex in 0..4 {
+            let stat = gpu.read_status_register();
+            assert!(stat.get_bit(28), "not ready for vertex {}", vertex);
+            assert!(!stat.get_bit(26));
+            gpu.send_gp0_command(0);
+        }
+        assert!(gpu.read_status_register().get_bit(26));
+    }
+
+    #[test]
+    fn test_status_dma_ready_bits() {
+        let mut gpu = Gpu::new();
+        assert!(gpu.read_status_register().get_bit(28));
+        assert!(!gpu.read_status_register().get_bit(25));
+
+        gpu.send_gp1_command(0x0400_0002); // CPU to GP0
+        let stat = gpu.read_status_register();
+        assert_eq!((stat >> 29) & 0x3, 2);
+        assert!(stat.get_bit(25) && stat.get_bit(26) && stat.get_bit(28));
+
+        // Half a triangle leaves the GPU waiting on parameters, which it still takes by DMA
+        gpu.send_gp0_command(0x2000_0000);
+        gpu.send_gp0_command(0);
+        let stat = gpu.read_status_register();
+        assert!(stat.get_bit(25) && !stat.get_bit(26) && stat.get_bit(28));
+
+        gpu.send_gp0_command(1);
+        gpu.send_gp0_command(1 << 16);
+        assert!(gpu.read_status_register().get_bit(26));
+
+        // Pixel data for a CPU to VRAM copy is taken by DMA, but not new commands
+        gpu.send_gp0_command(0xA000_0000);
+        gpu.send_gp0_command(0);
+        gpu.send_gp0_command((1 << 16) | 2);
+        let stat = gpu.read_status_register();
+        assert!(stat.get_bit(28) && !stat.get_bit(26));
+        gpu.send_gp0_command(0);
+        assert!(gpu.read_status_register().get_bit(26));
+
+        // In GPUREAD mode the DMA request follows the VRAM send flag
+        gpu.send_gp1_command(0x0400_0003);
+        assert!(!gpu.read_status_register().get_bit(25));
+        gpu.send_gp0_command(0xC000_0000);
+        gpu.send_gp0_command(0);
+        gpu.send_gp0_command((1 << 16) | 2);
+        assert!(gpu.read_status_register().get_bit(25));
+        gpu.read_word_gp0();
+        assert!(!gpu.read_status_register().get_bit(25));
+    }
+
+    #[test]
+    fn test_horizontal_line() {
+        let mut gpu = Gpu::new();
+        set_draw_area(&mut gpu, 0, 0, 1023, 511);
+        gpu.send_gp0_command(0x4000_00FF); // Red
+        gpu.send_gp0_command((20 << 16) | 10);
+        gpu.send_gp0_command((20 << 16) | 15);
+
+        let vram = gpu.get_vram();
+        assert_eq!(vram.iter().filter(|p| **p != 0).count(), 6);
+        for x in 10..=15 {
+            assert_eq!(vram[point_to_address(x, 20) as usize], 0x1F);
+        }
+    }
+
+    #[test]
+    fn test_gouraud_polyline() {
+        let mut gpu = Gpu::new();
+        set_draw_area(&mut gpu, 0, 0, 1023, 511);
+        gpu.send_gp0_command(0x5800_00F8); // Gouraud polyline starting red
+        gpu.send_gp0_command(0);
+        gpu.send_gp0_command(0x0000_F800); // Green
+        gpu.send_gp0_command(4);
+        gpu.send_gp0_command(0x0000_F800);
+        gpu.send_gp0_command(4 << 16 | 4);
+        assert_eq!(gpu.get_vram()[point_to_address(4, 0) as usize], 0);
+        gpu.send_gp0_command(0x5555_5555);
+
+        let vram = gpu.get_vram();
+        assert_eq!(vram[point_to_address(0, 0) as usize], 0x1F);
+        assert_eq!(vram[point_to_address(2, 0) as usize], (15 << 5) | 15);
+        assert_eq!(vram[point_to_address(4, 0) as usize], 0x1F << 5);
+        assert_eq!(vram[point_to_address(4, 4) as usize], 0x1F << 5);
+        assert!(gpu.gp0_buffer.is_empty());
+    }
+
+    #[test]
+    fn test_check_mask_protects_pixels() {
+        let mut gpu = Gpu::new();
+        set_draw_area(&mut gpu, 0, 0, 1023, 511);
+        gpu.vram[point_to_address(1, 0) as usize] = 0x8001;
+
+        gpu.send_gp0_command(0xE600_0002); // Check mask
+        gpu.send_gp0_command(0x6000_00FF); // 4x1 red rectangle
+        gpu.send_gp0_command(0);
+        gpu.send_gp0_command((1 << 16) | 4);
+        assert_eq!(gpu.vram[point_to_address(0, 0) as usize], 0x1F);
+        assert_eq!(gpu.vram[point_to_address(1, 0) as usize], 0x8001);
+
+        // Pixels drawn with set mask on are protected from later draws too
+        gpu.send_gp0_command(0xE600_0003);
+        gpu.send_gp0_command(0x6000_F800); // Green over the first pixel
+        gpu.send_gp0_command(0);
+        gpu.send_gp0_command((1 << 16) | 1);
+        gpu.send_gp0_command(0x6000_00F8);
+        gpu.send_gp0_command(0);
+        gpu.send_gp0_command((1 << 16) | 1);
+        assert_eq!(gpu.vram[point_to_address(0, 0) as usize], 0x8000 | (0x1F << 5));
+
+        // CPU to VRAM transfers follow the same rules
+        gpu.send_gp0_command(0xA000_0000);
+        gpu.send_gp0_command(0);
+        gpu.send_gp0_command((1 << 16) | 2);
+        gpu.send_gp0_command(0x1234_5678);
+        assert_eq!(gpu.vram[point_to_address(0, 0) as usize], 0x8000 | (0x1F << 5));
+        assert_eq!(gpu.vram[point_to_address(1, 0) as usize], 0x8001);
+    }
+
+    #[test]
+    fn test_quick_fill_ignores_draw_area_and_mask() {
+        let mut gpu = Gpu::new();
+        set_draw_area(&mut gpu, 0, 0, 7, 7);
+        gpu.send_gp0_command(0xE600_0002); // Check mask
+        gpu.vram[point_to_address(20, 10) as usize] = 0x8001;
+
+        // 20x2 at (21, 10) is aligned out to 32x2 at (16, 10)
+        gpu.send_gp0_command(0x0200_00FF);
+        gpu.send_gp0_command((10 << 16) | 21);
+        gpu.send_gp0_command((2 << 16) | 20);
+
+        let vram = gpu.get_vram();
+        assert_eq!(vram.iter().filter(|p| **p != 0).count(), 64);
+        assert_eq!(vram[point_to_address(16, 10) as usize], 0x1F);
+        assert_eq!(vram[point_to_address(20, 10) as usize], 0x1F);
+        assert_eq!(vram[point_to_address(47, 11) as usize], 0x1F);
+        assert_eq!(vram[point_to_address(48, 10) as usize], 0);
+        assert_eq!(vram[point_to_address(16, 12) as usize], 0);
+    }
+
+    fn run_field(gpu: &mut Gpu) {
+        for _ in 0..=CYCLES_PER_SCANLINE * TOTAL_SCANLINES {
+            gpu.execute_cycle();
+        }
+    }
+
+    #[test]
+    fn test_gpu_info_reads_back_settings() {
+        let mut gpu = Gpu::new();
+        gpu.send_gp0_command(0xE500_0000 | (0x7FF << 11) | 100); // Offset (100, -1)
+        gpu.send_gp0_command(0xE300_0000 | (20 << 10) | 10);
+        gpu.send_gp0_command(0xE200_0000 | (3 << 15) | 1);
+
+        gpu.send_gp1_command(0x1000_0005);
+        assert_eq!(gpu.read_word_gp0(), (0x7FF << 11) | 100);
+        gpu.send_gp1_command(0x1000_0003);
+        assert_eq!(gpu.read_word_gp0(), (20 << 10) | 10);
+        gpu.send_gp1_command(0x1000_0002);
+        assert_eq!(gpu.read_word_gp0(), (3 << 15) | 1);
+        gpu.send_gp1_command(0x1000_0007);
+        assert_eq!(gpu.read_word_gp0(), 2);
+
+        // Unused sub-commands keep the last result
+        gpu.send_gp1_command(0x1000_0000);
+        assert_eq!(gpu.read_word_gp0(), 2);
+    }
+
+    #[test]
+    fn test_interlace_field_alternates() {
+        let mut gpu = Gpu::new();
+        gpu.send_gp1_command(0x0800_0001);
+        for _ in 0..3 {
+            run_field(&mut gpu);
+            assert_eq!(gpu.read_status_register() & (1 << 31), 0);
+            assert!(gpu.take_frame_ready());
+        }
+
+        // 640x480 interlaced
+        gpu.send_gp1_command(0x0800_0027);
+        let mut fields = Vec::new();
+        let mut frames = 0;
+        for _ in 0..4 {
+            run_field(&mut gpu);
+            fields.push(gpu.read_status_register().get_bit(31));
+            frames += gpu.take_frame_ready() as u32;
+        }
+        assert_eq!(fields, vec![true, false, true, false]);
+        assert_eq!(frames, 2);
+    }
 }
@@ -0,0 +1,149 @@
+use bit_field::BitField;
+
+use crate::cpu::InterruptSource;
+
+pub const I_STAT: u32 = 0x1F801070;
+pub const I_MASK: u32 = 0x1F801074;
+/// Last byte of I_MASK. Half word and byte accesses can land anywhere in either register
+pub const I_MASK_END: u32 = 0x1F801077;
+
+/// Only the low 11 bits of I_STAT and I_MASK are wired to interrupt sources
+const IRQ_BITS: u32 = 0x7FF;
+
+/// Interrupt status (I_STAT) and mask (I_MASK) registers
+pub struct Interrupts {
+    status: u32,
+    mask: u32,
+}
+
+impl Interrupts {
+    pub fn new() -> Interrupts {
+        Interrupts { status: 0, mask: 0 }
+    }
+
+    /// Latches an interrupt request in I_STAT
+    pub fn request(&mut self, source: InterruptSource) {
+        self.status.set_bit(source as usize, true);
+    }
+
+    /// True if any requested interrupt is also enabled in I_MASK
+    pub fn pending(&self) -> bool {
+        self.status & self.mask != 0
+    }
+
+    pub fn status(&self) -> u32 {
+        self.status
+    }
+
+    pub fn mask(&self) -> u32 {
+        self.mask
+    }
+
+    pub fn read_word(&self, addr: u32) -> u32 {
+        match addr {
+            I_STAT => self.status,
+            I_MASK => self.mask,
+            _ => panic!("Invalid interrupt register read at {:#X}", addr),
+        }
+    }
+
+    pub fn read_half_word(&self, addr: u32) -> u16 {
+        (self.read_word(addr & !3) >> lane_shift(addr)) as u16
+    }
+
+    pub fn read_byte(&self, addr: u32) -> u8 {
+        (self.read_word(addr & !3) >> lane_shift(addr)) as u8
+    }
+
+    pub fn write_word(&mut self, addr: u32, value: u32) {
+        self.write_lanes(addr, value, 0xFFFF_FFFF);
+    }
+
+    pub fn write_half_word(&mut self, addr: u32, value: u16) {
+        let shift = lane_shift(addr);
+        self.write_lanes(addr & !3, (value as u32) << shift, 0xFFFF << shift);
+    }
+
+    pub fn write_byte(&mut self, addr: u32, value: u8) {
+        let shift = lane_shift(addr);
+        self.write_lanes(addr & !3, (value as u32) << shift, 0xFF << shift);
+    }
+
+    /// Writes only the bits covered by `lanes`. I_STAT bits are acknowledged by writing 0,
+    /// so the bits outside of the access are left alone
+    fn write_lanes(&mut self, addr: u32, value: u32, lanes: u32) {
+        match addr {
+            I_STAT => self.status &= (value | !lanes) & IRQ_BITS,
+            I_MASK => self.mask = ((self.mask & !lanes) | (value & lanes)) & IRQ_BITS,
+            _ => panic!("Invalid interrupt register write at {:#X}", addr),
+        }
+    }
+}
+
+/// Where the byte at addr sits within its register
+fn lane_shift(addr: u32) -> u32 {
+    (addr & 3) * 8
+}
+
+#[cfg(test)]
+mod interrupts_tests {
+    use super::*;
+
+    #[test]
+    fn test_word_acknowledge() {
+        let mut irq = Interrupts::new();
+        irq.request(InterruptSource::VBLANK);
+        irq.request(InterruptSource::DMA);
+        irq.write_word(I_STAT, !1);
+        assert_eq!(irq.read_word(I_STAT), 1 << 3);
+    }
+
+    #[test]
+    fn test_byte_acknowledge_keeps_upper_bits() {
+        let mut irq = Interrupts::new();
+        irq.request(InterruptSource::VBLANK);
+        irq.request(InterruptSource::Controller);
+        irq.request(InterruptSource::SPU);
+
+        // Clear VBLANK and leave the controller and SPU bits alone
+        irq.write_byte(I_STAT, 0xFE);
+        assert_eq!(irq.read_word(I_STAT), (1 << 7) | (1 << 9));
+
+        irq.write_half_word(I_STAT, !(1 << 9));
+        assert_eq!(irq.read_word(I_STAT), 1 << 7);
+    }
+
+    #[test]
+    fn test_byte_mask_write() {
+        let mut irq = Interrupts::new();
+        irq.write_word(I_MASK, 0x7FF);
+        irq.write_byte(I_MASK, 0x01);
+        assert_eq!(irq.read_word(I_MASK), 0x701);
+
+        irq.request(InterruptSource::GPU);
+        assert!(!irq.pending());
+        irq.request(InterruptSource::VBLANK);
+        assert!(irq.pending());
+    }
+
+    #[test]
+    fn test_half_word_vblank_acknowledge() {
+        let mut irq = Interrupts::new();
+        irq.request(InterruptSource::VBLANK);
+        irq.request(InterruptSource::TMR2);
+
+        // Writing the upper half can't acknowledge anything in the lower half
+        irq.write_half_word(I_STAT + 2, 0);
+        assert_eq!(irq.read_word(I_STAT), 0x41);
+        assert_eq!(irq.read_half_word(I_STAT), 0x41);
+        assert_eq!(irq.read_half_word(I_STAT + 2), 0);
+
+        irq.write_half_word(I_STAT, 0xFFFE);
+        assert_eq!(irq.read_word(I_STAT), 0x40);
+
+        irq.write_word(I_MASK, 0x7FF);
+        irq.write_half_word(I_MASK + 2, 0);
+        assert_eq!(irq.read_word(I_MASK), 0x7FF);
+        assert_eq!(irq.read_byte(I_MASK + 1), 0x07);
+    }
+}
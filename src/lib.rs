@@ -1,15 +1,25 @@
 use bios::Bios;
 use bus::MainBus;
-use controller::{ButtonState, controller_execute_cycle, ControllerType};
-use cpu::R3000;
-use gpu::Resolution;
-use log::trace;
+use memory_card::MemoryCard;
+use controller::{AnalogState, ButtonState, controller_execute_cycle};
+use cpu::{InstructionHook, InterruptHook, R3000};
+use gpu::{Field, Resolution, VideoMode, VRAM_HEIGHT, VRAM_WIDTH};
+use log::{info, warn};
+use std::fmt;
+use std::fs;
+use std::io;
 use std::panic;
+use std::path::Path;
 use timer::TimerState;
 
-use crate::cdrom::disc::Disc;
+use crate::cdrom::disc::{Disc, DiscError};
 use crate::cpu::InterruptSource;
 use crate::dma::execute_dma_cycle;
+pub use crate::bus::BusAccess;
+pub use crate::dma::DmaChannelSnapshot;
+#[cfg(feature = "realtime")]
+pub use crate::realtime::{Clock, RealtimeFrame, SystemClock};
+use crate::exe::{ExeError, PsxExe};
 use crate::gpu::Gpu;
 use crate::memory::Memory;
 
@@ -18,27 +28,168 @@ mod bus;
 pub mod cdrom;
 pub mod controller;
 pub mod cpu;
+mod crc;
 mod dma;
+pub mod exe;
 pub mod gpu;
+mod interrupts;
+mod mem_control;
 mod memory;
+mod memory_card;
+#[cfg(feature = "image")]
+mod png;
+#[cfg(feature = "realtime")]
+mod realtime;
+mod rng;
 mod spu;
 mod timer;
 
-static mut LOGGING: bool = false;
+/// Called every time the GPU enters vblank
+pub type VblankCallback = Box<dyn FnMut() + Send>;
+
+/// Receives the SPU's output as it's mixed, as 44100Hz stereo samples
+pub trait AudioSink: Send {
+    fn push_samples(&mut self, samples: &[(i16, i16)]);
+}
+
+/// Errors that can occur while constructing the emulator
+#[derive(Debug, PartialEq)]
+pub enum EmuInitError {
+    BiosWrongSize { expected: usize, got: usize },
+    /// The BIOS file couldn't be read
+    Io(io::ErrorKind),
+}
+
+impl fmt::Display for EmuInitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EmuInitError::BiosWrongSize { expected, got } => write!(
+                f,
+                "BIOS image is {} bytes, expected {} bytes",
+                got, expected
+            ),
+            EmuInitError::Io(kind) => write!(f, "Couldn't read the BIOS file: {}", kind),
+        }
+    }
+}
+
+impl std::error::Error for EmuInitError {}
+
+/// Errors from the checked register accessors
+#[derive(Debug, PartialEq)]
+pub enum RegError {
+    OutOfRange { reg_num: usize },
+}
+
+impl fmt::Display for RegError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RegError::OutOfRange { reg_num } => write!(
+                f,
+                "Register {} doesn't exist, there are only {}",
+                reg_num, NUM_GEN_REGS
+            ),
+        }
+    }
+}
+
+impl std::error::Error for RegError {}
+
+/// One second of emulated time. A frame normally takes around 565,000 cpu cycles
+pub const DEFAULT_FRAME_CYCLE_BUDGET: u64 = 33_868_800;
+
+const NUM_COP0_REGS: usize = 32;
+
+const NUM_GEN_REGS: usize = 32;
+
+/// Number of cpu cycles run by each step_cycle
+const CPU_CYCLES_PER_STEP: u64 = 2;
+
+/// The real GPU clock runs at 53.69MHz against the cpu's 33.87MHz, roughly 11:7. Stepping it
+/// twice per cpu cycle is only an approximation of that, running the gpu about a quarter too
+/// fast, but a whole number of steps keeps timing simple and deterministic
+pub const GPU_CYCLES_PER_CPU_CYCLE: u64 = 2;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum FrameResult {
+    /// The gpu finished a frame
+    Completed,
+    /// The cycle budget ran out before the frame finished
+    BudgetExhausted,
+    /// A breakpoint or watchpoint stopped emulation mid frame
+    Halted,
+}
+
+/// Timing of a frame run by run_frame_info, for front ends pacing video and resampling audio
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct FrameInfo {
+    pub result: FrameResult,
+    /// Cpu cycles the frame took
+    pub cycles: u64,
+    /// Scanlines per field in the video mode the frame ended in
+    pub scanlines: u32,
+    pub is_pal: bool,
+    /// The field the frame finished on
+    pub field: Field,
+}
+
+/// Which accesses a watchpoint stops on
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum WatchKind {
+    Read,
+    Write,
+    Access,
+}
+
+/// Why emulation last stopped
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum HaltReason {
+    /// The pc reached a software breakpoint
+    Breakpoint(u32),
+    /// A bus access touched a watched address. kind is Read or Write depending on the access
+    Watchpoint { addr: u32, kind: WatchKind },
+}
+
+/// How a `run_until` call ended
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum StepOutcome {
+    /// The pc reached the target address
+    Reached,
+    /// A breakpoint or watchpoint stopped emulation first
+    Halted(HaltReason),
+    /// The cycle budget ran out
+    BudgetExhausted,
+}
+
+/// What happened during a single `tick`
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct TickResult {
+    /// The gpu entered vblank
+    pub vblank: bool,
+    /// I_STAT bits that were newly requested, by InterruptSource number
+    pub interrupts: u32,
+    /// Set if a breakpoint or watchpoint stopped emulation
+    pub halted: Option<HaltReason>,
+}
 
 pub struct PSXEmu {
     pub r3000: R3000,
     timers: TimerState,
-    cycle_count: u32,
+    cycle_count: u64,
     halt_requested: bool,
+    halt_reason: Option<HaltReason>,
     sw_breakpoints: Vec<u32>,
-    watchpoints: Vec<u32>
+    vblank_callback: Option<VblankCallback>,
+    vblank_seen: bool,
+    audio_sink: Option<Box<dyn AudioSink>>,
+    #[cfg(feature = "realtime")]
+    pacer: realtime::Pacer,
 }
 
 impl PSXEmu {
-    /// Creates a new instance of the emulator.
-    pub fn new(bios: Vec<u8>) -> PSXEmu {
-        let bios = Bios::new(bios);
+    /// Creates a new instance of the emulator. Fails if the BIOS image isn't a valid size
+    pub fn new(bios: Vec<u8>) -> Result<PSXEmu, EmuInitError> {
+        let bios = Bios::new(bios)?;
         let memory = Memory::new();
         let gpu = Gpu::new();
         let bus = MainBus::new(bios, memory, gpu);
@@ -49,49 +200,132 @@ impl PSXEmu {
             timers: TimerState::new(),
             cycle_count: 0,
             halt_requested: false,
+            halt_reason: None,
             sw_breakpoints: Vec::new(),
-            watchpoints: Vec::new(),
+            vblank_callback: None,
+            vblank_seen: false,
+            audio_sink: None,
+            #[cfg(feature = "realtime")]
+            pacer: realtime::Pacer::new(Box::new(SystemClock::new())),
         };
         emu.reset();
-        emu
+        Ok(emu)
+    }
+
+    /// Reads a BIOS image from path and creates the emulator with it. Images that don't match
+    /// a known retail dump still load, but log a warning
+    pub fn from_bios_file(path: &Path) -> Result<PSXEmu, EmuInitError> {
+        let data = fs::read(path).map_err(|err| EmuInitError::Io(err.kind()))?;
+        let emu = PSXEmu::new(data)?;
+        match emu.bios_model() {
+            Some(model) => info!("Loaded {} BIOS", model),
+            None => warn!("{} isn't a known BIOS dump, it may be patched or corrupt", path.display()),
+        }
+        Ok(emu)
+    }
+
+    /// The model the loaded BIOS was dumped from, if its checksum is a known one
+    pub fn bios_model(&self) -> Option<&'static str> {
+        self.r3000.main_bus.bios.identify()
+    }
+
+    /// Creates an emulator with an empty BIOS, for running code that never calls into it
+    pub fn without_bios() -> PSXEmu {
+        PSXEmu::new(vec![0; bios::BIOS_SIZE]).unwrap()
+    }
+
+    /// Maps a ROM image at base and resets into entry, the way CPU test suites are run. The
+    /// ROM shadows anything else mapped there and stays loaded across resets
+    pub fn load_test_rom(&mut self, data: Vec<u8>, base: u32, entry: u32) {
+        self.r3000.main_bus.map_test_rom(base, data);
+        self.r3000.set_reset_vector(entry);
+        self.reset();
     }
 
-    /// Resets system to startup condition
+    /// Makes power on fill RAM, VRAM and SPU RAM with a pattern generated from seed, instead of
+    /// the zeros they normally start as, then power cycles. Some games depend on what's left in
+    /// uninitialized memory, and the same seed always gives the same contents. Nothing else in
+    /// the emulator is random, so runs with the same seed and inputs are identical
+    pub fn set_deterministic(&mut self, seed: u64) {
+        self.r3000.main_bus.set_power_on_seed(seed);
+        self.power_on();
+    }
+
+    /// Soft reset, like pressing the reset button. The CPU registers are cleared and execution
+    /// restarts at the BIOS entry point or test ROM entry, and the GPU is reset along with its
+    /// VRAM. Main RAM and every other device keep their state
     pub fn reset(&mut self) {
         self.r3000.reset();
         self.r3000.main_bus.gpu.reset();
     }
 
-    /// Runs a single time unit. Each unit has the correct-ish ratio of cpu:gpu cycles
+    /// Cold boot. On top of a reset this clears main RAM, the scratchpad, VRAM and SPU RAM (unless
+    /// set_deterministic gave them a seed), and puts COP0, the GTE, the timers and every bus device
+    /// back to their power on state. The BIOS, the inserted disc and memory card, breakpoints and
+    /// any sideloaded executable are kept
+    pub fn power_on(&mut self) {
+        self.r3000.power_on();
+        self.timers = TimerState::new();
+        self.halt_requested = false;
+        self.halt_reason = None;
+    }
+
+    /// Runs a single time unit of CPU_CYCLES_PER_STEP cpu cycles
     pub fn step_cycle(&mut self) {
-        for _ in 0..2 {
-            if self.halt_requested {return};
+        self.run_cycles(CPU_CYCLES_PER_STEP);
+    }
+
+    /// Runs n cpu cycles, each followed by GPU_CYCLES_PER_CPU_CYCLE gpu cycles.
+    /// Stops early if a breakpoint or watchpoint is hit
+    pub fn run_cycles(&mut self, n: u64) {
+        for _ in 0..n {
+            if self.halt_requested {
+                return;
+            }
             self.run_cpu_cycle();
-            self.run_gpu_cycle();
+            for _ in 0..GPU_CYCLES_PER_CPU_CYCLE {
+                self.run_gpu_cycle();
+            }
         }
+    }
 
-        //Two extra gpu cycles gets close enough to correct timing
-        self.run_gpu_cycle();
-        self.run_gpu_cycle();
+    /// Runs one cpu cycle, which executes one instruction, and the gpu cycles that go with it.
+    /// Lets front ends drive the emulator in small slices and react to what happened
+    pub fn tick(&mut self) -> TickResult {
+        let status_before = self.r3000.main_bus.interrupts.status();
+        self.vblank_seen = false;
+        self.run_cycles(1);
+        TickResult {
+            vblank: self.vblank_seen,
+            interrupts: self.r3000.main_bus.interrupts.status() & !status_before,
+            halted: if self.halt_requested { self.halt_reason } else { None },
+        }
+    }
+
+    /// Number of cpu cycles run since the emulator was created
+    pub fn cycle_count(&self) -> u64 {
+        self.cycle_count
     }
 
     pub fn run_cpu_cycle(&mut self) {
         if self.sw_breakpoints.contains(&self.r3000.pc) {
-            self.halt_requested = true;
-            return;
-        }
-
-        if self.watchpoints.contains(&self.r3000.last_touched_addr) {
-            self.halt_requested = true;
+            self.halt(HaltReason::Breakpoint(self.r3000.pc));
             return;
         }
 
-        
- 
         controller_execute_cycle(&mut self.r3000);
         cdrom::step_cycle(&mut self.r3000);
         self.r3000.step_instruction(&mut self.timers);
         execute_dma_cycle(&mut self.r3000);
+        if let Some(reason) = self.r3000.main_bus.take_watch_hit() {
+            self.halt(reason);
+        }
+        self.r3000.main_bus.spu.execute_cycle();
+        if let Some(sink) = &mut self.audio_sink {
+            if self.r3000.main_bus.spu.has_samples() {
+                sink.push_samples(&self.r3000.main_bus.spu.drain_samples());
+            }
+        }
         self.cycle_count += 1;
         self.timers.update_sys_clock(&mut self.r3000);
         if self.cycle_count % 8 == 0 {
@@ -101,37 +335,150 @@ impl PSXEmu {
 
     fn run_gpu_cycle(&mut self) {
         self.r3000.main_bus.gpu.execute_cycle();
+        if self.r3000.main_bus.gpu.consume_vblank() {
+            self.r3000.fire_external_interrupt(InterruptSource::VBLANK);
+            self.vblank_seen = true;
+            if let Some(callback) = &mut self.vblank_callback {
+                callback();
+            }
+        }
         self.timers.update_dot_clock(&mut self.r3000);
         if self.r3000.main_bus.gpu.consume_hblank() {
             self.timers.update_h_blank(&mut self.r3000);
         }
     }
 
-    ///Runs the emulator till one frame has been generated
-    pub fn run_frame(&mut self) {
+    ///Runs the emulator till one frame has been generated, or DEFAULT_FRAME_CYCLE_BUDGET cpu cycles pass
+    pub fn run_frame(&mut self) -> FrameResult {
+        self.run_frame_bounded(DEFAULT_FRAME_CYCLE_BUDGET)
+    }
+
+    ///Runs the emulator till one frame has been generated, giving up after max_cycles cpu cycles
+    pub fn run_frame_bounded(&mut self, max_cycles: u64) -> FrameResult {
+        self.run_frame_counted(max_cycles).0
+    }
+
+    /// Like run_frame, but also reports how long the frame took and what video mode it was in
+    pub fn run_frame_info(&mut self) -> FrameInfo {
+        let (result, cycles) = self.run_frame_counted(DEFAULT_FRAME_CYCLE_BUDGET);
+        let gpu = &self.r3000.main_bus.gpu;
+        FrameInfo {
+            result,
+            cycles,
+            scanlines: gpu.scanlines_per_frame(),
+            is_pal: gpu.is_pal(),
+            field: gpu.last_field(),
+        }
+    }
+
+    /// Like run_frame_info, but then sleeps until the frame has taken as long as it would on a
+    /// console, NTSC or PAL, counting from the end of the last call. When emulation is behind it
+    /// doesn't sleep and reports the overrun instead
+    #[cfg(feature = "realtime")]
+    pub fn run_realtime_frame(&mut self) -> RealtimeFrame {
+        let start = self.pacer.start_frame();
+        let info = self.run_frame_info();
+        self.pacer.finish_frame(start, info)
+    }
+
+    /// Replaces the clock run_realtime_frame paces against. Frame timing starts over
+    #[cfg(feature = "realtime")]
+    pub fn set_clock(&mut self, clock: Box<dyn Clock>) {
+        self.pacer = realtime::Pacer::new(clock);
+    }
+
+    fn run_frame_counted(&mut self, max_cycles: u64) -> (FrameResult, u64) {
+        let mut cycles: u64 = 0;
         while !self.r3000.main_bus.gpu.take_frame_ready() {
+            if self.halt_requested {
+                return (FrameResult::Halted, cycles);
+            }
+            if cycles >= max_cycles {
+                return (FrameResult::BudgetExhausted, cycles);
+            }
             self.step_cycle();
+            cycles += CPU_CYCLES_PER_STEP;
         }
         //Step the gpu once more to get it off this frame
         self.r3000.main_bus.gpu.execute_cycle();
+        (FrameResult::Completed, cycles)
+    }
+
+    /// Runs until the pc reaches addr, giving up after max_cycles cpu cycles. At least one cycle
+    /// is run, so calling this while already at addr runs until it comes back around
+    pub fn run_until(&mut self, addr: u32, max_cycles: u64) -> StepOutcome {
+        for _ in 0..max_cycles {
+            if self.halt_requested {
+                break;
+            }
+            self.run_cycles(1);
+            if self.r3000.pc == addr {
+                return StepOutcome::Reached;
+            }
+        }
+        match (self.halt_requested, self.halt_reason) {
+            (true, Some(reason)) => StepOutcome::Halted(reason),
+            _ => StepOutcome::BudgetExhausted,
+        }
     }
 
-    pub fn load_executable(&mut self, start_addr: u32, entrypoint: u32, _sp: u32, data: &Vec<u8>) {
-        for (index, val) in data.iter().enumerate() {
-            self.r3000
-                .main_bus
-                .write_byte((index + start_addr as usize) as u32, val.clone());
+    /// Sideloads an executable body that has already been split from its header.
+    /// See `sideload_psx_exe`. Without the header there's no gp value, so $gp starts at 0
+    pub fn load_executable(&mut self, start_addr: u32, entrypoint: u32, sp: u32, data: &[u8]) {
+        self.r3000.sideload_exe(PsxExe {
+            pc: entrypoint,
+            gp: 0,
+            load_address: start_addr,
+            sp,
+            data: data.to_vec(),
+        });
+    }
+
+    /// Lets the BIOS boot, then runs the PS-EXE in place of the shell once execution reaches
+    /// 0x80030000. Use `load_psx_exe` to start it immediately instead
+    pub fn sideload_psx_exe(&mut self, exe: &[u8]) -> Result<(), ExeError> {
+        self.r3000.sideload_exe(PsxExe::parse(exe)?);
+        Ok(())
+    }
+
+    /// Copies a PS-EXE into RAM and points the cpu at its entrypoint, with gp, sp and fp set up
+    /// from the header. sp and fp are left alone if the header doesn't give a stack
+    pub fn load_psx_exe(&mut self, exe: &[u8]) -> Result<(), ExeError> {
+        self.r3000.run_exe(&PsxExe::parse(exe)?);
+        Ok(())
+    }
+
+    /// Writes an instruction trace to the given path, or stops tracing when given None
+    pub fn set_trace_path(&mut self, path: Option<&Path>) -> io::Result<()> {
+        match path {
+            Some(path) => self.r3000.enable_trace(path),
+            None => {
+                self.r3000.disable_trace();
+                Ok(())
+            }
         }
-        self.r3000.load_exe = true;
-        //self.r3000.pc = entrypoint;
-        // self.r3000.gen_registers[29] = sp;
-        // self.r3000.gen_registers[30] = sp;
     }
 
     pub fn load_disc(&mut self, disc: Disc) {
         self.r3000.main_bus.cd_drive.load_disc(disc);
     }
 
+    /// Loads a disc image from a cue sheet and inserts it. Nothing changes if it can't be loaded
+    pub fn load_disc_file(&mut self, path: &Path) -> Result<(), DiscError> {
+        let is_cue = path
+            .extension()
+            .is_some_and(|extension| extension.eq_ignore_ascii_case("cue"));
+        if !is_cue {
+            return Err(DiscError::UnsupportedFormat(format!(
+                "{} isn't a cue sheet",
+                path.display()
+            )));
+        }
+        let disc = Disc::load_cue(path)?;
+        self.load_disc(disc);
+        Ok(())
+    }
+
     pub fn loaded_disc(&self) -> &Option<Disc> {
         self.r3000.main_bus.cd_drive.disc()
     }
@@ -144,6 +491,99 @@ impl PSXEmu {
         self.r3000.main_bus.gpu.get_vram()
     }
 
+    /// Selects NTSC or PAL timing, which sets how many scanlines a frame has and how long they
+    /// are. That changes the vblank and hblank rates, and the timers counting them. Software
+    /// can still switch modes through GP1 0x08. The mode acts as the console region, so unlike
+    /// on hardware a GP1 reset doesn't put it back to NTSC
+    pub fn set_video_mode(&mut self, mode: VideoMode) {
+        self.r3000.main_bus.gpu.set_video_mode(mode);
+    }
+
+    pub fn video_mode(&self) -> VideoMode {
+        self.r3000.main_bus.gpu.video_mode()
+    }
+
+    /// Mutable VRAM, row by row at 1024 pixels per row. Writes through this bypass the
+    /// GPU's mask bit checks, so they land even on protected pixels
+    pub fn get_vram_mut(&mut self) -> &mut [u16] {
+        self.r3000.main_bus.gpu.get_vram_mut()
+    }
+
+    /// Reads the pixel at (x, y) in VRAM. Out of range coordinates read as 0
+    pub fn read_vram_pixel(&self, x: u16, y: u16) -> u16 {
+        if x >= VRAM_WIDTH || y >= VRAM_HEIGHT {
+            warn!("Tried to read VRAM pixel ({}, {}) outside of VRAM", x, y);
+            return 0;
+        }
+        self.get_vram()[y as usize * VRAM_WIDTH as usize + x as usize]
+    }
+
+    /// Writes the pixel at (x, y) in VRAM, ignoring the mask bit settings. Writes to out of
+    /// range coordinates are ignored
+    pub fn write_vram_pixel(&mut self, x: u16, y: u16, pixel: u16) {
+        if x >= VRAM_WIDTH || y >= VRAM_HEIGHT {
+            warn!("Tried to write VRAM pixel ({}, {}) outside of VRAM", x, y);
+            return;
+        }
+        self.get_vram_mut()[y as usize * VRAM_WIDTH as usize + x as usize] = pixel;
+    }
+
+    /// Takes the stereo samples the SPU has generated since the last call, at 44100Hz. Always
+    /// empty while an audio sink is set
+    pub fn drain_audio(&mut self) -> Vec<(i16, i16)> {
+        self.r3000.main_bus.spu.drain_samples()
+    }
+
+    /// Hands every sample to sink as soon as the SPU mixes it, instead of buffering them for
+    /// drain_audio
+    pub fn set_audio_sink(&mut self, sink: Box<dyn AudioSink>) {
+        self.audio_sink = Some(sink);
+    }
+
+    /// Goes back to buffering audio for drain_audio
+    pub fn clear_audio_sink(&mut self) {
+        self.audio_sink = None;
+    }
+
+    /// True while the CD drive is streaming XA-ADPCM audio into the SPU
+    pub fn xa_playing(&self) -> bool {
+        self.r3000.main_bus.cd_drive.xa_playing()
+    }
+
+    /// The visible part of VRAM as RGBA8888, sized to `framebuffer_resolution`
+    pub fn get_framebuffer(&self) -> Vec<u8> {
+        self.r3000.main_bus.gpu.framebuffer_rgba()
+    }
+
+    /// Renders primitives at factor times the native resolution, from 1 up to
+    /// `gpu::MAX_INTERNAL_SCALE`. VRAM transfers, readback and texture sampling stay at native
+    /// resolution, only `get_framebuffer` gets bigger
+    pub fn set_internal_scale(&mut self, factor: u32) {
+        self.r3000.main_bus.gpu.set_internal_scale(factor);
+    }
+
+    pub fn internal_scale(&self) -> u32 {
+        self.r3000.main_bus.gpu.internal_scale()
+    }
+
+    /// Size of the frames `get_framebuffer` returns, the display resolution times the internal scale
+    pub fn framebuffer_resolution(&self) -> Resolution {
+        let resolution = self.display_resolution();
+        let scale = self.internal_scale();
+        Resolution {
+            width: resolution.width * scale,
+            height: resolution.height * scale,
+        }
+    }
+
+    /// Writes the visible part of VRAM to path as a PNG. Fails without touching the file if
+    /// the display area is empty
+    #[cfg(feature = "image")]
+    pub fn dump_framebuffer_png(&self, path: &Path) -> io::Result<()> {
+        let resolution = self.framebuffer_resolution();
+        png::write_rgba(path, resolution.width, resolution.height, &self.get_framebuffer())
+    }
+
     pub fn get_bios(&self) -> &Vec<u8> {
         self.r3000.main_bus.bios.get_data()
     }
@@ -152,6 +592,62 @@ impl PSXEmu {
         self.r3000.fire_external_interrupt(source);
     }
 
+    /// Requested interrupts that I_MASK lets through, as I_STAT & I_MASK. They're only taken
+    /// once interrupt_enabled is true and IM2 is set in the COP0 status register
+    pub fn pending_interrupts(&self) -> u32 {
+        let interrupts = &self.r3000.main_bus.interrupts;
+        interrupts.status() & interrupts.mask()
+    }
+
+    /// The IEc bit of the COP0 status register
+    pub fn interrupt_enabled(&self) -> bool {
+        self.r3000.cop0.interrupts_enabled()
+    }
+
+    /// Strict mode panics on unknown instructions, and is on by default. When it's off they're
+    /// logged and skipped like NOPs instead, to see how far a game gets
+    pub fn set_strict(&mut self, strict: bool) {
+        self.r3000.set_strict(strict);
+    }
+
+    /// Panic on accesses to unmapped addresses rather than logging them. Off by default, and CPU
+    /// loads from unmapped addresses outside the hardware registers then raise a bus error
+    pub fn set_strict_bus(&mut self, strict: bool) {
+        self.r3000.main_bus.set_strict(strict);
+    }
+
+    /// Reads emulated memory for a debugger. Addresses that aren't backed by RAM, BIOS or the
+    /// scratchpad read as 0, so I/O registers are never disturbed
+    pub fn read_memory(&mut self, addr: u32, len: usize) -> Vec<u8> {
+        self.read_memory_checked(addr, len)
+            .into_iter()
+            .map(|byte| byte.unwrap_or(0))
+            .collect()
+    }
+
+    /// Same as read_memory, but marks bytes that couldn't be read as None
+    pub fn read_memory_checked(&mut self, addr: u32, len: usize) -> Vec<Option<u8>> {
+        (0..len)
+            .map(|offset| self.r3000.main_bus.peek_byte(addr.wrapping_add(offset as u32)))
+            .collect()
+    }
+
+    /// Writes emulated memory for a debugger. Bytes outside of RAM and the scratchpad are skipped
+    pub fn write_memory(&mut self, addr: u32, data: &[u8]) {
+        for (offset, value) in data.iter().enumerate() {
+            let target = addr.wrapping_add(offset as u32);
+            if !self.r3000.main_bus.poke_byte(target, *value) {
+                warn!("Debugger write to unwritable address {:#X} ignored", target);
+            }
+        }
+    }
+
+    /// Bulk copies data into memory, for loading executables and test fixtures. RAM is written
+    /// directly and device registers get normal bus writes
+    pub fn load_ram(&mut self, addr: u32, data: &[u8]) {
+        self.r3000.main_bus.load_bytes(addr, data);
+    }
+
     pub fn read_gen_reg(&self, reg_num: usize) -> u32 {
         self.r3000.gen_registers[reg_num]
     }
@@ -160,6 +656,95 @@ impl PSXEmu {
         self.r3000.gen_registers[reg_num] = value;
     }
 
+    /// Like read_gen_reg, but returns None instead of panicking on an out of range register
+    pub fn try_read_gen_reg(&self, reg_num: usize) -> Option<u32> {
+        self.r3000.gen_registers.get(reg_num).copied()
+    }
+
+    /// Like set_gen_reg, but returns an error instead of panicking on an out of range register
+    pub fn try_set_gen_reg(&mut self, reg_num: usize, value: u32) -> Result<(), RegError> {
+        match self.r3000.gen_registers.get_mut(reg_num) {
+            Some(register) => {
+                *register = value;
+                Ok(())
+            }
+            None => Err(RegError::OutOfRange { reg_num }),
+        }
+    }
+
+    /// Calls hook with the pc and instruction word of every executed instruction
+    pub fn set_instruction_hook(&mut self, hook: InstructionHook) {
+        self.r3000.set_instruction_hook(hook);
+    }
+
+    pub fn clear_instruction_hook(&mut self) {
+        self.r3000.clear_instruction_hook();
+    }
+
+    /// Runs the common BIOS functions natively instead of through the BIOS, so homebrew can run
+    /// without a real BIOS image. Functions that aren't implemented still go to the BIOS
+    pub fn enable_bios_hle(&mut self, enabled: bool) {
+        self.r3000.set_bios_hle(enabled);
+    }
+
+    /// Takes the debug TTY text printed since the last call. The HLE TTY functions print here,
+    /// and characters sent through the BIOS putchar functions are captured with or without HLE
+    pub fn take_tty_output(&mut self) -> String {
+        self.r3000.take_tty()
+    }
+
+    /// Calls callback whenever the GPU enters vblank, right as the VBLANK interrupt is requested
+    pub fn set_vblank_callback(&mut self, callback: VblankCallback) {
+        self.vblank_callback = Some(callback);
+    }
+
+    pub fn clear_vblank_callback(&mut self) {
+        self.vblank_callback = None;
+    }
+
+    /// Calls hook with the source of every interrupt request, whether or not it's masked
+    pub fn set_interrupt_callback(&mut self, hook: InterruptHook) {
+        self.r3000.set_interrupt_hook(hook);
+    }
+
+    pub fn clear_interrupt_callback(&mut self) {
+        self.r3000.clear_interrupt_hook();
+    }
+
+    /// Reads a COP0 register. Out of range registers read as 0
+    pub fn read_cop0_reg(&self, reg_num: usize) -> u32 {
+        if reg_num >= NUM_COP0_REGS {
+            warn!("Tried to read nonexistent COP0 register {}", reg_num);
+            return 0;
+        }
+        self.r3000.cop0.read_reg(reg_num as u8)
+    }
+
+    /// Writes a COP0 register. Writes to out of range registers are ignored
+    pub fn write_cop0_reg(&mut self, reg_num: usize, value: u32) {
+        if reg_num >= NUM_COP0_REGS {
+            warn!("Tried to write nonexistent COP0 register {}", reg_num);
+            return;
+        }
+        self.r3000.cop0.write_reg(reg_num as u8, value);
+    }
+
+    pub fn status(&self) -> u32 {
+        self.r3000.cop0.status()
+    }
+
+    pub fn cause(&self) -> u32 {
+        self.r3000.cop0.cause()
+    }
+
+    pub fn epc(&self) -> u32 {
+        self.r3000.cop0.epc()
+    }
+
+    pub fn bad_vaddr(&self) -> u32 {
+        self.r3000.cop0.bad_vaddr()
+    }
+
     pub fn halt_requested(&self) -> bool {
         self.halt_requested
     }
@@ -168,8 +753,17 @@ impl PSXEmu {
         self.halt_requested = false;
     }
 
+    fn halt(&mut self, reason: HaltReason) {
+        self.halt_requested = true;
+        self.halt_reason = Some(reason);
+    }
+
+    /// What caused the most recent halt. Kept after clear_halt until the next one
+    pub fn last_halt_reason(&self) -> Option<HaltReason> {
+        self.halt_reason
+    }
+
     pub fn add_sw_breakpoint(&mut self, addr: u32) {
-        println!("Adding breakpoint");
         self.sw_breakpoints.push(addr);
     }
 
@@ -177,24 +771,562 @@ impl PSXEmu {
         self.sw_breakpoints.retain(|&x| x != addr);
     }
 
+    /// Registers of DMA channel n, from 0 (MDECin) to 6 (OTC). None for any other channel
+    pub fn dma_channel(&self, n: usize) -> Option<DmaChannelSnapshot> {
+        self.r3000.main_bus.dma.channel_snapshot(n)
+    }
+
+    /// The DPCR register, holding each channel's priority and master enable
+    pub fn dma_control(&self) -> u32 {
+        self.r3000.main_bus.dma.control()
+    }
+
+    /// The DICR register, holding the DMA interrupt enables and flags
+    pub fn dma_interrupt(&self) -> u32 {
+        self.r3000.main_bus.dma.interrupt()
+    }
+
     pub fn display_resolution(&self) -> Resolution {
         self.r3000.main_bus.gpu.resolution()
     }
 
+    /// False while software has the display blanked with GP1 0x03
+    pub fn display_enabled(&self) -> bool {
+        self.r3000.main_bus.gpu.display_enabled()
+    }
+
+    /// Where the displayed area starts in VRAM, for cropping `get_vram`
+    pub fn display_origin(&self) -> (u32, u32) {
+        self.r3000.main_bus.gpu.display_origin()
+    }
+
     pub fn update_controller_state(&mut self, state: ButtonState) {
         self.r3000.main_bus.controllers.update_button_state(state);
     }
 
+    /// Plugs a memory card into slot 1, creating a blank 128KiB image if the file doesn't exist
+    pub fn insert_memory_card(&mut self, path: &Path) -> io::Result<()> {
+        let card = MemoryCard::load(path)?;
+        self.r3000.main_bus.controllers.insert_memory_card(card);
+        Ok(())
+    }
+
+    /// The DualShock's motors as set by the last poll: the small one is 0 or 0xFF, the large
+    /// one a speed from 0 to 0xFF
+    pub fn controller_rumble(&self) -> (u8, u8) {
+        self.r3000.main_bus.controllers.rumble()
+    }
+
+    pub fn remove_memory_card(&mut self) {
+        self.r3000.main_bus.controllers.remove_memory_card();
+    }
+
+    pub fn update_analog_state(&mut self, state: AnalogState) {
+        self.r3000.main_bus.controllers.update_analog_state(state);
+    }
+
     pub fn frame_ready(&mut self) -> bool {
         self.r3000.main_bus.gpu.take_frame_ready()
     }
 
-    pub fn add_watchpoint(&mut self, addr: u32) {
-        println!("Adding watchpoint for addr {:#X} ({:#X} masked)", addr, addr & 0x1fffffff);
-        self.watchpoints.push(addr & 0x1FFFFFFF);
+    /// Halts after any cpu or DMA access of the given kind touches addr. Addresses are compared
+    /// physically, so a watchpoint covers every mirror in KUSEG/KSEG0/KSEG1
+    pub fn add_watchpoint(&mut self, addr: u32, kind: WatchKind) {
+        self.r3000.main_bus.add_watchpoint(addr, kind);
     }
 
     pub fn remove_watchpoint(&mut self, addr: u32) {
-        self.watchpoints.retain(|&x| x != addr & 0x1FFFFFFF);
+        self.r3000.main_bus.remove_watchpoint(addr);
+    }
+
+    /// Keeps the last capacity cpu accesses to the hardware registers at 0x1F801000-0x1F802FFF,
+    /// clearing anything logged before. A capacity of 0 turns the log back off
+    pub fn enable_bus_log(&mut self, capacity: usize) {
+        self.r3000.main_bus.enable_bus_log(capacity);
+    }
+
+    /// The logged accesses, oldest first
+    pub fn bus_log(&self) -> &[BusAccess] {
+        self.r3000.main_bus.bus_log()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rejects_wrong_size_bios() {
+        match PSXEmu::new(vec![0; 1024]) {
+            Err(err) => assert_eq!(
+                err,
+                EmuInitError::BiosWrongSize {
+                    expected: bios::BIOS_SIZE,
+                    got: 1024
+                }
+            ),
+            Ok(_) => panic!("Accepted a truncated BIOS"),
+        }
+    }
+
+    #[test]
+    fn test_from_bios_file() {
+        let path = std::env::temp_dir().join(format!("psx_emu_bios_test_{}.bin", std::process::id()));
+        fs::write(&path, vec![0; bios::BIOS_SIZE]).unwrap();
+        let emu = PSXEmu::from_bios_file(&path).unwrap();
+        assert_eq!(emu.bios_model(), None);
+
+        fs::write(&path, vec![0; 1024]).unwrap();
+        assert_eq!(
+            PSXEmu::from_bios_file(&path).err(),
+            Some(EmuInitError::BiosWrongSize { expected: bios::BIOS_SIZE, got: 1024 })
+        );
+
+        fs::remove_file(&path).unwrap();
+        assert_eq!(
+            PSXEmu::from_bios_file(&path).err(),
+            Some(EmuInitError::Io(io::ErrorKind::NotFound))
+        );
+    }
+
+    #[test]
+    fn test_same_seed_gives_same_state() {
+        let mut emus: Vec<PSXEmu> = (0..3).map(|_| PSXEmu::without_bios()).collect();
+        emus[0].set_deterministic(1234);
+        emus[1].set_deterministic(1234);
+        emus[2].set_deterministic(5678);
+        for emu in emus.iter_mut() {
+            emu.run_cycles(10000);
+        }
+
+        let state = |emu: &mut PSXEmu| {
+            (
+                emu.r3000.pc,
+                emu.r3000.gen_registers,
+                emu.read_memory(0x8000_0000, 0x1000),
+                emu.get_vram().clone(),
+            )
+        };
+        let first = state(&mut emus[0]);
+        assert_eq!(first, state(&mut emus[1]));
+        assert_ne!(first.2, state(&mut emus[2]).2);
+        assert!(first.2.iter().any(|byte| *byte != 0));
+    }
+
+    #[test]
+    fn test_pending_interrupt_not_taken_while_disabled() {
+        let mut emu = PSXEmu::without_bios();
+        emu.load_test_rom(vec![0; 16], 0x1F00_0000, 0xBF00_0000);
+        emu.r3000.cop0.set_status(0x400); // IM2 on, IEc off
+
+        // Requested, but masked off by I_MASK
+        emu.manually_fire_interrupt(InterruptSource::VBLANK);
+        assert_eq!(emu.pending_interrupts(), 0);
+
+        emu.r3000.main_bus.interrupts.write_word(interrupts::I_MASK, 1 << InterruptSource::VBLANK as u32);
+        emu.manually_fire_interrupt(InterruptSource::VBLANK);
+        assert_eq!(emu.pending_interrupts(), 1 << InterruptSource::VBLANK as u32);
+        assert!(!emu.interrupt_enabled());
+
+        emu.run_cpu_cycle();
+        assert_eq!(emu.r3000.pc, 0xBF00_0004);
+        assert_eq!(emu.pending_interrupts(), 1 << InterruptSource::VBLANK as u32);
+    }
+
+    #[test]
+    fn test_vram_pixel_access() {
+        let mut emu = PSXEmu::without_bios();
+        emu.write_vram_pixel(1023, 511, 0x7FFF);
+        assert_eq!(emu.read_vram_pixel(1023, 511), 0x7FFF);
+        assert_eq!(*emu.get_vram().last().unwrap(), 0x7FFF);
+
+        emu.get_vram_mut()[1024 + 2] = 0x1F;
+        assert_eq!(emu.read_vram_pixel(2, 1), 0x1F);
+
+        emu.write_vram_pixel(1024, 0, 0x1234);
+        assert_eq!(emu.read_vram_pixel(1024, 0), 0);
+        assert_eq!(emu.read_vram_pixel(0, 1), 0);
+    }
+
+    #[test]
+    fn test_debugger_memory_access() {
+        let mut emu = PSXEmu::new(vec![0; bios::BIOS_SIZE]).unwrap();
+        emu.write_memory(0x8000_1000, &[1, 2, 3, 4]);
+        assert_eq!(emu.read_memory(0xA000_1000, 4), vec![1, 2, 3, 4]);
+
+        // GPU status register reads must not go through the device
+        assert_eq!(emu.read_memory_checked(0x1F80_1812, 3), vec![None, None, None]);
+        assert_eq!(emu.read_memory(0x1F80_1814, 4), vec![0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_run_frame_info_reports_ntsc_timing() {
+        let mut emu = PSXEmu::without_bios();
+        let info = emu.run_frame_info();
+        assert_eq!(info.result, FrameResult::Completed);
+        assert_eq!(info.scanlines, 263);
+        assert!(!info.is_pal);
+        assert_eq!(info.field, Field::Even);
+        // Just past 263 lines of 3413 gpu cycles, at two gpu cycles per cpu cycle
+        assert_eq!(info.cycles, (3413 * 263 + 1) / 2);
+    }
+
+    #[test]
+    fn test_pal_frames_take_longer() {
+        let mut emu = PSXEmu::without_bios();
+        emu.set_video_mode(VideoMode::Pal);
+        let info = emu.run_frame_info();
+        assert_eq!(info.scanlines, 314);
+        assert!(info.is_pal);
+        // 314 lines of 3406 gpu cycles, rounded up to a whole step
+        assert_eq!(info.cycles, 534_744);
+
+        // The console region survives a power cycle and a GPU reset
+        emu.power_on();
+        emu.r3000.main_bus.gpu.send_gp1_command(0);
+        assert_eq!(emu.video_mode(), VideoMode::Pal);
+
+        emu.set_video_mode(VideoMode::Ntsc);
+        let ntsc_cycles = PSXEmu::without_bios().run_frame_info().cycles;
+        assert_eq!(emu.run_frame_info().cycles, ntsc_cycles);
+    }
+
+    #[test]
+    fn test_run_frame_bounded() {
+        let mut emu = PSXEmu::new(vec![0; bios::BIOS_SIZE]).unwrap();
+        assert_eq!(emu.run_frame_bounded(100), FrameResult::BudgetExhausted);
+        assert_eq!(emu.run_frame(), FrameResult::Completed);
+
+        emu.add_sw_breakpoint(emu.r3000.pc);
+        assert_eq!(emu.run_frame(), FrameResult::Halted);
+    }
+
+    #[test]
+    fn test_run_cycles_counts_exactly() {
+        let mut emu = PSXEmu::new(vec![0; bios::BIOS_SIZE]).unwrap();
+        emu.run_cycles(100);
+        assert_eq!(emu.cycle_count(), 100);
+        emu.step_cycle();
+        assert_eq!(emu.cycle_count(), 100 + CPU_CYCLES_PER_STEP);
+    }
+
+    #[test]
+    fn test_halt_reasons() {
+        let mut emu = PSXEmu::new(vec![0; bios::BIOS_SIZE]).unwrap();
+        // lw $t0, 0x100($zero) ; sw $t0, 0x104($zero)
+        emu.write_memory(0x1000, &[0x00, 0x01, 0x08, 0x8C, 0x04, 0x01, 0x08, 0xAC]);
+        emu.r3000.pc = 0x8000_1000;
+
+        emu.add_watchpoint(0xA000_0104, WatchKind::Write);
+        emu.add_watchpoint(0x0000_0100, WatchKind::Read);
+        emu.run_cpu_cycle();
+        assert!(emu.halt_requested());
+        assert_eq!(
+            emu.last_halt_reason(),
+            Some(HaltReason::Watchpoint { addr: 0x100, kind: WatchKind::Read })
+        );
+
+        emu.clear_halt();
+        emu.run_cpu_cycle();
+        assert_eq!(
+            emu.last_halt_reason(),
+            Some(HaltReason::Watchpoint { addr: 0x104, kind: WatchKind::Write })
+        );
+
+        emu.clear_halt();
+        emu.remove_watchpoint(0x104);
+        emu.add_sw_breakpoint(emu.r3000.pc);
+        emu.run_cpu_cycle();
+        assert_eq!(emu.last_halt_reason(), Some(HaltReason::Breakpoint(0x8000_1008)));
+    }
+
+    #[test]
+    fn test_run_until() {
+        let mut emu = PSXEmu::new(vec![0; bios::BIOS_SIZE]).unwrap();
+        // RAM is zeroed, so this runs through nops
+        emu.r3000.pc = 0x8000_1000;
+        assert_eq!(emu.run_until(0x8000_1010, 100), StepOutcome::Reached);
+        assert_eq!(emu.r3000.pc, 0x8000_1010);
+        assert_eq!(emu.cycle_count(), 4);
+
+        assert_eq!(emu.run_until(0x8000_2000, 10), StepOutcome::BudgetExhausted);
+        assert_eq!(emu.cycle_count(), 14);
+
+        emu.add_sw_breakpoint(0x8000_1040);
+        assert_eq!(
+            emu.run_until(0x8000_1100, 100),
+            StepOutcome::Halted(HaltReason::Breakpoint(0x8000_1040))
+        );
+        assert_eq!(emu.r3000.pc, 0x8000_1040);
+    }
+
+    #[test]
+    fn test_dma_channel_snapshot() {
+        let mut emu = PSXEmu::new(vec![0; bios::BIOS_SIZE]).unwrap();
+        emu.r3000.main_bus.write_word(0x1F80_10A0, 0x8012_3456);
+        emu.r3000.main_bus.write_word(0x1F80_10A4, 0x0004_0010);
+        emu.r3000.main_bus.write_word(0x1F80_10F4, 0x0084_0000);
+
+        let gpu = emu.dma_channel(2).unwrap();
+        assert_eq!(gpu.name, "GPU");
+        assert_eq!(gpu.base_addr, 0x12_3456);
+        assert_eq!(gpu.block, 0x0004_0010);
+        assert!(!gpu.busy());
+        assert_eq!(emu.dma_control(), 0x0765_4321);
+        assert_eq!(emu.dma_interrupt(), 0x0084_0000);
+        assert!(emu.dma_channel(7).is_none());
+    }
+
+    #[test]
+    fn test_cop0_access() {
+        let mut emu = PSXEmu::new(vec![0; bios::BIOS_SIZE]).unwrap();
+        emu.write_cop0_reg(14, 0x8000_1234);
+        assert_eq!(emu.epc(), 0x8000_1234);
+        emu.write_cop0_reg(12, 0x0040_0001);
+        assert_eq!(emu.status(), 0x0040_0001);
+
+        // Misaligned load, see it land in cause and BadVaddr
+        emu.write_memory(0x1000, &[0x01, 0x00, 0x08, 0x8C]); // lw $t0, 1($zero)
+        emu.r3000.pc = 0x8000_1000;
+        emu.run_cpu_cycle();
+        assert_eq!((emu.cause() >> 2) & 0x1F, 4);
+        assert_eq!(emu.bad_vaddr(), 1);
+        assert_eq!(emu.epc(), 0x8000_1000);
+
+        emu.write_cop0_reg(32, 0xFFFF_FFFF);
+        assert_eq!(emu.read_cop0_reg(32), 0);
+    }
+
+    #[test]
+    fn test_load_psx_exe() {
+        let mut emu = PSXEmu::new(vec![0; bios::BIOS_SIZE]).unwrap();
+        let exe = exe::exe_tests::build_exe(0x8001_0008, 0x8001_0000, 0x801F_FF00, &[0x78, 0x56, 0x34, 0x12]);
+        emu.load_psx_exe(&exe).unwrap();
+        assert_eq!(emu.read_memory(0x8001_0000, 4), vec![0x78, 0x56, 0x34, 0x12]);
+        assert_eq!(emu.r3000.pc, 0x8001_0008);
+        assert_eq!(emu.read_gen_reg(28), 0x8001_8000);
+        assert_eq!(emu.read_gen_reg(29), 0x801F_FF10);
+        assert_eq!(emu.read_gen_reg(30), 0x801F_FF10);
+
+        assert_eq!(emu.load_psx_exe(&[0; 0x800]), Err(ExeError::BadMagic));
+    }
+
+    #[test]
+    fn test_sideload_psx_exe_waits_for_shell() {
+        // A BIOS that does nothing but jump to the shell
+        let mut bios = vec![0; bios::BIOS_SIZE];
+        for (i, word) in [0x3C08_8003u32, 0x0100_0008, 0].iter().enumerate() {
+            bios[i * 4..i * 4 + 4].copy_from_slice(&word.to_le_bytes()); // lui $t0, 0x8003; jr $t0
+        }
+        let mut emu = PSXEmu::new(bios).unwrap();
+        let exe = exe::exe_tests::build_exe(0x8001_0000, 0x8001_0000, 0x801F_FF00, &[0; 4]);
+        emu.sideload_psx_exe(&exe).unwrap();
+
+        emu.run_cpu_cycle();
+        emu.run_cpu_cycle();
+        assert_eq!(emu.r3000.pc, 0x8003_0000);
+        assert_eq!(emu.read_gen_reg(29), 0);
+
+        emu.run_cpu_cycle();
+        assert_eq!(emu.r3000.pc, 0x8001_0004);
+        assert_eq!(emu.read_gen_reg(28), 0x8001_8000);
+        assert_eq!(emu.read_gen_reg(29), 0x801F_FF10);
+
+        // The hook only fires once
+        emu.r3000.pc = 0x8003_0000;
+        emu.run_cpu_cycle();
+        assert_eq!(emu.r3000.pc, 0x8003_0000 + 4);
+    }
+
+    #[test]
+    fn test_accepts_correct_size_bios() {
+        assert!(PSXEmu::new(vec![0; bios::BIOS_SIZE]).is_ok());
+    }
+
+    #[test]
+    fn test_runs_test_rom_without_bios() {
+        let mut emu = PSXEmu::without_bios();
+        // lui $t0, 0x1234 ; ori $t0, $t0, 0x5678
+        let rom = [0x3C08_1234u32, 0x3508_5678]
+            .iter()
+            .flat_map(|word| word.to_le_bytes().to_vec())
+            .collect();
+        emu.load_test_rom(rom, 0x1F00_0000, 0xBF00_0000);
+        assert_eq!(emu.r3000.pc, 0xBF00_0000);
+
+        emu.run_cpu_cycle();
+        assert_eq!(emu.read_gen_reg(8), 0x1234_0000);
+        assert_eq!(emu.r3000.pc, 0xBF00_0004);
+
+        emu.reset();
+        assert_eq!(emu.r3000.pc, 0xBF00_0000);
+    }
+
+    #[test]
+    fn test_bus_log_keeps_last_io_accesses() {
+        let mut emu = PSXEmu::without_bios();
+        let rom = [
+            0x3C08_1F80u32, // lui $t0, 0x1F80
+            0x3409_0055, // ori $t1, $zero, 0x55
+            0xAD09_1074, // sw $t1, 0x1074($t0) (I_MASK)
+            0xAC09_0000, // sw $t1, 0($zero)
+            0x8D0A_1074, // lw $t2, 0x1074($t0)
+            0x910B_1800, // lbu $t3, 0x1800($t0) (CDROM status)
+        ]
+        .iter()
+        .flat_map(|word| word.to_le_bytes().to_vec())
+        .collect();
+        emu.load_test_rom(rom, 0x1F00_0000, 0xBF00_0000);
+        assert!(emu.bus_log().is_empty());
+
+        emu.enable_bus_log(2);
+        for _ in 0..6 {
+            emu.run_cpu_cycle();
+        }
+
+        // The I_MASK write was pushed out, and the RAM store was never logged
+        let log = emu.bus_log().to_vec();
+        assert_eq!(log.len(), 2);
+        assert_eq!(
+            log[0],
+            BusAccess {
+                pc: 0xBF00_0010,
+                addr: 0x1F80_1074,
+                value: 0x55,
+                width: 4,
+                is_write: false,
+            }
+        );
+        assert_eq!((log[1].pc, log[1].addr, log[1].width, log[1].is_write), (0xBF00_0014, 0x1F80_1800, 1, false));
+
+        emu.enable_bus_log(0);
+        assert!(emu.bus_log().is_empty());
+    }
+
+    #[test]
+    fn test_checked_register_access() {
+        let mut emu = PSXEmu::new(vec![0; bios::BIOS_SIZE]).unwrap();
+        assert_eq!(emu.try_set_gen_reg(31, 0x8000_1234), Ok(()));
+        assert_eq!(emu.try_read_gen_reg(31), Some(0x8000_1234));
+
+        assert_eq!(emu.try_read_gen_reg(32), None);
+        assert_eq!(
+            emu.try_set_gen_reg(32, 1),
+            Err(RegError::OutOfRange { reg_num: 32 })
+        );
+    }
+
+    #[test]
+    fn test_reset_keeps_ram_and_power_on_clears_it() {
+        let mut emu = PSXEmu::new(vec![0; bios::BIOS_SIZE]).unwrap();
+        emu.write_memory(0x8000_1000, &[0xAB]);
+        emu.set_gen_reg(4, 0x1234);
+        emu.r3000.pc = 0x8000_1000;
+
+        emu.reset();
+        assert_eq!(emu.read_memory(0x8000_1000, 1), vec![0xAB]);
+        assert_eq!(emu.read_gen_reg(4), 0);
+        assert_eq!(emu.r3000.pc, 0xBFC0_0000);
+
+        emu.power_on();
+        assert_eq!(emu.read_memory(0x8000_1000, 1), vec![0]);
+        assert_eq!(emu.r3000.pc, 0xBFC0_0000);
+    }
+
+    #[test]
+    fn test_tick_reports_vblank() {
+        let mut emu = PSXEmu::without_bios();
+        let mut ticks = 0;
+        let result = loop {
+            let result = emu.tick();
+            ticks += 1;
+            if result.vblank {
+                break result;
+            }
+            assert_eq!(result, TickResult::default());
+        };
+        assert_eq!(result.interrupts, 1 << InterruptSource::VBLANK as u32);
+        assert_eq!(result.halted, None);
+        assert_eq!(emu.cycle_count(), ticks);
+
+        emu.add_sw_breakpoint(emu.r3000.pc);
+        assert_eq!(emu.tick().halted, Some(HaltReason::Breakpoint(emu.r3000.pc)));
+    }
+
+    #[test]
+    fn test_audio_sink_receives_samples() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        struct CountingSink(Arc<AtomicUsize>);
+        impl AudioSink for CountingSink {
+            fn push_samples(&mut self, samples: &[(i16, i16)]) {
+                self.0.fetch_add(samples.len(), Ordering::SeqCst);
+            }
+        }
+
+        let mut emu = PSXEmu::without_bios();
+        let pushed = Arc::new(AtomicUsize::new(0));
+        emu.set_audio_sink(Box::new(CountingSink(pushed.clone())));
+        let cycles = emu.run_frame_info().cycles;
+        // One sample every 768 cpu cycles
+        assert_eq!(pushed.load(Ordering::SeqCst), (cycles / 768) as usize);
+        assert!(emu.drain_audio().is_empty());
+
+        emu.clear_audio_sink();
+        emu.run_frame();
+        assert_eq!(pushed.load(Ordering::SeqCst), (cycles / 768) as usize);
+        assert!(!emu.drain_audio().is_empty());
+    }
+
+    #[test]
+    fn test_vblank_and_interrupt_callbacks() {
+        use std::sync::atomic::{AtomicU32, Ordering};
+        use std::sync::Arc;
+
+        let mut emu = PSXEmu::new(vec![0; bios::BIOS_SIZE]).unwrap();
+        let vblanks = Arc::new(AtomicU32::new(0));
+        let vblank_irqs = Arc::new(AtomicU32::new(0));
+        let counter = vblanks.clone();
+        emu.set_vblank_callback(Box::new(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+        }));
+        let counter = vblank_irqs.clone();
+        emu.set_interrupt_callback(Box::new(move |source| {
+            if let InterruptSource::VBLANK = source {
+                counter.fetch_add(1, Ordering::SeqCst);
+            }
+        }));
+
+        emu.run_frame();
+        emu.run_frame();
+        assert_eq!(vblanks.load(Ordering::SeqCst), 2);
+        assert_eq!(vblank_irqs.load(Ordering::SeqCst), 2);
+
+        emu.clear_vblank_callback();
+        emu.clear_interrupt_callback();
+        emu.run_frame();
+        assert_eq!(vblanks.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_tty_output_captures_putchar() {
+        let mut emu = PSXEmu::new(vec![0; bios::BIOS_SIZE]).unwrap();
+        let addiu = |rt: u32, imm: u32| (0x09 << 26) | (rt << 16) | imm;
+        let jalr_t2 = (10 << 21) | (31 << 11) | 0x9;
+        let putchar = |c: char| vec![addiu(4, c as u32), addiu(10, 0xB0), jalr_t2, addiu(9, 0x3D)];
+        let program: Vec<u32> = putchar('h').into_iter().chain(putchar('i')).collect();
+        let bytes: Vec<u8> = program.iter().flat_map(|word| word.to_le_bytes().to_vec()).collect();
+        emu.write_memory(0x8000_1000, &bytes);
+        // Stand in for the BIOS function with a bare return
+        emu.write_memory(0xB0, &((31 << 21) | 0x8u32).to_le_bytes());
+
+        emu.r3000.pc = 0x8000_1000;
+        for _ in 0..8 {
+            emu.run_cpu_cycle();
+        }
+        assert_eq!(emu.take_tty_output(), "hi");
+        assert_eq!(emu.r3000.pc, 0x8000_1020);
+        assert!(emu.take_tty_output().is_empty());
     }
 }
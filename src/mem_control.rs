@@ -0,0 +1,110 @@
+use log::warn;
+
+pub const MEM_CONTROL_START: u32 = 0x1F80_1000;
+pub const MEM_CONTROL_END: u32 = 0x1F80_1020;
+pub const RAM_SIZE: u32 = 0x1F80_1060;
+
+const EXP1_BASE: u32 = 0x1F80_1000;
+const EXP2_BASE: u32 = 0x1F80_1004;
+const DEFAULT_EXP1_BASE: u32 = 0x1F00_0000;
+
+/// Values the BIOS programs into 0x1F801000-0x1F801020 at boot: EXP1/EXP2 bases and the
+/// EXP1, EXP3, BIOS, SPU, CDROM, EXP2 and COM delay/size registers
+const DEFAULTS: [u32; 9] = [
+    DEFAULT_EXP1_BASE,
+    0x1F80_2000,
+    0x0013_243F,
+    0x0000_3022,
+    0x0013_243F,
+    0x2009_31E1,
+    0x0002_0843,
+    0x0007_0777,
+    0x0003_1125,
+];
+
+/// Memory control registers. Nothing is timed off them, they just have to read back what was written
+pub struct MemControl {
+    registers: [u32; 9],
+    ram_size: u32,
+}
+
+impl MemControl {
+    pub fn new() -> Self {
+        Self {
+            registers: DEFAULTS,
+            ram_size: 0x0000_0B88,
+        }
+    }
+
+    /// How much of the 8MiB RAM region is mapped, from RAM_SIZE bits 9-11. The 2MiB of RAM
+    /// repeats to fill it
+    pub fn ram_window(&self) -> u32 {
+        const MIB: u32 = 1024 * 1024;
+        match (self.ram_size >> 9) & 0x7 {
+            0 | 2 => MIB,
+            1 | 3 => 4 * MIB,
+            4 | 6 => 2 * MIB,
+            _ => 8 * MIB,
+        }
+    }
+
+    pub fn read_word(&self, addr: u32) -> u32 {
+        match addr {
+            RAM_SIZE => self.ram_size,
+            _ => self.registers[register_index(addr)],
+        }
+    }
+
+    pub fn write_word(&mut self, addr: u32, value: u32) {
+        match addr {
+            RAM_SIZE => self.ram_size = value,
+            // Only the low 24 bits of the expansion bases are writable
+            EXP1_BASE | EXP2_BASE => {
+                let value = 0x1F00_0000 | (value & 0x00FF_FFFF);
+                if addr == EXP1_BASE && value != DEFAULT_EXP1_BASE {
+                    warn!("Expansion 1 moved to {:#X}, it's still mapped at the default address", value);
+                }
+                self.registers[register_index(addr)] = value;
+            }
+            _ => self.registers[register_index(addr)] = value,
+        }
+    }
+}
+
+fn register_index(addr: u32) -> usize {
+    ((addr - MEM_CONTROL_START) / 4) as usize
+}
+
+#[cfg(test)]
+mod mem_control_tests {
+    use super::*;
+
+    #[test]
+    fn test_registers_read_back() {
+        let mut control = MemControl::new();
+        assert_eq!(control.read_word(0x1F80_1014), 0x2009_31E1);
+        assert_eq!(control.read_word(RAM_SIZE), 0x0000_0B88);
+
+        control.write_word(0x1F80_1018, 0x1234_5678);
+        control.write_word(RAM_SIZE, 0x0000_0888);
+        assert_eq!(control.read_word(0x1F80_1018), 0x1234_5678);
+        assert_eq!(control.read_word(RAM_SIZE), 0x0000_0888);
+    }
+
+    #[test]
+    fn test_ram_window() {
+        let mut control = MemControl::new();
+        assert_eq!(control.ram_window(), 8 * 1024 * 1024);
+        control.write_word(RAM_SIZE, 0x0000_0888);
+        assert_eq!(control.ram_window(), 2 * 1024 * 1024);
+    }
+
+    #[test]
+    fn test_nonstandard_expansion_base() {
+        let mut control = MemControl::new();
+        control.write_word(EXP1_BASE, 0xFF10_0000);
+        assert_eq!(control.read_word(EXP1_BASE), 0x1F10_0000);
+        control.write_word(EXP2_BASE, 0x0080_2000);
+        assert_eq!(control.read_word(EXP2_BASE), 0x1F80_2000);
+    }
+}
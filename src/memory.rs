@@ -1,5 +1,9 @@
 use byteorder::{ByteOrder, LittleEndian};
 
+pub const RAM_BYTES: usize = 2 * 1024 * 1024;
+/// RAM is mirrored through the rest of its window, so addresses wrap at 2MiB
+pub const RAM_MASK: u32 = RAM_BYTES as u32 - 1;
+
 pub struct Memory {
     pub data: Vec<u8>,
 }
@@ -8,7 +12,7 @@ impl Memory {
     /// Initializes 2MiB of system memory
     pub fn new() -> Memory {
         Memory {
-            data: vec![0; 2_100_000],
+            data: vec![0; RAM_BYTES],
         }
     }
 
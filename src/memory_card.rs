@@ -0,0 +1,276 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, Error, ErrorKind, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use log::{error, warn};
+
+pub const MEMORY_CARD_SIZE: usize = 128 * 1024;
+const FRAME_SIZE: usize = 128;
+const NUM_FRAMES: u16 = (MEMORY_CARD_SIZE / FRAME_SIZE) as u16;
+
+const READ_COMMAND: u8 = 0x52;
+const GET_ID_COMMAND: u8 = 0x53;
+const WRITE_COMMAND: u8 = 0x57;
+
+const END_GOOD: u8 = 0x47;
+const END_BAD_CHECKSUM: u8 = 0x4E;
+const END_BAD_SECTOR: u8 = 0xFF;
+
+/// Bit 3 of the flag byte stays set until the first successful write
+const FLAG_NEW_CARD: u8 = 0x08;
+
+/// A 128KiB memory card. Written frames are saved straight back to the backing file
+pub struct MemoryCard {
+    data: Vec<u8>,
+    path: Option<PathBuf>,
+    flag: u8,
+
+    command: u8,
+    sector: u16,
+    checksum: u8,
+    previous: u8,
+    frame: [u8; FRAME_SIZE],
+}
+
+impl MemoryCard {
+    /// A card that only lives in memory
+    pub fn new() -> Self {
+        Self {
+            data: vec![0; MEMORY_CARD_SIZE],
+            path: None,
+            flag: FLAG_NEW_CARD,
+
+            command: 0,
+            sector: 0,
+            checksum: 0,
+            previous: 0,
+            frame: [0; FRAME_SIZE],
+        }
+    }
+
+    /// Opens a card image, creating a blank one if the file doesn't exist yet
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let mut card = Self::new();
+        if path.exists() {
+            let data = fs::read(path)?;
+            if data.len() != MEMORY_CARD_SIZE {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "Memory card images must be {} bytes, got {}",
+                        MEMORY_CARD_SIZE,
+                        data.len()
+                    ),
+                ));
+            }
+            card.data = data;
+        } else {
+            fs::write(path, &card.data)?;
+        }
+        card.path = Some(path.to_path_buf());
+        Ok(card)
+    }
+
+    /// Handles one byte of a transfer after the 0x81 address byte. Returns the response and
+    /// whether the card expects another byte, which is when it acks
+    pub(super) fn transfer(&mut self, step: usize, val: u8) -> (u8, bool) {
+        if step == 0 {
+            self.command = val;
+            return match val {
+                READ_COMMAND | GET_ID_COMMAND | WRITE_COMMAND => (self.flag, true),
+                _ => {
+                    warn!("MEMCARD: Unknown command {:#X}", val);
+                    (0xFF, false)
+                }
+            };
+        }
+
+        match (self.command, step) {
+            (_, 1) => (0x5A, true),
+            (_, 2) => (0x5D, true),
+            (READ_COMMAND, _) => self.read_step(step, val),
+            (WRITE_COMMAND, _) => self.write_step(step, val),
+            (GET_ID_COMMAND, _) => self.get_id_step(step),
+            _ => (0xFF, false),
+        }
+    }
+
+    fn read_step(&mut self, step: usize, val: u8) -> (u8, bool) {
+        match step {
+            3 => {
+                self.sector = (val as u16) << 8;
+                (0x00, true)
+            }
+            4 => {
+                self.sector |= val as u16;
+                ((self.sector >> 8) as u8, true)
+            }
+            5 => (0x5C, true),
+            6 => (0x5D, true),
+            7 if self.sector >= NUM_FRAMES => (END_BAD_SECTOR, false),
+            7 => {
+                let msb = (self.sector >> 8) as u8;
+                self.checksum = msb;
+                (msb, true)
+            }
+            8 => {
+                let lsb = self.sector as u8;
+                self.checksum ^= lsb;
+                (lsb, true)
+            }
+            9..=136 => {
+                let byte = self.data[self.sector as usize * FRAME_SIZE + step - 9];
+                self.checksum ^= byte;
+                (byte, true)
+            }
+            137 => (self.checksum, true),
+            _ => (END_GOOD, false),
+        }
+    }
+
+    fn write_step(&mut self, step: usize, val: u8) -> (u8, bool) {
+        // The card echoes back whatever it received on the previous byte
+        let previous = self.previous;
+        self.previous = val;
+
+        match step {
+            3 => {
+                self.sector = (val as u16) << 8;
+                self.checksum = val;
+                (0x00, true)
+            }
+            4 => {
+                self.sector |= val as u16;
+                self.checksum ^= val;
+                (previous, true)
+            }
+            5..=132 => {
+                self.frame[step - 5] = val;
+                self.checksum ^= val;
+                (previous, true)
+            }
+            133 => {
+                self.checksum ^= val;
+                (previous, true)
+            }
+            134 => (0x5C, true),
+            135 => (0x5D, true),
+            _ => {
+                let end = if self.sector >= NUM_FRAMES {
+                    END_BAD_SECTOR
+                } else if self.checksum != 0 {
+                    END_BAD_CHECKSUM
+                } else {
+                    self.commit_frame();
+                    END_GOOD
+                };
+                (end, false)
+            }
+        }
+    }
+
+    fn get_id_step(&mut self, step: usize) -> (u8, bool) {
+        match step {
+            3 => (0x5C, true),
+            4 => (0x5D, true),
+            5 => (0x04, true),
+            6 => (0x00, true),
+            7 => (0x00, true),
+            _ => (0x80, false),
+        }
+    }
+
+    fn commit_frame(&mut self) {
+        let offset = self.sector as usize * FRAME_SIZE;
+        self.data[offset..offset + FRAME_SIZE].copy_from_slice(&self.frame);
+        self.flag &= !FLAG_NEW_CARD;
+
+        if let Some(path) = &self.path {
+            if let Err(e) = save_frame(path, offset, &self.frame) {
+                error!("MEMCARD: Failed to save frame {:#X}: {}", self.sector, e);
+            }
+        }
+    }
+}
+
+fn save_frame(path: &Path, offset: usize, frame: &[u8]) -> io::Result<()> {
+    let mut file = OpenOptions::new().write(true).open(path)?;
+    file.seek(SeekFrom::Start(offset as u64))?;
+    file.write_all(frame)
+}
+
+#[cfg(test)]
+mod memory_card_tests {
+    use super::*;
+
+    fn transfer(card: &mut MemoryCard, bytes: &[u8]) -> Vec<u8> {
+        let mut responses = Vec::new();
+        for (step, val) in bytes.iter().enumerate() {
+            let (response, more) = card.transfer(step, *val);
+            responses.push(response);
+            assert_eq!(more, step + 1 < bytes.len(), "Unexpected end at step {}", step);
+        }
+        responses
+    }
+
+    fn write_frame(card: &mut MemoryCard, sector: u16, frame: &[u8]) -> u8 {
+        let (msb, lsb) = ((sector >> 8) as u8, sector as u8);
+        let checksum = frame.iter().fold(msb ^ lsb, |acc, b| acc ^ b);
+        let mut bytes = vec![WRITE_COMMAND, 0, 0, msb, lsb];
+        bytes.extend_from_slice(frame);
+        bytes.extend_from_slice(&[checksum, 0, 0, 0]);
+        let responses = transfer(card, &bytes);
+        assert_eq!(&responses[1..3], &[0x5A, 0x5D]);
+        assert_eq!(&responses[134..136], &[0x5C, 0x5D]);
+        *responses.last().unwrap()
+    }
+
+    #[test]
+    fn test_write_then_read_frame() {
+        let path = std::env::temp_dir().join(format!("psx_emu_memory_card_test_{}.mcd", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let mut card = MemoryCard::load(&path).unwrap();
+
+        let frame: Vec<u8> = (0..FRAME_SIZE as u8).collect();
+        assert_eq!(card.flag, FLAG_NEW_CARD);
+        assert_eq!(write_frame(&mut card, 0x123, &frame), END_GOOD);
+
+        let mut bytes = vec![READ_COMMAND, 0, 0, 0x01, 0x23];
+        bytes.resize(bytes.len() + 2 + 2 + FRAME_SIZE + 2, 0);
+        let responses = transfer(&mut card, &bytes);
+        assert_eq!(responses[0], 0);
+        assert_eq!(&responses[5..9], &[0x5C, 0x5D, 0x01, 0x23]);
+        assert_eq!(&responses[9..137], &frame[..]);
+        let checksum = frame.iter().fold(0x01 ^ 0x23, |acc, b| acc ^ b);
+        assert_eq!(responses[137], checksum);
+        assert_eq!(responses[138], END_GOOD);
+
+        // The frame made it to disk as well
+        let reloaded = MemoryCard::load(&path).unwrap();
+        assert_eq!(&reloaded.data[0x123 * FRAME_SIZE..0x124 * FRAME_SIZE], &frame[..]);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_bad_writes_are_rejected() {
+        let mut card = MemoryCard::new();
+        let frame = [0xAA; FRAME_SIZE];
+
+        let mut bytes = vec![WRITE_COMMAND, 0, 0, 0, 1];
+        bytes.extend_from_slice(&frame);
+        bytes.extend_from_slice(&[0x55, 0, 0, 0]);
+        assert_eq!(*transfer(&mut card, &bytes).last().unwrap(), END_BAD_CHECKSUM);
+        assert_eq!(write_frame(&mut card, 0x400, &frame), END_BAD_SECTOR);
+        assert!(card.data.iter().all(|b| *b == 0));
+        assert_eq!(card.flag, FLAG_NEW_CARD);
+    }
+
+    #[test]
+    fn test_get_id() {
+        let mut card = MemoryCard::new();
+        assert_eq!(
+            transfer(&mut card, &[GET_ID_COMMAND, 0, 0, 0, 0, 0, 0, 0, 0]),
+            vec![FLAG_NEW_CARD, 0x5A, 0x5D, 0x5C, 0x5D, 0x04, 0x00, 0x00, 0x80]
+        );
+    }
+}
@@ -0,0 +1,173 @@
+//! Paces frames to the speed of a real console for front ends that don't have their own timing
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::FrameInfo;
+
+/// Cpu cycles per second
+const CPU_CLOCK: u64 = 33_868_800;
+
+/// Where frame pacing reads the time from and how it waits, so tests can swap in a fake clock
+pub trait Clock: Send {
+    /// Time since some fixed point in the past
+    fn now(&self) -> Duration;
+    fn sleep(&mut self, duration: Duration);
+}
+
+/// The host's monotonic clock
+pub struct SystemClock {
+    start: Instant,
+}
+
+impl SystemClock {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    fn sleep(&mut self, duration: Duration) {
+        thread::sleep(duration);
+    }
+}
+
+/// A frame run by run_realtime_frame, and how it lined up with real time
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct RealtimeFrame {
+    pub info: FrameInfo,
+    /// Wall clock time between the end of the last frame and the end of this one
+    pub duration: Duration,
+    /// How far the frame ran past the time it takes on a console. Nothing is slept when it's behind
+    pub overrun: Duration,
+}
+
+/// Real time an emulated frame of the given number of cpu cycles takes on a console
+fn frame_duration(cycles: u64) -> Duration {
+    Duration::from_nanos(cycles * 1_000_000_000 / CPU_CLOCK)
+}
+
+/// Splits the difference between how long a frame should take and how long it did into
+/// (time left to sleep, overrun). At most one of them is non zero
+fn pacing(target: Duration, elapsed: Duration) -> (Duration, Duration) {
+    (target.saturating_sub(elapsed), elapsed.saturating_sub(target))
+}
+
+/// Each frame is timed from the end of the last one, so time the front end spends between
+/// frames counts too. A slow frame isn't made up for later, the next one just starts late
+pub(crate) struct Pacer {
+    clock: Box<dyn Clock>,
+    last_frame_end: Option<Duration>,
+}
+
+impl Pacer {
+    pub fn new(clock: Box<dyn Clock>) -> Self {
+        Self {
+            clock,
+            last_frame_end: None,
+        }
+    }
+
+    /// When the frame about to run is timed from
+    pub fn start_frame(&self) -> Duration {
+        self.last_frame_end.unwrap_or_else(|| self.clock.now())
+    }
+
+    /// Sleeps out whatever is left of a frame that started at start
+    pub fn finish_frame(&mut self, start: Duration, info: FrameInfo) -> RealtimeFrame {
+        let elapsed = self.clock.now().saturating_sub(start);
+        let (sleep, overrun) = pacing(frame_duration(info.cycles), elapsed);
+        if sleep > Duration::ZERO {
+            self.clock.sleep(sleep);
+        }
+        let end = self.clock.now();
+        self.last_frame_end = Some(end);
+        RealtimeFrame {
+            info,
+            duration: end.saturating_sub(start),
+            overrun,
+        }
+    }
+}
+
+#[cfg(test)]
+mod realtime_tests {
+    use super::*;
+    use crate::gpu::Field;
+    use crate::FrameResult;
+    use std::sync::{Arc, Mutex};
+
+    /// Only moves when a test advances it or something sleeps
+    #[derive(Clone)]
+    struct MockClock {
+        now: Arc<Mutex<Duration>>,
+        sleeps: Arc<Mutex<Vec<Duration>>>,
+    }
+
+    impl MockClock {
+        fn advance(&self, duration: Duration) {
+            *self.now.lock().unwrap() += duration;
+        }
+    }
+
+    impl Clock for MockClock {
+        fn now(&self) -> Duration {
+            *self.now.lock().unwrap()
+        }
+
+        fn sleep(&mut self, duration: Duration) {
+            self.sleeps.lock().unwrap().push(duration);
+            self.advance(duration);
+        }
+    }
+
+    fn ntsc_frame() -> FrameInfo {
+        FrameInfo {
+            result: FrameResult::Completed,
+            // Exactly 1/60th of a second
+            cycles: CPU_CLOCK / 60,
+            scanlines: 263,
+            is_pal: false,
+            field: Field::Even,
+        }
+    }
+
+    #[test]
+    fn test_paces_frames_to_60hz() {
+        let clock = MockClock {
+            now: Arc::new(Mutex::new(Duration::from_secs(1))),
+            sleeps: Arc::new(Mutex::new(Vec::new())),
+        };
+        let mut pacer = Pacer::new(Box::new(clock.clone()));
+        let frame_time = Duration::from_nanos(16_666_666);
+
+        // Emulation faster than real time sleeps out the rest of the frame
+        let start = pacer.start_frame();
+        clock.advance(Duration::from_millis(10));
+        let frame = pacer.finish_frame(start, ntsc_frame());
+        assert_eq!(frame.duration, frame_time);
+        assert_eq!(frame.overrun, Duration::ZERO);
+        assert_eq!(*clock.sleeps.lock().unwrap(), vec![frame_time - Duration::from_millis(10)]);
+
+        // The next frame is timed from the end of the last, and a slow one doesn't sleep
+        let start = pacer.start_frame();
+        assert_eq!(start, Duration::from_secs(1) + frame_time);
+        clock.advance(Duration::from_millis(20));
+        let frame = pacer.finish_frame(start, ntsc_frame());
+        assert_eq!(frame.duration, Duration::from_millis(20));
+        assert_eq!(frame.overrun, Duration::from_millis(20) - frame_time);
+        assert_eq!(clock.sleeps.lock().unwrap().len(), 1);
+    }
+}
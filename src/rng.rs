@@ -0,0 +1,35 @@
+//! SplitMix64, for filling memory with a reproducible pattern from a seed
+
+pub(crate) struct SplitMix64 {
+    state: u64,
+}
+
+impl SplitMix64 {
+    pub fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    pub fn fill_bytes(&mut self, bytes: &mut [u8]) {
+        for chunk in bytes.chunks_mut(8) {
+            let value = self.next_u64().to_le_bytes();
+            chunk.copy_from_slice(&value[..chunk.len()]);
+        }
+    }
+
+    pub fn fill_halfwords(&mut self, halfwords: &mut [u16]) {
+        for chunk in halfwords.chunks_mut(4) {
+            let value = self.next_u64();
+            for (i, halfword) in chunk.iter_mut().enumerate() {
+                *halfword = (value >> (i * 16)) as u16;
+            }
+        }
+    }
+}
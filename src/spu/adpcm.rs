@@ -0,0 +1,81 @@
+pub const BLOCK_SIZE: usize = 16;
+pub const SAMPLES_PER_BLOCK: usize = 28;
+
+const POS_COEFFICIENTS: [i32; 5] = [0, 60, 115, 98, 122];
+const NEG_COEFFICIENTS: [i32; 5] = [0, 0, -52, -55, -60];
+
+/// Loop flags stored in the second byte of every block
+pub const FLAG_LOOP_END: u8 = 0x1;
+pub const FLAG_LOOP_REPEAT: u8 = 0x2;
+pub const FLAG_LOOP_START: u8 = 0x4;
+
+/// Decodes one 16 byte SPU-ADPCM block into 28 samples. `history` holds the last two
+/// decoded samples (newest first) and is updated for the next block
+pub fn decode_block(block: &[u8], history: &mut [i16; 2]) -> [i16; SAMPLES_PER_BLOCK] {
+    let mut shift = block[0] & 0xF;
+    if shift > 12 {
+        shift = 9;
+    }
+    let filter = ((block[0] >> 4) & 0x7).min(4) as usize;
+
+    let mut samples = [0; SAMPLES_PER_BLOCK];
+    for (i, sample) in samples.iter_mut().enumerate() {
+        let byte = block[2 + i / 2];
+        let nibble = if i & 1 == 0 { byte & 0xF } else { byte >> 4 };
+
+        // Sign extend the nibble into the top of a halfword, then scale it down
+        let raw = (((nibble as u16) << 12) as i16 >> shift) as i32;
+        let prediction = (history[0] as i32 * POS_COEFFICIENTS[filter]
+            + history[1] as i32 * NEG_COEFFICIENTS[filter]
+            + 32)
+            / 64;
+        let decoded = (raw + prediction).clamp(i16::MIN as i32, i16::MAX as i32) as i16;
+
+        history[1] = history[0];
+        history[0] = decoded;
+        *sample = decoded;
+    }
+    samples
+}
+
+#[cfg(test)]
+mod adpcm_tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_unfiltered() {
+        let mut block = [0; BLOCK_SIZE];
+        block[2] = 0x21;
+        block[3] = 0x8F;
+        let mut history = [0; 2];
+        let samples = decode_block(&block, &mut history);
+        assert_eq!(&samples[..4], &[0x1000, 0x2000, -0x1000, -0x8000]);
+        assert_eq!(samples[4], 0);
+    }
+
+    #[test]
+    fn test_decode_filtered() {
+        // Shift 12, filter 1
+        let mut block = [0; BLOCK_SIZE];
+        block[0] = 0x1C;
+        block[2] = 0x77;
+        let mut history = [0; 2];
+        let samples = decode_block(&block, &mut history);
+        assert_eq!(&samples[..4], &[7, 14, 13, 12]);
+        assert_eq!(history[0], samples[27]);
+        assert_eq!(history[1], samples[26]);
+    }
+
+    #[test]
+    fn test_decode_uses_history() {
+        // Shift 12, filter 2 with no new data relies only on the previous block
+        let mut block = [0; BLOCK_SIZE];
+        block[0] = 0x2C;
+        let mut history = [1000, 500];
+        let samples = decode_block(&block, &mut history);
+        // (1000 * 115 - 500 * 52 + 32) / 64
+        assert_eq!(samples[0], 1391);
+        // (1391 * 115 - 1000 * 52 + 32) / 64
+        assert_eq!(samples[1], 1687);
+    }
+}
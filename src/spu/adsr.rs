@@ -0,0 +1,163 @@
+use bit_field::BitField;
+
+const MAX_LEVEL: i32 = 0x7FFF;
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum AdsrPhase {
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+    Off,
+}
+
+/// One envelope phase described by a 7 bit rate
+#[derive(Debug, Copy, Clone)]
+struct Phase {
+    exponential: bool,
+    decreasing: bool,
+    rate: u8,
+}
+
+/// Volume envelope for a voice, driven by the two ADSR registers
+pub struct Adsr {
+    pub phase: AdsrPhase,
+    pub level: i16,
+    config: u32,
+    counter: u32,
+}
+
+impl Adsr {
+    pub fn new() -> Self {
+        Self {
+            phase: AdsrPhase::Off,
+            level: 0,
+            config: 0,
+            counter: 0,
+        }
+    }
+
+    pub fn config(&self) -> u32 {
+        self.config
+    }
+
+    pub fn set_config(&mut self, config: u32) {
+        self.config = config;
+    }
+
+    pub fn key_on(&mut self) {
+        self.phase = AdsrPhase::Attack;
+        self.level = 0;
+        self.counter = 0;
+    }
+
+    pub fn key_off(&mut self) {
+        if self.phase != AdsrPhase::Off {
+            self.phase = AdsrPhase::Release;
+            self.counter = 0;
+        }
+    }
+
+    fn sustain_level(&self) -> i32 {
+        (((self.config & 0xF) as i32 + 1) * 0x800).min(MAX_LEVEL)
+    }
+
+    fn current_phase(&self) -> Option<Phase> {
+        let config = self.config;
+        match self.phase {
+            AdsrPhase::Attack => Some(Phase {
+                exponential: config.get_bit(15),
+                decreasing: false,
+                rate: config.get_bits(8..15) as u8,
+            }),
+            AdsrPhase::Decay => Some(Phase {
+                exponential: true,
+                decreasing: true,
+                rate: (config.get_bits(4..8) as u8) << 2,
+            }),
+            AdsrPhase::Sustain => Some(Phase {
+                exponential: config.get_bit(31),
+                decreasing: config.get_bit(30),
+                rate: config.get_bits(22..29) as u8,
+            }),
+            AdsrPhase::Release => Some(Phase {
+                exponential: config.get_bit(21),
+                decreasing: true,
+                rate: (config.get_bits(16..21) as u8) << 2,
+            }),
+            AdsrPhase::Off => None,
+        }
+    }
+
+    /// Advances the envelope by one sample
+    pub fn tick(&mut self) {
+        let phase = match self.current_phase() {
+            Some(phase) => phase,
+            None => return,
+        };
+
+        let shift = (phase.rate >> 2) as i32;
+        let raw_step = (phase.rate & 3) as i32;
+        let mut step = if phase.decreasing { -8 + raw_step } else { 7 - raw_step };
+        let mut cycles = 1 << (shift - 11).max(0);
+        step <<= (11 - shift).max(0);
+
+        let level = self.level as i32;
+        if phase.exponential {
+            if !phase.decreasing && level > 0x6000 {
+                cycles *= 4;
+            }
+            if phase.decreasing {
+                step = (step * level) >> 15;
+            }
+        }
+
+        self.counter += 1;
+        if self.counter >= cycles {
+            self.counter = 0;
+            self.level = (level + step).clamp(0, MAX_LEVEL) as i16;
+        }
+
+        match self.phase {
+            AdsrPhase::Attack if self.level as i32 >= MAX_LEVEL => {
+                self.phase = AdsrPhase::Decay;
+            }
+            AdsrPhase::Decay if (self.level as i32) <= self.sustain_level() => {
+                self.phase = AdsrPhase::Sustain;
+            }
+            AdsrPhase::Release if self.level == 0 => {
+                self.phase = AdsrPhase::Off;
+            }
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod adsr_tests {
+    use super::*;
+
+    #[test]
+    fn test_envelope_phases() {
+        let mut adsr = Adsr::new();
+        // Fastest attack, decay to a sustain level of 0x4000 that then holds, fastest release
+        adsr.set_config(0x5FC0_0007);
+        adsr.key_on();
+        adsr.tick();
+        assert_eq!(adsr.phase, AdsrPhase::Attack);
+        assert!(adsr.level > 0);
+
+        for _ in 0..100 {
+            adsr.tick();
+        }
+        assert_eq!(adsr.phase, AdsrPhase::Sustain);
+        assert!(adsr.level <= 0x4000);
+
+        adsr.key_off();
+        for _ in 0..10000 {
+            adsr.tick();
+        }
+        assert_eq!(adsr.phase, AdsrPhase::Off);
+        assert_eq!(adsr.level, 0);
+    }
+}
@@ -0,0 +1,529 @@
+use std::collections::VecDeque;
+
+use bit_field::BitField;
+use log::warn;
+use reverb::Reverb;
+use voice::{fixed_volume, Voice};
+
+mod adpcm;
+mod adsr;
+mod reverb;
+mod voice;
+
+const NUM_VOICES: usize = 24;
+const RAM_SIZE: usize = 512 * 1024;
+
+/// The SPU outputs a sample every 768 cpu cycles, which works out to 44100Hz
+const CYCLES_PER_SAMPLE: u32 = 768;
+
+/// The data transfer fifo holds 32 half words before it has to be flushed into SPU RAM
+const TRANSFER_FIFO_SIZE: usize = 32;
+
+/// Roughly 1/5th of a second of audio. Old samples are dropped if nobody drains the buffer
+const SAMPLE_BUFFER_SIZE: usize = 8192;
+
+pub struct Spu {
+    main_volume_left: u16,
+    main_volume_right: u16,
+    reverb_volume: u32,
+    reverb: Reverb,
+    /// EON, voices that feed the reverb
+    reverb_voices: u32,
+    spu_control: u16,
+    spu_status: u16,
+    transfer_control: u16,
+    /// Address set through 0x1F801DA6, in 8 byte units
+    transfer_start: u16,
+    /// Byte address the next transferred half word goes to
+    transfer_address: usize,
+    transfer_fifo: VecDeque<u16>,
+
+    voices: Vec<Voice>,
+    ram: Vec<u8>,
+    key_on: u32,
+    key_off: u32,
+    /// ENDX, voices that have reached a loop end since their last key on
+    voice_ended: u32,
+
+    /// CD audio input, already at 44100Hz
+    cd_volume_left: u16,
+    cd_volume_right: u16,
+    cd_input: VecDeque<(i16, i16)>,
+
+    cycle_counter: u32,
+    samples: VecDeque<(i16, i16)>,
+}
+
+impl Spu {
+    pub fn new() -> Self {
+        Self {
+            main_volume_left: 0,
+            main_volume_right: 0,
+            reverb_volume: 0,
+            reverb: Reverb::new(),
+            reverb_voices: 0,
+            spu_control: 0x8000, //Start with spu enabled
+            spu_status: 0,
+            transfer_control: 0x4,
+            transfer_start: 0,
+            transfer_address: 0,
+            transfer_fifo: VecDeque::with_capacity(TRANSFER_FIFO_SIZE),
+
+            voices: (0..NUM_VOICES).map(|_| Voice::new()).collect(),
+            ram: vec![0; RAM_SIZE],
+            key_on: 0,
+            key_off: 0,
+            voice_ended: 0,
+
+            cd_volume_left: 0,
+            cd_volume_right: 0,
+            cd_input: VecDeque::with_capacity(SAMPLE_BUFFER_SIZE),
+
+            cycle_counter: 0,
+            samples: VecDeque::with_capacity(SAMPLE_BUFFER_SIZE),
+        }
+    }
+
+    pub fn read_half_word(&mut self, addr: u32) -> u16 {
+        match addr {
+            0x1F801C00..=0x1F801D7F => self.read_voice_register(addr),
+            0x1F801D80 => self.main_volume_left,
+            0x1F801D82 => self.main_volume_right,
+            0x1F801D84 => self.reverb_volume as u16,
+            0x1F801D86 => (self.reverb_volume >> 16) as u16,
+            0x1F801D88 => self.key_on as u16,
+            0x1F801D8A => (self.key_on >> 16) as u16,
+            0x1F801D8C => self.key_off as u16,
+            0x1F801D8E => (self.key_off >> 16) as u16,
+            0x1F801D9C => self.voice_ended as u16,
+            0x1F801D9E => (self.voice_ended >> 16) as u16,
+            0x1F801D98 => self.reverb_voices as u16,
+            0x1F801D9A => (self.reverb_voices >> 16) as u16,
+            0x1F801DA2 => self.reverb.base(),
+            0x1F801DC0..=0x1F801DFF => self.reverb.registers[((addr - 0x1F801DC0) / 2) as usize],
+            0x1F801DAE => self.spu_status,
+            0x1F801DAA => self.spu_control,
+            0x1F801DA6 => self.transfer_start,
+            0x1F801DAC => self.transfer_control,
+            0x1F801DB0 => self.cd_volume_left,
+            0x1F801DB2 => self.cd_volume_right,
+            _ => 0, //{println!("Read unknown SPU address {:#X}", addr); 0}
+        }
+    }
+
+    pub fn write_half_word(&mut self, addr: u32, value: u16) {
+        match addr {
+            0x1F801C00..=0x1F801D7F => self.write_voice_register(addr, value),
+            0x1F801D80 => self.main_volume_left = value,
+            0x1F801D82 => self.main_volume_right = value,
+            0x1F801D84 => self.reverb_volume = (value as u32) | (self.reverb_volume & 0xFFFF0000),
+            0x1F801D86 => {
+                self.reverb_volume = ((value as u32) << 16) | (self.reverb_volume & 0xFFFF)
+            }
+            0x1F801D88 => self.write_key_on(value as u32),
+            0x1F801D8A => self.write_key_on((value as u32) << 16),
+            0x1F801D8C => self.write_key_off(value as u32),
+            0x1F801D8E => self.write_key_off((value as u32) << 16),
+            0x1F801D98 => self.reverb_voices = (value as u32) | (self.reverb_voices & 0xFF0000),
+            0x1F801D9A => {
+                self.reverb_voices = (((value as u32) & 0xFF) << 16) | (self.reverb_voices & 0xFFFF)
+            }
+            0x1F801DA2 => self.reverb.set_base(value),
+            0x1F801DC0..=0x1F801DFF => self.reverb.registers[((addr - 0x1F801DC0) / 2) as usize] = value,
+            0x1F801DA6 => {
+                self.transfer_start = value;
+                self.transfer_address = value as usize * 8;
+            }
+            0x1F801DA8 => self.push_transfer_fifo(value),
+            0x1F801DAA => self.write_control(value),
+            0x1F801DAC => self.transfer_control = value,
+            0x1F801DB0 => self.cd_volume_left = value,
+            0x1F801DB2 => self.cd_volume_right = value,
+            _ => (), //println!("Wrote unknown SPU address {:#X} with {:#X}", addr, value)
+        }
+    }
+
+    fn read_voice_register(&self, addr: u32) -> u16 {
+        let voice = &self.voices[((addr - 0x1F801C00) / 0x10) as usize];
+        match addr & 0xF {
+            0x0 => voice.volume_left,
+            0x2 => voice.volume_right,
+            0x4 => voice.pitch,
+            0x6 => voice.start_address,
+            0x8 => voice.adsr.config() as u16,
+            0xA => (voice.adsr.config() >> 16) as u16,
+            0xC => voice.adsr.level as u16,
+            0xE => voice.repeat_address,
+            _ => 0,
+        }
+    }
+
+    fn write_voice_register(&mut self, addr: u32, value: u16) {
+        let voice = &mut self.voices[((addr - 0x1F801C00) / 0x10) as usize];
+        match addr & 0xF {
+            0x0 => voice.volume_left = value,
+            0x2 => voice.volume_right = value,
+            0x4 => voice.pitch = value,
+            0x6 => voice.start_address = value,
+            0x8 => voice.adsr.set_config((voice.adsr.config() & 0xFFFF_0000) | value as u32),
+            0xA => voice
+                .adsr
+                .set_config((voice.adsr.config() & 0xFFFF) | ((value as u32) << 16)),
+            0xC => voice.adsr.level = value as i16,
+            0xE => voice.repeat_address = value,
+            _ => (),
+        }
+    }
+
+    fn write_control(&mut self, value: u16) {
+        self.spu_control = value;
+        // The mode bits are mirrored into the status register. Transfers complete instantly,
+        // so the busy flag (bit 10) is never set
+        let mut status = value & 0x3F;
+        let mode = value.get_bits(4..6);
+        status.set_bit(7, mode.get_bit(1));
+        status.set_bit(8, mode == 2);
+        status.set_bit(9, mode == 3);
+        self.spu_status = status;
+
+        if mode == 1 {
+            self.flush_transfer_fifo();
+        }
+    }
+
+    fn transfer_mode(&self) -> u16 {
+        self.spu_control.get_bits(4..6)
+    }
+
+    /// Manual writes are buffered until the transfer mode is set to manual write
+    fn push_transfer_fifo(&mut self, value: u16) {
+        if self.transfer_fifo.len() >= TRANSFER_FIFO_SIZE {
+            warn!("SPU transfer fifo overflowed, dropping {:#X}", value);
+            return;
+        }
+        self.transfer_fifo.push_back(value);
+        if self.transfer_mode() == 1 {
+            self.flush_transfer_fifo();
+        }
+    }
+
+    fn flush_transfer_fifo(&mut self) {
+        while let Some(value) = self.transfer_fifo.pop_front() {
+            self.write_ram_half_word(value);
+        }
+    }
+
+    fn write_ram_half_word(&mut self, value: u16) {
+        let addr = self.transfer_address;
+        self.ram[addr] = value as u8;
+        self.ram[addr + 1] = (value >> 8) as u8;
+        self.transfer_address = (addr + 2) & (RAM_SIZE - 1);
+    }
+
+    fn read_ram_half_word(&mut self) -> u16 {
+        let addr = self.transfer_address;
+        self.transfer_address = (addr + 2) & (RAM_SIZE - 1);
+        self.ram[addr] as u16 | (self.ram[addr + 1] as u16) << 8
+    }
+
+    /// Writes one word from a DMA4 transfer into SPU RAM at the current transfer address
+    pub fn dma_write_word(&mut self, word: u32) {
+        self.write_ram_half_word(word as u16);
+        self.write_ram_half_word((word >> 16) as u16);
+    }
+
+    /// Reads one word of SPU RAM for a DMA4 transfer from the current transfer address
+    pub fn dma_read_word(&mut self) -> u32 {
+        let low = self.read_ram_half_word() as u32;
+        let high = self.read_ram_half_word() as u32;
+        low | high << 16
+    }
+
+    fn write_key_on(&mut self, voices: u32) {
+        self.key_on |= voices;
+        for i in 0..NUM_VOICES {
+            if voices.get_bit(i) {
+                self.voices[i].key_on(&self.ram);
+                self.voice_ended.set_bit(i, false);
+            }
+        }
+    }
+
+    fn write_key_off(&mut self, voices: u32) {
+        self.key_off |= voices;
+        for i in 0..NUM_VOICES {
+            if voices.get_bit(i) {
+                self.voices[i].key_off();
+            }
+        }
+    }
+
+    /// Queues decoded CD audio to be mixed in. Like the output buffer, old samples are dropped
+    /// once it fills up
+    pub fn push_cd_audio(&mut self, samples: &[(i16, i16)]) {
+        for sample in samples {
+            if self.cd_input.len() >= SAMPLE_BUFFER_SIZE {
+                self.cd_input.pop_front();
+            }
+            self.cd_input.push_back(*sample);
+        }
+    }
+
+    pub fn execute_cycle(&mut self) {
+        self.cycle_counter += 1;
+        if self.cycle_counter >= CYCLES_PER_SAMPLE {
+            self.cycle_counter = 0;
+            self.generate_sample();
+        }
+    }
+
+    /// Mixes every voice down to one stereo sample and pushes it onto the output buffer.
+    /// Voices in EON, and the CD if control bit 2 is set, also feed the reverb, whose output
+    /// joins the mix before the main volume
+    fn generate_sample(&mut self) {
+        let mut left = 0;
+        let mut right = 0;
+        let mut reverb_left = 0;
+        let mut reverb_right = 0;
+        for (i, voice) in self.voices.iter_mut().enumerate() {
+            let sample = voice.tick(&self.ram) as i32;
+            let voice_left = (sample * fixed_volume(voice.volume_left)) >> 15;
+            let voice_right = (sample * fixed_volume(voice.volume_right)) >> 15;
+            left += voice_left;
+            right += voice_right;
+            if self.reverb_voices.get_bit(i) {
+                reverb_left += voice_left;
+                reverb_right += voice_right;
+            }
+            if voice.reached_end {
+                self.voice_ended.set_bit(i, true);
+            }
+        }
+
+        // The CD input is consumed even while it's disabled, so it doesn't fall behind
+        if let Some((cd_left, cd_right)) = self.cd_input.pop_front() {
+            if self.spu_control.get_bit(0) {
+                let cd_left = (cd_left as i32 * self.cd_volume_left as i16 as i32) >> 15;
+                let cd_right = (cd_right as i32 * self.cd_volume_right as i16 as i32) >> 15;
+                left += cd_left;
+                right += cd_right;
+                if self.spu_control.get_bit(2) {
+                    reverb_left += cd_left;
+                    reverb_right += cd_right;
+                }
+            }
+        }
+
+        // Bit 7 only stops the reverb from writing its buffer, the output still plays
+        let (echo_left, echo_right) = self.reverb.tick(
+            &mut self.ram,
+            (clamp_sample(reverb_left), clamp_sample(reverb_right)),
+            self.spu_control.get_bit(7),
+            self.reverb_volume,
+        );
+        left += echo_left;
+        right += echo_right;
+
+        let left = (clamp_sample(left) * fixed_volume(self.main_volume_left)) >> 15;
+        let right = (clamp_sample(right) * fixed_volume(self.main_volume_right)) >> 15;
+
+        let output = if self.spu_control.get_bit(15) && self.spu_control.get_bit(14) {
+            (left as i16, right as i16)
+        } else {
+            (0, 0)
+        };
+
+        if self.samples.len() >= SAMPLE_BUFFER_SIZE {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(output);
+    }
+
+    /// Sound RAM, for filling it at power on
+    pub fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+    pub fn has_samples(&self) -> bool {
+        !self.samples.is_empty()
+    }
+
+    /// Takes all of the audio generated since the last call, as 44100Hz stereo samples
+    pub fn drain_samples(&mut self) -> Vec<(i16, i16)> {
+        self.samples.drain(..).collect()
+    }
+}
+
+fn clamp_sample(sample: i32) -> i32 {
+    sample.clamp(i16::MIN as i32, i16::MAX as i32)
+}
+
+#[cfg(test)]
+mod spu_tests {
+    use super::*;
+
+    fn run_samples(spu: &mut Spu, count: u32) -> Vec<(i16, i16)> {
+        for _ in 0..count * CYCLES_PER_SAMPLE {
+            spu.execute_cycle();
+        }
+        spu.drain_samples()
+    }
+
+    fn setup_voice(spu: &mut Spu) {
+        // A looping block of full scale positive samples at address 0x1000
+        spu.ram[0x1000] = 0x00;
+        spu.ram[0x1001] = adpcm::FLAG_LOOP_START | adpcm::FLAG_LOOP_END | adpcm::FLAG_LOOP_REPEAT;
+        for byte in spu.ram[0x1002..0x1010].iter_mut() {
+            *byte = 0x77;
+        }
+
+        spu.write_half_word(0x1F801DAA, 0xC000);
+        spu.write_half_word(0x1F801D80, 0x3FFF);
+        spu.write_half_word(0x1F801D82, 0x3FFF);
+        spu.write_half_word(0x1F801C00, 0x3FFF);
+        spu.write_half_word(0x1F801C02, 0x1FFF);
+        spu.write_half_word(0x1F801C04, 0x1000);
+        spu.write_half_word(0x1F801C06, 0x1000 / 8);
+        spu.write_half_word(0x1F801C08, 0x000F); // Fastest attack, sustain at max
+        spu.write_half_word(0x1F801C0A, 0x1FC0); // Hold sustain
+    }
+
+    #[test]
+    fn test_key_on_produces_audio() {
+        let mut spu = Spu::new();
+        setup_voice(&mut spu);
+        assert!(run_samples(&mut spu, 10).iter().all(|s| *s == (0, 0)));
+
+        spu.write_half_word(0x1F801D88, 0x1);
+        let samples = run_samples(&mut spu, 100);
+        assert_eq!(samples.len(), 100);
+        let (left, right) = samples[99];
+        assert!(left > 0x3000);
+        // Right voice volume is half of the left
+        assert!((right as i32 - left as i32 / 2).abs() < 0x100);
+        assert!(spu.read_half_word(0x1F801D9C).get_bit(0));
+
+        spu.write_half_word(0x1F801D8C, 0x1);
+        let samples = run_samples(&mut spu, 1000);
+        assert_eq!(*samples.last().unwrap(), (0, 0));
+    }
+
+    #[test]
+    fn test_reverb_writes_work_area() {
+        let mut spu = Spu::new();
+        setup_voice(&mut spu);
+        spu.write_half_word(0x1F801DA2, 0xE000); // Work area at 0x70000
+        spu.write_half_word(0x1F801DC4, 0x4000); // vIIR
+        spu.write_half_word(0x1F801DD4, 0x0100); // mLSAME
+        spu.write_half_word(0x1F801DD6, 0x0200); // mRSAME
+        spu.write_half_word(0x1F801DFC, 0x7FFF); // vLIN
+        spu.write_half_word(0x1F801DFE, 0x7FFF); // vRIN
+        spu.write_half_word(0x1F801D98, 0x1);
+        assert_eq!(spu.read_half_word(0x1F801DD4), 0x0100);
+        spu.write_half_word(0x1F801D88, 0x1);
+
+        // The buffer is left alone until reverb is enabled in SPUCNT
+        run_samples(&mut spu, 32);
+        assert!(spu.ram[0x70000..].iter().all(|b| *b == 0));
+
+        spu.write_half_word(0x1F801DAA, 0xC080);
+        run_samples(&mut spu, 32);
+        // The same side reflection lands at mLSAME, 0x800 bytes past the buffer position
+        assert!(spu.ram[0x70800..0x70900].iter().any(|b| *b != 0));
+        assert!(spu.ram[0x1010..0x70000].iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn test_muted_output_is_silent() {
+        let mut spu = Spu::new();
+        setup_voice(&mut spu);
+        spu.write_half_word(0x1F801DAA, 0x8000);
+        spu.write_half_word(0x1F801D88, 0x1);
+        assert!(run_samples(&mut spu, 100).iter().all(|s| *s == (0, 0)));
+        // The voice still plays while muted
+        assert!(spu.read_half_word(0x1F801C0C) > 0);
+    }
+
+    #[test]
+    fn test_manual_transfer() {
+        let mut spu = Spu::new();
+        spu.write_half_word(0x1F801DA6, 0x200);
+        spu.write_half_word(0x1F801DA8, 0x1234);
+        spu.write_half_word(0x1F801DA8, 0xABCD);
+        // Nothing reaches RAM until the transfer mode is set to manual write
+        assert_eq!(spu.ram[0x1000], 0);
+
+        spu.write_half_word(0x1F801DAA, 0xC010);
+        assert_eq!(&spu.ram[0x1000..0x1004], &[0x34, 0x12, 0xCD, 0xAB]);
+        assert_eq!(spu.read_half_word(0x1F801DAE) & 0x3F, 0x10);
+        assert!(!spu.read_half_word(0x1F801DAE).get_bit(10));
+    }
+
+    #[test]
+    fn test_dma_transfer() {
+        let mut spu = Spu::new();
+        spu.write_half_word(0x1F801DA6, 0x10);
+        spu.write_half_word(0x1F801DAA, 0xC020);
+        assert!(spu.read_half_word(0x1F801DAE).get_bit(8));
+        spu.dma_write_word(0xDEADBEEF);
+        spu.dma_write_word(0x01234567);
+
+        spu.write_half_word(0x1F801DA6, 0x10);
+        spu.write_half_word(0x1F801DAA, 0xC030);
+        assert!(spu.read_half_word(0x1F801DAE).get_bit(9));
+        assert_eq!(spu.dma_read_word(), 0xDEADBEEF);
+        assert_eq!(spu.dma_read_word(), 0x01234567);
+    }
+
+    #[test]
+    fn test_sample_buffer_drops_old_samples() {
+        let mut spu = Spu::new();
+        let samples = run_samples(&mut spu, SAMPLE_BUFFER_SIZE as u32 + 10);
+        assert_eq!(samples.len(), SAMPLE_BUFFER_SIZE);
+        assert!(spu.drain_samples().is_empty());
+    }
+
+    #[test]
+    fn test_cd_audio_input() {
+        let mut spu = Spu::new();
+        spu.write_half_word(0x1F801DAA, 0xC000);
+        spu.write_half_word(0x1F801D80, 0x3FFF);
+        spu.write_half_word(0x1F801D82, 0x3FFF);
+        spu.write_half_word(0x1F801DB0, 0x7FFF);
+        spu.write_half_word(0x1F801DB2, 0x4000);
+        spu.push_cd_audio(&[(0x4000, 0x4000); 2]);
+
+        // CD audio is disabled in SPUCNT
+        assert_eq!(run_samples(&mut spu, 1), vec![(0, 0)]);
+        spu.write_half_word(0x1F801DAA, 0xC001);
+        let (left, right) = run_samples(&mut spu, 1)[0];
+        assert!((left as i32 - 0x3FFF).abs() < 4);
+        assert!((right as i32 - 0x2000).abs() < 4);
+        assert_eq!(run_samples(&mut spu, 1), vec![(0, 0)]);
+    }
+
+    #[test]
+    fn test_cd_and_main_volume_scale_the_mix() {
+        let mut spu = Spu::new();
+        spu.write_half_word(0x1F801DAA, 0xC001);
+        spu.write_half_word(0x1F801D80, 0x3FFF);
+        spu.write_half_word(0x1F801D82, 0x3FFF);
+        spu.write_half_word(0x1F801DB0, 0x7FFF);
+        spu.write_half_word(0x1F801DB2, 0x7FFF);
+        spu.push_cd_audio(&[(0x7FFF, -0x6000)]);
+        let (full_left, full_right) = run_samples(&mut spu, 1)[0];
+
+        spu.write_half_word(0x1F801DB0, 0x4000);
+        spu.write_half_word(0x1F801DB2, 0x4000);
+        spu.push_cd_audio(&[(0x7FFF, -0x6000)]);
+        let (half_left, half_right) = run_samples(&mut spu, 1)[0];
+        assert!((full_left as i32 / 2 - half_left as i32).abs() <= 1);
+        assert!((full_right as i32 / 2 - half_right as i32).abs() <= 1);
+
+        // Main volume scales the final mix the same way
+        spu.write_half_word(0x1F801D80, 0x2000);
+        spu.push_cd_audio(&[(0x7FFF, 0)]);
+        let (left, _) = run_samples(&mut spu, 1)[0];
+        assert!((half_left as i32 / 2 - left as i32).abs() <= 1);
+    }
+}
@@ -0,0 +1,191 @@
+use super::{clamp_sample, RAM_SIZE};
+
+/// Registers 0x1F801DC0-0x1F801DFF. Addresses and offsets are in 8 byte units, like mBASE
+const NUM_REGISTERS: usize = 32;
+
+const D_APF1: usize = 0;
+const D_APF2: usize = 1;
+const V_IIR: usize = 2;
+const V_COMB1: usize = 3;
+const V_WALL: usize = 7;
+const V_APF1: usize = 8;
+const V_APF2: usize = 9;
+const M_LSAME: usize = 10;
+const M_RSAME: usize = 11;
+const M_LCOMB1: usize = 12;
+const M_RCOMB1: usize = 13;
+const M_LCOMB2: usize = 14;
+const M_RCOMB2: usize = 15;
+const D_LSAME: usize = 16;
+const D_RSAME: usize = 17;
+const M_LDIFF: usize = 18;
+const M_RDIFF: usize = 19;
+const M_LCOMB3: usize = 20;
+const M_RCOMB3: usize = 21;
+const M_LCOMB4: usize = 22;
+const M_RCOMB4: usize = 23;
+const D_LDIFF: usize = 24;
+const D_RDIFF: usize = 25;
+const M_LAPF1: usize = 26;
+const M_RAPF1: usize = 27;
+const M_LAPF2: usize = 28;
+const M_RAPF2: usize = 29;
+const V_LIN: usize = 30;
+const V_RIN: usize = 31;
+
+/// Which registers one side of the reverb uses. The other side mirrors them
+struct Side {
+    m_same: usize,
+    d_same: usize,
+    m_diff: usize,
+    /// The different side reflection reads the opposite channel's buffer
+    d_diff: usize,
+    m_comb: [usize; 4],
+    m_apf1: usize,
+    m_apf2: usize,
+    v_in: usize,
+}
+
+const LEFT: Side = Side {
+    m_same: M_LSAME,
+    d_same: D_LSAME,
+    m_diff: M_LDIFF,
+    d_diff: D_RDIFF,
+    m_comb: [M_LCOMB1, M_LCOMB2, M_LCOMB3, M_LCOMB4],
+    m_apf1: M_LAPF1,
+    m_apf2: M_LAPF2,
+    v_in: V_LIN,
+};
+
+const RIGHT: Side = Side {
+    m_same: M_RSAME,
+    d_same: D_RSAME,
+    m_diff: M_RDIFF,
+    d_diff: D_LDIFF,
+    m_comb: [M_RCOMB1, M_RCOMB2, M_RCOMB3, M_RCOMB4],
+    m_apf1: M_RAPF1,
+    m_apf2: M_RAPF2,
+    v_in: V_RIN,
+};
+
+/// The reverb unit. Its echo buffer is a ring running from mBASE to the end of SPU RAM,
+/// and every buffer address is relative to a position that moves along it each step
+pub struct Reverb {
+    pub registers: [u16; NUM_REGISTERS],
+    /// mBASE, set through 0x1F801DA2
+    base: u16,
+    /// Byte address the buffer offsets are relative to
+    current_address: usize,
+    /// The reverb runs at 22050Hz, so every other sample reuses the last output
+    odd_tick: bool,
+    output: (i32, i32),
+}
+
+impl Reverb {
+    pub fn new() -> Self {
+        Self {
+            registers: [0; NUM_REGISTERS],
+            base: 0,
+            current_address: 0,
+            odd_tick: false,
+            output: (0, 0),
+        }
+    }
+
+    pub fn base(&self) -> u16 {
+        self.base
+    }
+
+    pub fn set_base(&mut self, base: u16) {
+        self.base = base;
+        self.current_address = base as usize * 8;
+    }
+
+    /// Runs the filters on one stereo input sample and returns the reverb output, scaled by
+    /// the output volume. Nothing is written back to the buffer unless write_enabled is set
+    pub fn tick(&mut self, ram: &mut [u8], input: (i32, i32), write_enabled: bool, volume: u32) -> (i32, i32) {
+        self.odd_tick = !self.odd_tick;
+        if !self.odd_tick {
+            return self.output;
+        }
+
+        let left = self.process_side(ram, &LEFT, input.0, write_enabled);
+        let right = self.process_side(ram, &RIGHT, input.1, write_enabled);
+        self.output = (
+            multiply(left, volume as u16 as i16 as i32),
+            multiply(right, (volume >> 16) as u16 as i16 as i32),
+        );
+
+        let next = (self.current_address + 2) & (RAM_SIZE - 2);
+        self.current_address = next.max(self.base as usize * 8);
+        self.output
+    }
+
+    fn process_side(&self, ram: &mut [u8], side: &Side, input: i32, write_enabled: bool) -> i32 {
+        let input = multiply(input, self.volume(side.v_in));
+        let wall = self.volume(V_WALL);
+        let iir = self.volume(V_IIR);
+
+        if write_enabled {
+            // Same side, then different side reflection
+            for (m, d) in [(side.m_same, side.d_same), (side.m_diff, side.d_diff)].iter() {
+                let previous = self.read(ram, self.offset(*m) - 2);
+                let reflected = clamp_sample(input + multiply(self.read(ram, self.offset(*d)), wall) - previous);
+                self.write(ram, self.offset(*m), multiply(reflected, iir) + previous);
+            }
+        }
+
+        // Early echo from the four comb taps
+        let mut output = clamp_sample(
+            side.m_comb
+                .iter()
+                .enumerate()
+                .map(|(i, m)| multiply(self.read(ram, self.offset(*m)), self.volume(V_COMB1 + i)))
+                .sum(),
+        );
+
+        // Late reverb through the two all pass filters
+        for (m, d, v) in [(side.m_apf1, D_APF1, V_APF1), (side.m_apf2, D_APF2, V_APF2)].iter() {
+            let delayed = self.read(ram, self.offset(*m) - self.offset(*d));
+            output = clamp_sample(output - multiply(self.volume(*v), delayed));
+            if write_enabled {
+                self.write(ram, self.offset(*m), output);
+            }
+            output = clamp_sample(multiply(output, self.volume(*v)) + delayed);
+        }
+        output
+    }
+
+    fn volume(&self, register: usize) -> i32 {
+        self.registers[register] as i16 as i32
+    }
+
+    /// A buffer address register as a byte offset
+    fn offset(&self, register: usize) -> isize {
+        self.registers[register] as isize * 8
+    }
+
+    /// Where a byte offset from the current address lands, wrapping within the buffer
+    fn address(&self, offset: isize) -> usize {
+        let start = self.base as usize * 8;
+        let size = (RAM_SIZE - start) as isize;
+        let relative = (self.current_address - start) as isize + offset;
+        (start + relative.rem_euclid(size) as usize) & (RAM_SIZE - 2)
+    }
+
+    fn read(&self, ram: &[u8], offset: isize) -> i32 {
+        let address = self.address(offset);
+        (ram[address] as u16 | (ram[address + 1] as u16) << 8) as i16 as i32
+    }
+
+    fn write(&self, ram: &mut [u8], offset: isize, value: i32) {
+        let address = self.address(offset);
+        let value = clamp_sample(value) as i16 as u16;
+        ram[address] = value as u8;
+        ram[address + 1] = (value >> 8) as u8;
+    }
+}
+
+fn multiply(sample: i32, volume: i32) -> i32 {
+    (sample * volume) >> 15
+}
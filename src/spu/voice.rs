@@ -0,0 +1,132 @@
+use super::adpcm::{
+    decode_block, BLOCK_SIZE, FLAG_LOOP_END, FLAG_LOOP_REPEAT, FLAG_LOOP_START, SAMPLES_PER_BLOCK,
+};
+use super::adsr::{Adsr, AdsrPhase};
+
+/// Pitch of 0x1000 plays samples back at 44100Hz
+const PITCH_UNITY: u32 = 0x1000;
+
+pub struct Voice {
+    pub volume_left: u16,
+    pub volume_right: u16,
+    pub pitch: u16,
+    /// Addresses are in 8 byte units, like the registers
+    pub start_address: u16,
+    pub repeat_address: u16,
+    pub adsr: Adsr,
+
+    current_address: u32,
+    pitch_counter: u32,
+    samples: [i16; SAMPLES_PER_BLOCK],
+    sample_index: usize,
+    history: [i16; 2],
+    last_sample: i16,
+    current_sample: i16,
+    block_flags: u8,
+    /// Set when the voice reaches a block with the loop end flag
+    pub reached_end: bool,
+}
+
+impl Voice {
+    pub fn new() -> Self {
+        Self {
+            volume_left: 0,
+            volume_right: 0,
+            pitch: 0,
+            start_address: 0,
+            repeat_address: 0,
+            adsr: Adsr::new(),
+
+            current_address: 0,
+            pitch_counter: 0,
+            samples: [0; SAMPLES_PER_BLOCK],
+            sample_index: 0,
+            history: [0; 2],
+            last_sample: 0,
+            current_sample: 0,
+            block_flags: 0,
+            reached_end: false,
+        }
+    }
+
+    pub fn key_on(&mut self, ram: &[u8]) {
+        self.current_address = self.start_address as u32 * 8;
+        self.pitch_counter = 0;
+        self.history = [0; 2];
+        self.last_sample = 0;
+        self.current_sample = 0;
+        self.reached_end = false;
+        self.decode_next_block(ram);
+        self.adsr.key_on();
+    }
+
+    pub fn key_off(&mut self) {
+        self.adsr.key_off();
+    }
+
+    fn decode_next_block(&mut self, ram: &[u8]) {
+        let address = self.current_address as usize & (ram.len() - 1);
+        let block = &ram[address..address + BLOCK_SIZE];
+        self.block_flags = block[1];
+        if self.block_flags & FLAG_LOOP_START != 0 {
+            self.repeat_address = (address / 8) as u16;
+        }
+        self.samples = decode_block(block, &mut self.history);
+        self.sample_index = 0;
+    }
+
+    /// Moves on to the decoded sample after the last one, following loop flags at block boundaries
+    fn advance(&mut self, ram: &[u8]) {
+        self.last_sample = self.current_sample;
+        self.current_sample = self.samples[self.sample_index];
+        self.sample_index += 1;
+
+        if self.sample_index == SAMPLES_PER_BLOCK {
+            if self.block_flags & FLAG_LOOP_END != 0 {
+                self.reached_end = true;
+                self.current_address = self.repeat_address as u32 * 8;
+                if self.block_flags & FLAG_LOOP_REPEAT == 0 {
+                    // One shot sample, so go quiet
+                    self.adsr.phase = AdsrPhase::Off;
+                    self.adsr.level = 0;
+                }
+            } else {
+                self.current_address += BLOCK_SIZE as u32;
+            }
+            self.decode_next_block(ram);
+        }
+    }
+
+    /// Produces the next output sample at 44100Hz. The returned sample has the envelope applied,
+    /// but not the left/right volume
+    pub fn tick(&mut self, ram: &[u8]) -> i16 {
+        if self.adsr.phase == AdsrPhase::Off {
+            return 0;
+        }
+
+        self.pitch_counter += (self.pitch as u32).min(0x4000);
+        while self.pitch_counter >= PITCH_UNITY {
+            self.pitch_counter -= PITCH_UNITY;
+            self.advance(ram);
+        }
+
+        // Linear interpolation between the last two samples
+        let fraction = self.pitch_counter as i32;
+        let sample = self.last_sample as i32
+            + ((self.current_sample as i32 - self.last_sample as i32) * fraction) / PITCH_UNITY as i32;
+
+        let output = (sample * self.adsr.level as i32) >> 15;
+        self.adsr.tick();
+        output as i16
+    }
+}
+
+/// Fixed volume registers are 15 bits of magnitude with bit 15 clear. Sweeps aren't emulated,
+/// so they play at full volume
+pub fn fixed_volume(register: u16) -> i32 {
+    if register & 0x8000 == 0 {
+        ((register << 1) as i16) as i32
+    } else {
+        0x7FFF
+    }
+}
@@ -1,5 +1,6 @@
 use crate::cpu::{InterruptSource, R3000};
 use bit_field::BitField;
+use log::trace;
 
 #[derive(PartialEq, Debug)]
 enum Cause {
@@ -12,6 +13,8 @@ pub struct Timer {
     pub value: u32,
     pub target: u32,
     pub mode: u32,
+    /// One shot timers only fire once per mode write
+    irq_fired: bool,
 }
 
 impl Timer {
@@ -21,40 +24,52 @@ impl Timer {
             value: 0,
             target: 0,
             mode: 0,
+            irq_fired: false,
         }
     }
 
     pub fn increment(&mut self, cpu: &mut R3000) {
-        self.value += 1;
-        match self.mode.get_bit(3) {
-            true => {
-                if self.value as u16 >= self.target as u16 {
-                    self.value = 0;
-                    self.trigger(cpu, Cause::Full);
-                }
+        self.value = (self.value + 1) & 0xFFFF;
+
+        if self.value == self.target & 0xFFFF {
+            self.mode.set_bit(11, true);
+            if self.mode.get_bit(4) {
+                self.trigger(cpu, Cause::Target);
+            }
+            //Bit 3 resets the counter at the target instead of at 0xFFFF
+            if self.mode.get_bit(3) {
+                self.value = 0;
             }
-            false => {
-                if self.value as u16 >= 0xFFFF {
-                    self.value = 0;
-                    self.trigger(cpu, Cause::Target);
-                }
+        }
+
+        if self.value == 0xFFFF {
+            self.mode.set_bit(12, true);
+            if self.mode.get_bit(5) {
+                self.trigger(cpu, Cause::Full);
             }
         }
     }
 
     fn trigger(&mut self, cpu: &mut R3000, cause: Cause) {
-        match cause {
-            Cause::Full => self.mode.set_bit(12, true),
-            Cause::Target => self.mode.set_bit(11, true),
-        };
+        trace!("Timer {} triggered because of {:?}", self.timer_number, cause);
 
-        // println!("Timer {} triggered because of {:?}", self.timer_number, cause);
-        // println!("Timer {} mode is {:#X}", self.timer_number, self.mode);
+        //Bit 6 clear means one shot
+        if !self.mode.get_bit(6) && self.irq_fired {
+            return;
+        }
+        self.irq_fired = true;
+
+        //Bit 10 is the active low interrupt request. Pulse mode only pulls it low for a few
+        //cycles, so it always reads back as 1. Toggle mode flips it on every trigger
+        let fire = if self.mode.get_bit(7) {
+            let request = self.mode.get_bit(10);
+            self.mode.set_bit(10, !request);
+            request
+        } else {
+            true
+        };
 
-        if (self.mode.get_bit(4) == true && cause == Cause::Target)
-            || (self.mode.get_bit(5) == true && cause == Cause::Full)
-        {
-            println!("Firing timer interrupt");
+        if fire {
             let source = match self.timer_number {
                 0 => InterruptSource::TMR0,
                 1 => InterruptSource::TMR1,
@@ -73,8 +88,11 @@ impl Timer {
     }
 
     pub fn write_mode(&mut self, value: u32) {
-        self.mode = value;
+        //The reached flags are read only
+        self.mode = (value & 0x3FF) | (self.mode & 0x1800);
         self.mode.set_bit(10, true);
+        self.value = 0;
+        self.irq_fired = false;
     }
 }
 
@@ -158,17 +176,17 @@ impl TimerState {
 
     pub fn write_word(&mut self, addr: u32, val: u32) {
         match addr {
-            0x1F801100 => self.timer_0.value = val,
+            0x1F801100 => self.timer_0.value = val & 0xFFFF,
             0x1F801104 => self.timer_0.write_mode(val),
-            0x1F801108 => self.timer_0.target = val,
+            0x1F801108 => self.timer_0.target = val & 0xFFFF,
 
-            0x1F801110 => self.timer_1.value = val,
+            0x1F801110 => self.timer_1.value = val & 0xFFFF,
             0x1F801114 => self.timer_1.write_mode(val),
-            0x1F801118 => self.timer_1.target = val,
+            0x1F801118 => self.timer_1.target = val & 0xFFFF,
 
-            0x1F801120 => self.timer_2.value = val,
+            0x1F801120 => self.timer_2.value = val & 0xFFFF,
             0x1F801124 => self.timer_2.write_mode(val),
-            0x1F801128 => self.timer_2.target = val,
+            0x1F801128 => self.timer_2.target = val & 0xFFFF,
             _ => println!("Unknown timer address"),
         }
     }
@@ -211,32 +229,110 @@ impl TimerState {
     }
 
     pub fn write_half_word(&mut self, addr: u32, value: u16) {
-        //println!("Tried to write timer half");
+        //Timer registers are only 16 bits wide, so the upper half is ignored
         match addr {
-            0x1F801100 => self.timer_0.value &= (value as u32) & 0xFFFF0000,
-            0x1F801102 => self.timer_0.value &= ((value as u32) << 16) & 0xFFFF,
-
-            0x1F801104 => self.timer_0.write_mode(value as u32),
-            //0x1F801106 => self.timer_0.read_mode(value as u32),
-            0x1F801108 => self.timer_0.target = value as u32,
-            0x1F80110A => self.timer_0.target &= (value as u32) << 16,
-
-            0x1F801110 => self.timer_1.value = value as u32,
-            0x1F801112 => self.timer_1.value &= (value as u32) << 16,
-
-            0x1F801114 => self.timer_1.write_mode(value as u32),
-            //0x1F801116 => self.timer_1.read_mode(value as u32),
-            0x1F801118 => self.timer_1.target = value as u32,
-            //0x1F80111A => (self.timer_1.target >> 16) as u16,
-            0x1F801120 => self.timer_2.value = value as u32,
-            //0x1F801122 => (self.timer_2.value >> 16) as u16,
-            0x1F801124 => self.timer_2.write_mode(value as u32),
-            //0x1F801126 => (self.timer_2.read_mode() >> 16) as u16,
-            0x1F801128 => self.timer_2.target = value as u32,
-            //0x1F80112A => (self.timer_2.target >> 16) as u16,
+            0x1F801100 | 0x1F801104 | 0x1F801108 | 0x1F801110 | 0x1F801114 | 0x1F801118
+            | 0x1F801120 | 0x1F801124 | 0x1F801128 => self.write_word(addr, value as u32),
+            0x1F801102..=0x1F80112A => (),
             _ => {
                 println!("Half wrote unknown timer address {:#X}", addr)
             }
         }
     }
 }
+
+#[cfg(test)]
+mod timer_tests {
+    use super::*;
+    use crate::bios::{Bios, BIOS_SIZE};
+    use crate::bus::MainBus;
+    use crate::gpu::Gpu;
+    use crate::memory::Memory;
+
+    fn test_cpu() -> R3000 {
+        let bus = MainBus::new(Bios::new(vec![0; BIOS_SIZE]).unwrap(), Memory::new(), Gpu::new());
+        R3000::new(bus)
+    }
+
+    fn timer_irq(cpu: &R3000, timer: usize) -> bool {
+        cpu.main_bus.interrupts.status().get_bit(4 + timer)
+    }
+
+    fn ack(cpu: &mut R3000) {
+        cpu.main_bus.interrupts.write_word(crate::interrupts::I_STAT, 0);
+    }
+
+    #[test]
+    fn test_reset_at_target_repeat_irq() {
+        let mut cpu = test_cpu();
+        let mut timers = TimerState::new();
+        timers.write_word(0x1F801108, 10);
+        //Reset at target, irq on target, repeat
+        timers.write_word(0x1F801104, 0b0101_1000);
+
+        for _ in 0..9 {
+            timers.update_sys_clock(&mut cpu);
+        }
+        assert!(!timer_irq(&cpu, 0));
+        timers.update_sys_clock(&mut cpu);
+        assert!(timer_irq(&cpu, 0));
+        assert_eq!(timers.read_word(0x1F801100), 0);
+        assert!(timers.read_word(0x1F801104).get_bit(11));
+        assert!(!timers.read_word(0x1F801104).get_bit(11));
+
+        ack(&mut cpu);
+        for _ in 0..10 {
+            timers.update_sys_clock(&mut cpu);
+        }
+        assert!(timer_irq(&cpu, 0));
+    }
+
+    #[test]
+    fn test_one_shot_irq_on_overflow() {
+        let mut cpu = test_cpu();
+        let mut timers = TimerState::new();
+        timers.write_word(0x1F801118, 10);
+        //Hblank source, irq on 0xFFFF, one shot. Passing the target doesn't reset
+        timers.write_word(0x1F801114, 0b01_0010_0000);
+
+        timers.update_sys_clock(&mut cpu);
+        assert_eq!(timers.read_word(0x1F801110), 0);
+
+        for _ in 0..0xFFFF {
+            timers.update_h_blank(&mut cpu);
+        }
+        assert!(timer_irq(&cpu, 1));
+        assert_eq!(timers.read_word(0x1F801114) & 0x1800, 0x1800);
+
+        ack(&mut cpu);
+        timers.update_h_blank(&mut cpu);
+        assert_eq!(timers.read_word(0x1F801110), 0);
+        for _ in 0..0xFFFF {
+            timers.update_h_blank(&mut cpu);
+        }
+        assert!(!timer_irq(&cpu, 1));
+    }
+
+    #[test]
+    fn test_sys_div_8_source() {
+        let mut cpu = test_cpu();
+        let mut timers = TimerState::new();
+        timers.write_word(0x1F801128, 2);
+        //Sysclock / 8, irq on target, toggle, repeat
+        timers.write_word(0x1F801124, 0b10_1101_0000);
+
+        timers.update_sys_clock(&mut cpu);
+        timers.update_sys_div_8(&mut cpu);
+        timers.update_sys_div_8(&mut cpu);
+        assert!(timer_irq(&cpu, 2));
+        assert!(!timers.read_word(0x1F801124).get_bit(10));
+
+        //The next toggle sets the request bit back without an interrupt
+        ack(&mut cpu);
+        timers.write_word(0x1F801120, 0);
+        timers.update_sys_div_8(&mut cpu);
+        timers.update_sys_div_8(&mut cpu);
+        assert!(!timer_irq(&cpu, 2));
+        assert!(timers.read_word(0x1F801124).get_bit(10));
+    }
+}